@@ -15,15 +15,17 @@ mod server;
 
 struct Hello {
     c: hello::Client,
+    ctx: ipc::Context,
 }
 
 impl Hello {
     pub fn new(
-        _descriptor: ipc::Descriptor,
+        descriptor: ipc::Descriptor,
         _local: &tokio::task::LocalSet,
     ) -> Result<Box<dyn ipc::Handler>> {
         Ok(Box::new(Self {
             c: capnp_rpc::new_client(HelloServer {}),
+            ctx: descriptor.context().clone(),
         }) as Box<dyn ipc::Handler>)
     }
 }
@@ -32,8 +34,8 @@ impl ipc::Handler for Hello {
     fn handle(
         &self,
         network: twoparty::VatNetwork<tokio_util::compat::Compat<tokio::net::tcp::OwnedReadHalf>>,
-    ) -> RpcSystem<Side> {
-        RpcSystem::new(Box::new(network), Some(self.c.clone().client))
+    ) -> Result<RpcSystem<Side>> {
+        Ok(ipc::server_rpc_system(&self.ctx, network, self.c.clone().client))
     }
 }
 
@@ -82,6 +84,15 @@ struct Args {
     #[clap(long, help = "The socket is passed on the given file descriptor")]
     pub socket: Option<usize>,
 
+    #[clap(
+        long,
+        help = "Read the out-of-band cookie from the given file descriptor"
+    )]
+    pub cookie_fd: Option<i32>,
+
+    #[clap(long, help = "Signal readiness on the given file descriptor")]
+    pub ready_fd: Option<i32>,
+
     #[clap(long, help = "Whether to run as client")]
     pub client: bool,
 }
@@ -97,11 +108,27 @@ fn main() -> Result<()> {
         config.set_ephemeral();
     }
 
+    #[cfg(unix)]
+    if let Some(fd) = args.cookie_fd {
+        use std::os::unix::io::{FromRawFd, OwnedFd};
+        // Safety: the client that started us passed us the file
+        // descriptor, and we take ownership.
+        config = config.cookie_fd(unsafe { OwnedFd::from_raw_fd(fd) })?;
+    }
+
+    #[cfg(unix)]
+    if let Some(fd) = args.ready_fd {
+        use std::os::unix::io::{FromRawFd, OwnedFd};
+        // Safety: the client that started us passed us the file
+        // descriptor, and we take ownership.
+        config.set_ready_fd(unsafe { OwnedFd::from_raw_fd(fd) });
+    }
+
     let c = config.build()?;
 
     let desc = ipc::Descriptor::new(
         &c,
-        c.home().join("ipc-standalone.cookie"),
+        ipc::rendezvous_path(&c, "ipc-standalone"),
         std::env::current_exe()?,
         Hello::new,
     );
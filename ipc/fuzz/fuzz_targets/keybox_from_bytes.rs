@@ -0,0 +1,61 @@
+#![no_main]
+
+use libfuzzer_sys::{Corpus, fuzz_target};
+
+use sequoia_ipc::keybox::{Keybox, KeyboxRecord};
+
+fuzz_target!(|data: &[u8]| -> Corpus {
+    let kbx = match Keybox::from_bytes(data) {
+        Ok(kbx) => kbx,
+        Err(_) => return Corpus::Reject,
+    };
+
+    let mut keep = false;
+    for record in kbx {
+        let record = match record {
+            Ok(record) => record,
+            Err(_) => continue,
+        };
+        keep = true;
+
+        match record {
+            KeyboxRecord::Header(h) => {
+                let _ = (h.flags(), h.check_magic(), h.created_at(),
+                         h.last_maintained());
+            }
+            KeyboxRecord::OpenPGP(r) => {
+                let _ = (r.flags(), r.blob_flags(), r.metadata_section());
+                if let Ok(keys) = r.keys() {
+                    for key in keys {
+                        let _ = (key.fingerprint(), key.keyid_offset(),
+                                 key.flags());
+                    }
+                }
+                let _ = r.user_ids();
+                let _ = r.signature_expirations();
+                let _ = (r.ownertrust(), r.all_validity(), r.created_at(),
+                         r.recorded_at(), r.recheck_after());
+                let _ = r.data_section();
+                let _ = r.cert();
+            }
+            _ => (),
+        }
+    }
+
+    // None of these may panic or loop forever on malformed input.
+    if let Ok(kbx) = Keybox::from_bytes(data) {
+        let _ = kbx.verify();
+    }
+    if let Ok(kbx) = Keybox::from_bytes(data) {
+        let _ = kbx.duplicates();
+    }
+    if let Ok(kbx) = Keybox::from_bytes(data) {
+        let _ = kbx.find_by_userid("example").count();
+    }
+
+    if keep {
+        Corpus::Keep
+    } else {
+        Corpus::Reject
+    }
+});
@@ -0,0 +1,50 @@
+#![no_main]
+
+use libfuzzer_sys::{Corpus, fuzz_target};
+
+use sequoia_ipc::{
+    ConnectPlan,
+    Context,
+    Descriptor,
+    DescriptorBuilder,
+    Handler,
+    MemoryRendezvous,
+    Rendezvous,
+};
+
+fn unused_factory(_: Descriptor, _: &tokio::task::LocalSet)
+                  -> anyhow::Result<Box<dyn Handler>>
+{
+    unreachable!("the server is never started")
+}
+
+fuzz_target!(|data: &[u8]| -> Corpus {
+    // The first byte selects whether the cookie is passed out of
+    // band, in which case the rendezvous point only holds the
+    // address.
+    let (oob, rendezvous) = match data.split_first() {
+        Some((first, rest)) => (first & 1 == 1, rest),
+        None => return Corpus::Reject,
+    };
+
+    let mut config = Context::configure()
+        .home("/nonexistent")
+        .lib("/nonexistent");
+    if oob {
+        config = config.out_of_band_cookie([0; 32]);
+    }
+    let ctx = config.build().expect("no I/O");
+
+    let backend = MemoryRendezvous::new();
+    backend.write(rendezvous).expect("in memory");
+    let descriptor = DescriptorBuilder::new(
+        &ctx, "/nonexistent/rendezvous".into(),
+        "/nonexistent/server".into(), unused_factory)
+        .rendezvous(backend)
+        .build();
+
+    match descriptor.plan_connect() {
+        Ok(ConnectPlan::Connect(_)) => Corpus::Keep,
+        _ => Corpus::Reject,
+    }
+});
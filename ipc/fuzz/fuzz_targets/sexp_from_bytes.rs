@@ -0,0 +1,28 @@
+#![no_main]
+
+use libfuzzer_sys::{Corpus, fuzz_target};
+
+use sequoia_ipc::sexp::{Sexp, SexpRef};
+
+fuzz_target!(|data: &[u8]| -> Corpus {
+    let advanced = Sexp::from_bytes(data);
+    let canonical = SexpRef::from_bytes(data).map(|s| s.to_owned());
+
+    // Expressions read from a stream must be canonical.
+    let mut reader = data;
+    while let Ok(Some(sexp)) = Sexp::read_canonical(&mut reader) {
+        let mut buf = Vec::new();
+        sexp.serialize(&mut buf).expect("serializing to a vector");
+        assert_eq!(SexpRef::from_bytes(&buf).expect("canonical").to_owned(),
+                   sexp);
+    }
+
+    match (advanced, canonical) {
+        (Err(_), Err(_)) => Corpus::Reject,
+        (advanced, canonical) => {
+            // Formatting recurses into the lists.
+            let _ = format!("{:?} {:?}", advanced.ok(), canonical.ok());
+            Corpus::Keep
+        }
+    }
+});
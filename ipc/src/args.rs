@@ -0,0 +1,543 @@
+//! The arguments passed to external servers.
+
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+
+use crate::core::Context;
+use crate::Result;
+
+/// The arguments passed to an external server.
+///
+/// When a client starts an external server, it passes the server's
+/// configuration on the command line.  `ServerArgs` is used on both
+/// ends: [`Descriptor`] serializes them using [`ServerArgs::to_args`],
+/// and the server parses them using [`ServerArgs::parse`] (or
+/// [`Server::context`], if it only needs the [`Context`]).
+///
+/// The command line looks like this:
+///
+/// ```text
+/// --home <HOMEDIR> --lib <LIBDIR> --ephemeral true|false
+///     [--socket <N>] [--cookie-fd <FD>] [--ready-fd <FD>]
+///     [--fd <NAME>=<FD>]... [--<NAME> <VALUE>]...
+/// ```
+///
+/// Additional arguments can be added using
+/// [`DescriptorBuilder::arg`].  On Unix, additional file descriptors
+/// can be passed using `DescriptorBuilder::pass_fd`.  Elsewhere,
+/// `--fd` is rejected.
+///
+/// [`Descriptor`]: crate::Descriptor
+/// [`DescriptorBuilder::arg`]: crate::DescriptorBuilder::arg()
+/// [`Server::context`]: crate::Server::context()
+///
+/// # Examples
+///
+/// ```
+/// # use sequoia_ipc::{Context, Result, ServerArgs};
+/// # fn main() -> Result<()> {
+/// use std::ffi::OsStr;
+///
+/// let ctx = Context::configure().ephemeral().build()?;
+/// let args = ServerArgs::new(&ctx).arg("log-target", "syslog")?;
+///
+/// let parsed = ServerArgs::parse(args.to_args())?;
+/// assert_eq!(parsed, args);
+/// assert_eq!(parsed.home(), ctx.home());
+/// assert_eq!(parsed.get("log-target"), Some(OsStr::new("syslog")));
+/// # Ok(()) }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerArgs {
+    home: PathBuf,
+    lib: PathBuf,
+    ephemeral: bool,
+    pub(crate) socket: Option<usize>,
+    cookie_fd: Option<i32>,
+    ready_fd: Option<i32>,
+    #[cfg(unix)]
+    fds: Vec<(String, i32)>,
+    extra: Vec<(String, OsString)>,
+}
+
+/// The names of the arguments that are not passed through to the
+/// server.
+const RESERVED: &[&str] =
+    &["home", "lib", "ephemeral", "socket", "cookie-fd", "ready-fd", "fd"];
+
+impl ServerArgs {
+    /// Returns the arguments for a server sharing the given context.
+    pub fn new(ctx: &Context) -> Self {
+        ServerArgs {
+            home: ctx.home().to_path_buf(),
+            lib: ctx.lib().to_path_buf(),
+            ephemeral: ctx.ephemeral(),
+            socket: None,
+            cookie_fd: None,
+            ready_fd: None,
+            #[cfg(unix)]
+            fds: Vec::new(),
+            extra: Vec::new(),
+        }
+    }
+
+    /// Adds an additional argument.
+    ///
+    /// It is passed as `--<name> <value>`.  `name` must consist of
+    /// ASCII alphanumeric characters and dashes, must not start with
+    /// a dash, and must not be one of the arguments listed in
+    /// [`ServerArgs`].  If the argument is already present, its
+    /// value is replaced.
+    pub fn arg<S: AsRef<OsStr>>(mut self, name: &str, value: S)
+                                -> Result<Self>
+    {
+        self.set_arg(name, value)?;
+        Ok(self)
+    }
+
+    /// Adds an additional argument.
+    ///
+    /// See [`ServerArgs::arg`].  Returns the old value, if any.
+    pub fn set_arg<S: AsRef<OsStr>>(&mut self, name: &str, value: S)
+                                    -> Result<Option<OsString>>
+    {
+        check_name(name)?;
+        let value = value.as_ref().to_os_string();
+        if let Some((_, v)) = self.extra.iter_mut().find(|(n, _)| n == name) {
+            Ok(Some(std::mem::replace(v, value)))
+        } else {
+            self.extra.push((name.into(), value));
+            Ok(None)
+        }
+    }
+
+    /// Returns the directory containing shared state.
+    pub fn home(&self) -> &Path {
+        &self.home
+    }
+
+    /// Returns the directory containing backend servers.
+    pub fn lib(&self) -> &Path {
+        &self.lib
+    }
+
+    /// Returns whether the context is ephemeral.
+    pub fn ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+
+    /// Returns the file descriptor of the listening socket, if any.
+    pub fn socket(&self) -> Option<usize> {
+        self.socket
+    }
+
+    /// Returns the file descriptor carrying the out-of-band cookie,
+    /// if any.
+    ///
+    /// See [`Config::cookie_fd`].
+    ///
+    /// [`Config::cookie_fd`]: crate::Config::cookie_fd()
+    pub fn cookie_fd(&self) -> Option<i32> {
+        self.cookie_fd
+    }
+
+    /// Returns the file descriptor to signal readiness on, if any.
+    ///
+    /// See [`Config::ready_fd`].
+    ///
+    /// [`Config::ready_fd`]: crate::Config::ready_fd()
+    pub fn ready_fd(&self) -> Option<i32> {
+        self.ready_fd
+    }
+
+    /// Returns the additional file descriptor called `name`, if any.
+    ///
+    /// The server inherited the file descriptor, and owns it.  See
+    /// [`DescriptorBuilder::pass_fd`].
+    ///
+    /// [`DescriptorBuilder::pass_fd`]: crate::DescriptorBuilder::pass_fd()
+    #[cfg(unix)]
+    pub fn fd(&self, name: &str) -> Option<i32> {
+        self.fds.iter().find(|(n, _)| n == name).map(|(_, fd)| *fd)
+    }
+
+    /// Returns the additional file descriptors in the order they were
+    /// added.
+    #[cfg(unix)]
+    pub fn fds(&self) -> impl Iterator<Item = (&str, i32)> {
+        self.fds.iter().map(|(n, fd)| (n.as_str(), *fd))
+    }
+
+    /// Adds an additional file descriptor.
+    ///
+    /// If a file descriptor with that name is already present, it is
+    /// replaced.
+    #[cfg(unix)]
+    pub(crate) fn set_fd(&mut self, name: &str, fd: i32) {
+        if let Some((_, f)) = self.fds.iter_mut().find(|(n, _)| n == name) {
+            *f = fd;
+        } else {
+            self.fds.push((name.into(), fd));
+        }
+    }
+
+    /// Returns the value of an additional argument.
+    pub fn get(&self, name: &str) -> Option<&OsStr> {
+        self.extra.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_os_str())
+    }
+
+    /// Returns the additional arguments in the order they were
+    /// added.
+    pub fn extra(&self) -> impl Iterator<Item = (&str, &OsStr)> {
+        self.extra.iter().map(|(n, v)| (n.as_str(), v.as_os_str()))
+    }
+
+    /// Returns the command line.
+    ///
+    /// The program name is not included.
+    pub fn to_args(&self) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec![
+            "--home".into(), self.home.clone().into(),
+            "--lib".into(), self.lib.clone().into(),
+            "--ephemeral".into(), self.ephemeral.to_string().into(),
+        ];
+        if let Some(socket) = self.socket {
+            args.push("--socket".into());
+            args.push(socket.to_string().into());
+        }
+        if let Some(fd) = self.cookie_fd {
+            args.push("--cookie-fd".into());
+            args.push(fd.to_string().into());
+        }
+        if let Some(fd) = self.ready_fd {
+            args.push("--ready-fd".into());
+            args.push(fd.to_string().into());
+        }
+        #[cfg(unix)]
+        for (name, fd) in &self.fds {
+            args.push("--fd".into());
+            args.push(format!("{}={}", name, fd).into());
+        }
+        for (name, value) in &self.extra {
+            args.push(format!("--{}", name).into());
+            args.push(value.clone());
+        }
+        args
+    }
+
+    /// Parses a command line.
+    ///
+    /// The program name must not be included.
+    pub fn parse<I, S>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        fn once<T>(slot: &mut Option<T>, name: &str, value: T)
+                   -> Result<()>
+        {
+            if slot.replace(value).is_some() {
+                return Err(anyhow!("--{} given more than once", name));
+            }
+            Ok(())
+        }
+
+        fn number<T: std::str::FromStr>(name: &str, value: &OsStr)
+                                        -> Result<T>
+        {
+            value.to_str().and_then(|v| v.parse().ok())
+                .ok_or_else(|| anyhow!(
+                    "Expected a number for --{}, got: {:?}", name, value))
+        }
+
+        let mut home = None;
+        let mut lib = None;
+        let mut ephemeral = None;
+        let mut socket = None;
+        let mut cookie_fd = None;
+        let mut ready_fd = None;
+        #[cfg(unix)]
+        let mut fds: Vec<(String, i32)> = Vec::new();
+        let mut extra: Vec<(String, OsString)> = Vec::new();
+
+        let mut args = args.into_iter().map(Into::into);
+        while let Some(flag) = args.next() {
+            let name = flag.to_str()
+                .and_then(|f| f.strip_prefix("--"))
+                .ok_or_else(|| anyhow!("Expected an option, got {:?}", flag))?
+                .to_string();
+            let value = args.next()
+                .ok_or_else(|| anyhow!("Missing value for --{}", name))?;
+
+            match name.as_str() {
+                "home" => once(&mut home, &name, PathBuf::from(value))?,
+                "lib" => once(&mut lib, &name, PathBuf::from(value))?,
+                "ephemeral" => {
+                    let e = value.to_str().and_then(|v| v.parse().ok())
+                        .ok_or_else(|| anyhow!(
+                            "Expected 'true' or 'false' for --ephemeral, \
+                             got: {:?}", value))?;
+                    once(&mut ephemeral, &name, e)?
+                },
+                "socket" =>
+                    once(&mut socket, &name, number(&name, &value)?)?,
+                "cookie-fd" =>
+                    once(&mut cookie_fd, &name, number(&name, &value)?)?,
+                "ready-fd" =>
+                    once(&mut ready_fd, &name, number(&name, &value)?)?,
+                // Elsewhere, "fd" is a reserved name, and rejected
+                // below.
+                #[cfg(unix)]
+                "fd" => {
+                    let (n, fd) = value.to_str()
+                        .and_then(|v| v.split_once('='))
+                        .ok_or_else(|| anyhow!(
+                            "Expected <NAME>=<FD> for --fd, got: {:?}", value))?;
+                    check_name(n)?;
+                    if fds.iter().any(|(other, _)| other == n) {
+                        return Err(anyhow!("--fd {} given more than once", n));
+                    }
+                    let fd = number(&name, OsStr::new(fd))?;
+                    fds.push((n.into(), fd));
+                },
+                _ => {
+                    check_name(&name)?;
+                    if extra.iter().any(|(n, _)| n == &name) {
+                        return Err(anyhow!("--{} given more than once", name));
+                    }
+                    extra.push((name, value));
+                },
+            }
+        }
+
+        Ok(ServerArgs {
+            home: home.ok_or_else(|| anyhow!("Missing --home"))?,
+            lib: lib.ok_or_else(|| anyhow!("Missing --lib"))?,
+            ephemeral: ephemeral.unwrap_or(false),
+            socket,
+            cookie_fd,
+            ready_fd,
+            #[cfg(unix)]
+            fds,
+            extra,
+        })
+    }
+
+    /// Parses the process's command line.
+    pub fn from_env() -> Result<Self> {
+        Self::parse(std::env::args_os().skip(1))
+    }
+
+    /// Returns a context for the server.
+    ///
+    /// If a cookie file descriptor was given, the cookie is read from
+    /// it.  The file descriptors named on the command line are owned
+    /// by the server, this takes ownership of them, and closes them
+    /// once they are no longer used.  Hence, this must be called only
+    /// once, and only with the server's own command line.
+    ///
+    /// The home and lib directories must exist.  Otherwise, the
+    /// server would silently operate on the wrong data.
+    pub fn context(&self) -> Result<Context> {
+        for (name, dir) in [("home", &self.home), ("lib", &self.lib)] {
+            if ! dir.is_dir() {
+                return Err(anyhow!("The server's {} directory {} does not \
+                                    exist or is not a directory",
+                                   name, dir.display()));
+            }
+        }
+
+        let mut cfg = Context::configure()
+            .home(&self.home).lib(&self.lib);
+        if self.ephemeral {
+            cfg.set_ephemeral();
+        }
+        if let Some(fd) = self.cookie_fd {
+            platform! {
+                unix => {
+                    cfg = cfg.cookie_fd(inherited_fd("cookie-fd", fd)?)?;
+                },
+                windows => {
+                    return Err(anyhow!("--cookie-fd {} is not supported \
+                                        on Windows", fd));
+                }
+            }
+        }
+        if let Some(fd) = self.ready_fd {
+            platform! {
+                unix => {
+                    cfg.set_ready_fd(inherited_fd("ready-fd", fd)?);
+                },
+                windows => {
+                    return Err(anyhow!("--ready-fd {} is not supported \
+                                        on Windows", fd));
+                }
+            }
+        }
+        cfg.build()
+    }
+}
+
+/// Takes ownership of the file descriptor `fd` passed using
+/// `--<name>`.
+///
+/// Standard input is the listening socket, see [`Server::serve`],
+/// and the standard streams are never passed this way, so they are
+/// rejected, as are file descriptors that are not open.
+///
+/// [`Server::serve`]: crate::Server::serve()
+#[cfg(unix)]
+fn inherited_fd(name: &str, fd: i32) -> Result<std::os::unix::io::OwnedFd> {
+    use std::os::unix::io::FromRawFd;
+
+    if fd <= 2 {
+        return Err(anyhow!("Invalid file descriptor for --{}: {}", name, fd));
+    }
+    // Safety: fcntl doesn't touch memory.
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(anyhow::Error::from(std::io::Error::last_os_error())
+                   .context(format!("Invalid file descriptor for --{}: {}",
+                                    name, fd)));
+    }
+    // Safety: the client that started us passed the file descriptor
+    // to us, and we take ownership, see [`ServerArgs::context`].
+    Ok(unsafe { std::os::unix::io::OwnedFd::from_raw_fd(fd) })
+}
+
+/// Checks that `name` can be used for an additional argument.
+pub(crate) fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('-')
+        || ! name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err(anyhow!("Invalid argument name {:?}", name));
+    }
+    if RESERVED.contains(&name) {
+        return Err(anyhow!("The argument name {:?} is reserved", name));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let ctx = Context::configure().ephemeral().build()?;
+        let mut args = ServerArgs::new(&ctx)
+            .arg("tls-cert", "/etc/cert.pem")?
+            .arg("log-target", "stderr")?;
+        args.socket = Some(0);
+        args.ready_fd = Some(7);
+        assert_eq!(args.set_arg("log-target", "syslog")?,
+                   Some("stderr".into()));
+
+        let parsed = ServerArgs::parse(args.to_args())?;
+        assert_eq!(parsed, args);
+        assert!(parsed.ephemeral());
+        assert_eq!(parsed.socket(), Some(0));
+        assert_eq!(parsed.cookie_fd(), None);
+        assert_eq!(parsed.ready_fd(), Some(7));
+        assert_eq!(parsed.extra().collect::<Vec<_>>(),
+                   vec![("tls-cert", OsStr::new("/etc/cert.pem")),
+                        ("log-target", OsStr::new("syslog"))]);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fds() -> Result<()> {
+        let ctx = Context::configure().ephemeral().build()?;
+        let mut args = ServerArgs::new(&ctx);
+        args.set_fd("log", 8);
+        args.set_fd("notify", 9);
+        args.set_fd("log", 10);
+
+        let parsed = ServerArgs::parse(args.to_args())?;
+        assert_eq!(parsed, args);
+        assert_eq!(parsed.fd("log"), Some(10));
+        assert_eq!(parsed.fd("missing"), None);
+        assert_eq!(parsed.fds().collect::<Vec<_>>(),
+                   vec![("log", 10), ("notify", 9)]);
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_command_line() -> Result<()> {
+        let args = ServerArgs::parse(
+            ["--home", "/h", "--lib", "/l", "--ephemeral", "false"])?;
+        assert_eq!(args.home(), Path::new("/h"));
+        assert_eq!(args.lib(), Path::new("/l"));
+        assert!(! args.ephemeral());
+        assert_eq!(args.socket(), None);
+        assert_eq!(args.extra().count(), 0);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn relative_directories() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let home = tmp.path().join("home");
+        std::fs::create_dir(&home)?;
+
+        // Home, relative to the current working directory.
+        let cwd = std::env::current_dir()?;
+        let mut relative = PathBuf::new();
+        for _ in cwd.ancestors().skip(1) {
+            relative.push("..");
+        }
+        relative.push(home.strip_prefix("/")?);
+        assert!(relative.is_relative());
+
+        let ctx = Context::configure()
+            .ephemeral().home(&relative).lib(tmp.path())
+            .build()?;
+        assert!(ctx.home().is_absolute());
+        assert_eq!(ctx.home().canonicalize()?, home.canonicalize()?);
+
+        // The server uses the same directory, even if its working
+        // directory is different.
+        let args = ServerArgs::parse(ServerArgs::new(&ctx).to_args())?;
+        let server = args.context()?;
+        assert_eq!(server.home(), ctx.home());
+        assert_eq!(server.lib(), ctx.lib());
+
+        // Servers refuse to use directories that don't exist.
+        let ctx = Context::configure()
+            .ephemeral().home(tmp.path().join("missing")).lib(tmp.path())
+            .build()?;
+        let args = ServerArgs::parse(ServerArgs::new(&ctx).to_args())?;
+        assert!(args.context().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid() -> Result<()> {
+        let ctx = Context::configure().ephemeral().build()?;
+        for name in ["", "-x", "a b", "home", "cookie-fd", "ready-fd", "fd"] {
+            assert!(ServerArgs::new(&ctx).arg(name, "v").is_err(), "{:?}", name);
+        }
+
+        for args in [
+            &["--home", "/h"][..],
+            &["--lib", "/l"],
+            &["--home", "/h", "--lib", "/l", "--ephemeral"],
+            &["--home", "/h", "--lib", "/l", "--ephemeral", "yes"],
+            &["--home", "/h", "--lib", "/l", "--socket", "x"],
+            &["--home", "/h", "--lib", "/l", "--home", "/h"],
+            &["--home", "/h", "--lib", "/l", "--x", "1", "--x", "2"],
+            &["--home", "/h", "--lib", "/l", "stray", "1"],
+            &["--home", "/h", "--lib", "/l", "--fd", "3"],
+            &["--home", "/h", "--lib", "/l", "--fd", "log=x"],
+            &["--home", "/h", "--lib", "/l", "--fd", "-x=3"],
+            &["--home", "/h", "--lib", "/l", "--fd", "a=3", "--fd", "a=4"],
+        ] {
+            assert!(ServerArgs::parse(args.iter().copied()).is_err(),
+                    "{:?}", args);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,275 @@
+//! Compressed connections.
+//!
+//! If both peers agree to (see [`Config::compression`]), the data
+//! following the handshake is compressed using raw deflate.  Every
+//! flush is a deflate sync flush, so that the peer can decompress
+//! each Cap'n Proto message as soon as it arrives.
+//!
+//! Deflate is used, and not, say, zstd, because it is implemented in
+//! pure Rust by flate2's default backend, so it doesn't need a C
+//! toolchain, and works on every platform we support.  Cap'n Proto
+//! messages are small, and compressed one flush at a time, so a
+//! better compression ratio on large inputs would hardly matter.
+//! Other algorithms can be added later, the peers negotiate which
+//! one to use.
+//!
+//! [`Config::compression`]: crate::Config::compression()
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use flate2::{
+    Compress,
+    Decompress,
+    FlushCompress,
+    FlushDecompress,
+    Status,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The size of the buffers.
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Decompresses the data read from `R`.
+pub(crate) struct DeflateReader<R> {
+    inner: R,
+    decompress: Decompress,
+    /// Compressed data read from `inner`.
+    buffer: Box<[u8]>,
+    pos: usize,
+    len: usize,
+    eof: bool,
+}
+
+impl<R> DeflateReader<R> {
+    /// Returns a reader decompressing the data read from `inner`.
+    pub(crate) fn new(inner: R) -> Self {
+        DeflateReader {
+            inner,
+            decompress: Decompress::new(false),
+            buffer: vec![0; BUFFER_SIZE].into_boxed_slice(),
+            pos: 0,
+            len: 0,
+            eof: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DeflateReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>,
+                 out: &mut ReadBuf<'_>)
+                 -> Poll<io::Result<()>>
+    {
+        let this = self.get_mut();
+        if out.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            if this.pos == this.len && ! this.eof {
+                let mut buf = ReadBuf::new(&mut this.buffer);
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf))?;
+                this.len = buf.filled().len();
+                this.pos = 0;
+                this.eof = this.len == 0;
+            }
+
+            let total_in = this.decompress.total_in();
+            let total_out = this.decompress.total_out();
+            let status = this.decompress.decompress(
+                &this.buffer[this.pos..this.len], out.initialize_unfilled(),
+                FlushDecompress::None)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let consumed = (this.decompress.total_in() - total_in) as usize;
+            let produced = (this.decompress.total_out() - total_out) as usize;
+            this.pos += consumed;
+            out.advance(produced);
+
+            if produced > 0 || status == Status::StreamEnd {
+                return Poll::Ready(Ok(()));
+            }
+            if this.eof {
+                return Poll::Ready(if this.pos == this.len {
+                    // The peer closed the connection.
+                    Ok(())
+                } else {
+                    Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                       "truncated deflate stream"))
+                });
+            }
+            if consumed == 0 && this.pos < this.len {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "deflate stream makes no progress")));
+            }
+        }
+    }
+}
+
+/// Compresses the data written to `W`.
+pub(crate) struct DeflateWriter<W> {
+    inner: W,
+    compress: Compress,
+    /// Compressed data not yet written to `inner`.
+    pending: Vec<u8>,
+    written: usize,
+    /// Whether the sync flush has been emitted, and only has to be
+    /// written out.
+    flushed: bool,
+}
+
+impl<W> DeflateWriter<W> {
+    /// Returns a writer compressing the data written to `inner`.
+    pub(crate) fn new(inner: W) -> Self {
+        DeflateWriter {
+            inner,
+            compress: Compress::new(flate2::Compression::fast(), false),
+            pending: Vec::with_capacity(BUFFER_SIZE),
+            written: 0,
+            flushed: false,
+        }
+    }
+
+    /// Compresses `data` into the pending buffer.
+    fn compress(&mut self, data: &[u8], flush: FlushCompress)
+                -> io::Result<usize>
+    {
+        let total_in = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - total_in) as usize;
+            self.pending.reserve(BUFFER_SIZE);
+            let before = self.pending.len();
+            self.compress.compress_vec(&data[consumed..], &mut self.pending,
+                                       flush)
+                .map_err(io::Error::other)?;
+            // If the output filled the buffer, there may be more.
+            if self.pending.len() < self.pending.capacity()
+                || self.pending.len() == before
+            {
+                break;
+            }
+        }
+        Ok((self.compress.total_in() - total_in) as usize)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> DeflateWriter<W> {
+    /// Writes the pending compressed data to `inner`.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let n = ready!(Pin::new(&mut self.inner)
+                           .poll_write(cx, &self.pending[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for DeflateWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+                  -> Poll<io::Result<usize>>
+    {
+        let this = self.get_mut();
+        // Don't buffer without bound.
+        if this.pending.len() >= BUFFER_SIZE {
+            ready!(this.poll_drain(cx))?;
+        }
+        this.flushed = false;
+        let n = this.compress(buf, FlushCompress::None)?;
+        // Write what we have, but don't wait for it.
+        if let Poll::Ready(Err(err)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>)
+                  -> Poll<io::Result<()>>
+    {
+        let this = self.get_mut();
+        if ! this.flushed {
+            this.compress(&[], FlushCompress::Sync)?;
+            this.flushed = true;
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>)
+                     -> Poll<io::Result<()>>
+    {
+        let this = self.get_mut();
+        if ! this.flushed {
+            this.compress(&[], FlushCompress::Finish)?;
+            this.flushed = true;
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn roundtrip() -> io::Result<()> {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        rt.block_on(async {
+            let (client, server) = tokio::io::duplex(1024);
+            let mut writer = DeflateWriter::new(client);
+            let mut reader = DeflateReader::new(server);
+
+            // Compressible, and larger than the buffers.
+            let message = b"Hello, compressed world! ".repeat(4096);
+            let writing = async {
+                for chunk in message.chunks(1000) {
+                    writer.write_all(chunk).await?;
+                    writer.flush().await?;
+                }
+                writer.shutdown().await?;
+                Ok::<_, io::Error>(())
+            };
+            let mut received = Vec::new();
+            let reading = reader.read_to_end(&mut received);
+            let (written, read) = tokio::join!(writing, reading);
+            written?;
+            read?;
+            assert_eq!(received, message);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn flush_delivers() -> io::Result<()> {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        rt.block_on(async {
+            let (client, server) = tokio::io::duplex(1024);
+            let mut writer = DeflateWriter::new(client);
+            let mut reader = DeflateReader::new(server);
+
+            // Each flushed message can be read without closing the
+            // stream.
+            for message in [&b"ping"[..], b"pong"] {
+                writer.write_all(message).await?;
+                writer.flush().await?;
+                let mut buf = vec![0; message.len()];
+                reader.read_exact(&mut buf).await?;
+                assert_eq!(buf, message);
+            }
+            Ok(())
+        })
+    }
+}
@@ -19,10 +19,41 @@
 
 #![warn(missing_docs)]
 
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use capnp::message::ReaderOptions;
+use sequoia_openpgp::crypto::mem::Protected;
 
 use crate::Result;
 
+/// A random number generator shared by clones of a context.
+#[cfg(any(test, feature = "test-util"))]
+type SharedRng = Arc<Mutex<dyn rand::RngCore + Send>>;
+
+/// Records RPC calls, see [`Config::audit`].
+pub(crate) type AuditHook = Arc<dyn Fn(&crate::AuditRecord) + Send + Sync>;
+
+/// The method names of interfaces, by interface ID, see
+/// [`Config::audit_methods`].
+pub(crate) type AuditMethods = BTreeMap<u64, &'static [&'static str]>;
+
+/// The file descriptor servers signal readiness on, see
+/// [`Config::ready_fd`].
+///
+/// It is shared by all clones of the context, and taken by the first
+/// server built from one of them.
+#[cfg(unix)]
+type ReadyFd = Arc<Mutex<Option<std::os::unix::io::OwnedFd>>>;
+
+/// Resolves the home directory, see [`Config::default_home`].
+type DefaultHome = Arc<dyn Fn() -> Option<PathBuf> + Send + Sync>;
+
 /// A `Context` for Sequoia.
 ///
 /// # Examples
@@ -53,8 +84,32 @@ use crate::Result;
 /// ```
 pub struct Context {
     home: PathBuf,
+    home_mode: u32,
     lib: PathBuf,
+    rendezvous_prefix: String,
     ipc_policy: IPCPolicy,
+    reader_options: ReaderOptions,
+    cookie: Option<Protected>,
+    trace_id: Option<String>,
+    idle_timeout: Option<Duration>,
+    max_concurrent_calls: Option<usize>,
+    ready_timeout: Option<Duration>,
+    lock_timeout: Option<Duration>,
+    #[cfg(unix)]
+    ready_fd: Option<ReadyFd>,
+    debug_port: Option<u16>,
+    bind_address: IpAddr,
+    network_exposure: bool,
+    compression: bool,
+    internal_cookie_auth: bool,
+    keepalive: Option<(Duration, Duration, u32)>,
+    executable_override: bool,
+    server_log: bool,
+    #[cfg(any(test, feature = "test-util"))]
+    rng: Option<SharedRng>,
+    default_home: Option<DefaultHome>,
+    audit: Option<AuditHook>,
+    audit_methods: AuditMethods,
     ephemeral: bool,
     cleanup: bool,
 }
@@ -63,8 +118,32 @@ impl Clone for Context {
     fn clone(&self) -> Self {
         Context {
             home: self.home.clone(),
+            home_mode: self.home_mode,
             lib: self.lib.clone(),
+            rendezvous_prefix: self.rendezvous_prefix.clone(),
             ipc_policy: self.ipc_policy,
+            reader_options: self.reader_options,
+            cookie: self.cookie.clone(),
+            trace_id: self.trace_id.clone(),
+            idle_timeout: self.idle_timeout,
+            max_concurrent_calls: self.max_concurrent_calls,
+            ready_timeout: self.ready_timeout,
+            lock_timeout: self.lock_timeout,
+            #[cfg(unix)]
+            ready_fd: self.ready_fd.clone(),
+            debug_port: self.debug_port,
+            bind_address: self.bind_address,
+            network_exposure: self.network_exposure,
+            compression: self.compression,
+            internal_cookie_auth: self.internal_cookie_auth,
+            keepalive: self.keepalive,
+            executable_override: self.executable_override,
+            server_log: self.server_log,
+            #[cfg(any(test, feature = "test-util"))]
+            rng: self.rng.clone(),
+            default_home: self.default_home.clone(),
+            audit: self.audit.clone(),
+            audit_methods: self.audit_methods.clone(),
             ephemeral: self.ephemeral,
             cleanup: false, // Prevent cleanup.
         }
@@ -81,6 +160,48 @@ impl Drop for Context {
     }
 }
 
+/// The environment variable seeding [`Config::debug_port`].
+pub const DEBUG_PORT_VAR: &str = "SEQUOIA_IPC_DEBUG_PORT";
+
+/// Parses the value of [`DEBUG_PORT_VAR`].
+///
+/// Invalid values are ignored with a warning.
+fn parse_debug_port(value: &str) -> Option<u16> {
+    match value.trim().parse::<u16>() {
+        Ok(0) => None,
+        Ok(port) => Some(port),
+        Err(err) => {
+            tracing::warn!("Ignoring invalid {}={:?}: {}",
+                           DEBUG_PORT_VAR, value, err);
+            None
+        },
+    }
+}
+
+/// The environment variable seeding [`Config::server_log`].
+pub const SERVER_LOG_VAR: &str = "SEQUOIA_IPC_SERVER_LOG";
+
+/// Parses the value of [`SERVER_LOG_VAR`].
+///
+/// Any value except for the empty string and `0` enables the log.
+fn parse_server_log(value: &std::ffi::OsStr) -> bool {
+    ! value.is_empty() && value != "0"
+}
+
+/// The default mode of the home directory, see [`Config::home_mode`].
+pub(crate) const DEFAULT_HOME_MODE: u32 = 0o700;
+
+/// Returns the default home directory given the user's home.
+///
+/// Returns `None` if the user has no home, or if it is the root
+/// directory, which is what system users without a home directory
+/// commonly get.
+fn sequoia_home(user_home: Option<PathBuf>) -> Option<PathBuf> {
+    user_home
+        .filter(|home| home.is_absolute() && home.parent().is_some())
+        .map(|home| home.join(".sequoia"))
+}
+
 /// Returns $PREXIX at compile-time, or a reasonable default prefix.
 fn prefix() -> PathBuf {
     /* XXX: Windows support.  */
@@ -101,32 +222,289 @@ impl Context {
     pub fn configure() -> Config {
         Config(Context {
             home: PathBuf::from(""), // Defer computation of default.
+            home_mode: DEFAULT_HOME_MODE,
             lib: prefix().join("lib").join("sequoia"),
+            rendezvous_prefix: String::new(),
             ipc_policy: IPCPolicy::Robust,
+            reader_options: ReaderOptions::new(),
+            cookie: None,
+            trace_id: None,
+            idle_timeout: None,
+            max_concurrent_calls: None,
+            ready_timeout: None,
+            lock_timeout: None,
+            #[cfg(unix)]
+            ready_fd: None,
+            debug_port: std::env::var(DEBUG_PORT_VAR).ok()
+                .and_then(|v| parse_debug_port(&v)),
+            bind_address: Ipv4Addr::LOCALHOST.into(),
+            network_exposure: false,
+            compression: false,
+            internal_cookie_auth: true,
+            keepalive: None,
+            executable_override: false,
+            server_log: std::env::var_os(SERVER_LOG_VAR)
+                .map(|v| parse_server_log(&v)).unwrap_or(false),
+            #[cfg(any(test, feature = "test-util"))]
+            rng: None,
+            default_home: None,
+            audit: None,
+            audit_methods: Default::default(),
             ephemeral: false,
             cleanup: false,
         })
     }
 
+    /// Creates a configuration seeded from this context.
+    ///
+    /// This is useful to create a context that differs from an
+    /// existing one in a few settings: change them, and finalize the
+    /// configuration using `.build()`.  The new context shares the
+    /// home directory with this one.  If this context is ephemeral,
+    /// its home directory is still removed when it is dropped, not
+    /// when the new context is.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use sequoia_ipc::{Context, IPCPolicy, Result};
+    /// # fn main() -> Result<()> {
+    /// let c = Context::configure().ephemeral().build()?;
+    /// let d = c.reconfigure().ipc_policy(IPCPolicy::Internal).build()?;
+    /// assert_eq!(d.home(), c.home());
+    /// assert_eq!(d.ipc_policy(), &IPCPolicy::Internal);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn reconfigure(&self) -> Config {
+        Config(self.clone())
+    }
+
     /// Returns the directory containing shared state.
     pub fn home(&self) -> &Path {
         &self.home
     }
 
+    /// Returns the mode the home directory is created with.
+    ///
+    /// See [`Config::home_mode`].
+    pub fn home_mode(&self) -> u32 {
+        self.home_mode
+    }
+
     /// Returns the directory containing backend servers.
     pub fn lib(&self) -> &Path {
         &self.lib
     }
 
+    /// Returns the prefix of the rendezvous points' file names.
+    ///
+    /// See [`Config::rendezvous_prefix`].
+    pub fn rendezvous_prefix(&self) -> &str {
+        &self.rendezvous_prefix
+    }
+
     /// Returns the IPC policy.
     pub fn ipc_policy(&self) -> &IPCPolicy {
         &self.ipc_policy
     }
 
+    /// Returns the options used to read RPC messages.
+    pub fn reader_options(&self) -> &ReaderOptions {
+        &self.reader_options
+    }
+
+    /// Returns the out-of-band cookie, if any.
+    ///
+    /// See [`Config::out_of_band_cookie`].
+    pub fn out_of_band_cookie(&self) -> Option<&[u8; 32]> {
+        self.cookie.as_ref()
+            .map(|c| (&c[..]).try_into().expect("cookie has the right size"))
+    }
+
+    /// Returns the trace ID sent to servers, if any.
+    ///
+    /// See [`Config::trace_id`].
+    pub fn trace_id(&self) -> Option<&str> {
+        self.trace_id.as_deref()
+    }
+
+    /// Returns the servers' idle timeout, if any.
+    ///
+    /// See [`Config::idle_timeout`].
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// Returns the maximum number of concurrent calls per connection,
+    /// if any.
+    ///
+    /// See [`Config::max_concurrent_calls`].
+    pub fn max_concurrent_calls(&self) -> Option<usize> {
+        self.max_concurrent_calls
+    }
+
+    /// Returns how long to wait for external servers to become
+    /// ready, if at all.
+    ///
+    /// See [`Config::ready_timeout`].
+    pub fn ready_timeout(&self) -> Option<Duration> {
+        self.ready_timeout
+    }
+
+    /// Returns how long to wait for the lock on rendezvous points,
+    /// if the wait is bounded.
+    ///
+    /// See [`Config::lock_timeout`].
+    pub fn lock_timeout(&self) -> Option<Duration> {
+        self.lock_timeout
+    }
+
+    /// Takes the file descriptor servers signal readiness on, if
+    /// any.
+    ///
+    /// See [`Config::ready_fd`].
+    #[cfg(unix)]
+    pub(crate) fn take_ready_fd(&self) -> Option<std::os::unix::io::OwnedFd> {
+        self.ready_fd.as_ref()
+            .and_then(|fd| fd.lock().expect("not poisoned").take())
+    }
+
+    /// Returns the port servers are started on, if any.
+    ///
+    /// See [`Config::debug_port`].
+    pub fn debug_port(&self) -> Option<u16> {
+        self.debug_port
+    }
+
+    /// Returns the address servers are started on.
+    ///
+    /// See [`Config::bind_address`].
+    pub fn bind_address(&self) -> IpAddr {
+        self.bind_address
+    }
+
+    /// Returns whether servers may be exposed to the network.
+    ///
+    /// See [`Config::allow_network_exposure`].
+    pub fn network_exposure_allowed(&self) -> bool {
+        self.network_exposure
+    }
+
+    /// Returns whether connections may be compressed.
+    ///
+    /// See [`Config::compression`].
+    pub fn compression(&self) -> bool {
+        self.compression
+    }
+
+    /// Returns whether clients authenticate to servers started on a
+    /// thread using a cookie.
+    ///
+    /// See [`Config::disable_internal_cookie_auth`].
+    pub fn internal_cookie_auth(&self) -> bool {
+        self.internal_cookie_auth
+    }
+
+    /// Returns the TCP keepalive parameters, if any.
+    ///
+    /// These are the idle time, the interval, and the number of
+    /// retries, see [`Config::keepalive`].
+    pub fn keepalive(&self) -> Option<(Duration, Duration, u32)> {
+        self.keepalive
+    }
+
+    /// Returns whether the servers' executables may be overridden
+    /// using the environment.
+    ///
+    /// See [`Config::executable_override`].
+    pub fn executable_override(&self) -> bool {
+        self.executable_override
+    }
+
+    /// Returns whether the output of external servers is logged.
+    ///
+    /// See [`Config::server_log`].
+    pub fn server_log(&self) -> bool {
+        self.server_log
+    }
+
+    /// Fills `buf` with random bytes.
+    ///
+    /// See [`Config::rng`].
+    pub(crate) fn random(&self, buf: &mut [u8]) -> Result<()> {
+        #[cfg(any(test, feature = "test-util"))]
+        if let Some(rng) = &self.rng {
+            use rand::RngCore;
+
+            return Ok(rng.lock().expect("not poisoned").try_fill_bytes(buf)?);
+        }
+
+        sequoia_openpgp::crypto::random(buf)
+    }
+
+    /// Returns the hook recording RPC calls, if any.
+    ///
+    /// See [`Config::audit`].
+    pub(crate) fn audit(&self) -> Option<&AuditHook> {
+        self.audit.as_ref()
+    }
+
+    /// Returns the method names used in audit records.
+    ///
+    /// See [`Config::audit_methods`].
+    pub(crate) fn audit_methods(&self) -> &AuditMethods {
+        &self.audit_methods
+    }
+
     /// Returns whether or not this is an ephemeral context.
     pub fn ephemeral(&self) -> bool {
         self.ephemeral
     }
+
+    /// Lists the services that have a rendezvous point in the home
+    /// directory.
+    ///
+    /// Every file named like a rendezvous point (see
+    /// [`rendezvous_path`]) is inspected, and the server it points to
+    /// is probed by connecting to it and sending it the cookie, see
+    /// [`ServiceStatus::is_live`] for what this does and doesn't
+    /// tell.  No server is started, and the rendezvous points are not
+    /// modified.  The services are sorted by name.
+    ///
+    /// [`ServiceStatus::is_live`]: crate::ServiceStatus::is_live()
+    ///
+    /// [`rendezvous_path`]: crate::rendezvous_path()
+    pub fn discover_services(&self) -> Result<Vec<crate::ServiceStatus>> {
+        crate::discover_services(self)
+    }
+
+    /// Removes stale rendezvous points from the home directory.
+    ///
+    /// After a crash, the home directory may contain rendezvous
+    /// points pointing to servers that are no longer running.  This
+    /// inspects every file named like a rendezvous point (see
+    /// [`rendezvous_path`]), probes the server it points to by
+    /// connecting to it and sending it the cookie, and removes the
+    /// file if that fails.  No server is started.  Returns the number
+    /// of rendezvous points removed.
+    ///
+    /// Servers don't acknowledge the cookie, so a rendezvous point
+    /// is kept as long as something listens on its address, even if
+    /// it is not the server, see [`ServiceStatus::is_live`].
+    ///
+    /// [`ServiceStatus::is_live`]: crate::ServiceStatus::is_live()
+    ///
+    /// Each rendezvous point is locked while it is inspected, so this
+    /// doesn't race clients connecting to the service.  Rendezvous
+    /// points that are locked by a client are skipped.  On Windows,
+    /// stale rendezvous points are truncated instead of removed, and
+    /// are not counted.
+    ///
+    /// [`rendezvous_path`]: crate::rendezvous_path()
+    pub fn gc_rendezvous(&self) -> Result<usize> {
+        crate::gc_rendezvous(self)
+    }
 }
 
 /// Represents a `Context` configuration.
@@ -164,9 +542,28 @@ pub struct Config(Context);
 
 impl Config {
     /// Finalizes the configuration and returns a `Context`.
+    ///
+    /// Relative home and lib directories are resolved relative to
+    /// the current working directory.
+    ///
+    /// If no home directory is set, and the context is not
+    /// ephemeral, the default home directory is used, see
+    /// [`Config::default_home`].  If it cannot be determined, this
+    /// fails with [`Error::NoHome`].
+    ///
+    /// [`Error::NoHome`]: crate::Error::NoHome
     pub fn build(self) -> Result<Context> {
         let mut c = self.0;
 
+        if c.bind_address.is_unspecified() {
+            return Err(anyhow::anyhow!(
+                "Cannot bind servers to {}, clients need a specific \
+                 address to connect to", c.bind_address));
+        }
+        if ! c.bind_address.is_loopback() && ! c.network_exposure {
+            return Err(crate::Error::NetworkExposed(c.bind_address).into());
+        }
+
         // As a special case, we defer the computation of the default
         // home, because env::home_dir() may fail.
         let home_not_set = c.home == PathBuf::from("");
@@ -180,9 +577,19 @@ impl Config {
             c.home = tmp.into_path();
             c.cleanup = true;
         } else if home_not_set {
-            c.home = dirs::home_dir()
-                .ok_or_else(|| anyhow::anyhow!("Failed to get users home directory"))?
-            .join(".sequoia");
+            let home = match &c.default_home {
+                Some(default_home) => default_home(),
+                None => sequoia_home(dirs::home_dir()),
+            };
+            c.home = home.ok_or(crate::Error::NoHome)?;
+        }
+
+        // Make the directories absolute.  They are passed to the
+        // servers we start, which may run in a different working
+        // directory.
+        for dir in [&mut c.home, &mut c.lib] {
+            *dir = std::path::absolute(&*dir).map_err(|e| anyhow::anyhow!(
+                "Making {:?} absolute: {}", dir, e))?;
         }
         Ok(c)
     }
@@ -198,6 +605,74 @@ impl Config {
         ::std::mem::replace(&mut self.0.home, PathBuf::new().join(home))
     }
 
+    /// Sets how the home directory is determined if none is set.
+    ///
+    /// By default, the home directory is `.sequoia` in the user's
+    /// home directory.  In some environments, for instance, services
+    /// running as system users, or containers, there is no such
+    /// directory.  Then, `resolve` can provide a different one, or
+    /// return `None`, in which case [`Config::build`] fails with
+    /// [`Error::NoHome`].
+    ///
+    /// `resolve` is only called by [`Config::build`], and only if
+    /// neither [`Config::home`] nor [`Config::ephemeral`] is used.
+    ///
+    /// [`Error::NoHome`]: crate::Error::NoHome
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use sequoia_ipc::{Context, Result};
+    /// # fn main() -> Result<()> {
+    /// # let tmp = tempfile::tempdir()?;
+    /// # let state = tmp.path().to_path_buf();
+    /// let c = Context::configure()
+    ///     .default_home(move || {
+    ///         dirs::home_dir().map(|h| h.join(".sequoia"))
+    ///             .or_else(|| Some(state.join("sequoia")))
+    ///     })
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn default_home<F>(mut self, resolve: F) -> Self
+    where
+        F: Fn() -> Option<PathBuf> + Send + Sync + 'static,
+    {
+        self.set_default_home(resolve);
+        self
+    }
+
+    /// Sets how the home directory is determined if none is set.
+    ///
+    /// Returns whether a resolver was set before.
+    pub fn set_default_home<F>(&mut self, resolve: F) -> bool
+    where
+        F: Fn() -> Option<PathBuf> + Send + Sync + 'static,
+    {
+        self.0.default_home.replace(Arc::new(resolve)).is_some()
+    }
+
+    /// Sets the mode the home directory is created with.
+    ///
+    /// The home directory holds the rendezvous points, which reveal
+    /// the servers' addresses.  If it doesn't exist, it is created
+    /// along with any missing parents using `mode`, subject to the
+    /// umask.  Existing directories are not changed.
+    ///
+    /// The default is `0o700`, i.e., only the owner can list the
+    /// directory.  This is ignored on Windows, where the directory
+    /// inherits the ACL of its parent.
+    pub fn home_mode(mut self, mode: u32) -> Self {
+        self.set_home_mode(mode);
+        self
+    }
+
+    /// Sets the mode the home directory is created with.
+    pub fn set_home_mode(&mut self, mode: u32) -> u32 {
+        ::std::mem::replace(&mut self.0.home_mode, mode)
+    }
+
     /// Sets the directory containing backend servers.
     pub fn lib<P: AsRef<Path>>(mut self, lib: P) -> Self {
         self.set_lib(lib);
@@ -209,6 +684,60 @@ impl Config {
         ::std::mem::replace(&mut self.0.lib, PathBuf::new().join(lib))
     }
 
+    /// Sets the prefix of the rendezvous points' file names.
+    ///
+    /// By default, the rendezvous point of a service is the file
+    /// `<service>.v<version>.cookie` in the home directory, see
+    /// [`rendezvous_path`].  These names don't collide with the
+    /// files GnuPG keeps in its home directory, whose sockets are
+    /// named `S.<component>`, e.g., `S.gpg-agent`.  Still, if the
+    /// home directory is shared with other programs, a prefix, e.g.
+    /// `sequoia-`, makes it obvious which files belong to Sequoia.
+    /// Then, the rendezvous point is
+    /// `<prefix><service>.v<version>.cookie`.
+    ///
+    /// Only the rendezvous points located using [`rendezvous_path`]
+    /// are affected, and [`Context::discover_services`] and
+    /// [`Context::gc_rendezvous`] only consider files with the
+    /// prefix.  All clients of a service must use the same prefix.
+    ///
+    /// The prefix must not contain path separators, and must not
+    /// start with a dot.
+    ///
+    /// [`rendezvous_path`]: crate::rendezvous_path()
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use sequoia_ipc::{Context, Result};
+    /// # fn main() -> Result<()> {
+    /// use sequoia_ipc::rendezvous_path;
+    ///
+    /// let ctx = Context::configure()
+    ///     .ephemeral()
+    ///     .rendezvous_prefix("sequoia-")?
+    ///     .build()?;
+    /// assert_eq!(rendezvous_path(&ctx, "keystore"),
+    ///            ctx.home().join("sequoia-keystore.v1.cookie"));
+    /// # Ok(()) }
+    /// ```
+    pub fn rendezvous_prefix(mut self, prefix: &str) -> Result<Self> {
+        self.set_rendezvous_prefix(prefix)?;
+        Ok(self)
+    }
+
+    /// Sets the prefix of the rendezvous points' file names.
+    pub fn set_rendezvous_prefix(&mut self, prefix: &str) -> Result<String> {
+        if prefix.starts_with('.')
+            || prefix.chars().any(|c| std::path::is_separator(c) || c == '\0')
+        {
+            return Err(anyhow::anyhow!(
+                "Invalid rendezvous prefix {:?}: must not start with a dot, \
+                 or contain path separators", prefix));
+        }
+        Ok(::std::mem::replace(&mut self.0.rendezvous_prefix, prefix.into()))
+    }
+
     /// Sets the IPC policy.
     pub fn ipc_policy(mut self, policy: IPCPolicy) -> Self {
         self.set_ipc_policy(policy);
@@ -220,6 +749,605 @@ impl Config {
         ::std::mem::replace(&mut self.0.ipc_policy, policy)
     }
 
+    /// Sets the IPC policy from its string representation.
+    ///
+    /// This is useful if the policy comes from a configuration file
+    /// or an environment variable.  See [`IPCPolicy`]'s `FromStr`
+    /// implementation for the accepted values.
+    pub fn ipc_policy_str(mut self, policy: &str) -> Result<Self> {
+        self.set_ipc_policy(policy.parse::<IPCPolicy>()?);
+        Ok(self)
+    }
+
+    /// Sets the options used to read RPC messages.
+    ///
+    /// These options are used by both clients and servers, and limit
+    /// the size and the nesting depth of the messages that are
+    /// accepted from the peer.  Messages exceeding the limits are
+    /// rejected before they are buffered, which bounds the memory a
+    /// malicious peer can make us allocate per RPC.
+    ///
+    /// The default is Cap'n Proto's default, i.e., a traversal limit
+    /// of 8 Mi words (64 MiB), and a nesting limit of 64.
+    pub fn reader_options(mut self, options: ReaderOptions) -> Self {
+        self.set_reader_options(options);
+        self
+    }
+
+    /// Sets the options used to read RPC messages.
+    pub fn set_reader_options(&mut self, options: ReaderOptions)
+                              -> ReaderOptions
+    {
+        ::std::mem::replace(&mut self.0.reader_options, options)
+    }
+
+    /// Uses the given out-of-band cookie to authenticate clients.
+    ///
+    /// By default, the client starting a server generates a cookie,
+    /// and stores it in the rendezvous point along with the server's
+    /// address.  If the cookie is instead supplied out of band, e.g.
+    /// by a launcher that hands it to both the clients and the
+    /// server, the rendezvous point only stores the address, and the
+    /// cookie never touches the disk.
+    ///
+    /// Clients and servers using this context use `cookie`.
+    /// External servers started by us receive it over an inherited
+    /// file descriptor, whose number is passed using `--cookie-fd`.
+    /// On Linux, this is a sealed memfd, elsewhere, it is a pipe.
+    /// This is not supported on Windows.
+    ///
+    /// Note: all clients of a service must agree on whether an
+    /// out-of-band cookie is used.
+    ///
+    /// The context stores the cookie in protected memory, which is
+    /// cleared when it is dropped, and clears `cookie`.
+    pub fn out_of_band_cookie(mut self, cookie: [u8; 32]) -> Self {
+        self.set_out_of_band_cookie(cookie);
+        self
+    }
+
+    /// Uses the given out-of-band cookie to authenticate clients.
+    ///
+    /// Returns the old cookie, if any.
+    pub fn set_out_of_band_cookie(&mut self, mut cookie: [u8; 32])
+                                  -> Option<Protected>
+    {
+        let protected = Protected::from(&cookie[..]);
+        // Safety: `cookie` is valid for `cookie.len()` bytes.
+        unsafe {
+            memsec::memzero(cookie.as_mut_ptr(), cookie.len());
+        }
+        ::std::mem::replace(&mut self.0.cookie, Some(protected))
+    }
+
+    /// Reads the out-of-band cookie from the given file descriptor.
+    ///
+    /// External servers started with an out-of-band cookie receive
+    /// the number of the file descriptor using `--cookie-fd`, see
+    /// [`ServerArgs::context`], which calls this.  The file
+    /// descriptor is closed.  See [`Config::out_of_band_cookie`].
+    ///
+    /// This is only supported on Unix.
+    ///
+    /// [`ServerArgs::context`]: crate::ServerArgs::context()
+    #[cfg(unix)]
+    pub fn cookie_fd(mut self, fd: std::os::unix::io::OwnedFd) -> Result<Self> {
+        self.0.cookie = Some(crate::read_cookie_fd(fd)?);
+        Ok(self)
+    }
+
+    /// Sets the trace ID sent to servers.
+    ///
+    /// The trace ID is an opaque string that is sent to the server
+    /// right after the cookie when connecting.  The server records
+    /// it in the `connection` span covering the connection, which
+    /// allows correlating the client's and the server's logs.  Only
+    /// the first 255 bytes are sent.
+    ///
+    /// By default, no trace ID is sent.  Servers that don't know
+    /// about trace IDs can only be used without one.
+    pub fn trace_id<S: Into<String>>(mut self, id: S) -> Self {
+        self.set_trace_id(id);
+        self
+    }
+
+    /// Sets the trace ID sent to servers.
+    pub fn set_trace_id<S: Into<String>>(&mut self, id: S) -> Option<String> {
+        ::std::mem::replace(&mut self.0.trace_id, Some(id.into()))
+    }
+
+    /// Sets the servers' idle timeout.
+    ///
+    /// Servers using this context close connections that are idle
+    /// for longer than `timeout`.  A connection is idle if the client
+    /// does not complete the handshake, or the server does not send
+    /// anything, i.e., no call returns.  Hence, the timeout must be
+    /// larger than the time the longest call takes.
+    ///
+    /// This protects the server from clients that connect and then
+    /// go silent.  By default, there is no timeout.  Independently of
+    /// this, clients have to complete the handshake within a few
+    /// seconds.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.set_idle_timeout(timeout);
+        self
+    }
+
+    /// Sets the servers' idle timeout.
+    pub fn set_idle_timeout(&mut self, timeout: Duration)
+                            -> Option<Duration>
+    {
+        ::std::mem::replace(&mut self.0.idle_timeout, Some(timeout))
+    }
+
+    /// Limits the number of concurrent calls per connection.
+    ///
+    /// Cap'n Proto does not limit the number of calls a client can
+    /// have in flight, so a client flooding the server with calls
+    /// can make it use arbitrary amounts of memory.  If a limit is
+    /// set, calls on the bootstrap capability that are made while
+    /// `limit` calls are in flight fail with an
+    /// [`Overloaded`](capnp::ErrorKind::Overloaded) error.
+    ///
+    /// The limit is applied by handlers that build their RPC system
+    /// using [`server_rpc_system`], see [`limit_calls`].  By default,
+    /// there is no limit.
+    ///
+    /// [`server_rpc_system`]: crate::server_rpc_system()
+    /// [`limit_calls`]: crate::limit_calls()
+    pub fn max_concurrent_calls(mut self, limit: usize) -> Self {
+        self.set_max_concurrent_calls(limit);
+        self
+    }
+
+    /// Limits the number of concurrent calls per connection.
+    pub fn set_max_concurrent_calls(&mut self, limit: usize)
+                                    -> Option<usize>
+    {
+        ::std::mem::replace(&mut self.0.max_concurrent_calls, Some(limit))
+    }
+
+    /// Records the RPC calls servers receive.
+    ///
+    /// `hook` is called for every call on the bootstrap capability,
+    /// before it is served, even if it is then rejected, for
+    /// instance, because of [`Config::max_concurrent_calls`].  It is
+    /// passed an [`AuditRecord`], which identifies the method, the
+    /// connection, and when the call was made.  To record the
+    /// methods by name, register the interfaces' method names using
+    /// [`Config::audit_methods`].  By design, it doesn't
+    /// include the call's parameters or results, which may contain
+    /// secrets, so the records are safe to log.  The hook runs on
+    /// the server's thread, and should return quickly.
+    ///
+    /// Calls are recorded by handlers that build their RPC system
+    /// using [`Connection::into_rpc_system`], or
+    /// [`server_rpc_system`], whose records don't identify the
+    /// connection.  By default, calls are not recorded.
+    ///
+    /// [`AuditRecord`]: crate::AuditRecord
+    /// [`Connection::into_rpc_system`]: crate::Connection::into_rpc_system()
+    /// [`server_rpc_system`]: crate::server_rpc_system()
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use sequoia_ipc::{Context, Result};
+    /// # fn main() -> Result<()> {
+    /// let c = Context::configure()
+    /// #   .ephemeral()
+    ///     .audit_methods(0xaa3b_0e6f_0cd4_7cb6, &["hello"])
+    ///     .audit(|record| {
+    ///         eprintln!("Connection {:?} called {}",
+    ///                   record.connection().map(|c| c.id()),
+    ///                   record.method().unwrap_or("an unknown method"));
+    ///     })
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn audit<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&crate::AuditRecord) + Send + Sync + 'static,
+    {
+        self.set_audit(hook);
+        self
+    }
+
+    /// Records the RPC calls servers receive.
+    ///
+    /// Returns whether a hook was set before.
+    pub fn set_audit<F>(&mut self, hook: F) -> bool
+    where
+        F: Fn(&crate::AuditRecord) + Send + Sync + 'static,
+    {
+        self.0.audit.replace(Arc::new(hook)).is_some()
+    }
+
+    /// Names the methods of an interface in audit records.
+    ///
+    /// Cap'n Proto identifies methods using the interface's ID and the
+    /// method's ordinal.  `methods` lists the names of the methods of
+    /// the interface with the ID `interface_id`, ordered by their
+    /// ordinal.  The ID of a generated interface `foo` is
+    /// `<foo::Client as capnp::traits::HasTypeId>::TYPE_ID`.  Records
+    /// of calls to methods that are not named have no name, see
+    /// [`AuditRecord::method`].
+    ///
+    /// [`AuditRecord::method`]: crate::AuditRecord::method()
+    pub fn audit_methods(mut self, interface_id: u64,
+                         methods: &'static [&'static str])
+                         -> Self
+    {
+        self.set_audit_methods(interface_id, methods);
+        self
+    }
+
+    /// Names the methods of an interface in audit records.
+    ///
+    /// Returns the names set before, if any.
+    pub fn set_audit_methods(&mut self, interface_id: u64,
+                             methods: &'static [&'static str])
+                             -> Option<&'static [&'static str]>
+    {
+        self.0.audit_methods.insert(interface_id, methods)
+    }
+
+    /// Waits for external servers to signal that they are ready.
+    ///
+    /// Normally, a client only learns that an external server it
+    /// started failed when the first call fails.  If a timeout is
+    /// set, the client passes the write end of a pipe to the server
+    /// using `--ready-fd`, and waits for up to `timeout` for the
+    /// server to signal that it has received the cookie and is
+    /// serving clients (see [`Config::ready_fd`]).  If the server
+    /// exits or the timeout expires first, starting the server fails,
+    /// and with [`IPCPolicy::Robust`], an internal server is started
+    /// instead.  A server that is still running then is killed.
+    ///
+    /// The server must understand `--ready-fd`.  This is the case for
+    /// servers using [`Server::context`] to parse their command line.
+    ///
+    /// This is not supported on Windows, and ignored there.  By
+    /// default, clients don't wait.
+    ///
+    /// [`Server::context`]: crate::Server::context()
+    pub fn ready_timeout(mut self, timeout: Duration) -> Self {
+        self.set_ready_timeout(timeout);
+        self
+    }
+
+    /// Waits for external servers to signal that they are ready.
+    pub fn set_ready_timeout(&mut self, timeout: Duration)
+                             -> Option<Duration>
+    {
+        ::std::mem::replace(&mut self.0.ready_timeout, Some(timeout))
+    }
+
+    /// Bounds how long clients wait for the lock on rendezvous
+    /// points.
+    ///
+    /// Clients take the lock to start servers.  If another client
+    /// holds it for longer than `timeout`, for instance, because it
+    /// hangs while starting the server, connecting fails with
+    /// [`ConnectError::LockTimedOut`].  By default, clients wait
+    /// until the lock is released.
+    ///
+    /// This applies to the default rendezvous backend, see
+    /// [`FileRendezvous::lock_timeout`].
+    ///
+    /// [`ConnectError::LockTimedOut`]: crate::ConnectError::LockTimedOut
+    /// [`FileRendezvous::lock_timeout`]: crate::FileRendezvous::lock_timeout()
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.set_lock_timeout(timeout);
+        self
+    }
+
+    /// Bounds how long clients wait for the lock on rendezvous
+    /// points.
+    pub fn set_lock_timeout(&mut self, timeout: Duration)
+                            -> Option<Duration>
+    {
+        ::std::mem::replace(&mut self.0.lock_timeout, Some(timeout))
+    }
+
+    /// Signals readiness on the given file descriptor.
+    ///
+    /// External servers started by a client waiting for them to
+    /// become ready (see [`Config::ready_timeout`]) receive the
+    /// number of the file descriptor using `--ready-fd`, see
+    /// [`ServerArgs::context`], which calls this.  Once the server
+    /// has received the first cookie, and is about to serve clients,
+    /// it writes a byte to the file descriptor and closes it.
+    ///
+    /// The file descriptor is shared by all clones of the context,
+    /// and only the first server built from one of them signals
+    /// readiness on it.
+    ///
+    /// This is only supported on Unix.
+    ///
+    /// [`ServerArgs::context`]: crate::ServerArgs::context()
+    #[cfg(unix)]
+    pub fn ready_fd(mut self, fd: std::os::unix::io::OwnedFd) -> Self {
+        self.set_ready_fd(fd);
+        self
+    }
+
+    /// Signals readiness on the given file descriptor.
+    ///
+    /// Returns the old file descriptor, if any, unless a server has
+    /// already taken it.
+    #[cfg(unix)]
+    pub fn set_ready_fd(&mut self, fd: std::os::unix::io::OwnedFd)
+                        -> Option<std::os::unix::io::OwnedFd>
+    {
+        ::std::mem::replace(&mut self.0.ready_fd,
+                            Some(Arc::new(Mutex::new(Some(fd)))))
+            .and_then(|old| old.lock().expect("not poisoned").take())
+    }
+
+    /// Starts servers on a fixed port.
+    ///
+    /// By default, servers listen on an ephemeral port chosen by the
+    /// operating system.  A fixed port makes it easier to attach a
+    /// debugging tool, like a Cap'n Proto inspector, to the server.
+    /// If the port is taken, an ephemeral port is used instead.
+    ///
+    /// This is for debugging only: anybody can find the server on a
+    /// well-known port, and a fixed port makes it easier to
+    /// impersonate the server.  A warning is logged whenever a server
+    /// is started on the debug port.
+    ///
+    /// The default is taken from the environment variable
+    /// `SEQUOIA_IPC_DEBUG_PORT` (see [`DEBUG_PORT_VAR`]).
+    pub fn debug_port(mut self, port: u16) -> Self {
+        self.set_debug_port(port);
+        self
+    }
+
+    /// Starts servers on a fixed port.
+    pub fn set_debug_port(&mut self, port: u16) -> Option<u16> {
+        ::std::mem::replace(&mut self.0.debug_port, Some(port))
+    }
+
+    /// Starts servers on the given address.
+    ///
+    /// By default, servers listen on `127.0.0.1`, so that only local
+    /// processes can connect to them.  When the service is forwarded
+    /// across a container boundary, it may have to listen on a
+    /// specific interface instead.  Clients started with this context
+    /// bind the listener for the servers they start, both threads and
+    /// external servers, and publish its address at the rendezvous
+    /// point.
+    ///
+    /// A non-loopback address exposes the service to the network:
+    /// anybody who can reach the address can try to connect, and only
+    /// the cookie stands between them and the server.  Hence, these
+    /// are only accepted if [`Config::allow_network_exposure`] is set
+    /// as well, otherwise [`Config::build`] fails with
+    /// [`Error::NetworkExposed`].  A warning is logged whenever a
+    /// server is started on such an address.  The unspecified
+    /// addresses, like `0.0.0.0`, are rejected, because clients
+    /// couldn't connect to them.
+    ///
+    /// [`Error::NetworkExposed`]: crate::Error::NetworkExposed
+    pub fn bind_address(mut self, address: IpAddr) -> Self {
+        self.set_bind_address(address);
+        self
+    }
+
+    /// Starts servers on the given address.
+    pub fn set_bind_address(&mut self, address: IpAddr) -> IpAddr {
+        ::std::mem::replace(&mut self.0.bind_address, address)
+    }
+
+    /// Allows starting servers on addresses reachable from the
+    /// network.
+    ///
+    /// This acknowledges that the service is exposed to the network,
+    /// see [`Config::bind_address`].  It does nothing else.
+    pub fn allow_network_exposure(mut self) -> Self {
+        self.set_allow_network_exposure();
+        self
+    }
+
+    /// Allows starting servers on addresses reachable from the
+    /// network.
+    pub fn set_allow_network_exposure(&mut self) -> bool {
+        ::std::mem::replace(&mut self.0.network_exposure, true)
+    }
+
+    /// Allows compressing connections.
+    ///
+    /// Clients using this context offer to compress the connection
+    /// during the handshake, and servers using it accept the offer.
+    /// The connection is only compressed if both the client and the
+    /// server allow it.  This is most useful for remote servers
+    /// reached over a slow link, see
+    /// [`DescriptorBuilder::remote`].
+    ///
+    /// Servers that predate the negotiation close the connection
+    /// when offered compression, so this must only be enabled if the
+    /// server supports it.  External servers parse their own
+    /// configuration, and don't inherit this setting from the client
+    /// that starts them.
+    ///
+    /// Compression requires the `compression` feature.  Without it,
+    /// no algorithm is offered or accepted, and connections are not
+    /// compressed.  By default, connections are not compressed.
+    ///
+    /// [`DescriptorBuilder::remote`]: crate::DescriptorBuilder::remote()
+    pub fn compression(mut self) -> Self {
+        self.set_compression();
+        self
+    }
+
+    /// Allows compressing connections.
+    pub fn set_compression(&mut self) -> bool {
+        ::std::mem::replace(&mut self.0.compression, true)
+    }
+
+    /// Serves internal servers in-process, without a cookie.
+    ///
+    /// By default, a server started on a thread (see
+    /// [`IPCPolicy::Internal`]) listens on a loopback TCP socket
+    /// published at the rendezvous point, and clients authenticate
+    /// using a cookie, like they do with external servers.  If this
+    /// is set, [`Descriptor::connect`] instead serves the descriptor
+    /// on a thread of this process, and connects to it over an
+    /// in-memory stream.  No socket is opened, and no cookie is
+    /// generated or checked: the stream can't be reached from
+    /// outside of the process, so there is nobody to authenticate.
+    /// This saves the handshake for embedders that only ever use
+    /// internal servers.
+    ///
+    /// This only affects connections made with the internal policy,
+    /// including when the `no-subprocess` feature forces it.  The
+    /// in-process server isn't published at the rendezvous point, so
+    /// other processes don't find it, and it is shared only by the
+    /// clones of a [`Descriptor`].  With [`IPCPolicy::External`] and
+    /// [`IPCPolicy::Robust`], for remote servers, and for servers
+    /// started using [`Descriptor::bootstrap`] or
+    /// [`Descriptor::ensure_started`], clients connect over TCP, and
+    /// always authenticate using a cookie.
+    ///
+    /// The handler must serve connections using
+    /// [`Handler::handle_connection`] (or one of the methods calling
+    /// it), because there is no socket which [`Handler::handle`]
+    /// could be given.  The server's idle timeout, and its connection
+    /// and shutdown handles don't apply.  By default, all clients
+    /// authenticate using a cookie.
+    ///
+    /// [`Descriptor`]: crate::Descriptor
+    /// [`Descriptor::connect`]: crate::Descriptor::connect()
+    /// [`Descriptor::bootstrap`]: crate::Descriptor::bootstrap()
+    /// [`Descriptor::ensure_started`]: crate::Descriptor::ensure_started()
+    /// [`Handler::handle_connection`]: crate::Handler::handle_connection()
+    /// [`Handler::handle`]: crate::Handler::handle()
+    pub fn disable_internal_cookie_auth(mut self) -> Self {
+        self.set_disable_internal_cookie_auth();
+        self
+    }
+
+    /// Serves internal servers in-process, without a cookie.
+    ///
+    /// Returns whether this was already set.
+    pub fn set_disable_internal_cookie_auth(&mut self) -> bool {
+        ! ::std::mem::replace(&mut self.0.internal_cookie_auth, false)
+    }
+
+    /// Enables TCP keepalive on connections.
+    ///
+    /// If the peer vanishes without closing the connection, for
+    /// instance, because a forwarded connection to a remote server
+    /// breaks, calls on the connection hang forever.  With
+    /// keepalive, the operating system probes connections that have
+    /// been idle for `idle`, every `interval`, and closes the
+    /// connection after `retries` unanswered probes.  Then, the
+    /// outstanding calls fail.
+    ///
+    /// This applies to the connections of clients and servers using
+    /// this context.  Not all platforms allow configuring the
+    /// interval and the number of retries, they are ignored there.
+    /// By default, keepalive is disabled.
+    pub fn keepalive(mut self, idle: Duration, interval: Duration,
+                     retries: u32)
+                     -> Self
+    {
+        self.set_keepalive(idle, interval, retries);
+        self
+    }
+
+    /// Enables TCP keepalive on connections.
+    pub fn set_keepalive(&mut self, idle: Duration, interval: Duration,
+                         retries: u32)
+                         -> Option<(Duration, Duration, u32)>
+    {
+        ::std::mem::replace(&mut self.0.keepalive,
+                            Some((idle, interval, retries)))
+    }
+
+    /// Allows overriding the servers' executables using the
+    /// environment.
+    ///
+    /// If enabled, external servers are started using the executable
+    /// named by the environment variable `SEQUOIA_<SERVICE>_SERVER`,
+    /// if it is set, instead of the executable recorded in the
+    /// descriptor (see [`executable_override_var`] for how the
+    /// variable is named).  This makes it possible to test a freshly
+    /// built server without installing it.  A warning is logged
+    /// whenever the override is used.
+    ///
+    /// This is for development only: anybody who can change the
+    /// environment can make the client start an arbitrary program,
+    /// and hand it the cookie.  Hence, the variables are ignored
+    /// unless this is enabled.  By default, they are ignored.
+    ///
+    /// [`executable_override_var`]: crate::executable_override_var()
+    pub fn executable_override(mut self) -> Self {
+        self.set_executable_override();
+        self
+    }
+
+    /// Allows overriding the servers' executables using the
+    /// environment.
+    pub fn set_executable_override(&mut self) -> bool {
+        ::std::mem::replace(&mut self.0.executable_override, true)
+    }
+
+    /// Logs the output of external servers.
+    ///
+    /// By default, the standard output and error of external servers
+    /// are discarded, so a server that fails during startup leaves no
+    /// trace.  If enabled, they are appended to a log file in the
+    /// home directory instead, see [`Descriptor::log_file`].  The log
+    /// is never rotated.
+    ///
+    /// This is for debugging only.  The default is taken from the
+    /// environment variable `SEQUOIA_IPC_SERVER_LOG` (see
+    /// [`SERVER_LOG_VAR`]): unless it is empty or `0`, the output
+    /// is logged.
+    ///
+    /// [`Descriptor::log_file`]: crate::Descriptor::log_file()
+    pub fn server_log(mut self) -> Self {
+        self.set_server_log();
+        self
+    }
+
+    /// Logs the output of external servers.
+    pub fn set_server_log(&mut self) -> bool {
+        ::std::mem::replace(&mut self.0.server_log, true)
+    }
+
+    /// Generates cookies using the given random number generator.
+    ///
+    /// By default, cookies are generated using the random number
+    /// generator of the crypto backend `sequoia-openpgp` was built
+    /// with, so that there is a single source of randomness.  A
+    /// seeded generator makes the cookies reproducible, which is
+    /// useful for tests.  The generator is shared by the clones of
+    /// the context.
+    ///
+    /// The cookies authenticate clients: if they are predictable,
+    /// anybody can use the servers.  Hence, this is only available
+    /// with the `test-util` feature, which must not be enabled in
+    /// production.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn rng<R: rand::RngCore + Send + 'static>(mut self, rng: R) -> Self {
+        self.set_rng(rng);
+        self
+    }
+
+    /// Generates cookies using the given random number generator.
+    ///
+    /// Returns whether a generator was set before.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn set_rng<R: rand::RngCore + Send + 'static>(&mut self, rng: R)
+                                                      -> bool
+    {
+        self.0.rng.replace(Arc::new(Mutex::new(rng))).is_some()
+    }
+
     /// Makes this context ephemeral.
     pub fn ephemeral(mut self) -> Self {
         self.set_ephemeral();
@@ -238,6 +1366,10 @@ impl Config {
 ///
 /// With this policy you can control how Sequoia starts background
 /// servers.
+///
+/// If the `no-subprocess` feature is enabled, external servers are
+/// not supported, and the policy is ignored: servers are always
+/// started as threads, as with [`IPCPolicy::Internal`].
 #[derive(PartialEq, Debug, Copy, Clone)]
 pub enum IPCPolicy {
     /// External background servers only.
@@ -296,6 +1428,37 @@ impl<'a> From<&'a IPCPolicy> for u8 {
     }
 }
 
+impl fmt::Display for IPCPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            IPCPolicy::External => "external",
+            IPCPolicy::Internal => "internal",
+            IPCPolicy::Robust => "robust",
+        })
+    }
+}
+
+/// Parses an IPC policy.
+///
+/// Accepts `"external"`, `"internal"`, and `"robust"`, ignoring
+/// case.  This is the inverse of the `Display` implementation.
+impl FromStr for IPCPolicy {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        for policy in [IPCPolicy::External,
+                       IPCPolicy::Internal,
+                       IPCPolicy::Robust]
+        {
+            if s.eq_ignore_ascii_case(&policy.to_string()) {
+                return Ok(policy);
+            }
+        }
+
+        Err(crate::Error::InvalidIPCPolicy(s.into()))
+    }
+}
+
 // XXX: TryFrom would be nice.
 impl From<u8> for IPCPolicy {
     fn from(policy: u8) -> Self {
@@ -307,3 +1470,228 @@ impl From<u8> for IPCPolicy {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipc_policy_strings() {
+        for (s, policy) in [("external", IPCPolicy::External),
+                            ("internal", IPCPolicy::Internal),
+                            ("robust", IPCPolicy::Robust),
+                            ("External", IPCPolicy::External),
+                            ("INTERNAL", IPCPolicy::Internal),
+                            ("rObUsT", IPCPolicy::Robust)]
+        {
+            assert_eq!(s.parse::<IPCPolicy>().unwrap(), policy);
+            assert_eq!(policy.to_string(), s.to_lowercase());
+            assert_eq!(policy.to_string().parse::<IPCPolicy>().unwrap(),
+                       policy);
+        }
+
+        assert!(matches!("".parse::<IPCPolicy>(),
+                         Err(crate::Error::InvalidIPCPolicy(_))));
+        let err = "sometimes".parse::<IPCPolicy>().unwrap_err();
+        assert!(matches!(&err, crate::Error::InvalidIPCPolicy(s)
+                         if s == "sometimes"));
+        // The error lists the valid values.
+        let msg = err.to_string();
+        assert!(msg.contains("external"));
+        assert!(msg.contains("internal"));
+        assert!(msg.contains("robust"));
+    }
+
+    #[test]
+    fn debug_port() -> Result<()> {
+        assert_eq!(parse_debug_port("4711"), Some(4711));
+        assert_eq!(parse_debug_port(" 4711\n"), Some(4711));
+        assert_eq!(parse_debug_port("0"), None);
+        assert_eq!(parse_debug_port(""), None);
+        assert_eq!(parse_debug_port("65536"), None);
+        assert_eq!(parse_debug_port("http"), None);
+
+        let c = Context::configure()
+            .ephemeral()
+            .debug_port(4711)
+            .build()?;
+        assert_eq!(c.debug_port(), Some(4711));
+        assert_eq!(c.clone().debug_port(), Some(4711));
+        Ok(())
+    }
+
+    #[test]
+    fn bind_address() -> Result<()> {
+        use std::net::Ipv6Addr;
+
+        let c = Context::configure().ephemeral().build()?;
+        assert_eq!(c.bind_address(), IpAddr::from(Ipv4Addr::LOCALHOST));
+        assert!(! c.network_exposure_allowed());
+
+        // Any loopback address will do.
+        let c = Context::configure()
+            .ephemeral()
+            .bind_address(Ipv6Addr::LOCALHOST.into())
+            .build()?;
+        assert_eq!(c.bind_address(), IpAddr::from(Ipv6Addr::LOCALHOST));
+
+        // Other addresses expose the servers, and have to be allowed.
+        let exposed: IpAddr = "192.0.2.1".parse()?;
+        let err = Context::configure()
+            .ephemeral()
+            .bind_address(exposed)
+            .build().err().expect("refused");
+        assert!(matches!(err.downcast_ref::<crate::Error>(),
+                         Some(crate::Error::NetworkExposed(a)) if *a == exposed));
+
+        let c = Context::configure()
+            .ephemeral()
+            .bind_address(exposed)
+            .allow_network_exposure()
+            .build()?;
+        assert_eq!(c.bind_address(), exposed);
+        assert!(c.network_exposure_allowed());
+        assert_eq!(c.clone().bind_address(), exposed);
+
+        // Clients can't connect to the unspecified address.
+        assert!(Context::configure()
+                .ephemeral()
+                .bind_address(Ipv4Addr::UNSPECIFIED.into())
+                .allow_network_exposure()
+                .build().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn server_log() -> Result<()> {
+        use std::ffi::OsStr;
+
+        assert!(parse_server_log(OsStr::new("1")));
+        assert!(parse_server_log(OsStr::new("yes")));
+        assert!(! parse_server_log(OsStr::new("0")));
+        assert!(! parse_server_log(OsStr::new("")));
+
+        let c = Context::configure()
+            .ephemeral()
+            .server_log()
+            .build()?;
+        assert!(c.server_log());
+        assert!(c.clone().server_log());
+        Ok(())
+    }
+
+    #[test]
+    fn no_home() -> Result<()> {
+        assert_eq!(sequoia_home(None), None);
+        assert_eq!(sequoia_home(Some("".into())), None);
+        assert_eq!(sequoia_home(Some("relative".into())), None);
+        #[cfg(unix)]
+        {
+            assert_eq!(sequoia_home(Some("/".into())), None);
+            assert_eq!(sequoia_home(Some("/home/alice".into())),
+                       Some("/home/alice/.sequoia".into()));
+        }
+
+        let err = Context::configure()
+            .default_home(|| None)
+            .build()
+            .err()
+            .expect("no home");
+        assert!(matches!(err.downcast_ref::<crate::Error>(),
+                         Some(crate::Error::NoHome)));
+        // The error tells the user what to do.
+        assert!(err.to_string().contains("Config::home"));
+
+        // The resolver is only used if no home is set.
+        let tmp = tempfile::tempdir()?;
+        let c = Context::configure()
+            .default_home(|| None)
+            .home(tmp.path())
+            .build()?;
+        assert_eq!(c.home(), tmp.path());
+        let c = Context::configure()
+            .default_home(|| None)
+            .ephemeral()
+            .build()?;
+        assert!(c.home().exists());
+
+        let fallback = tmp.path().join("fallback");
+        let home = fallback.clone();
+        let c = Context::configure()
+            .default_home(move || Some(home.clone()))
+            .build()?;
+        assert_eq!(c.home(), fallback);
+        Ok(())
+    }
+
+    #[test]
+    fn reconfigure() -> Result<()> {
+        let c = Context::configure()
+            .ephemeral()
+            .ipc_policy(IPCPolicy::External)
+            .trace_id("trace")
+            .idle_timeout(Duration::from_secs(7))
+            .build()?;
+        let d = c.reconfigure()
+            .ipc_policy(IPCPolicy::Internal)
+            .build()?;
+
+        // Only the policy changed.
+        assert_eq!(d.ipc_policy(), &IPCPolicy::Internal);
+        assert_eq!(c.ipc_policy(), &IPCPolicy::External);
+        assert_eq!(d.home(), c.home());
+        assert_eq!(d.lib(), c.lib());
+        assert_eq!(d.trace_id(), Some("trace"));
+        assert_eq!(d.idle_timeout(), Some(Duration::from_secs(7)));
+        assert!(d.ephemeral());
+
+        // The home directory belongs to the original context.
+        let home = c.home().to_path_buf();
+        drop(d);
+        assert!(home.exists());
+        drop(c);
+        assert!(! home.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn config_ipc_policy_str() -> Result<()> {
+        let c = Context::configure()
+            .ephemeral()
+            .ipc_policy_str("Internal")?
+            .build()?;
+        assert_eq!(c.ipc_policy(), &IPCPolicy::Internal);
+
+        assert!(Context::configure().ipc_policy_str("bogus").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reader_options() -> Result<()> {
+        let mut options = ReaderOptions::new();
+        options.traversal_limit_in_words(Some(1024));
+        let c = Context::configure()
+            .ephemeral()
+            .reader_options(options)
+            .build()?;
+        assert_eq!(c.reader_options().traversal_limit_in_words, Some(1024));
+        // Cloning preserves the options.
+        assert_eq!(c.clone().reader_options().traversal_limit_in_words,
+                   Some(1024));
+
+        // A message that is larger than the limit.
+        let mut message = capnp::message::Builder::new_default();
+        message.initn_root::<capnp::data::Builder>(64 * 1024);
+        let mut buf = Vec::new();
+        capnp::serialize::write_message(&mut buf, &message)
+            .expect("can serialize");
+
+        // Is rejected using our options...
+        assert!(capnp::serialize::read_message(
+            &buf[..], *c.reader_options()).is_err());
+        // ... but accepted using the defaults.
+        assert!(capnp::serialize::read_message(
+            &buf[..], ReaderOptions::new()).is_ok());
+        Ok(())
+    }
+}
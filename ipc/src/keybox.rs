@@ -8,11 +8,14 @@ use anyhow::Context;
 use openpgp::parse::buffered_reader::{self, BufferedReader};
 
 use openpgp::cert::Cert;
+use openpgp::Fingerprint;
 use openpgp::parse::{Cookie, Parse};
 use openpgp::types::HashAlgorithm::SHA1;
 use openpgp::Result;
 use sequoia_openpgp as openpgp;
 
+use crate::Keygrip;
+
 use std::convert::TryInto;
 use std::fmt::Display;
 
@@ -48,32 +51,271 @@ use std::fmt::Display;
 /// ```
 pub struct Keybox<'a> {
     /// Offset into the Keybox file.
-    offset: usize,
+    ///
+    /// The records' lengths are 32 bit, but the file may be larger
+    /// than 4 GiB, so this is 64 bit even on 32 bit platforms.  See
+    /// [`public_offset`].
+    offset: u64,
 
     reader: Box<dyn BufferedReader<()> + 'a>,
 }
 
+/// Converts an offset for the public interface.
+///
+/// On 32 bit platforms, offsets that don't fit into a `usize`
+/// saturate.
+fn public_offset(offset: u64) -> usize {
+    usize::try_from(offset).unwrap_or(usize::MAX)
+}
+
+/// The maximum length of a record.
+///
+/// Like GnuPG, we refuse to read larger records.  This avoids
+/// allocating huge buffers for corrupted length fields.
+const MAX_RECORD_SIZE: usize = 5 * 1024 * 1024;
+
 impl<'a> Keybox<'a> {
     fn read_next_record(&mut self) -> Result<KeyboxRecord> {
+        let offset = self.offset;
+
         // The first 4 bytes contain the record's length,
         // bytes 5 and 6 the type and version.
-        let input = self
-            .reader
-            .data_hard(6)
-            .map_err(|e| Error::NotEnoughData(e.to_string()))?;
-        // input holds at least 4 bytes, so this cannot fail.
-        let len = u32::from_be_bytes(input[..4].try_into().unwrap()) as usize;
+        let len = match self.reader.data_hard(6) {
+            // input holds at least 4 bytes, so this cannot fail.
+            Ok(input) =>
+                u32::from_be_bytes(input[..4].try_into().unwrap()) as usize,
+            Err(e) => return Err(self.framing_error(
+                Error::NotEnoughData(e.to_string()))),
+        };
 
+        if len < 6 {
+            return Err(self.framing_error(Error::InvalidData(format!(
+                "record at offset {} has length {}, which is shorter than \
+                 the record header", offset, len))));
+        }
+        if len > MAX_RECORD_SIZE {
+            return Err(self.framing_error(Error::InvalidData(format!(
+                "record at offset {} has length {}, which exceeds the \
+                 limit of {} bytes", offset, len, MAX_RECORD_SIZE))));
+        }
+
+        if self.reader.data_hard(len).is_err() {
+            // Don't read the rest of the input, what is buffered is
+            // all that is left.
+            let available = self.reader.buffer().len();
+            return Err(self.framing_error(Error::NotEnoughData(format!(
+                "record at offset {} has length {}, but only {} bytes \
+                 are left", offset, len, available))));
+        }
         let content = self.reader.data_consume_hard(len)?;
 
         // The length includes the four byte length itself.
-        let offset = self.offset;
-        self.offset += len;
+        self.offset += len as u64;
 
-        let kbx_record = KeyboxRecord::new(offset, (&content[..len]).to_vec())?;
+        let kbx_record = KeyboxRecord::new(public_offset(offset),
+                                           (&content[..len]).to_vec())?;
         Ok(kbx_record)
     }
 
+    /// Stops the iteration after a framing error.
+    ///
+    /// If a record's length is corrupted, the following records
+    /// cannot be located.  Skip the rest of the input so that the
+    /// iterator ends instead of returning the same error forever.
+    fn framing_error(&mut self, err: Error) -> anyhow::Error {
+        let _ = self.reader.drop_eof();
+        err.into()
+    }
+
+    /// Checks the structural integrity of the keybox.
+    ///
+    /// Walks over all records, and returns the problems found along
+    /// with the offset of the affected record.  If no problems are
+    /// found, the returned list is empty.
+    ///
+    /// This checks that the records' lengths match the available
+    /// data, that the first record is a header record with the
+    /// correct magic, and that the offsets stored in OpenPGP records
+    /// fall within the record.  It also checks the records'
+    /// checksums.
+    ///
+    /// Unlike iterating over the keybox, this does not stop at the
+    /// first problem.  However, if a record's length is corrupted,
+    /// the following records cannot be located, and the walk stops.
+    pub fn verify(mut self) -> Vec<(usize, Error)> {
+        let mut problems = Vec::new();
+        let mut first = true;
+
+        while ! self.reader.eof() {
+            let offset = public_offset(self.offset);
+            let len = match self.reader.data_hard(4) {
+                Ok(input) =>
+                    u32::from_be_bytes(input[..4].try_into().unwrap()) as usize,
+                Err(e) => {
+                    problems.push((offset, Error::NotEnoughData(
+                        format!("record length truncated: {}", e))));
+                    break;
+                }
+            };
+
+            if len < 6 {
+                problems.push((offset, Error::InvalidData(format!(
+                    "record length {} is shorter than the record header",
+                    len))));
+                break;
+            }
+            if len > MAX_RECORD_SIZE {
+                problems.push((offset, Error::InvalidData(format!(
+                    "record length {} exceeds the limit of {} bytes",
+                    len, MAX_RECORD_SIZE))));
+                break;
+            }
+
+            let bytes = match self.reader.data_consume_hard(len) {
+                Ok(content) => content[..len].to_vec(),
+                Err(_) => {
+                    let available = self.reader.buffer().len();
+                    problems.push((offset, Error::NotEnoughData(format!(
+                        "record length is {}, but only {} bytes are left",
+                        len, available))));
+                    break;
+                }
+            };
+            self.offset += len as u64;
+
+            let typ = KeyboxRecordType::from(bytes[4]);
+            if first && typ != KeyboxRecordType::Header {
+                problems.push((offset, Error::InvalidData(
+                    "the first record is not a header record".into())));
+            }
+            first = false;
+
+            match typ {
+                KeyboxRecordType::Header => {
+                    let header = HeaderRecord { offset, bytes };
+                    if header.bytes.len() < 0x18 {
+                        problems.push((offset, Error::NotEnoughData(format!(
+                            "header record is 24 bytes, got {}",
+                            header.bytes.len()))));
+                    } else if ! header.check_magic() {
+                        problems.push((offset, Error::InvalidData(
+                            "bad magic".into())));
+                    }
+                }
+                KeyboxRecordType::OpenPGP => {
+                    let record = OpenPGPRecordV1 { offset, bytes };
+                    problems.extend(
+                        record.verify().into_iter().map(|p| (offset, p)));
+                }
+                _ => (),
+            }
+        }
+
+        problems
+    }
+
+    /// Returns the OpenPGP records with a user ID containing
+    /// `pattern`.
+    ///
+    /// The match is case insensitive: both the user IDs and
+    /// `pattern` are converted to lowercase, and the lowercase user
+    /// ID must contain the lowercase pattern.  User IDs that are not
+    /// valid UTF-8 are converted lossily.  An empty pattern matches
+    /// every record with at least one user ID.
+    ///
+    /// The user IDs are taken from the record's metadata section.  If
+    /// it doesn't list any user IDs, the cert is parsed instead.
+    /// Records that fail to parse are skipped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use sequoia_ipc::keybox::Keybox;
+    /// # fn search(kbx: Keybox) -> sequoia_openpgp::Result<()> {
+    /// for record in kbx.find_by_userid("@example.org") {
+    ///     println!("{}", record.cert()?.fingerprint());
+    /// }
+    /// # Ok(()) }
+    /// ```
+    pub fn find_by_userid(self, pattern: &str)
+                          -> impl Iterator<Item = OpenPGPRecordV1> + 'a
+    {
+        let pattern = pattern.to_lowercase();
+        self
+            .filter_map(|record| match record {
+                Ok(KeyboxRecord::OpenPGP(r)) => Some(r),
+                _ => None,
+            })
+            .filter(move |r| {
+                r.user_id_values().iter().any(|uid| {
+                    String::from_utf8_lossy(uid).to_lowercase()
+                        .contains(&pattern)
+                })
+            })
+    }
+
+    /// Returns the first OpenPGP record containing a key with the
+    /// given fingerprint.
+    ///
+    /// The fingerprints are taken from the records' metadata
+    /// sections, see [`KeyInfo::fingerprint`].  The cert is only
+    /// parsed for the matching record.
+    ///
+    /// A keybox may contain several records for the same cert, for
+    /// instance, if an update was interrupted.  In that case, the
+    /// first match wins: the record that comes first in the keybox is
+    /// returned, and the others are ignored.  GnuPG does the same.
+    /// Use [`Keybox::duplicates`] to find such records.
+    ///
+    /// Fails if a record preceding the match is malformed.
+    pub fn find_by_fingerprint(self, fingerprint: &Fingerprint)
+                               -> Result<Option<OpenPGPRecordV1>>
+    {
+        for record in self {
+            if let KeyboxRecord::OpenPGP(r) = record? {
+                if r.keys()?.iter().any(|k| &k.fingerprint() == fingerprint) {
+                    return Ok(Some(r));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the primary key fingerprints that appear in more than
+    /// one OpenPGP record.
+    ///
+    /// For each such fingerprint, the offsets of the records are
+    /// returned, in the order they appear in the keybox.  The
+    /// fingerprints are ordered by their first occurrence.  Lookups
+    /// return the first record, see [`Keybox::find_by_fingerprint`],
+    /// so repair tools will usually want to merge the others into it,
+    /// or delete them.
+    ///
+    /// Fails if a record is malformed.  Use [`Keybox::verify`] to
+    /// find the problems.
+    pub fn duplicates(self) -> Result<Vec<(Fingerprint, Vec<usize>)>> {
+        let mut seen: Vec<(Fingerprint, Vec<usize>)> = Vec::new();
+        let mut index = std::collections::HashMap::new();
+        for record in self {
+            let r = match record? {
+                KeyboxRecord::OpenPGP(r) => r,
+                _ => continue,
+            };
+            let primary = match r.keys()?.first() {
+                Some(key) => key.fingerprint(),
+                None => continue,
+            };
+            let i = *index.entry(primary.clone()).or_insert_with(|| {
+                seen.push((primary, Vec::new()));
+                seen.len() - 1
+            });
+            seen[i].1.push(r.offset());
+        }
+
+        seen.retain(|(_, offsets)| offsets.len() > 1);
+        Ok(seen)
+    }
+
     /// Reads from the given buffered reader.
     ///
     /// Implementations of this function should be short.  Ideally,
@@ -107,6 +349,11 @@ impl<'a> Keybox<'a> {
     /// The default implementation just uses
     /// [`Parse::from_buffered_reader`], but implementations can
     /// provide their own specialized version.
+    ///
+    /// The file is opened read-only, and no lock is taken, so this
+    /// works for keyboxes the user cannot write to, like a shared or
+    /// system keybox.  GnuPG only locks keyboxes to modify them, and
+    /// replaces them atomically, so readers don't need the lock.
     pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> Result<Self>
     {
         Self::from_buffered_reader(
@@ -125,6 +372,54 @@ impl<'a> Keybox<'a> {
             buffered_reader::Memory::with_cookie(data.as_ref(), Default::default())
                 .into_boxed())
     }
+
+    /// Converts an OpenPGP keyring to a keybox.
+    ///
+    /// Parses `keyring`, a sequence of certs like GnuPG's legacy
+    /// `pubring.gpg`, and returns a keybox containing a header record
+    /// followed by one OpenPGP record per cert, in the order of the
+    /// keyring.  The records' metadata sections list the keys'
+    /// fingerprints and the user IDs, so that they can be indexed.
+    /// Secret key material is not included.
+    ///
+    /// Certs that cannot be parsed or converted are skipped.  They are
+    /// returned along with their position in the keyring.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # fn convert(pubring: &std::path::Path) -> sequoia_openpgp::Result<()> {
+    /// use sequoia_ipc::keybox::Keybox;
+    ///
+    /// let (kbx, skipped) =
+    ///     Keybox::import_keyring(std::fs::File::open(pubring)?)?;
+    /// for (i, err) in skipped {
+    ///     eprintln!("Skipping cert #{}: {}", i, err);
+    /// }
+    /// std::fs::write(pubring.with_file_name("pubring.kbx"), kbx)?;
+    /// # Ok(()) }
+    /// ```
+    pub fn import_keyring<R: std::io::Read + Send + Sync>(keyring: R)
+        -> Result<(Vec<u8>, Vec<(usize, anyhow::Error)>)>
+    {
+        use openpgp::cert::CertParser;
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs().try_into().unwrap_or(u32::MAX))
+            .unwrap_or(0);
+
+        let mut kbx = header_record(now);
+        let mut skipped = Vec::new();
+        for (i, cert) in CertParser::from_reader(keyring)?.enumerate() {
+            match cert.and_then(|cert| openpgp_record(&cert, now)) {
+                Ok(record) => kbx.extend_from_slice(&record),
+                Err(err) => skipped.push((i, err)),
+            }
+        }
+
+        Ok((kbx, skipped))
+    }
 }
 
 impl<'a> Iterator for Keybox<'a> {
@@ -139,6 +434,277 @@ impl<'a> Iterator for Keybox<'a> {
     }
 }
 
+/// A memory-mapped keybox.
+///
+/// Created using [`Keybox::open_mmap`].  The records are accessed in
+/// place using [`MappedKeybox::blobs`], which allows scanning for a
+/// fingerprint without copying the records.
+///
+/// # Safety
+///
+/// The mapping reflects changes to the underlying file.  Rust
+/// assumes that the data behind a shared reference does not change,
+/// which the mapping cannot guarantee: if another process modifies
+/// the file while it is mapped, the records change under our feet.
+/// That is why mapping a file is `unsafe`.  The records are only read
+/// using bounds-checked accessors, and are treated as untrusted
+/// input, so in practice, a concurrent modification results in a
+/// garbled record or a checksum mismatch.
+///
+/// If the file is truncated while it is mapped, accessing the pages
+/// beyond the new end of the file raises `SIGBUS` on Unix, which
+/// terminates the process.  [`MappedKeybox::blobs`] and
+/// [`MappedKeybox::find_by_fingerprint`] check that the file didn't
+/// shrink before walking the records, but that cannot rule out that
+/// it is truncated concurrently.  GnuPG never truncates a keybox in
+/// place: it writes the new keybox to a temporary file, and renames
+/// it over the old one, which leaves the mapping intact.  Only map
+/// keyboxes that are updated that way.  On Windows, files that are
+/// mapped cannot be truncated.
+#[cfg(feature = "mmap")]
+pub struct MappedKeybox {
+    file: std::fs::File,
+
+    /// The mapping, `None` if the file is empty.
+    ///
+    /// Zero-length mappings are not supported on all platforms.
+    map: Option<memmap2::Mmap>,
+}
+
+#[cfg(feature = "mmap")]
+impl<'a> Keybox<'a> {
+    /// Memory-maps the given file.
+    ///
+    /// Use this instead of [`Keybox::from_file`] for repeated lookups
+    /// in large keyboxes.  See [`MappedKeybox`] for the caveats.
+    ///
+    /// `path` must be a regular file.  Like [`Keybox::from_file`], this
+    /// opens the file read-only, and doesn't take a lock.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # fn lookup(fp: &sequoia_openpgp::Fingerprint)
+    /// #     -> sequoia_openpgp::Result<()> {
+    /// use sequoia_ipc::keybox::Keybox;
+    ///
+    /// let kbx = Keybox::open_mmap("/home/alice/.gnupg/pubring.kbx")?;
+    /// if let Some(record) = kbx.find_by_fingerprint(fp)? {
+    ///     println!("Found {}", record.cert()?.fingerprint());
+    /// }
+    /// # Ok(()) }
+    /// ```
+    pub fn open_mmap<P: AsRef<std::path::Path>>(path: P)
+                                                -> Result<MappedKeybox>
+    {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Opening {}", path.display()))?;
+
+        // Mapping pipes, character devices, etc. either fails or
+        // doesn't do what we want.
+        let metadata = file.metadata()?;
+        if ! metadata.is_file() {
+            return Err(openpgp::Error::InvalidArgument(format!(
+                "{} is not a regular file", path.display())).into());
+        }
+
+        let map = if metadata.len() == 0 {
+            None
+        } else {
+            // Safety: see the documentation of `MappedKeybox`.
+            Some(unsafe { memmap2::Mmap::map(&file) }
+                 .with_context(|| format!("Mapping {}", path.display()))?)
+        };
+
+        Ok(MappedKeybox { file, map })
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl MappedKeybox {
+    /// Returns the mapped data.
+    fn data(&self) -> &[u8] {
+        self.map.as_deref().unwrap_or(&[])
+    }
+
+    /// Checks that the file didn't shrink since it was mapped.
+    fn check_size(&self) -> Result<()> {
+        let len = self.file.metadata()?.len();
+        if len < self.data().len() as u64 {
+            return Err(Error::NotEnoughData(format!(
+                "keybox shrank from {} to {} bytes while it was mapped",
+                self.data().len(), len)).into());
+        }
+        Ok(())
+    }
+
+    /// Returns the keybox's records.
+    ///
+    /// The records are borrowed from the mapping.
+    pub fn blobs(&self) -> Result<Blobs<'_>> {
+        self.check_size()?;
+        Ok(Blobs {
+            data: self.data(),
+            offset: 0,
+        })
+    }
+
+    /// Returns a parser for the mapped keybox.
+    ///
+    /// Unlike [`MappedKeybox::blobs`], this copies the records.
+    pub fn keybox(&self) -> Result<Keybox<'_>> {
+        self.check_size()?;
+        Keybox::from_bytes(self.data())
+    }
+
+    /// Returns the OpenPGP record containing a key with the given
+    /// fingerprint.
+    ///
+    /// This scans the fingerprints stored in the records' metadata
+    /// sections, and only copies and parses the matching record.  See
+    /// [`KeyInfo::fingerprint`] for how version 6 fingerprints are
+    /// stored.
+    ///
+    /// If several records contain the key, the first match wins, see
+    /// [`Keybox::find_by_fingerprint`].
+    ///
+    /// Fails if a record is malformed.
+    pub fn find_by_fingerprint(&self, fingerprint: &Fingerprint)
+                               -> Result<Option<OpenPGPRecordV1>>
+    {
+        for blob in self.blobs()? {
+            let blob = blob?;
+            if blob.fingerprints()?.iter().any(|fp| fp == fingerprint) {
+                return match blob.record()? {
+                    KeyboxRecord::OpenPGP(record) => Ok(Some(record)),
+                    _ => unreachable!("only OpenPGP records have fingerprints"),
+                };
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Iterates over a keybox's records without copying them.
+///
+/// Returned by [`MappedKeybox::blobs`].  If a record's length is
+/// corrupted, the following records cannot be located, and the
+/// iteration stops after returning an error.
+#[cfg(feature = "mmap")]
+pub struct Blobs<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+#[cfg(feature = "mmap")]
+impl<'a> Iterator for Blobs<'a> {
+    type Item = Result<Blob<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.data.get(self.offset..).filter(|r| ! r.is_empty())?;
+        let offset = self.offset;
+
+        let len = rest.get(..4)
+            .map(|l| u32::from_be_bytes(l.try_into().unwrap()) as usize);
+        let bytes = match len {
+            Some(len) if len >= 6 && len <= rest.len() => &rest[..len],
+            _ => {
+                // Stop.
+                self.offset = self.data.len();
+                return Some(Err(Error::NotEnoughData(format!(
+                    "record at offset {} is truncated", offset)).into()));
+            }
+        };
+        self.offset += bytes.len();
+
+        Some(Ok(Blob { offset, bytes }))
+    }
+}
+
+/// A keybox record borrowed from a [`MappedKeybox`].
+#[cfg(feature = "mmap")]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Blob<'a> {
+    offset: usize,
+    bytes: &'a [u8],
+}
+
+#[cfg(feature = "mmap")]
+impl<'a> Blob<'a> {
+    /// Returns the offset in the Keybox file.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the record's raw bytes.
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// The 5th byte contains the record's type.
+    pub fn typ(&self) -> KeyboxRecordType {
+        KeyboxRecordType::from(self.bytes[4])
+    }
+
+    /// Returns the fingerprints stored in the metadata section.
+    ///
+    /// For records other than OpenPGP records, this is empty.  The
+    /// checksum is not verified.
+    pub fn fingerprints(&self) -> Result<Vec<Fingerprint>> {
+        if self.typ() != KeyboxRecordType::OpenPGP {
+            return Ok(Vec::new());
+        }
+        Ok(parse_metadata(self.bytes)?.keys.iter()
+           .map(KeyInfo::fingerprint)
+           .collect())
+    }
+
+    /// Returns the unix timestamp when the record was created.
+    ///
+    /// For records other than OpenPGP records, this is `None`.  See
+    /// [`OpenPGPRecordV1::created_at`].
+    pub fn created_at(&self) -> Result<Option<u32>> {
+        self.metadata(|m| Some(m.created_at))
+    }
+
+    /// Returns the newest timestamp in the cert.
+    ///
+    /// For records other than OpenPGP records, and if the field is
+    /// not set, this is `None`.  See [`OpenPGPRecordV1::recorded_at`].
+    pub fn recorded_at(&self) -> Result<Option<u32>> {
+        self.metadata(|m| Some(m.latest_timestamp).filter(|t| *t != 0))
+    }
+
+    /// Returns the unix timestamp after which the cert's validity
+    /// should be rechecked.
+    ///
+    /// For records other than OpenPGP records, and if the field is
+    /// not set, this is `None`.  See
+    /// [`OpenPGPRecordV1::recheck_after`].
+    pub fn recheck_after(&self) -> Result<Option<u32>> {
+        self.metadata(|m| Some(m.recheck_after).filter(|t| *t != 0))
+    }
+
+    /// Parses the metadata section of OpenPGP records, and applies
+    /// `f` to it.
+    fn metadata<T>(&self, f: impl FnOnce(&Metadata) -> Option<T>)
+                   -> Result<Option<T>>
+    {
+        if self.typ() != KeyboxRecordType::OpenPGP {
+            return Ok(None);
+        }
+        Ok(f(&parse_metadata(self.bytes)?))
+    }
+
+    /// Copies and parses the record.
+    ///
+    /// This verifies the checksum of OpenPGP records.
+    pub fn record(&self) -> Result<KeyboxRecord> {
+        KeyboxRecord::new(self.offset, self.bytes.to_vec())
+    }
+}
+
 /// Types of keybox records.
 ///
 /// Note: This enum cannot be exhaustively matched to allow future extensions.
@@ -269,7 +835,12 @@ pub struct HeaderRecord {
 
 impl HeaderRecord {
     fn new(offset: usize, bytes: Vec<u8>) -> Result<Self> {
-        //TODO at least check length?
+        // The accessors read up to offset 0x17.
+        if bytes.len() < 0x18 {
+            return Err(Error::NotEnoughData(format!(
+                "header record is 24 bytes, got {}", bytes.len()))
+                .into());
+        }
         Ok(Self { offset, bytes })
     }
 
@@ -338,7 +909,9 @@ pub struct OpenPGPRecordV1 {
 impl OpenPGPRecordV1 {
     fn new(offset: usize, record: &KeyboxRecord) -> Result<Self> {
         // Check type and version
-        if record.typ() != KeyboxRecordType::OpenPGP || record.version() != 1 {
+        if record.typ() != KeyboxRecordType::OpenPGP
+            || ! matches!(record.version(), 1 | 2)
+        {
             return Err(
                 Error::UnhandledRecord(record.typ(), record.version()).into()
             );
@@ -358,6 +931,15 @@ impl OpenPGPRecordV1 {
             bytes: record.bytes().to_vec(),
         };
 
+        // The checksum follows the data section, make sure both fit.
+        if record.data_end().is_none() {
+            return Err(Error::InvalidData(format!(
+                "data section at {} of length {} and checksum exceed \
+                 the record of length {}",
+                record.data_offset(), record.data_length(),
+                record.bytes.len())).into());
+        }
+
         // Check checksum
         if record.checksum_field()[..] != record.compute_checksum()? {
             return Err(Error::InvalidData("wrong checksum".to_string()).into());
@@ -366,6 +948,76 @@ impl OpenPGPRecordV1 {
         Ok(record)
     }
 
+    /// Checks the structural integrity of the record.
+    ///
+    /// Unlike [`OpenPGPRecordV1::new`], this does not stop at the
+    /// first problem, and does not panic on malformed offsets.
+    fn verify(&self) -> Vec<Error> {
+        let mut problems = Vec::new();
+        let len = self.bytes.len();
+
+        if ! matches!(self.bytes[5], 1 | 2) {
+            problems.push(
+                Error::UnhandledRecord(KeyboxRecordType::OpenPGP, self.bytes[5]));
+            return problems;
+        }
+        if len < 0x10 {
+            problems.push(Error::NotEnoughData(format!(
+                "OpenPGP record header is 16 bytes, got {}", len)));
+            return problems;
+        }
+
+        let data_end = if let Some(data_end) = self.data_end() {
+            data_end
+        } else {
+            problems.push(Error::InvalidData(format!(
+                "data section at {} of length {} and checksum exceed \
+                 the record of length {}",
+                self.data_offset(), self.data_length(), len)));
+            return problems;
+        };
+
+        match self.compute_checksum() {
+            Ok(checksum) if checksum[..] == self.checksum_field()[..] => (),
+            Ok(_) => problems.push(Error::InvalidData("wrong checksum".into())),
+            Err(e) => problems.push(Error::InvalidData(
+                format!("computing checksum: {}", e))),
+        }
+
+        match self.parse_metadata() {
+            Ok(metadata) => {
+                for (i, key) in metadata.keys.iter().enumerate() {
+                    // Zero means the key ID isn't recorded.
+                    let keyid = key.keyid_offset as usize;
+                    if keyid != 0 && keyid.checked_add(8).map(|e| e > len).unwrap_or(true) {
+                        problems.push(Error::InvalidData(format!(
+                            "key {}: key ID offset {} out of bounds",
+                            i, keyid)));
+                    }
+                }
+
+                for (i, uid) in metadata.user_ids.iter().enumerate() {
+                    let start = uid.offset as usize;
+                    let end = start.checked_add(uid.length as usize);
+                    if start < self.data_offset()
+                        || end.map(|e| e > data_end).unwrap_or(true)
+                    {
+                        problems.push(Error::InvalidData(format!(
+                            "user ID {}: offset {} and length {} are outside \
+                             the data section",
+                            i, uid.offset, uid.length)));
+                    }
+                }
+            }
+            Err(e) => problems.push(match e.downcast::<Error>() {
+                Ok(e) => e,
+                Err(e) => Error::InvalidData(e.to_string()),
+            }),
+        }
+
+        problems
+    }
+
     /// Returns the offset in the Keybox file.
     pub fn offset(&self) -> usize {
         self.offset
@@ -377,6 +1029,121 @@ impl OpenPGPRecordV1 {
         self.bytes[0x6..=0x7].try_into().unwrap()
     }
 
+    /// Blob flags.
+    ///
+    /// The same as [`OpenPGPRecordV1::flags`], as a big-endian
+    /// integer.  Bit 0 is set if the blob holds secret key material,
+    /// bit 1 if the blob is ephemeral.
+    pub fn blob_flags(&self) -> u16 {
+        u16::from_be_bytes(self.flags())
+    }
+
+    /// Returns whether the blob holds secret key material.
+    pub fn has_secret_key(&self) -> bool {
+        self.blob_flags() & BLOBFLAG_SECRET != 0
+    }
+
+    /// Returns whether the blob is ephemeral.
+    ///
+    /// GnuPG marks certificates that were only imported temporarily,
+    /// e.g. while verifying a signature using an auto-retrieved
+    /// certificate, as ephemeral.  They are not returned by regular
+    /// key listings.
+    pub fn is_ephemeral(&self) -> bool {
+        self.blob_flags() & BLOBFLAG_EPHEMERAL != 0
+    }
+
+    /// Returns information about the keys stored in the metadata
+    /// section.
+    ///
+    /// The first entry is the primary key.
+    pub fn keys(&self) -> Result<Vec<KeyInfo>> {
+        Ok(self.parse_metadata()?.keys)
+    }
+
+    /// Returns information about the user IDs stored in the metadata
+    /// section.
+    ///
+    /// Keybox does not store a validity per key, but per user ID.
+    pub fn user_ids(&self) -> Result<Vec<UserIDInfo>> {
+        Ok(self.parse_metadata()?.user_ids)
+    }
+
+    /// Returns the values of the user IDs.
+    ///
+    /// They are taken from the data section using the offsets stored
+    /// in the metadata section.  If there are none, the cert is
+    /// parsed instead.
+    fn user_id_values(&self) -> Vec<Vec<u8>> {
+        let uids: Vec<Vec<u8>> = self.user_ids().unwrap_or_default().iter()
+            .filter_map(|uid| {
+                let start = uid.offset() as usize;
+                let end = start.checked_add(uid.length() as usize)?;
+                self.bytes.get(start..end).map(<[u8]>::to_vec)
+            })
+            .collect();
+        if ! uids.is_empty() {
+            return uids;
+        }
+
+        self.cert()
+            .map(|cert| cert.userids().map(|u| u.userid().value().to_vec())
+                 .collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the signatures' expiration information stored in the
+    /// metadata section.
+    ///
+    /// There is one entry per signature packet in the data section,
+    /// in the same order.  GnuPG 2.2 doesn't check the signatures,
+    /// and writes [`SignatureExpiration::NotChecked`].  Records we
+    /// write carry the expiration time of the key for self
+    /// signatures, see [`Keybox::import_keyring`].
+    pub fn signature_expirations(&self) -> Result<Vec<SignatureExpiration>> {
+        Ok(self.parse_metadata()?.signatures)
+    }
+
+    /// The ownertrust value stored in the metadata section.
+    pub fn ownertrust(&self) -> Result<u8> {
+        Ok(self.parse_metadata()?.ownertrust)
+    }
+
+    /// The validity of the cert as a whole, stored in the metadata
+    /// section.
+    pub fn all_validity(&self) -> Result<u8> {
+        Ok(self.parse_metadata()?.all_validity)
+    }
+
+    /// The unix timestamp when this record was created, stored in
+    /// the metadata section.
+    pub fn created_at(&self) -> Result<u32> {
+        Ok(self.parse_metadata()?.created_at)
+    }
+
+    /// The newest timestamp in the cert, stored in the metadata
+    /// section.
+    ///
+    /// GnuPG 2.2 doesn't set this field, in which case `None` is
+    /// returned.
+    pub fn recorded_at(&self) -> Result<Option<u32>> {
+        Ok(Some(self.parse_metadata()?.latest_timestamp).filter(|t| *t != 0))
+    }
+
+    /// The unix timestamp after which the cert's validity should be
+    /// rechecked, stored in the metadata section.
+    ///
+    /// GnuPG 2.2 doesn't set this field, in which case `None` is
+    /// returned.
+    pub fn recheck_after(&self) -> Result<Option<u32>> {
+        Ok(Some(self.parse_metadata()?.recheck_after).filter(|t| *t != 0))
+    }
+
+    /// Parses the metadata section.
+    fn parse_metadata(&self) -> Result<Metadata> {
+        parse_metadata(&self.bytes)
+    }
+
     /// Data offset field.
     pub fn data_offset(&self) -> usize {
         u32::from_be_bytes((self.bytes[0x8..=0xB]).try_into().unwrap()) as usize
@@ -389,23 +1156,30 @@ impl OpenPGPRecordV1 {
 
     /// The record's contained raw data.
     pub fn data_section(&self) -> Result<&[u8]> {
-        let data_end = self.data_offset() + self.data_length();
+        let data_end = self.data_offset().checked_add(self.data_length())
+            .filter(|end| *end <= self.bytes.len());
         // Check if data length is correct
-        if self.bytes.len() < data_end {
-            return Err(Error::NotEnoughData(
-                "data section truncated".to_string(),
-            )
-            .into());
-        };
+        let data_end = data_end.ok_or_else(|| Error::NotEnoughData(
+            "data section truncated".to_string(),
+        ))?;
         Ok(&self.bytes[self.data_offset()..data_end])
     }
 
-    /// Metadata section, unhandled.
-    // Not handled, contains:
-    // Redundant data (fingerprints, keyids, userids) of the
-    // following cert,
-    // management fields (ownertrust, all-validity?)
-    // timestamps (recheck?, latest change?, creation date)
+    /// Returns the end of the data section, if the data section and
+    /// the checksum following it fit into the record.
+    fn data_end(&self) -> Option<usize> {
+        self.data_offset().checked_add(self.data_length())
+            .filter(|end| self.data_offset() >= 0x10
+                    && end.checked_add(20).map(|e| e <= self.bytes.len())
+                        .unwrap_or(false))
+    }
+
+    /// Metadata section.
+    ///
+    /// Contains redundant data (fingerprints, keyids, userids) of the
+    /// following cert, management fields (ownertrust, validity), and
+    /// timestamps.  See [`OpenPGPRecordV1::keys`] and
+    /// [`OpenPGPRecordV1::user_ids`] for the parsed fields.
     pub fn metadata_section(&self) -> &[u8] {
         &self.bytes[0x10..self.data_offset()]
     }
@@ -431,7 +1205,7 @@ impl OpenPGPRecordV1 {
         ctx.into_digest()
     }
 
-    /// Extract the cert from a keybox openpgp version 1 record.
+    /// Extract the cert from a keybox openpgp version 1 or 2 record.
     /// Ignores metadata and flags stored in the record, but
     /// checks the checksum.
     pub fn cert(&self) -> Result<Cert> {
@@ -443,6 +1217,505 @@ impl OpenPGPRecordV1 {
     }
 }
 
+/// Parses the metadata section of the OpenPGP record `bytes`.
+///
+/// The layout is described in GnuPG's `kbx/keybox-blob.c`.  The
+/// entry sizes are stored in the record, and may be larger than the
+/// ones we understand.  The layout of the key info entries depends on
+/// the record's version: version 1 records have 20 byte fingerprints
+/// and key ID offsets, version 2 records have 32 byte fingerprints
+/// and keygrips, but no key ID offsets.
+fn parse_metadata(bytes: &[u8]) -> Result<Metadata> {
+    let version = bytes.get(5).copied().unwrap_or(0);
+    let data_offset = bytes.get(0x8..0xC)
+        .map(|b| u32::from_be_bytes(b.try_into().unwrap()) as usize)
+        .ok_or_else(|| Error::NotEnoughData(format!(
+            "OpenPGP record header is 16 bytes, got {}", bytes.len())))?;
+    if data_offset < 0x10 || data_offset > bytes.len() {
+        return Err(Error::InvalidData(format!(
+            "data offset {} out of range", data_offset)).into());
+    }
+    let mut fields = Fields {
+        data: &bytes[..data_offset],
+        pos: 0x10,
+    };
+
+    let nkeys = fields.u16()?;
+    let v2 = version == 2;
+    let key_size = fields.entry_size(
+        if v2 { KEY_INFO_SIZE_V2 } else { KEY_INFO_SIZE }, "key info")?;
+    let mut keys = Vec::with_capacity(nkeys.into());
+    for i in 0..nkeys {
+        let mut entry = Fields { data: fields.take(key_size)?, pos: 0 };
+        let key = if v2 {
+            // Shorter fingerprints are padded with zeros.
+            let fingerprint = entry.take(32)?;
+            let flags = entry.u16()?;
+            // Reserved.
+            entry.u16()?;
+            let keygrip = entry.take(20)?;
+            KeyInfo {
+                fingerprint: if flags & KEYFLAG_FPR32 == 0 {
+                    fingerprint[..20].to_vec()
+                } else {
+                    fingerprint.to_vec()
+                },
+                keyid_offset: 0,
+                flags,
+                keygrip: Some(keygrip.try_into().unwrap()),
+            }
+        } else {
+            let fingerprint = entry.take(20)?;
+            let keyid_offset = entry.u32()?;
+            let flags = entry.u16()?;
+            if flags & KEYFLAG_FPR32 != 0 {
+                return Err(Error::InvalidData(format!(
+                    "key {}: 32 byte fingerprint in a version 1 record", i))
+                           .into());
+            }
+            KeyInfo {
+                fingerprint: fingerprint.to_vec(),
+                keyid_offset,
+                flags,
+                keygrip: None,
+            }
+        };
+        keys.push(key);
+    }
+
+    // Serial number, only used by X.509 records.
+    let serial_len = fields.u16()?;
+    fields.take(serial_len.into())?;
+
+    let nuids = fields.u16()?;
+    let uid_size = fields.entry_size(12, "user ID info")?;
+    let mut user_ids = Vec::with_capacity(nuids.into());
+    for _ in 0..nuids {
+        let mut entry = Fields { data: fields.take(uid_size)?, pos: 0 };
+        user_ids.push(UserIDInfo {
+            offset: entry.u32()?,
+            length: entry.u32()?,
+            flags: entry.u16()?,
+            validity: entry.u8()?,
+        });
+    }
+
+    let nsigs = fields.u16()?;
+    let sig_size = fields.entry_size(4, "signature info")?;
+    let mut signatures = Vec::with_capacity(nsigs.into());
+    for _ in 0..nsigs {
+        let mut entry = Fields { data: fields.take(sig_size)?, pos: 0 };
+        signatures.push(entry.u32()?.into());
+    }
+
+    let ownertrust = fields.u8()?;
+    let all_validity = fields.u8()?;
+    // Reserved.
+    fields.u16()?;
+    let recheck_after = fields.u32()?;
+    let latest_timestamp = fields.u32()?;
+    let created_at = fields.u32()?;
+
+    Ok(Metadata {
+        keys, user_ids, signatures, ownertrust, all_validity,
+        recheck_after, latest_timestamp, created_at,
+    })
+}
+
+/// Blob flag: the blob holds secret key material.
+const BLOBFLAG_SECRET: u16 = 1 << 0;
+/// Blob flag: the blob is ephemeral.
+const BLOBFLAG_EPHEMERAL: u16 = 1 << 1;
+
+/// The size of a key info entry in a version 1 record.
+const KEY_INFO_SIZE: usize = 20 + 4 + 2 + 2;
+/// The size of a key info entry in a version 2 record.
+const KEY_INFO_SIZE_V2: usize = 32 + 2 + 2 + 20;
+/// Key flag: the key has a 32 byte fingerprint.
+const KEYFLAG_FPR32: u16 = 1 << 7;
+
+/// Key information stored in the metadata section of an OpenPGP
+/// record.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct KeyInfo {
+    fingerprint: Vec<u8>,
+    keyid_offset: u32,
+    flags: u16,
+    keygrip: Option<[u8; 20]>,
+}
+
+impl KeyInfo {
+    /// The key's fingerprint.
+    ///
+    /// Version 4 keys have 20 byte fingerprints.  Version 6 keys
+    /// have 32 byte fingerprints, which only fit into version 2
+    /// records.  There, like GnuPG 2.3 and later, such fingerprints
+    /// are marked using bit 7 of the key flags.  Since GnuPG's
+    /// version 5 keys also have 32 byte fingerprints, and the
+    /// metadata section doesn't record the key's version, 32 byte
+    /// fingerprints are returned as version 6 fingerprints.
+    pub fn fingerprint(&self) -> Fingerprint {
+        let version = if self.fingerprint.len() == 32 { 6 } else { 4 };
+        Fingerprint::from_bytes(version, &self.fingerprint)
+            .expect("20 and 32 bytes are valid fingerprints")
+    }
+
+    /// Offset of the key ID within the record.
+    ///
+    /// In version 1 records, this points to the last eight bytes of
+    /// the fingerprint stored in the metadata section.  Version 2
+    /// records don't record it, and zero is returned.
+    pub fn keyid_offset(&self) -> u32 {
+        self.keyid_offset
+    }
+
+    /// The key's keygrip.
+    ///
+    /// Only version 2 records store it.
+    pub fn keygrip(&self) -> Option<Keygrip> {
+        self.keygrip.map(Keygrip::from)
+    }
+
+    /// Key flags field.
+    ///
+    /// Bit 7 is set for keys with 32 byte fingerprints, see
+    /// [`KeyInfo::fingerprint`].
+    // The other bits are always zero in GnuPG 2.2.
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+}
+
+/// Signature information stored in the metadata section of an
+/// OpenPGP record.
+///
+/// keyboxd uses this to avoid checking the signatures again.  The
+/// values are described in GnuPG's `kbx/keybox-blob.c`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SignatureExpiration {
+    /// The signature has not been checked.
+    NotChecked,
+    /// The key that made the signature is missing.
+    MissingKey,
+    /// The signature is bad.
+    Bad,
+    /// The signature is valid, and expires at the given unix
+    /// timestamp.
+    Expires(u32),
+    /// The signature is valid, and does not expire.
+    Never,
+}
+
+impl From<u32> for SignatureExpiration {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => SignatureExpiration::NotChecked,
+            1 => SignatureExpiration::MissingKey,
+            2 => SignatureExpiration::Bad,
+            u32::MAX => SignatureExpiration::Never,
+            t => SignatureExpiration::Expires(t),
+        }
+    }
+}
+
+impl From<SignatureExpiration> for u32 {
+    fn from(value: SignatureExpiration) -> Self {
+        match value {
+            SignatureExpiration::NotChecked => 0,
+            SignatureExpiration::MissingKey => 1,
+            SignatureExpiration::Bad => 2,
+            // Don't collide with the special values.
+            SignatureExpiration::Expires(t) => t.clamp(3, u32::MAX - 1),
+            SignatureExpiration::Never => u32::MAX,
+        }
+    }
+}
+
+/// User ID information stored in the metadata section of an OpenPGP
+/// record.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct UserIDInfo {
+    offset: u32,
+    length: u32,
+    flags: u16,
+    validity: u8,
+}
+
+impl UserIDInfo {
+    /// Offset of the user ID within the record.
+    ///
+    /// Points into the data section, i.e. to the user ID packet's
+    /// body.
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// Length of the user ID.
+    pub fn length(&self) -> u32 {
+        self.length
+    }
+
+    /// User ID flags field.
+    // Semantics unknown.
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+
+    /// The user ID's validity as computed by GnuPG's trust model.
+    ///
+    /// The values correspond to GnuPG's `TRUST_*` constants.
+    pub fn validity(&self) -> u8 {
+        self.validity
+    }
+}
+
+/// The parsed metadata section of an OpenPGP record.
+struct Metadata {
+    keys: Vec<KeyInfo>,
+    user_ids: Vec<UserIDInfo>,
+    signatures: Vec<SignatureExpiration>,
+    ownertrust: u8,
+    all_validity: u8,
+    recheck_after: u32,
+    latest_timestamp: u32,
+    created_at: u32,
+}
+
+/// Bounds-checked access to big-endian fields.
+struct Fields<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(n)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| Error::NotEnoughData(format!(
+                "metadata section truncated at offset {}", self.pos)))?;
+        let field = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(field)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// Reads the size of the following entries, which must be at
+    /// least `min` bytes.
+    fn entry_size(&mut self, min: usize, what: &str) -> Result<usize> {
+        let size = self.u16()?.into();
+        if size < min {
+            return Err(Error::InvalidData(format!(
+                "{} size is {}, expected at least {}", what, size, min))
+                .into());
+        }
+        Ok(size)
+    }
+}
+
+/// Serializes a header record.
+///
+/// `now` is used as the creation and the maintenance time.
+fn header_record(now: u32) -> Vec<u8> {
+    let mut record = Vec::with_capacity(32);
+    record.extend_from_slice(&32u32.to_be_bytes());
+    // Type and version.
+    record.extend_from_slice(&[1, 1]);
+    // Flags, GnuPG sets bit 1.
+    record.extend_from_slice(&2u16.to_be_bytes());
+    record.extend_from_slice(b"KBXf");
+    // Reserved.
+    record.extend_from_slice(&[0; 4]);
+    record.extend_from_slice(&now.to_be_bytes());
+    record.extend_from_slice(&now.to_be_bytes());
+    // Reserved.
+    record.extend_from_slice(&[0; 8]);
+    record
+}
+
+/// Serializes an OpenPGP record for `cert`.
+///
+/// The layout is described in GnuPG's `kbx/keybox-blob.c`, see
+/// [`OpenPGPRecordV1::parse_metadata`].  `created` is used as the
+/// record's creation time.
+///
+/// For the self signatures, which have been checked when the cert
+/// was parsed, the expiration time of the key they bind is recorded.
+/// The other signatures are recorded as not checked.
+fn openpgp_record(cert: &Cert, created: u32) -> Result<Vec<u8>> {
+    use openpgp::serialize::Serialize;
+    use openpgp::Packet;
+
+    /// The sizes of the entries we write.
+    const UID_SIZE: usize = 12;
+    const SIG_SIZE: usize = 4;
+
+    fn u16_len(n: usize, what: &str) -> Result<u16> {
+        n.try_into().map_err(|_| Error::InvalidData(format!(
+            "too many {}: {}", what, n)).into())
+    }
+
+    // The expiration times of the keys bound by the self
+    // signatures.
+    let expiration = |creation: std::time::SystemTime,
+                      sig: &openpgp::packet::Signature|
+    {
+        match sig.key_validity_period() {
+            None => SignatureExpiration::Never,
+            Some(validity) => SignatureExpiration::Expires(
+                (creation + validity)
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_secs().try_into().unwrap_or(u32::MAX))
+                    .unwrap_or(0)),
+        }
+    };
+    let primary_creation = cert.primary_key().key().creation_time();
+    let mut self_sigs = Vec::new();
+    self_sigs.extend(cert.primary_key().self_signatures()
+                     .map(|s| (s, expiration(primary_creation, s))));
+    for ua in cert.userids() {
+        self_sigs.extend(ua.self_signatures()
+                         .map(|s| (s, expiration(primary_creation, s))));
+    }
+    for ua in cert.user_attributes() {
+        self_sigs.extend(ua.self_signatures()
+                         .map(|s| (s, expiration(primary_creation, s))));
+    }
+    for ka in cert.keys().subkeys() {
+        let creation = ka.key().creation_time();
+        self_sigs.extend(ka.self_signatures()
+                         .map(|s| (s, expiration(creation, s))));
+    }
+
+    // The data section, i.e. the cert without secret key material.
+    // Remember where the user IDs' values are.
+    let mut data = Vec::new();
+    let mut uids = Vec::new();
+    let mut sigs = Vec::new();
+    for packet in cert.clone().into_packets() {
+        packet.serialize(&mut data)?;
+        match packet {
+            Packet::UserID(u) =>
+                uids.push((data.len() - u.value().len(), u.value().len())),
+            Packet::Signature(s) => sigs.push(
+                self_sigs.iter().find(|(self_sig, _)| **self_sig == s)
+                    .map(|(_, e)| *e)
+                    .unwrap_or(SignatureExpiration::NotChecked)),
+            _ => (),
+        }
+    }
+
+    let fingerprints = cert.keys().map(|ka| match ka.key().fingerprint() {
+        Fingerprint::V4(fp) => Ok(fp.to_vec()),
+        Fingerprint::V6(fp) => Ok(fp.to_vec()),
+        fp => Err(Error::InvalidData(format!(
+            "{}: only version 4 and 6 keys are supported", fp)).into()),
+    }).collect::<Result<Vec<Vec<u8>>>>()?;
+    // Like GnuPG, only use version 2 records, which have room for
+    // 32 byte fingerprints, if they are needed.
+    let v2 = fingerprints.iter().any(|fp| fp.len() == 32);
+    let key_size = if v2 { KEY_INFO_SIZE_V2 } else { KEY_INFO_SIZE };
+
+    let nkeys = u16_len(fingerprints.len(), "keys")?;
+    let nuids = u16_len(uids.len(), "user IDs")?;
+    let nsigs = u16_len(sigs.len(), "signatures")?;
+
+    let data_offset = 0x10
+        + 2 + 2 + fingerprints.len() * key_size
+        + 2
+        + 2 + 2 + uids.len() * UID_SIZE
+        + 2 + 2 + usize::from(nsigs) * SIG_SIZE
+        + 1 + 1 + 2 + 4 + 4 + 4 + 4;
+    let length = data_offset + data.len() + 20;
+    let too_large = || Error::InvalidData(format!(
+        "record of {} bytes is too large", length));
+    let data_offset_field: u32 = data_offset.try_into().map_err(|_| too_large())?;
+    let data_len_field: u32 = data.len().try_into().map_err(|_| too_large())?;
+    let length_field: u32 = length.try_into().map_err(|_| too_large())?;
+
+    let mut record = Vec::with_capacity(length);
+    record.extend_from_slice(&length_field.to_be_bytes());
+    // Type and version.
+    record.extend_from_slice(&[2, if v2 { 2 } else { 1 }]);
+    // Blob flags.
+    record.extend_from_slice(&0u16.to_be_bytes());
+    record.extend_from_slice(&data_offset_field.to_be_bytes());
+    record.extend_from_slice(&data_len_field.to_be_bytes());
+
+    record.extend_from_slice(&nkeys.to_be_bytes());
+    record.extend_from_slice(&(key_size as u16).to_be_bytes());
+    for (fp, ka) in fingerprints.iter().zip(cert.keys()) {
+        if v2 {
+            let flags = if fp.len() == 32 { KEYFLAG_FPR32 } else { 0 };
+            record.extend_from_slice(fp);
+            // Pad short fingerprints.
+            record.resize(record.len() + 32 - fp.len(), 0);
+            record.extend_from_slice(&flags.to_be_bytes());
+            // Reserved.
+            record.extend_from_slice(&[0; 2]);
+            // GnuPG writes zeros for keys it can't compute the
+            // keygrip of.
+            let keygrip = Keygrip::try_from(ka.key())
+                .map(<[u8; 20]>::from)
+                .unwrap_or([0; 20]);
+            record.extend_from_slice(&keygrip);
+        } else {
+            // The key ID is the last eight bytes of the fingerprint.
+            let keyid_offset = record.len() + 12;
+            record.extend_from_slice(fp);
+            record.extend_from_slice(&(keyid_offset as u32).to_be_bytes());
+            // Flags and reserved.
+            record.extend_from_slice(&[0; 4]);
+        }
+    }
+
+    // No serial number.
+    record.extend_from_slice(&0u16.to_be_bytes());
+
+    record.extend_from_slice(&nuids.to_be_bytes());
+    record.extend_from_slice(&(UID_SIZE as u16).to_be_bytes());
+    for (offset, len) in &uids {
+        record.extend_from_slice(&((data_offset + offset) as u32).to_be_bytes());
+        record.extend_from_slice(&(*len as u32).to_be_bytes());
+        // Flags, validity, and reserved.
+        record.extend_from_slice(&[0; 4]);
+    }
+
+    record.extend_from_slice(&nsigs.to_be_bytes());
+    record.extend_from_slice(&(SIG_SIZE as u16).to_be_bytes());
+    for sig in &sigs {
+        record.extend_from_slice(&u32::from(*sig).to_be_bytes());
+    }
+
+    // Ownertrust, all validity, and reserved.
+    record.extend_from_slice(&[0; 4]);
+    // Recheck after, newest timestamp.
+    record.extend_from_slice(&[0; 8]);
+    record.extend_from_slice(&created.to_be_bytes());
+    // Size of the reserved space.
+    record.extend_from_slice(&0u32.to_be_bytes());
+    debug_assert_eq!(record.len(), data_offset);
+
+    record.extend_from_slice(&data);
+
+    let mut ctx = SHA1.context()?.for_digest();
+    ctx.update(&record);
+    record.extend_from_slice(&ctx.into_digest()?);
+    debug_assert_eq!(record.len(), length);
+
+    Ok(record)
+}
+
 #[derive(thiserror::Error, Debug)]
 /// Errors used in this module.
 pub enum Error {
@@ -566,6 +1839,740 @@ mod tests {
         Ok(())
     }
 
+    fn openpgp_record(name: &str) -> Result<OpenPGPRecordV1> {
+        match KeyboxRecord::new(0, crate::tests::keybox(name).to_vec())? {
+            KeyboxRecord::OpenPGP(r) => Ok(r),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn openpgp_record_flags() -> Result<()> {
+        let r = openpgp_record("testy_openpgp")?;
+        assert_eq!(r.blob_flags(), 0);
+        assert!(! r.is_ephemeral());
+        assert!(! r.has_secret_key());
+
+        let r = openpgp_record("testy_openpgp_ephemeral")?;
+        assert_eq!(r.blob_flags(), 2);
+        assert!(r.is_ephemeral());
+        assert!(! r.has_secret_key());
+        // The flags do not affect the cert.
+        let testy = Cert::from_bytes(crate::tests::key("testy.pgp"))?;
+        assert_eq!(r.cert()?, testy);
+        Ok(())
+    }
+
+    #[test]
+    fn openpgp_record_metadata() -> Result<()> {
+        let r = openpgp_record("testy_openpgp")?;
+        let testy = Cert::from_bytes(crate::tests::key("testy.pgp"))?;
+
+        let keys = r.keys()?;
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys.iter().map(|k| k.fingerprint()).collect::<Vec<_>>(),
+                   testy.keys().map(|k| k.key().fingerprint())
+                   .collect::<Vec<_>>());
+        assert_eq!(keys[0].keyid_offset(), 32);
+        assert_eq!(keys[1].keyid_offset(), 60);
+        assert!(keys.iter().all(|k| k.flags() == 0));
+
+        let uids = r.user_ids()?;
+        assert_eq!(uids.len(), 1);
+        assert_eq!(uids[0].offset(), 414);
+        assert_eq!(uids[0].length(), 36);
+        assert_eq!(uids[0].validity(), 0);
+        let uid = &r.bytes[uids[0].offset() as usize..]
+            [..uids[0].length() as usize];
+        assert_eq!(uid, testy.userids().next().unwrap().userid().value());
+
+        assert_eq!(r.ownertrust()?, 0);
+        assert_eq!(r.all_validity()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn openpgp_record_timestamps() -> Result<()> {
+        // The records were created by GnuPG 2.2 on 2021-04-22, at the
+        // same time as the keybox.
+        let header = match KeyboxRecord::new(
+            0, crate::tests::keybox("header_sample").to_vec())?
+        {
+            KeyboxRecord::Header(h) => h,
+            _ => unreachable!(),
+        };
+        for name in ["testy_openpgp", "alpha_openpgp"] {
+            let r = openpgp_record(name)?;
+            assert_eq!(r.created_at()?, 1619103374);
+            assert_eq!(r.created_at()?, header.created_at());
+            assert_eq!(r.recorded_at()?, None);
+            assert_eq!(r.recheck_after()?, None);
+        }
+
+        // The timestamps are after the legacy fields.
+        let mut r = openpgp_record("testy_openpgp")?;
+        let created = r.data_offset() - 8;
+        let latest = created - 4;
+        let recheck = latest - 4;
+        r.bytes[latest..latest + 4]
+            .copy_from_slice(&1700000000u32.to_be_bytes());
+        r.bytes[recheck..recheck + 4]
+            .copy_from_slice(&1800000000u32.to_be_bytes());
+        assert_eq!(r.recorded_at()?, Some(1700000000));
+        assert_eq!(r.recheck_after()?, Some(1800000000));
+        assert_eq!(r.created_at()?, 1619103374);
+
+        // Records we create carry the creation time.
+        let testy = Cert::from_bytes(crate::tests::key("testy.pgp"))?;
+        let bytes = super::openpgp_record(&testy, 1234567890)?;
+        match KeyboxRecord::new(0, bytes)? {
+            KeyboxRecord::OpenPGP(r) => {
+                assert_eq!(r.created_at()?, 1234567890);
+                assert_eq!(r.recorded_at()?, None);
+            },
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn openpgp_record_signature_expirations() -> Result<()> {
+        use std::time::{Duration, UNIX_EPOCH};
+        use openpgp::cert::CertBuilder;
+        use openpgp::types::KeyFlags;
+
+        // GnuPG 2.2 doesn't check the signatures.
+        let r = openpgp_record("testy_openpgp")?;
+        assert_eq!(r.signature_expirations()?,
+                   vec![SignatureExpiration::NotChecked; 2]);
+
+        let record = |cert: &Cert| -> Result<OpenPGPRecordV1> {
+            match KeyboxRecord::new(0, super::openpgp_record(cert, 0)?)? {
+                KeyboxRecord::OpenPGP(r) => Ok(r),
+                _ => unreachable!(),
+            }
+        };
+
+        let creation = UNIX_EPOCH + Duration::from_secs(1600000000);
+        let year = Duration::from_secs(365 * 24 * 60 * 60);
+        let (cert, _) = CertBuilder::new()
+            .set_creation_time(creation)
+            .add_userid("expiring <expiring@example.org>")
+            .set_validity_period(2 * year)
+            .add_signing_subkey()
+            .add_subkey(KeyFlags::empty().set_storage_encryption(), year, None)
+            .generate()?;
+        // Direct key signature, user ID binding, and two subkey
+        // bindings.  The signing subkey inherits the primary key's
+        // validity period.
+        let mut expirations = record(&cert)?.signature_expirations()?
+            .into_iter().map(u32::from).collect::<Vec<_>>();
+        // The subkeys may be reordered.
+        expirations[2..].sort();
+        assert_eq!(expirations, vec![
+            1600000000 + 2 * 31536000,
+            1600000000 + 2 * 31536000,
+            1600000000 + 31536000,
+            1600000000 + 2 * 31536000,
+        ]);
+
+        let (cert, _) = CertBuilder::new()
+            .add_userid("eternal <eternal@example.org>")
+            .set_validity_period(None)
+            .add_signing_subkey()
+            .generate()?;
+        assert_eq!(record(&cert)?.signature_expirations()?,
+                   vec![SignatureExpiration::Never; 3]);
+
+        // Third-party certifications are not checked.
+        let (certifier, _) = CertBuilder::new()
+            .add_userid("certifier").generate()?;
+        let mut signer = certifier.primary_key().key().clone()
+            .parts_into_secret()?.into_keypair()?;
+        let userid = cert.userids().next().expect("has a user ID");
+        let certification = openpgp::packet::signature::SignatureBuilder::new(
+            openpgp::types::SignatureType::GenericCertification)
+            .sign_userid_binding(&mut signer, cert.primary_key().key(),
+                                 userid.userid())?;
+        let cert = cert.insert_packets(Some(certification))?.0;
+        let expirations = record(&cert)?.signature_expirations()?;
+        assert_eq!(expirations.len(), 4);
+        assert_eq!(expirations.iter()
+                   .filter(|e| **e == SignatureExpiration::NotChecked)
+                   .count(), 1);
+
+        // The special values are not used for timestamps.
+        assert_eq!(u32::from(SignatureExpiration::Expires(1)), 3);
+        assert_eq!(u32::from(SignatureExpiration::Expires(u32::MAX)),
+                   u32::MAX - 1);
+        for v in [0, 1, 2, 3, 1600000000, u32::MAX] {
+            assert_eq!(u32::from(SignatureExpiration::from(v)), v);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn openpgp_record_metadata_truncated() -> Result<()> {
+        let mut r = openpgp_record("testy_openpgp")?;
+        // Claim a huge number of keys.
+        r.bytes[0x10] = 0xff;
+        assert!(r.keys().is_err());
+
+        let mut r = openpgp_record("testy_openpgp")?;
+        // Key info entries that are too small.
+        r.bytes[0x13] = 8;
+        assert!(r.keys().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn find_by_userid() -> Result<()> {
+        let mut bytes = crate::tests::keybox("header_sample").to_vec();
+        // Alpha has two user IDs, once stored in the metadata, and
+        // once only in the cert.
+        bytes.extend_from_slice(crate::tests::keybox("alpha_openpgp"));
+        bytes.extend_from_slice(crate::tests::keybox("testy_openpgp"));
+        bytes.extend_from_slice(
+            crate::tests::keybox("alpha_openpgp_no_uids"));
+        assert!(Keybox::from_bytes(&bytes)?.verify().is_empty());
+        // X.509 records are skipped.
+        bytes.extend_from_slice(crate::tests::keybox("testy_x509"));
+
+        let alpha: Fingerprint =
+            "A0FF4590BB6122EDEF6E3C542D727CC768697734".parse()?;
+        let testy: Fingerprint =
+            "3E8877C877274692975189F5D03F6F865226FE8B".parse()?;
+
+        let find = |pattern: &str| -> Result<Vec<(usize, Fingerprint)>> {
+            Ok(Keybox::from_bytes(&bytes)?.find_by_userid(pattern)
+               .map(|r| (r.offset(), r.keys().unwrap()[0].fingerprint()))
+               .collect())
+        };
+        let header_len = crate::tests::keybox("header_sample").len();
+        let alpha_len = crate::tests::keybox("alpha_openpgp").len();
+        let testy_len = crate::tests::keybox("testy_openpgp").len();
+        let first_alpha = (header_len, alpha.clone());
+        let second_alpha = (header_len + alpha_len + testy_len, alpha.clone());
+
+        // Both user IDs match, in both records.
+        assert_eq!(find("Alpha Test")?,
+                   vec![first_alpha.clone(), second_alpha.clone()]);
+        assert_eq!(find("ALFA@example.NET")?,
+                   vec![first_alpha.clone(), second_alpha.clone()]);
+        assert_eq!(find("(demo key)")?,
+                   vec![first_alpha.clone(), second_alpha.clone()]);
+        assert_eq!(find("testy@")?,
+                   vec![(header_len + alpha_len, testy.clone())]);
+        assert_eq!(find("example")?.len(), 3);
+        assert_eq!(find("")?.len(), 3);
+        assert!(find("Alice")?.is_empty());
+        assert!(find("nobody@example.org")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn duplicates() -> Result<()> {
+        // testy's record, alpha's record, and testy's record again.
+        let bytes = crate::tests::keybox("duplicate.kbx");
+        let header = crate::tests::keybox("header_sample").len();
+        let testy_len = crate::tests::keybox("testy_openpgp").len();
+        let alpha_len = crate::tests::keybox("alpha_openpgp").len();
+        let testy = Cert::from_bytes(crate::tests::key("testy.pgp"))?;
+        let alpha = Cert::from_bytes(crate::tests::key("alpha.pgp"))?;
+
+        // The first match wins, also for subkeys.
+        for key in testy.keys() {
+            let record = Keybox::from_bytes(bytes)?
+                .find_by_fingerprint(&key.key().fingerprint())?
+                .expect("testy is in the keybox");
+            assert_eq!(record.offset(), header);
+            assert_eq!(record.cert()?, testy);
+        }
+        let record = Keybox::from_bytes(bytes)?
+            .find_by_fingerprint(&alpha.fingerprint())?
+            .expect("alpha is in the keybox");
+        assert_eq!(record.offset(), header + testy_len);
+        let neal = Cert::from_bytes(crate::tests::key("neal.pgp"))?;
+        assert!(Keybox::from_bytes(bytes)?
+                .find_by_fingerprint(&neal.fingerprint())?.is_none());
+
+        // Only testy is reported.
+        assert_eq!(Keybox::from_bytes(bytes)?.duplicates()?, vec![
+            (testy.fingerprint(),
+             vec![header, header + testy_len + alpha_len]),
+        ]);
+        assert!(Keybox::from_bytes(crate::tests::keybox("keybox.kbx"))?
+                .duplicates()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn v6_fingerprints() -> Result<()> {
+        use openpgp::cert::CertBuilder;
+
+        let v4 = Cert::from_bytes(crate::tests::key("testy.pgp"))?;
+        let (v6, _) = CertBuilder::general_purpose(Some("v6 <v6@example.org>"))
+            .set_profile(openpgp::Profile::RFC9580)?
+            .generate()?;
+        let v6 = v6.strip_secret_key_material();
+
+        let mut kbx = super::header_record(0);
+        let v4_offset = kbx.len();
+        kbx.extend_from_slice(&super::openpgp_record(&v4, 0)?);
+        let v6_offset = kbx.len();
+        kbx.extend_from_slice(&super::openpgp_record(&v6, 0)?);
+        assert!(Keybox::from_bytes(&kbx)?.verify().is_empty());
+
+        for (offset, cert, version, key_size, flags) in [
+            (v4_offset, &v4, 1, KEY_INFO_SIZE, 0),
+            (v6_offset, &v6, 2, KEY_INFO_SIZE_V2, KEYFLAG_FPR32),
+        ] {
+            let bytes = &kbx[offset..];
+            assert_eq!(bytes[5], version);
+            assert_eq!(usize::from(u16::from_be_bytes([bytes[18], bytes[19]])),
+                       key_size);
+
+            let record = match Keybox::from_bytes(bytes)?.next().unwrap()? {
+                KeyboxRecord::OpenPGP(r) => r,
+                r => panic!("Expected an OpenPGP record, got {:?}", r),
+            };
+            let keys = record.keys()?;
+            assert_eq!(keys.iter().map(KeyInfo::fingerprint)
+                       .collect::<Vec<_>>(),
+                       cert.keys().map(|ka| ka.key().fingerprint())
+                       .collect::<Vec<_>>());
+            for (key, ka) in keys.iter().zip(cert.keys()) {
+                assert_eq!(key.flags(), flags);
+                if version == 1 {
+                    // The key ID offset points to the key ID.
+                    let keyid = key.keyid_offset() as usize;
+                    assert_eq!(
+                        openpgp::KeyID::from_bytes(&bytes[keyid..keyid + 8]),
+                        ka.key().keyid());
+                    assert_eq!(key.keygrip(), None);
+                } else {
+                    assert_eq!(key.keyid_offset(), 0);
+                    assert_eq!(key.keygrip(),
+                               Some(Keygrip::try_from(ka.key())?));
+                }
+            }
+
+            let found = Keybox::from_bytes(&kbx)?
+                .find_by_fingerprint(&cert.fingerprint())?
+                .expect("found");
+            assert_eq!(&found.cert()?, cert);
+        }
+
+        // A 32 byte fingerprint doesn't fit into a version 1 record.
+        let mut bytes = kbx[v4_offset..v6_offset].to_vec();
+        // The first key's flags.
+        bytes[0x14 + 20 + 4 + 1] |= KEYFLAG_FPR32 as u8;
+        assert!(parse_metadata(&bytes).is_err());
+        Ok(())
+    }
+
+    /// Builds a version 2 record following the layout in GnuPG's
+    /// `kbx/keybox-blob.c`, independently of [`super::openpgp_record`].
+    ///
+    /// `keys` are the fingerprints and keygrips, `key_size` is the
+    /// size of the key info entries, which may be padded.
+    fn gnupg_v2_record(cert: &Cert, keys: &[(Vec<u8>, [u8; 20])],
+                       key_size: usize)
+        -> Result<Vec<u8>>
+    {
+        use openpgp::serialize::Serialize;
+
+        let mut data = Vec::new();
+        cert.serialize(&mut data)?;
+
+        let mut meta = Vec::new();
+        meta.extend_from_slice(&(keys.len() as u16).to_be_bytes());
+        meta.extend_from_slice(&(key_size as u16).to_be_bytes());
+        for (fpr, keygrip) in keys {
+            let mut entry = fpr.clone();
+            entry.resize(32, 0);
+            let flags: u16 = if fpr.len() == 32 { 1 << 7 } else { 0 };
+            entry.extend_from_slice(&flags.to_be_bytes());
+            entry.extend_from_slice(&[0; 2]);
+            entry.extend_from_slice(keygrip);
+            entry.resize(key_size, 0);
+            meta.extend_from_slice(&entry);
+        }
+        // Serial number, no user IDs, no signatures.
+        meta.extend_from_slice(&[0, 0, 0, 0, 0, 12, 0, 0, 0, 4]);
+        // Ownertrust, validity, reserved, recheck after, latest
+        // timestamp, created at, and size of the reserved space.
+        meta.extend_from_slice(&[0; 20]);
+
+        let data_offset = 16 + meta.len();
+        let length = data_offset + data.len() + 20;
+        let mut record = Vec::new();
+        record.extend_from_slice(&(length as u32).to_be_bytes());
+        record.extend_from_slice(&[2, 2, 0, 0]);
+        record.extend_from_slice(&(data_offset as u32).to_be_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        record.extend_from_slice(&meta);
+        record.extend_from_slice(&data);
+        let mut ctx = SHA1.context()?.for_digest();
+        ctx.update(&record);
+        record.extend_from_slice(&ctx.into_digest()?);
+        Ok(record)
+    }
+
+    #[test]
+    fn gnupg_v2_records() -> Result<()> {
+        use openpgp::cert::CertBuilder;
+
+        let (v6, _) = CertBuilder::general_purpose(Some("v6 <v6@example.org>"))
+            .set_profile(openpgp::Profile::RFC9580)?
+            .generate()?;
+        let v6 = v6.strip_secret_key_material();
+        let testy = Cert::from_bytes(crate::tests::key("testy.pgp"))?;
+
+        for (cert, key_size) in [
+            (&v6, KEY_INFO_SIZE_V2),
+            // 20 byte fingerprints are padded.
+            (&testy, KEY_INFO_SIZE_V2),
+            // Larger entries are skipped over.
+            (&v6, KEY_INFO_SIZE_V2 + 8),
+        ] {
+            let keys = cert.keys().map(|ka| Ok((
+                ka.key().fingerprint().as_bytes().to_vec(),
+                <[u8; 20]>::from(Keygrip::try_from(ka.key())?),
+            ))).collect::<Result<Vec<_>>>()?;
+            let bytes = gnupg_v2_record(cert, &keys, key_size)?;
+            assert!(Keybox::from_bytes(&bytes)?.verify().is_empty());
+
+            let record = match Keybox::from_bytes(&bytes)?.next().unwrap()? {
+                KeyboxRecord::OpenPGP(r) => r,
+                r => panic!("Expected an OpenPGP record, got {:?}", r),
+            };
+            assert_eq!(&record.cert()?, cert);
+            let infos = record.keys()?;
+            assert_eq!(infos.iter().map(KeyInfo::fingerprint)
+                       .collect::<Vec<_>>(),
+                       cert.keys().map(|ka| ka.key().fingerprint())
+                       .collect::<Vec<_>>());
+            for (info, (_, keygrip)) in infos.iter().zip(keys.iter()) {
+                assert_eq!(info.keygrip(), Some(Keygrip::from(*keygrip)));
+                assert_eq!(info.keyid_offset(), 0);
+            }
+            assert!(Keybox::from_bytes(&bytes)?
+                    .find_by_fingerprint(&cert.fingerprint())?.is_some());
+
+            // Our writer produces the same key info entries.
+            if key_size == KEY_INFO_SIZE_V2 {
+                let ours = super::openpgp_record(cert, 0)?;
+                let end = 0x14 + keys.len() * KEY_INFO_SIZE_V2;
+                if cert == &v6 {
+                    assert_eq!(ours[5], 2);
+                    assert_eq!(ours[0x10..end], bytes[0x10..end]);
+                } else {
+                    // Unless a v4 cert, which gets a version 1 record.
+                    assert_eq!(ours[5], 1);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn import_keyring() -> Result<()> {
+        use openpgp::cert::CertBuilder;
+        use openpgp::serialize::Serialize;
+
+        let testy = Cert::from_bytes(crate::tests::key("testy.pgp"))?;
+        let alpha = Cert::from_bytes(crate::tests::key("alpha.pgp"))?;
+        let (v6, _) = CertBuilder::general_purpose(Some("v6 <v6@example.org>"))
+            .set_profile(openpgp::Profile::RFC9580)?
+            .generate()?;
+        let (tsk, _) =
+            CertBuilder::general_purpose(Some("tsk <tsk@example.org>"))
+            .generate()?;
+        assert!(tsk.is_tsk());
+
+        let mut keyring = Vec::new();
+        testy.serialize(&mut keyring)?;
+        v6.serialize(&mut keyring)?;
+        alpha.serialize(&mut keyring)?;
+        tsk.as_tsk().serialize(&mut keyring)?;
+
+        let (kbx, skipped) = Keybox::import_keyring(&keyring[..])?;
+        assert!(skipped.is_empty(), "{:?}", skipped);
+        assert!(Keybox::from_bytes(&kbx)?.verify().is_empty());
+
+        let records = Keybox::from_bytes(&kbx)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(records.len(), 5);
+        match &records[0] {
+            KeyboxRecord::Header(h) => {
+                assert!(h.check_magic());
+                assert!(h.created_at() > 0);
+            },
+            r => panic!("Expected a header record, got {:?}", r),
+        }
+
+        // The order is preserved, and secret key material stripped.
+        let expected = [testy, v6, alpha, tsk.strip_secret_key_material()];
+        for (record, cert) in records[1..].iter().zip(expected.iter()) {
+            let record = match record {
+                KeyboxRecord::OpenPGP(r) => r,
+                r => panic!("Expected an OpenPGP record, got {:?}", r),
+            };
+            assert_eq!(&record.cert()?, cert);
+            assert!(! record.has_secret_key());
+
+            assert_eq!(record.keys()?.iter().map(KeyInfo::fingerprint)
+                       .collect::<Vec<_>>(),
+                       cert.keys().map(|ka| ka.key().fingerprint())
+                       .collect::<Vec<_>>());
+            assert_eq!(record.user_ids()?.len(), cert.userids().count());
+            assert_eq!(record.user_id_values(),
+                       cert.userids().map(|u| u.userid().value().to_vec())
+                       .collect::<Vec<_>>());
+        }
+
+        // The user IDs are indexed.
+        assert_eq!(Keybox::from_bytes(&kbx)?
+                   .find_by_userid("tsk@example.org").count(), 1);
+
+        // An empty keyring results in an empty keybox.
+        let (kbx, skipped) = Keybox::import_keyring(&b""[..])?;
+        assert!(skipped.is_empty());
+        assert_eq!(Keybox::from_bytes(&kbx)?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn verify() -> Result<()> {
+        let kbx = Keybox::from_bytes(crate::tests::keybox("keybox.kbx"))?;
+        assert!(kbx.verify().is_empty());
+
+        let header = crate::tests::keybox("header_sample");
+        let openpgp = crate::tests::keybox("testy_openpgp");
+
+        // A user ID offset pointing outside of the record.
+        let mut bytes = header.to_vec();
+        bytes.extend_from_slice(
+            crate::tests::keybox("testy_openpgp_bad_uid_offset"));
+        bytes.extend_from_slice(openpgp);
+        let problems = Keybox::from_bytes(&bytes)?.verify();
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert_eq!(problems[0].0, header.len());
+        assert!(problems[0].1.to_string().contains("user ID 0"));
+
+        // A wrong checksum.
+        let mut bytes = header.to_vec();
+        bytes.extend_from_slice(openpgp);
+        *bytes.last_mut().unwrap() ^= 1;
+        let problems = Keybox::from_bytes(&bytes)?.verify();
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].1.to_string().contains("wrong checksum"));
+
+        // A missing header and a truncated record.
+        let mut bytes = openpgp.to_vec();
+        bytes.extend_from_slice(&openpgp[..100]);
+        let problems = Keybox::from_bytes(&bytes)?.verify();
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert_eq!(problems[0].0, 0);
+        assert!(problems[0].1.to_string().contains("header"));
+        assert_eq!(problems[1].0, openpgp.len());
+        assert!(matches!(problems[1].1, Error::NotEnoughData(_)));
+
+        // A record length of zero.
+        let mut bytes = header.to_vec();
+        bytes.extend_from_slice(&[0; 8]);
+        let problems = Keybox::from_bytes(&bytes)?.verify();
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert_eq!(problems[0].0, header.len());
+
+        // A data section out of bounds.
+        let mut bytes = header.to_vec();
+        bytes.extend_from_slice(openpgp);
+        bytes[header.len() + 0xC] = 0xff;
+        let problems = Keybox::from_bytes(&bytes)?.verify();
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].1.to_string().contains("data section"));
+        Ok(())
+    }
+
+    #[test]
+    fn large_offsets() -> Result<()> {
+        let header = crate::tests::keybox("header_sample");
+        let openpgp = crate::tests::keybox("testy_openpgp");
+        let mut bytes = header.to_vec();
+        bytes.extend_from_slice(openpgp);
+        bytes.extend_from_slice(openpgp);
+
+        // Pretend that the records are located just before the 4 GiB
+        // boundary.  Writing such a file would take too long.
+        let start = u64::from(u32::MAX) - header.len() as u64;
+        let kbx = || Keybox {
+            offset: start,
+            reader: buffered_reader::Memory::with_cookie(&bytes, ())
+                .into_boxed(),
+        };
+        let offsets = kbx().map(|r| r.map(|r| r.offset()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(offsets, vec![
+            public_offset(start),
+            public_offset(u64::from(u32::MAX)),
+            public_offset(u64::from(u32::MAX) + openpgp.len() as u64),
+        ]);
+        assert!(kbx().verify().is_empty());
+
+        // A record that is longer than the rest of the file.
+        let mut bytes = header.to_vec();
+        bytes.extend_from_slice(&openpgp[..100]);
+        let mut kbx = Keybox {
+            offset: start,
+            reader: buffered_reader::Memory::with_cookie(&bytes, ())
+                .into_boxed(),
+        };
+        assert!(kbx.next().expect("header").is_ok());
+        let err = kbx.next().expect("truncated record").unwrap_err();
+        assert_eq!(err.to_string(), format!(
+            "Not enough data: record at offset {} has length {}, but only \
+             100 bytes are left", u32::MAX, openpgp.len()));
+        Ok(())
+    }
+
+    #[test]
+    fn framing_errors() -> Result<()> {
+        let header = crate::tests::keybox("header_sample");
+        let openpgp = crate::tests::keybox("testy_openpgp");
+
+        // After a record whose length is corrupted, the iteration
+        // stops, it doesn't return the same error forever.
+        for garbage in [
+            &[0u8, 0, 0, 0, 2, 1][..],
+            &[0, 0, 0, 3, 2, 1],
+            &[0, 0],
+            &[0, 0xff, 0xff, 0xff, 2, 1],
+            &[0xff, 0xff, 0xff, 0xff, 2, 1, 0, 0],
+        ] {
+            let mut bytes = header.to_vec();
+            bytes.extend_from_slice(garbage);
+            bytes.extend_from_slice(openpgp);
+            let records = Keybox::from_bytes(&bytes)?.collect::<Vec<_>>();
+            assert_eq!(records.len(), 2, "{:?}", garbage);
+            assert!(records[0].is_ok());
+            assert!(records[1].is_err());
+        }
+
+        // A header record that is too short.
+        let mut short = header[..0x10].to_vec();
+        short[..4].copy_from_slice(&0x10u32.to_be_bytes());
+        assert!(KeyboxRecord::new(0, short).is_err());
+
+        // A data section outside of the record is an error, not a
+        // panic.
+        for (data_offset, data_length) in [
+            (0, 0),
+            (0x10, u32::MAX),
+            (u32::MAX, 1),
+            (openpgp.len() as u32, 0),
+        ] {
+            let mut record = openpgp.to_vec();
+            record[0x8..0xC].copy_from_slice(&data_offset.to_be_bytes());
+            record[0xC..0x10].copy_from_slice(&data_length.to_be_bytes());
+            assert!(KeyboxRecord::new(0, record).is_err(),
+                    "{}, {}", data_offset, data_length);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn open_mmap() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("pubring.kbx");
+        let bytes = crate::tests::keybox("keybox.kbx");
+        std::fs::write(&path, bytes)?;
+        let kbx = Keybox::open_mmap(&path)?;
+
+        // The blobs are the records the streaming parser returns.
+        let blobs = kbx.blobs()?.collect::<Result<Vec<_>>>()?;
+        let records = Keybox::from_bytes(bytes)?.collect::<Vec<_>>();
+        assert_eq!(blobs.len(), records.len());
+        for (blob, record) in blobs.iter().zip(records) {
+            match record {
+                Err(_) => assert!(blob.record().is_err()),
+                Ok(KeyboxRecord::OpenPGP(r)) => {
+                    assert_eq!(blob.created_at()?, Some(r.created_at()?));
+                    assert_eq!(blob.recorded_at()?, r.recorded_at()?);
+                    assert_eq!(blob.recheck_after()?, r.recheck_after()?);
+                    assert_eq!(blob.record()?, KeyboxRecord::OpenPGP(r));
+                },
+                Ok(record) => {
+                    assert_eq!(blob.created_at()?, None);
+                    assert_eq!(blob.record()?, record);
+                },
+            }
+        }
+        assert_eq!(kbx.keybox()?.count(), blobs.len());
+
+        let testy = Cert::from_bytes(crate::tests::key("testy.pgp"))?;
+        for key in testy.keys() {
+            let record = kbx.find_by_fingerprint(&key.key().fingerprint())?
+                .expect("testy is in the keybox");
+            assert_eq!(record.cert()?, testy);
+        }
+        let neal = Cert::from_bytes(crate::tests::key("neal.pgp"))?;
+        assert!(kbx.find_by_fingerprint(&neal.fingerprint())?.is_none());
+
+        // A truncated keybox.
+        let truncated = dir.path().join("truncated.kbx");
+        std::fs::write(&truncated, &bytes[..bytes.len() - 10])?;
+        let blobs = Keybox::open_mmap(&truncated)?.blobs()?.collect::<Vec<_>>();
+        assert!(blobs.last().unwrap().is_err());
+        assert!(blobs[..blobs.len() - 1].iter().all(|b| b.is_ok()));
+
+        // An empty keybox.
+        let empty = dir.path().join("empty.kbx");
+        std::fs::write(&empty, b"")?;
+        assert_eq!(Keybox::open_mmap(&empty)?.blobs()?.count(), 0);
+
+        // Not a regular file.
+        assert!(Keybox::open_mmap(dir.path()).is_err());
+
+        // The file shrinks while it is mapped.  Windows doesn't allow
+        // that.
+        #[cfg(unix)]
+        {
+            std::fs::OpenOptions::new().write(true).open(&path)?.set_len(10)?;
+            assert!(kbx.blobs().is_err());
+            assert!(kbx.find_by_fingerprint(&testy.fingerprint()).is_err());
+        }
+        Ok(())
+    }
+
+    /// Checks that keyboxes we can't write to can be read.
+    #[cfg(unix)]
+    #[test]
+    fn read_only() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("pubring.kbx");
+        let bytes = crate::tests::keybox("keybox.kbx");
+        std::fs::write(&path, bytes)?;
+        std::fs::set_permissions(&path,
+                                 std::fs::Permissions::from_mode(0o444))?;
+
+        let expected = Keybox::from_bytes(bytes)?
+            .map(|r| r.ok()).collect::<Vec<_>>();
+        let records = Keybox::from_file(&path)?
+            .map(|r| r.ok()).collect::<Vec<_>>();
+        assert_eq!(records, expected);
+        assert!(records.iter().any(
+            |r| matches!(r, Some(KeyboxRecord::OpenPGP(_)))));
+
+        #[cfg(feature = "mmap")]
+        assert_eq!(Keybox::open_mmap(&path)?.blobs()?.count(),
+                   expected.len());
+        Ok(())
+    }
+
     #[test]
     fn header_record() -> Result<()> {
         let header_bytes = crate::tests::keybox("header_sample");
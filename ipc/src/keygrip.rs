@@ -1,16 +1,38 @@
 use std::fmt;
 use std::io::Write;
+use std::path::Path;
 
 use sequoia_openpgp as openpgp;
+use openpgp::Cert;
 use openpgp::Error;
+use openpgp::Fingerprint;
 use openpgp::Result;
 use openpgp::crypto::mpi::{MPI, PublicKey};
+use openpgp::packet::Key;
+use openpgp::packet::key::{KeyParts, KeyRole};
 use openpgp::types::{Curve, HashAlgorithm};
 
+use crate::sexp::Sexp;
+
 /// A proprietary, protocol agnostic identifier for public keys.
 ///
 /// This is defined and used by GnuPG.
 ///
+/// The keygrip only depends on the public key material, not on the
+/// OpenPGP key version.  In particular, a version 4 key and a version
+/// 6 key with the same key material have the same keygrip.  This is
+/// also true for the native Ed25519 and X25519 algorithms introduced
+/// with version 6 keys, and their legacy EdDSA and ECDH counterparts.
+///
+/// The keygrip is a SHA-1 digest over a canonical S-expression
+/// encoding of the key material.  SHA-1 is used here as an
+/// identifier, not to provide any security guarantee: an attacker
+/// who can produce a colliding public key gains nothing, because the
+/// keygrip is only used to look up keys that the user already has.
+/// The digest is computed using the crypto backend `sequoia-openpgp`
+/// was built with, so there is a single crypto provider.  If the
+/// backend does not provide SHA-1, computing a keygrip fails.
+///
 /// # Examples
 ///
 /// ```
@@ -22,9 +44,57 @@ use openpgp::types::{Curve, HashAlgorithm};
 /// assert_eq!(&k.to_string(), "DD143ABA8D1D7D09875D6209E01BCF020788FF77");
 /// # Ok(()) }
 /// ```
-#[derive(Clone, PartialEq, Eq, Hash)]
+///
+/// Keygrips can be used as keys in maps.  They are ordered by their
+/// bytes, which is also the order of their string representations.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Keygrip([u8; 20]);
 
+/// Returns the keygrip with the given bytes.
+///
+/// The bytes are the raw SHA-1 digest, not its hexadecimal
+/// representation, which is parsed using [`str::parse`].  Because
+/// this conversion can't fail, `TryFrom<[u8; 20]>` is provided by the
+/// standard library's blanket implementation.
+impl From<[u8; 20]> for Keygrip {
+    fn from(bytes: [u8; 20]) -> Self {
+        Keygrip(bytes)
+    }
+}
+
+/// Returns the keygrip with the given bytes.
+///
+/// The bytes are the raw, 20-byte SHA-1 digest, not its hexadecimal
+/// representation.  This fails if `bytes` has a different length.
+///
+/// # Examples
+///
+/// ```
+/// # fn main() -> sequoia_openpgp::Result<()> {
+/// use sequoia_ipc::Keygrip;
+///
+/// let k: Keygrip = "DD143ABA8D1D7D09875D6209E01BCF020788FF77".parse()?;
+/// assert_eq!(Keygrip::try_from(&k.as_bytes()[..])?, k);
+/// assert!(Keygrip::try_from(&k.as_bytes()[..19]).is_err());
+/// # Ok(()) }
+/// ```
+impl TryFrom<&[u8]> for Keygrip {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        let digest: [u8; 20] = bytes.try_into()
+            .map_err(|_| Error::InvalidArgument(
+                format!("Expected 20 bytes, got {}", bytes.len())))?;
+        Ok(Keygrip(digest))
+    }
+}
+
+impl From<Keygrip> for [u8; 20] {
+    fn from(keygrip: Keygrip) -> Self {
+        keygrip.0
+    }
+}
+
 impl fmt::Debug for Keygrip {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for b in self.0.iter() {
@@ -48,18 +118,28 @@ impl std::str::FromStr for Keygrip {
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         let bytes = openpgp::fmt::hex::decode_pretty(s)?;
-        if bytes.len() == 20 {
-            let mut digest = [0; 20];
-            digest[..].copy_from_slice(&bytes[..]);
-            Ok(Keygrip(digest))
-        } else {
-            Err(Error::InvalidArgument(
-                format!("Expected 20 bytes, got {}", bytes.len())).into())
-        }
+        Keygrip::try_from(&bytes[..])
     }
 }
 
 impl Keygrip {
+    /// Returns the keygrip's bytes.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> sequoia_openpgp::Result<()> {
+    /// use sequoia_ipc::Keygrip;
+    ///
+    /// let k: Keygrip = "DD143ABA8D1D7D09875D6209E01BCF020788FF77".parse()?;
+    /// assert_eq!(k.as_bytes()[..2], [0xDD, 0x14]);
+    /// assert_eq!(Keygrip::from(*k.as_bytes()), k);
+    /// # Ok(()) }
+    /// ```
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
     /// Computes the keygrip of the given public key.
     ///
     /// # Examples
@@ -91,8 +171,7 @@ impl Keygrip {
     pub fn of(key: &PublicKey) -> Result<Keygrip> {
         use openpgp::crypto::hash;
         use self::PublicKey::*;
-        let mut hash =
-            HashAlgorithm::SHA1.context().unwrap().for_digest();
+        let mut hash = HashAlgorithm::SHA1.context()?.for_digest();
 
         fn hash_sexp_mpi(hash: &mut hash::Context, kind: char, mpi: &MPI)
         {
@@ -117,44 +196,6 @@ impl Keygrip {
             hash_sexp(hash, kind, prefix, mpi.value());
         }
 
-        fn hash_sexp(hash: &mut hash::Context, kind: char, prefix: &[u8],
-                     buf: &[u8])
-        {
-            write!(hash, "(1:{}{}:",
-                   kind, buf.len() + prefix.len()).unwrap();
-            hash.update(prefix);
-            hash.update(buf);
-            write!(hash, ")").unwrap();
-        }
-
-        fn hash_ecc(hash: &mut hash::Context, curve: &Curve, q: &MPI)
-            -> Result<()>
-        {
-            for (i, name) in "pabgnhq".chars().enumerate() {
-                if i == 5 {
-                    continue;  // Skip cofactor.
-                }
-
-                let param;
-                let mut m = if i == 6 {
-                    q.value()
-                } else {
-                    param = ecc_param(curve, i)?;
-                    param.value()
-                };
-
-                // Opaque encoding?
-                if m[0] == 0x40 {
-                    // Drop the prefix!
-                    m = &m[1..];
-                }
-
-                hash_sexp(hash, name, &[], m);
-            }
-
-            Ok(())
-        }
-
         match key {
             // From libgcrypt/cipher/rsa.c:
             //
@@ -164,10 +205,16 @@ impl Keygrip {
             //     integer) or whether the DER required 0 should be
             //     prefixed.  We hash the raw bytes.
             &RSA { ref n, .. } => {
-                // Contrary to the comment reproduced above,
-                // overwhelming empirical evidence suggest that we
-                // need to prepend a 0.
-                hash.update(&[0]);
+                // Contrary to the comment reproduced above, gcrypt
+                // hashes the modulus as a signed integer, i.e. it
+                // prepends a 0 if the most significant bit is set.
+                // Most moduli have the top bit set, but an RSA key's
+                // modulus can be a bit shorter than its nominal size.
+                if n.value().get(0).map(|msb| msb & 0x80 > 0)
+                    .unwrap_or(false)
+                {
+                    hash.update(&[0]);
+                }
                 hash.update(n.value());
             },
 
@@ -184,9 +231,17 @@ impl Keygrip {
                 hash_sexp_mpi(&mut hash, 'y', y);
             },
 
-            &EdDSA { ref curve, ref q } => hash_ecc(&mut hash, curve, q)?,
-            &ECDSA { ref curve, ref q } => hash_ecc(&mut hash, curve, q)?,
-            &ECDH { ref curve, ref q, .. } => hash_ecc(&mut hash, curve, q)?,
+            &EdDSA { ref curve, ref q } =>
+                hash_ecc(&mut hash, curve, strip_opaque(q.value()))?,
+            &ECDSA { ref curve, ref q } =>
+                hash_ecc(&mut hash, curve, strip_opaque(q.value()))?,
+            &ECDH { ref curve, ref q, .. } =>
+                hash_ecc(&mut hash, curve, strip_opaque(q.value()))?,
+
+            // The native encodings used by version 6 keys hash like
+            // their legacy counterparts.
+            &Ed25519 { ref a } => hash_ecc(&mut hash, &Curve::Ed25519, &a[..])?,
+            &X25519 { ref u } => hash_ecc(&mut hash, &Curve::Cv25519, &u[..])?,
 
             // crypto::mpi::PublicKey is non_exhaustive, match on &_ to handle
             // future additions.
@@ -197,9 +252,544 @@ impl Keygrip {
         }
 
         let mut digest = [0; 20];
-        let _ = hash.digest(&mut digest);
+        hash.digest(&mut digest)?;
         Ok(Keygrip(digest))
     }
+
+    /// Computes the keygrips of all keys in the cert.
+    ///
+    /// Returns the fingerprint and the keygrip of the primary key,
+    /// followed by those of the subkeys, in the order of
+    /// [`Cert::keys`].  No policy is applied.  gpg-agent stores secret
+    /// keys by their keygrip, so this is what a cert is matched
+    /// against, see [`Keygrip::agent_file_name`].
+    ///
+    /// Keys whose keygrip can't be computed, for instance, because
+    /// their algorithm is not supported (see [`Keygrip::of`]), are
+    /// skipped, and a note is logged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> sequoia_openpgp::Result<()> {
+    /// use sequoia_openpgp as openpgp;
+    /// use sequoia_ipc as ipc;
+    /// use ipc::Keygrip;
+    ///
+    /// let cert: openpgp::Cert = // ...
+    /// #   "-----BEGIN PGP PUBLIC KEY BLOCK-----
+    /// #
+    /// #   xjMEWlNvABYJKwYBBAHaRw8BAQdA+EC2pvebpEbzPA9YplVgVXzkIG5eK+7wEAez
+    /// #   lcBgLJrNMVRlc3R5IE1jVGVzdGZhY2UgKG15IG5ldyBrZXkpIDx0ZXN0eUBleGFt
+    /// #   cGxlLm9yZz7CkAQTFggAOBYhBDnRAKtn1b2MBAECBfs3UfFYfa7xBQJaU28AAhsD
+    /// #   BQsJCAcCBhUICQoLAgQWAgMBAh4BAheAAAoJEPs3UfFYfa7xJHQBAO4/GABMWUcJ
+    /// #   5D/DZ9b+6YiFnysSjCT/gILJgxMgl7uoAPwJherI1pAAh49RnPHBR1IkWDtwzX65
+    /// #   CJG8sDyO2FhzDs44BFpTbwASCisGAQQBl1UBBQEBB0B+A0GRHuBgdDX50T1nePjb
+    /// #   mKQ5PeqXJbWEtVrUtVJaPwMBCAfCeAQYFggAIBYhBDnRAKtn1b2MBAECBfs3UfFY
+    /// #   fa7xBQJaU28AAhsMAAoJEPs3UfFYfa7xzjIBANX2/FgDX3WkmvwpEHg/sn40zACM
+    /// #   W2hrBY5x0sZ8H7JlAP47mCfCuRVBqyaePuzKbxLJeLe2BpDdc0n2izMVj8t9Cg==
+    /// #   =QetZ
+    /// #   -----END PGP PUBLIC KEY BLOCK-----".parse()?;
+    /// let keygrips = Keygrip::of_cert(&cert);
+    /// assert_eq!(keygrips.len(), 2);
+    /// assert_eq!(keygrips[0].0, cert.fingerprint());
+    /// assert_eq!(keygrips[0].1.to_string(),
+    ///            "DD143ABA8D1D7D09875D6209E01BCF020788FF77");
+    /// # Ok(()) }
+    /// ```
+    pub fn of_cert(cert: &Cert) -> Vec<(Fingerprint, Keygrip)> {
+        cert.keys().filter_map(|ka| {
+            let key = ka.key();
+            match Keygrip::of(key.mpis()) {
+                Ok(keygrip) => Some((key.fingerprint(), keygrip)),
+                Err(err) => {
+                    tracing::info!(cert = %cert.fingerprint(),
+                                   key = %key.fingerprint(),
+                                   "Skipping key, cannot compute its \
+                                    keygrip: {}", err);
+                    None
+                },
+            }
+        }).collect()
+    }
+
+    /// Computes the keygrip of an elliptic curve public key.
+    ///
+    /// `q` is the public point, encoded as in OpenPGP's MPI
+    /// representation: the point prefixed with `0x40` for Ed25519 and
+    /// Cv25519, and the uncompressed point prefixed with `0x04` for
+    /// the other curves.
+    ///
+    /// The keygrip only covers the curve and the public point.  In
+    /// particular, the KDF parameters (the hash and symmetric
+    /// algorithms) of an OpenPGP ECDH key are *not* part of the
+    /// keygrip, so they should not be passed here.  Two ECDH keys
+    /// that only differ in their KDF parameters have the same
+    /// keygrip.  [`Keygrip::of`] ignores them as well.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> sequoia_openpgp::Result<()> {
+    /// use sequoia_openpgp as openpgp;
+    /// use openpgp::crypto::mpi::PublicKey;
+    /// use openpgp::types::{Curve, HashAlgorithm, SymmetricAlgorithm};
+    /// use sequoia_ipc::Keygrip;
+    ///
+    /// let q = openpgp::fmt::hex::decode(
+    ///     "407E0341911EE0607435F9D13D6778F8DB98A4393DEA9725B584B55AD4B5525A3F")?;
+    /// let key = PublicKey::ECDH {
+    ///     curve: Curve::Cv25519,
+    ///     q: q.clone().into(),
+    ///     hash: HashAlgorithm::SHA256,
+    ///     sym: SymmetricAlgorithm::AES128,
+    /// };
+    /// assert_eq!(Keygrip::from_components(&Curve::Cv25519, &q)?,
+    ///            Keygrip::of(&key)?);
+    /// # Ok(()) }
+    /// ```
+    pub fn from_components(curve: &Curve, q: &[u8]) -> Result<Keygrip> {
+        let mut hash = HashAlgorithm::SHA1.context()?.for_digest();
+        hash_ecc(&mut hash, curve, strip_opaque(q))?;
+
+        let mut digest = [0; 20];
+        hash.digest(&mut digest)?;
+        Ok(Keygrip(digest))
+    }
+
+    /// Computes the keygrip of a key stored as S-expression.
+    ///
+    /// This understands the forms gpg-agent stores keys in:
+    /// `public-key`, `private-key`, `protected-private-key` (the
+    /// secret key material is encrypted with a passphrase), and
+    /// `shadowed-private-key` (the secret key material lives on a
+    /// smartcard).  Only the public key material is used, so
+    /// protected keys don't need to be unlocked.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> sequoia_openpgp::Result<()> {
+    /// use sequoia_ipc::Keygrip;
+    /// use sequoia_ipc::sexp::Sexp;
+    ///
+    /// let key = Sexp::from_bytes(
+    ///     b"(20:shadowed-private-key(3:ecc(5:curve7:Ed25519)\
+    ///       (5:flags5:eddsa)\
+    ///       (1:q33:\x40\xf8\x40\xb6\xa6\xf7\x9b\xa4\x46\xf3\x3c\x0f\x58\
+    ///       \xa6\x55\x60\x55\x7c\xe4\x20\x6e\x5e\x2b\xee\xf0\x10\x07\xb3\
+    ///       \x95\xc0\x60\x2c\x9a)(8:shadowed5:t1-v1(4:12342:43))))")?;
+    /// assert_eq!(Keygrip::from_sexp(&key)?.to_string(),
+    ///            "DD143ABA8D1D7D09875D6209E01BCF020788FF77");
+    /// # Ok(()) }
+    /// ```
+    pub fn from_sexp(sexp: &Sexp) -> Result<Keygrip> {
+        let key = match sexp.list() {
+            Some([Sexp::String(kind), key, ..])
+                if matches!(&kind[..],
+                            b"public-key"
+                            | b"private-key"
+                            | b"protected-private-key"
+                            | b"shadowed-private-key") => key,
+            _ => return Err(Error::InvalidArgument(
+                "S-expression is not a key".into()).into()),
+        };
+        let (algo, params) = match key.list() {
+            Some([Sexp::String(algo), params @ ..]) => (algo, params),
+            _ => return Err(Error::InvalidArgument(
+                "Malformed key S-expression".into()).into()),
+        };
+
+        // Only look at the key's direct parameters.  In particular,
+        // the protection parameters of protected and shadowed keys
+        // are not key material.
+        fn param<'a>(params: &'a [Sexp], name: &str) -> Result<&'a [u8]> {
+            params.iter()
+                .find_map(|p| match p.list() {
+                    Some([Sexp::String(key), Sexp::String(value)])
+                        if &key[..] == name.as_bytes() => Some(&value[..]),
+                    _ => None,
+                })
+                .ok_or_else(|| Error::InvalidArgument(
+                    format!("Key is missing the {} parameter", name)).into())
+        }
+        let mpi = |name: &str| -> Result<MPI> {
+            param(params, name).map(MPI::new)
+        };
+
+        match &algo[..] {
+            b"rsa" => Keygrip::of(&PublicKey::RSA {
+                e: mpi("e")?,
+                n: mpi("n")?,
+            }),
+            b"dsa" => Keygrip::of(&PublicKey::DSA {
+                p: mpi("p")?,
+                q: mpi("q")?,
+                g: mpi("g")?,
+                y: mpi("y")?,
+            }),
+            b"elg" => Keygrip::of(&PublicKey::ElGamal {
+                p: mpi("p")?,
+                g: mpi("g")?,
+                y: mpi("y")?,
+            }),
+            b"ecc" | b"ecdsa" | b"ecdh" | b"eddsa" =>
+                Keygrip::from_components(
+                    &curve_from_name(param(params, "curve")?)?,
+                    param(params, "q")?),
+            _ => Err(Error::InvalidArgument(
+                format!("Keygrip not defined for {} keys",
+                        String::from_utf8_lossy(algo))).into()),
+        }
+    }
+
+    /// Returns the name of the file gpg-agent stores the secret key
+    /// in.
+    ///
+    /// gpg-agent stores secret keys in its `private-keys-v1.d`
+    /// directory, one file per key, named after the key's keygrip.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> sequoia_openpgp::Result<()> {
+    /// use sequoia_ipc::Keygrip;
+    ///
+    /// let k: Keygrip = "DD143ABA8D1D7D09875D6209E01BCF020788FF77".parse()?;
+    /// assert_eq!(k.agent_file_name(),
+    ///            "DD143ABA8D1D7D09875D6209E01BCF020788FF77.key");
+    /// # Ok(()) }
+    /// ```
+    pub fn agent_file_name(&self) -> String {
+        format!("{}.key", self)
+    }
+
+    /// Returns whether `path` names gpg-agent's file for this key.
+    ///
+    /// Only the file name is considered, see
+    /// [`Keygrip::agent_file_name`].  The keygrip is compared
+    /// case-insensitively.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> sequoia_openpgp::Result<()> {
+    /// use sequoia_ipc::Keygrip;
+    ///
+    /// let k: Keygrip = "DD143ABA8D1D7D09875D6209E01BCF020788FF77".parse()?;
+    /// assert!(k.matches_agent_file(
+    ///     "private-keys-v1.d/DD143ABA8D1D7D09875D6209E01BCF020788FF77.key"));
+    /// # Ok(()) }
+    /// ```
+    pub fn matches_agent_file<P: AsRef<Path>>(&self, path: P) -> bool {
+        let name = match path.as_ref().file_name().and_then(|n| n.to_str()) {
+            Some(name) => name,
+            None => return false,
+        };
+        let grip = match name.strip_suffix(".key") {
+            Some(grip) => grip,
+            None => return false,
+        };
+
+        // Don't accept the spaces and colons `FromStr` tolerates.
+        grip.len() == 2 * self.0.len()
+            && grip.bytes().all(|b| b.is_ascii_hexdigit())
+            && grip.parse::<Keygrip>().map(|k| &k == self).unwrap_or(false)
+    }
+}
+
+/// Where gpg-agent stores a key, see [`AgentKeyInfo::storage`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyStorage {
+    /// The key is stored on disk (`D`).
+    Disk,
+    /// The key is stored on a smartcard (`T`).
+    Smartcard,
+    /// The agent doesn't know (`X`).
+    Unknown,
+    /// The key is missing (`-`).
+    Missing,
+}
+
+/// How gpg-agent protects a key, see [`AgentKeyInfo::protection`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyProtection {
+    /// The key is protected with a passphrase (`P`).
+    Protected,
+    /// The key is not protected (`C`).
+    Clear,
+    /// The agent doesn't know (`-`).
+    Unknown,
+}
+
+/// gpg-agent's view of a key.
+///
+/// This is parsed from a line of the agent's response to the
+/// `KEYINFO` command, which looks like this:
+///
+/// ```text
+/// S KEYINFO <KEYGRIP> <TYPE> <SERIALNO> <IDSTR> <CACHED> <PROTECTION> <FPR> <TTL> <FLAGS>
+/// ```
+///
+/// The trailing fields are optional, and older agents don't emit
+/// them.  Fields that are not known are given as `-`:
+///
+///   - `TYPE` is `D` for keys stored on disk, `T` for keys stored on
+///     a smartcard, `X` if unknown, and `-` if the key is missing.
+///   - `SERIALNO` is the smartcard's serial number, and `IDSTR`
+///     identifies the key on the card.
+///   - `CACHED` is `1` if the passphrase is cached.
+///   - `PROTECTION` is `P` if the key is protected with a
+///     passphrase, and `C` if it is not.
+///   - `FPR` is the key's ssh fingerprint, which the agent only
+///     returns when asked to with `--ssh-fpr`.
+///   - `TTL` is the ssh key's time to live in seconds.
+///   - `FLAGS` is a word of one-letter flags: `D` if the key is
+///     disabled, `S` if it is listed in `sshcontrol`, and `c` if
+///     using the key needs to be confirmed.
+///
+/// The `S ` prefix marking an Assuan status line is optional.  This
+/// makes it possible to compare the agent's keygrips with locally
+/// computed ones, see [`Keygrip::of`].
+///
+/// # Examples
+///
+/// ```
+/// # fn main() -> sequoia_openpgp::Result<()> {
+/// use sequoia_ipc::{Keygrip, AgentKeyInfo, KeyProtection, KeyStorage};
+///
+/// let info: AgentKeyInfo =
+///     "S KEYINFO 8AB46809D0512DD50745AD655102B53D632379D2 D - - - P - - -"
+///     .parse()?;
+/// assert_eq!(info.keygrip(),
+///            &"8AB46809D0512DD50745AD655102B53D632379D2".parse::<Keygrip>()?);
+/// assert_eq!(info.storage(), KeyStorage::Disk);
+/// assert_eq!(info.protection(), KeyProtection::Protected);
+/// assert!(! info.cached());
+/// # Ok(()) }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AgentKeyInfo {
+    keygrip: Keygrip,
+    storage: KeyStorage,
+    serial_number: Option<String>,
+    id: Option<String>,
+    cached: bool,
+    protection: KeyProtection,
+    ssh_fingerprint: Option<String>,
+    ttl: Option<u64>,
+    flags: String,
+}
+
+impl std::str::FromStr for AgentKeyInfo {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = |msg: String| -> anyhow::Error {
+            Error::InvalidArgument(
+                format!("Invalid KEYINFO line {:?}: {}", s, msg)).into()
+        };
+
+        let line = s.trim_end_matches(['\r', '\n']);
+        let line = line.strip_prefix("S ").unwrap_or(line);
+        let mut fields = line.split(' ');
+        if fields.next() != Some("KEYINFO") {
+            return Err(invalid("expected KEYINFO".into()));
+        }
+
+        let keygrip = fields.next()
+            .ok_or_else(|| invalid("missing keygrip".into()))?;
+        let keygrip = if keygrip.len() == 40 {
+            keygrip.parse::<Keygrip>()
+                .map_err(|e| invalid(e.to_string()))?
+        } else {
+            return Err(invalid(format!("malformed keygrip {:?}", keygrip)));
+        };
+
+        let storage = match fields.next() {
+            Some("D") => KeyStorage::Disk,
+            Some("T") => KeyStorage::Smartcard,
+            Some("X") => KeyStorage::Unknown,
+            Some("-") => KeyStorage::Missing,
+            Some(t) => return Err(invalid(format!("unknown type {:?}", t))),
+            None => return Err(invalid("missing type".into())),
+        };
+
+        // The remaining fields are optional.
+        let mut next = || fields.next().filter(|f| *f != "-");
+        let serial_number = next().map(Into::into);
+        let id = next().map(Into::into);
+        let cached = match next() {
+            None => false,
+            Some("1") => true,
+            Some(c) => return Err(invalid(format!("malformed cached {:?}", c))),
+        };
+        let protection = match next() {
+            None => KeyProtection::Unknown,
+            Some("P") => KeyProtection::Protected,
+            Some("C") => KeyProtection::Clear,
+            Some(p) => return Err(invalid(
+                format!("unknown protection {:?}", p))),
+        };
+        let ssh_fingerprint = next().map(Into::into);
+        let ttl = match next() {
+            None => None,
+            Some(t) => Some(t.parse::<u64>().map_err(
+                |_| invalid(format!("malformed TTL {:?}", t)))?),
+        };
+        let flags = next().unwrap_or("").to_string();
+
+        Ok(AgentKeyInfo {
+            keygrip,
+            storage,
+            serial_number,
+            id,
+            cached,
+            protection,
+            ssh_fingerprint,
+            ttl,
+            flags,
+        })
+    }
+}
+
+impl AgentKeyInfo {
+    /// Returns the key's keygrip.
+    pub fn keygrip(&self) -> &Keygrip {
+        &self.keygrip
+    }
+
+    /// Returns where the key is stored.
+    pub fn storage(&self) -> KeyStorage {
+        self.storage
+    }
+
+    /// Returns the serial number of the smartcard storing the key,
+    /// if any.
+    pub fn serial_number(&self) -> Option<&str> {
+        self.serial_number.as_deref()
+    }
+
+    /// Returns the string identifying the key on the smartcard, if
+    /// any.
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Returns whether the key's passphrase is cached.
+    pub fn cached(&self) -> bool {
+        self.cached
+    }
+
+    /// Returns how the key is protected.
+    pub fn protection(&self) -> KeyProtection {
+        self.protection
+    }
+
+    /// Returns the key's ssh fingerprint, if the agent returned it.
+    pub fn ssh_fingerprint(&self) -> Option<&str> {
+        self.ssh_fingerprint.as_deref()
+    }
+
+    /// Returns the ssh key's time to live in seconds, if any.
+    pub fn ttl(&self) -> Option<u64> {
+        self.ttl
+    }
+
+    /// Returns whether the key is disabled.
+    pub fn disabled(&self) -> bool {
+        self.flags.contains('D')
+    }
+
+    /// Returns whether the key is listed in `sshcontrol`.
+    pub fn ssh_control(&self) -> bool {
+        self.flags.contains('S')
+    }
+
+    /// Returns whether using the key needs to be confirmed.
+    pub fn confirm(&self) -> bool {
+        self.flags.contains('c')
+    }
+}
+
+fn hash_sexp(hash: &mut openpgp::crypto::hash::Context, kind: char,
+             prefix: &[u8], buf: &[u8])
+{
+    write!(hash, "(1:{}{}:",
+           kind, buf.len() + prefix.len()).unwrap();
+    hash.update(prefix);
+    hash.update(buf);
+    write!(hash, ")").unwrap();
+}
+
+// Strips the prefix of opaquely encoded points.
+fn strip_opaque(m: &[u8]) -> &[u8] {
+    match m {
+        // Drop the prefix!
+        [0x40, rest @ ..] => rest,
+        _ => m,
+    }
+}
+
+// `q` is the public point, without any prefix.
+fn hash_ecc(hash: &mut openpgp::crypto::hash::Context, curve: &Curve,
+            q: &[u8])
+    -> Result<()>
+{
+    for (i, name) in "pabgnhq".chars().enumerate() {
+        if i == 5 {
+            continue;  // Skip cofactor.
+        }
+
+        let param;
+        let m = if i == 6 {
+            q
+        } else {
+            param = ecc_param(curve, i)?;
+            strip_opaque(param.value())
+        };
+
+        hash_sexp(hash, name, &[], m);
+    }
+
+    Ok(())
+}
+
+/// Computes the keygrip of the given key.
+///
+/// This works for all key versions, see [`Keygrip::of`].
+impl<P, R> TryFrom<&Key<P, R>> for Keygrip
+where
+    P: KeyParts,
+    R: KeyRole,
+{
+    type Error = anyhow::Error;
+
+    fn try_from(key: &Key<P, R>) -> Result<Self> {
+        Keygrip::of(key.mpis())
+    }
+}
+
+/// The OID of brainpoolP384r1.
+const BRAINPOOL_P384_OID: &[u8] =
+    &[0x2B, 0x24, 0x03, 0x03, 0x02, 0x08, 0x01, 0x01, 0x0B];
+
+/// Maps libgcrypt's curve names to curves.
+fn curve_from_name(name: &[u8]) -> Result<Curve> {
+    Ok(match name {
+        b"Ed25519" | b"ed25519" => Curve::Ed25519,
+        b"Curve25519" | b"cv25519" => Curve::Cv25519,
+        b"NIST P-256" | b"nistp256" | b"prime256v1" | b"secp256r1" =>
+            Curve::NistP256,
+        b"NIST P-384" | b"nistp384" | b"secp384r1" => Curve::NistP384,
+        b"NIST P-521" | b"nistp521" | b"secp521r1" => Curve::NistP521,
+        b"brainpoolP256r1" => Curve::BrainpoolP256,
+        // See `ecc_param`.
+        b"brainpoolP384r1" => Curve::Unknown(BRAINPOOL_P384_OID.into()),
+        b"brainpoolP512r1" => Curve::BrainpoolP512,
+        _ => return Err(Error::InvalidArgument(
+            format!("Unknown curve {}", String::from_utf8_lossy(name))).into()),
+    })
 }
 
 /// Returns curve parameters.
@@ -280,9 +870,6 @@ fn ecc_param(curve: &Curve, i: usize) -> Result<MPI> {
                             curve, i)).into())
             };
 
-            const BRAINPOOL_P384_OID: &[u8] =
-                &[0x2B, 0x24, 0x03, 0x03, 0x02, 0x08, 0x01, 0x01, 0x0B];
-
             if curve.oid() == BRAINPOOL_P384_OID {
                 match i {
                     0 => "0x8cb91e82a3386d280f5d6f7e50e641df152f7109ed5456b412b1da197fb71123acd3a729901d1a71874700133107ec53",
@@ -369,6 +956,64 @@ mod tests {
         }
     }
 
+    /// Checks the keygrip of a single key against the one computed
+    /// by GnuPG, whatever crypto backend the tests are built with.
+    #[test]
+    fn known_answer() -> Result<()> {
+        // The primary key of testy-new.pgp, GnuPG 2.2 reports:
+        //
+        //     pub   ed25519 2018-01-08 [SC]
+        //           39D100AB67D5BD8C04010205FB3751F1587DAEF1
+        //           Keygrip = DD143ABA8D1D7D09875D6209E01BCF020788FF77
+        let key = PublicKey::EdDSA {
+            curve: Curve::Ed25519,
+            q: hex::decode(
+                "40f840b6a6f79ba446f33c0f58a65560557ce4206e5e2beef01007b395c0602c9a")?
+                .into(),
+        };
+        let keygrip: Keygrip = "DD143ABA8D1D7D09875D6209E01BCF020788FF77".parse()?;
+        assert_eq!(Keygrip::of(&key)?, keygrip);
+        Ok(())
+    }
+
+    /// RSA moduli are hashed as signed integers, using GnuPG as
+    /// oracle.
+    #[test]
+    fn rsa_modulus_msb() -> Result<()> {
+        // A 2047-bit modulus, the most significant bit is clear.
+        let key = PublicKey::RSA {
+            n: hex::decode(
+                "5bb596d41335dee92512f63fba665e3b93412d53c320a9dee11d966e0d85f628\
+                 ae01e8c9fd56c92fdefffbb63d4b1f25aeb45ebfef7a9b362e23ac47092db238\
+                 8cdabe3f2e44fe7b04782e06811c8098990019b05ecbbecbddc86a2125b1cc67\
+                 1ddb499f8cd5beec7618299b2a71d7d7274ca89ef5f5893665902d0439f0569c\
+                 c03964d435f03b63a7b81c062a92123b59f8f43ab7096366cdc650febf8fbc92\
+                 dba017a2c0ef7b42b03fdcc02e3f60be8e20b067f94264ef92d8ea45826d4342\
+                 4d80ad8a5b0ac0a18a8ddc5213192211c52676975f275c72aaca89fb76b29645\
+                 a1a7e88305d1677fa17d4d57606dd71275cef22dbe6f35bdb705a2f20599aa01")?.into(),
+            e: hex::decode("010001")?.into(),
+        };
+        assert_eq!(Keygrip::of(&key)?,
+                   "4FFEE737217B557FBDA8DED6084BF803ECB76C0F".parse::<Keygrip>()?);
+
+        // A 2048-bit modulus, the most significant bit is set.
+        let key = PublicKey::RSA {
+            n: hex::decode(
+                "d851117dbea75c4c3e4a9ad13e7a02813415794fe7a5b8d5d5ea6d0b7962f836\
+                 0d2a0c039a39ced0f55215d08f6829ff3afd8774d976d7459c2b00e5189b35c6\
+                 f904ec188e1405950d9f333c152ec963c60662e7a37db23ced024f085fb7d819\
+                 4e94ce9a77a06737fd10c6e90193a75a4beef4f2e3a9643f2ac61ecea9751d0d\
+                 0178a3f15fb9a28fc49525dac6f1d9ddd83876c7890b19a5551eb3ffbeabc0d4\
+                 c5cf80e74ab04df1d19c6baf0cb5edbbcc78184fa8d73d34ebb9440bb5ef613f\
+                 8edb1a0b2fd03eb1268aa443ddf915342164165a99c1cdbfa1c365c33ef2821e\
+                 2957aa93b5142d5784a1abe2cf4c1d5b7e7bd8f2c56f13dd2c38eb9b9f7bf09d")?.into(),
+            e: hex::decode("010001")?.into(),
+        };
+        assert_eq!(Keygrip::of(&key)?,
+                   "3BE395EFDE29906FEF080DF5620847278A9450D2".parse::<Keygrip>()?);
+        Ok(())
+    }
+
     /// Tests from our test keys, using GnuPG as oracle.
     #[test]
     fn our_keys() {
@@ -444,6 +1089,324 @@ mod tests {
         }
     }
 
+    #[test]
+    fn of_cert() -> Result<()> {
+        use openpgp::parse::Parse;
+
+        let cert = Cert::from_bytes(crate::tests::key("testy-new.pgp"))?;
+        assert_eq!(cert.keys().subkeys().count(), 1);
+        assert_eq!(Keygrip::of_cert(&cert), vec![
+            ("39D100AB67D5BD8C04010205FB3751F1587DAEF1".parse::<Fingerprint>()?,
+             "DD143ABA8D1D7D09875D6209E01BCF020788FF77".parse::<Keygrip>()?),
+            ("F4D1450B041F622FCEFBFDB18BD88E94C0D20333".parse::<Fingerprint>()?,
+             "583225FBC0A88293472FB95F37E9595E1367188C".parse::<Keygrip>()?),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn of_cert_unsupported_subkey() -> Result<()> {
+        use openpgp::cert::CertBuilder;
+        use openpgp::packet::key::Key4;
+        use openpgp::packet::signature::SignatureBuilder;
+        use openpgp::types::{PublicKeyAlgorithm, SignatureType};
+
+        let (cert, _) = CertBuilder::new()
+            .add_userid("alice <alice@example.org>")
+            .add_signing_subkey()
+            .generate()?;
+        let expected = cert.keys()
+            .map(|ka| Ok((ka.key().fingerprint(),
+                          Keygrip::of(ka.key().mpis())?)))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(expected.len(), 2);
+
+        // A subkey using an algorithm we know nothing about, properly
+        // bound to the primary key.
+        let unknown: Key<_, _> = Key4::new(
+            cert.primary_key().key().creation_time(),
+            PublicKeyAlgorithm::Unknown(99),
+            PublicKey::Unknown {
+                mpis: vec![MPI::new(&[1, 2, 3])].into_boxed_slice(),
+                rest: vec![4, 5, 6].into_boxed_slice(),
+            })?
+            .role_into_subordinate()
+            .into();
+        let mut signer = cert.primary_key().key().clone()
+            .parts_into_secret()?.into_keypair()?;
+        let binding = SignatureBuilder::new(SignatureType::SubkeyBinding)
+            .sign_subkey_binding(&mut signer, cert.primary_key().key(),
+                                 &unknown)?;
+        let unknown_fpr = unknown.fingerprint();
+        let cert = cert.insert_packets(vec![
+            openpgp::Packet::from(unknown),
+            binding.into(),
+        ])?.0;
+        assert_eq!(cert.keys().subkeys().count(), 2);
+        assert!(cert.keys().subkeys()
+                .any(|ka| ka.key().fingerprint() == unknown_fpr));
+
+        // The unsupported subkey is skipped, the others are returned
+        // in order.
+        assert_eq!(Keygrip::of_cert(&cert), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn agent_file_name() -> Result<()> {
+        use openpgp::parse::Parse;
+
+        let cert = openpgp::Cert::from_bytes(crate::tests::key("testy-new.pgp"))?;
+        let grip = Keygrip::of(cert.primary_key().key().mpis())?;
+        assert_eq!(grip.agent_file_name(),
+                   "DD143ABA8D1D7D09875D6209E01BCF020788FF77.key");
+
+        let dir = Path::new("/home/alice/.gnupg/private-keys-v1.d");
+        assert!(grip.matches_agent_file(dir.join(grip.agent_file_name())));
+        assert!(grip.matches_agent_file(
+            "dd143aba8d1d7d09875d6209e01bcf020788ff77.key"));
+
+        let subkey = cert.keys().subkeys().next().unwrap();
+        let other = Keygrip::of(subkey.key().mpis())?;
+        assert!(! grip.matches_agent_file(other.agent_file_name()));
+        assert!(! grip.matches_agent_file(dir));
+        assert!(! grip.matches_agent_file(
+            "DD143ABA8D1D7D09875D6209E01BCF020788FF77"));
+        assert!(! grip.matches_agent_file(
+            "DD143ABA8D1D7D09875D6209E01BCF020788FF77.key.tmp"));
+        assert!(! grip.matches_agent_file(
+            "DD14 3ABA 8D1D 7D09 875D 6209 E01B CF02 0788 FF77.key"));
+        Ok(())
+    }
+
+    #[test]
+    fn key_info() -> Result<()> {
+        // Taken from gpg-agent 2.2.40.
+        let info: AgentKeyInfo =
+            "S KEYINFO F510F6DC2E3547A461A721D166938CFE9BF82242 D - - - C \
+             MD5:17:f4:94:f4:41:35:e4:6e:63:fe:8d:70:50:9e:da:eb - -\n"
+            .parse()?;
+        assert_eq!(info.keygrip().to_string(),
+                   "F510F6DC2E3547A461A721D166938CFE9BF82242");
+        assert_eq!(info.storage(), KeyStorage::Disk);
+        assert_eq!(info.serial_number(), None);
+        assert_eq!(info.id(), None);
+        assert!(! info.cached());
+        assert_eq!(info.protection(), KeyProtection::Clear);
+        assert_eq!(info.ssh_fingerprint(),
+                   Some("MD5:17:f4:94:f4:41:35:e4:6e:63:fe:8d:70:50:9e:da:eb"));
+        assert_eq!(info.ttl(), None);
+        assert!(! info.disabled());
+        assert!(! info.ssh_control());
+        assert!(! info.confirm());
+
+        // A key on a smartcard, with all fields set.
+        let info: AgentKeyInfo =
+            "KEYINFO 8AB46809D0512DD50745AD655102B53D632379D2 T \
+             D2760001240103040006123456780000 OPENPGP.1 1 P - 600 Sc"
+            .parse()?;
+        assert_eq!(info.storage(), KeyStorage::Smartcard);
+        assert_eq!(info.serial_number(),
+                   Some("D2760001240103040006123456780000"));
+        assert_eq!(info.id(), Some("OPENPGP.1"));
+        assert!(info.cached());
+        assert_eq!(info.protection(), KeyProtection::Protected);
+        assert_eq!(info.ttl(), Some(600));
+        assert!(! info.disabled());
+        assert!(info.ssh_control());
+        assert!(info.confirm());
+
+        // Older agents omit the trailing fields.
+        let info: AgentKeyInfo =
+            "S KEYINFO 8AB46809D0512DD50745AD655102B53D632379D2 X".parse()?;
+        assert_eq!(info.storage(), KeyStorage::Unknown);
+        assert_eq!(info.protection(), KeyProtection::Unknown);
+
+        for bad in ["",
+                    "OK",
+                    "S KEYINFO",
+                    "S KEYINFO 8AB46809D0512DD50745AD655102B53D632379D2",
+                    "S KEYINFO 8AB4:6809D0512DD50745AD655102B53D632379D2 D",
+                    "S KEYINFO 8AB46809D0512DD50745AD655102B53D632379D2 Q",
+                    "S KEYINFO 8AB46809D0512DD50745AD655102B53D632379D2 D - - 2",
+                    "S KEYINFO 8AB46809D0512DD50745AD655102B53D632379D2 D - - - Z",
+                    "S KEYINFO 8AB46809D0512DD50745AD655102B53D632379D2 D - - - P - x -"]
+        {
+            assert!(bad.parse::<AgentKeyInfo>().is_err(), "{:?}", bad);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn from_sexp() -> Result<()> {
+        // Keys as stored by gpg-agent, named after their keygrips.
+        for (kind, grip) in [
+            ("shadowed-private-key", "8BC1C5721611EE8BC42D0F3A53D144B3DFF8C8E3"),
+            ("protected-private-key", "AC0238024D8B69AC0D3B0B5651A7C7FF131BDE00"),
+            ("protected-private-key", "5152F0589E8FC024611E36EAD6C3F898F283B783"),
+            ("protected-private-key", "C1FAA1A67DA9243A32843C57835F82D56CA6BD5E"),
+        ] {
+            let sexp = Sexp::from_bytes(
+                crate::tests::file(&format!("sexp/agent-keys/{}.key", grip)))?;
+            assert_eq!(sexp.list().and_then(|l| l[0].string())
+                       .map(|s| &s[..]),
+                       Some(kind.as_bytes()));
+            assert_eq!(Keygrip::from_sexp(&sexp)?.to_string(), grip);
+        }
+
+        // Unprotected keys.
+        let sexp = Sexp::from_bytes(crate::tests::file(
+            "sexp/keys/rsa3072-8BC1C5721611EE8BC42D0F3A53D144B3DFF8C8E3.sexp"))?;
+        assert_eq!(Keygrip::from_sexp(&sexp)?.to_string(),
+                   "8BC1C5721611EE8BC42D0F3A53D144B3DFF8C8E3");
+
+        // Not keys.
+        let sexp = Sexp::from_bytes(
+            crate::tests::file("sexp/rsa-signature.sexp"))?;
+        assert!(Keygrip::from_sexp(&sexp).is_err());
+        let sexp = Sexp::from_bytes(b"(10:public-key(3:foo(1:x1:1)))")?;
+        assert!(Keygrip::from_sexp(&sexp).is_err());
+        let sexp = Sexp::from_bytes(b"(10:public-key(3:rsa(1:e3:\x01\x00\x01)))")?;
+        assert!(Keygrip::from_sexp(&sexp).is_err());
+        Ok(())
+    }
+
+    /// Checks that version 6 keys with native Ed25519 and X25519 key
+    /// material have the same keygrip as their legacy version 4
+    /// counterparts.
+    #[test]
+    fn v6_keys() -> Result<()> {
+        use openpgp::packet::key::{Key6, PublicParts, UnspecifiedRole};
+        use openpgp::parse::Parse;
+
+        // testy-new.pgp has a legacy EdDSA primary key and a legacy
+        // ECDH subkey.
+        let cert = openpgp::Cert::from_bytes(
+            crate::tests::key("testy-new.pgp"))?;
+
+        let primary = cert.primary_key().key();
+        let a = match primary.mpis() {
+            PublicKey::EdDSA { curve: Curve::Ed25519, q } =>
+                q.value()[1..].to_vec(),
+            _ => unreachable!(),
+        };
+        let ed25519: Key<PublicParts, UnspecifiedRole> =
+            Key6::import_public_ed25519(&a, primary.creation_time())?.into();
+        assert_eq!(ed25519.version(), 6);
+        assert_eq!(Keygrip::try_from(&ed25519)?,
+                   "DD143ABA8D1D7D09875D6209E01BCF020788FF77".parse::<Keygrip>()?);
+        assert_eq!(Keygrip::try_from(&ed25519)?, Keygrip::try_from(primary)?);
+
+        let subkey = cert.keys().subkeys().next().unwrap().key();
+        let u = match subkey.mpis() {
+            PublicKey::ECDH { curve: Curve::Cv25519, q, .. } =>
+                q.value()[1..].to_vec(),
+            _ => unreachable!(),
+        };
+        let x25519: Key<PublicParts, UnspecifiedRole> =
+            Key6::import_public_x25519(&u, subkey.creation_time())?.into();
+        assert_eq!(x25519.version(), 6);
+        assert_eq!(Keygrip::try_from(&x25519)?,
+                   "583225FBC0A88293472FB95F37E9595E1367188C".parse::<Keygrip>()?);
+        assert_eq!(Keygrip::try_from(&x25519)?, Keygrip::try_from(subkey)?);
+
+        Ok(())
+    }
+
+    /// Checks that the KDF parameters of ECDH keys do not affect the
+    /// keygrip.
+    #[test]
+    fn ecdh_kdf_parameters() -> Result<()> {
+        use openpgp::parse::Parse;
+        use openpgp::types::SymmetricAlgorithm;
+
+        // testy.pgp has a Cv25519 ECDH subkey.
+        let cert = openpgp::Cert::from_bytes(crate::tests::key("testy.pgp"))?;
+        let subkey = cert.keys().subkeys().next().unwrap().key();
+        let (curve, q) = match subkey.mpis() {
+            PublicKey::ECDH { curve, q, .. } => (curve.clone(), q.clone()),
+            _ => unreachable!(),
+        };
+        let expected: Keygrip =
+            "CB6149C50DF90DC88626283A6B6C918A1C29E37D".parse()?;
+
+        assert_eq!(Keygrip::from_components(&curve, q.value())?, expected);
+
+        for hash in [HashAlgorithm::SHA256, HashAlgorithm::SHA384,
+                     HashAlgorithm::SHA512]
+        {
+            for sym in [SymmetricAlgorithm::AES128,
+                        SymmetricAlgorithm::AES192,
+                        SymmetricAlgorithm::AES256]
+            {
+                let key = PublicKey::ECDH {
+                    curve: curve.clone(),
+                    q: q.clone(),
+                    hash,
+                    sym,
+                };
+                assert_eq!(Keygrip::of(&key)?, expected);
+            }
+        }
+
+        // But the curve matters.
+        assert_ne!(Keygrip::from_components(&Curve::Ed25519, q.value())?,
+                   expected);
+        Ok(())
+    }
+
+    #[test]
+    fn bytes() -> Result<()> {
+        let s = "DD143ABA8D1D7D09875D6209E01BCF020788FF77";
+        let k: Keygrip = s.parse()?;
+        assert!(hex::encode(k.as_bytes()).eq_ignore_ascii_case(s));
+
+        let bytes: [u8; 20] = k.clone().into();
+        assert_eq!(&bytes, k.as_bytes());
+        assert_eq!(Keygrip::from(bytes), k);
+        assert_eq!(Keygrip::try_from(bytes).unwrap(), k);
+
+        // The raw digest.
+        assert_eq!(Keygrip::try_from(&bytes[..])?, k);
+        let mut long = bytes.to_vec();
+        long.push(0);
+        for wrong in [&[][..], &bytes[..19], &long[..]] {
+            assert!(Keygrip::try_from(wrong).is_err());
+        }
+        // Not the hex representation.
+        assert!(Keygrip::try_from(s.as_bytes()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn map_key() -> Result<()> {
+        use std::collections::{BTreeMap, HashMap};
+
+        let grips = [
+            "DD143ABA8D1D7D09875D6209E01BCF020788FF77",
+            "13CBE3758AFE42B5E5E2AE4CED27AFA455E3F87F",
+            "7A030357C0F253A5BBCD282FFC4E521B37558F5C",
+            "A0747D5F9425E6664F4FFBEED20FBCA79FDED2BD",
+        ].iter().map(|s| s.parse::<Keygrip>()).collect::<Result<Vec<_>>>()?;
+
+        let hashed: HashMap<Keygrip, usize> =
+            grips.iter().cloned().zip(0..).collect();
+        let ordered: BTreeMap<Keygrip, usize> =
+            grips.iter().cloned().zip(0..).collect();
+        for (i, k) in grips.iter().enumerate() {
+            assert_eq!(hashed.get(k), Some(&i));
+            assert_eq!(ordered.get(k), Some(&i));
+        }
+
+        // The order is the order of the string representation.
+        let mut strings = grips.iter().map(|k| k.to_string())
+            .collect::<Vec<_>>();
+        strings.sort();
+        assert_eq!(ordered.keys().map(|k| k.to_string()).collect::<Vec<_>>(),
+                   strings);
+        Ok(())
+    }
+
     /// Tests vectors from GPGME, using GnuPG as oracle.
     #[test]
     fn gpgme_keys() {
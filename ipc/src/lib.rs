@@ -17,7 +17,22 @@
 //! authenticate us.  If the file does not exist, is malformed, or
 //! does not point to a usable server, we start a new one on demand.
 //!
-//! This design mimics Unix sockets, but works on Windows too.
+//! The file is only modified under the lock.  To connect to a
+//! running server, clients read it without taking the lock first,
+//! and only take it if that fails, see [`Rendezvous::read_unlocked`].
+//!
+//! This design mimics Unix sockets, but works on Windows too.  Where
+//! there is no shared, writable directory, the rendezvous point can
+//! be stored elsewhere, see [`Rendezvous`].
+//!
+//! Cookies don't expire, and rendezvous points carry no timestamps.
+//! Whether a rendezvous point is still usable is decided by sending
+//! the cookie to the server, never by comparing clocks.  Hence,
+//! clients and servers whose wall clocks differ, for instance,
+//! because they run in different containers sharing the home
+//! directory, still agree.  Timeouts, like [`Config::idle_timeout`]
+//! and [`Config::ready_timeout`], are measured using a monotonic
+//! clock by a single party.
 //!
 //! # External vs internal servers
 //!
@@ -31,644 +46,10785 @@
 //! frowned upon for various reasons.
 //!
 //! Please see [`IPCPolicy`] for more information.
+//!
+//! # Features
+//!
+//! If the `no-subprocess` feature is enabled, the code for starting
+//! external servers is compiled out, and servers are always started
+//! as threads, regardless of the [`IPCPolicy`].  This is useful in
+//! sandboxes that forbid spawning processes.
+//!
+//! If the `mmap` feature is enabled, keyboxes can be memory-mapped
+//! using [`keybox::Keybox::open_mmap`].
+//!
+//! If the `test-util` feature is enabled, the `test_util` module
+//! helps testing external servers without a separate server
+//! executable, and `Config::rng` makes cookies reproducible.  Don't
+//! enable it in production.
+//!
+//! If the `compression` feature is enabled, connections can be
+//! compressed, see [`Config::compression`].
 
 #![doc(html_favicon_url = "https://docs.sequoia-pgp.org/favicon.png")]
 #![doc(html_logo_url = "https://docs.sequoia-pgp.org/logo.svg")]
 #![warn(missing_docs)]
 
+use std::cell::Cell;
 use std::fs;
+use std::future::Future;
 use std::io::{self, Read, Seek, Write};
-use std::net::{Ipv4Addr, SocketAddr, TcpStream, TcpListener};
+use std::net::{SocketAddr, TcpStream, TcpListener};
 use std::path::Path;
 use std::path::PathBuf;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use anyhow::Context as _;
 
 use fs2::FileExt;
 
+use sequoia_openpgp::crypto::mem::Protected;
+
 use capnp_rpc::{RpcSystem, twoparty};
 use capnp_rpc::rpc_twoparty_capnp::Side;
 pub use capnp_rpc as capnp_rpc;
+use capnp::message::ReaderOptions;
 
 #[cfg(unix)]
-use std::os::unix::{io::{IntoRawFd, FromRawFd}, fs::OpenOptionsExt};
+use std::os::unix::{io::FromRawFd, fs::OpenOptionsExt};
+#[cfg(all(unix, not(feature = "no-subprocess")))]
+use std::os::unix::io::IntoRawFd;
 #[cfg(windows)]
-use std::os::windows::io::{AsRawSocket, IntoRawSocket, FromRawSocket};
+use std::os::windows::io::FromRawSocket;
+#[cfg(all(windows, not(feature = "no-subprocess")))]
+use std::os::windows::io::{AsRawSocket, IntoRawSocket};
 #[cfg(windows)]
 use winapi::um::winsock2;
 
+#[cfg(not(feature = "no-subprocess"))]
 use std::process::{Command, Stdio};
 use std::thread;
 
 #[macro_use] mod macros;
 pub mod keybox;
 mod keygrip;
-pub use self::keygrip::Keygrip;
+pub use self::keygrip::{Keygrip, AgentKeyInfo, KeyProtection, KeyStorage};
 pub mod sexp;
 mod core;
-pub use crate::core::{Config, Context, IPCPolicy};
+pub use crate::core::{
+    Config,
+    Context,
+    IPCPolicy,
+    DEBUG_PORT_VAR,
+    SERVER_LOG_VAR,
+};
+mod args;
+pub use crate::args::ServerArgs;
+mod rendezvous;
+pub use crate::rendezvous::{
+    FileRendezvous,
+    MemoryRendezvous,
+    Rendezvous,
+    RendezvousLock,
+};
+#[cfg(all(feature = "test-util", not(feature = "no-subprocess")))]
+pub mod test_util;
+#[cfg(feature = "compression")]
+mod compression;
 
 #[cfg(test)]
 mod tests;
 
+// A trivial capnp protocol for the tests.
+#[cfg(test)]
+#[allow(missing_docs, dead_code, clippy::all)]
+#[path = "../examples/ipc-standalone/hello_protocol_capnp.rs"]
+mod hello_protocol_capnp;
+
 /// Servers need to implement this trait.
 pub trait Handler {
-    /// Called on every connection.
+    /// Called on every uncompressed connection.
+    ///
+    /// The client has already been authenticated using the cookie.
+    /// To implement an additional, per-connection policy, the
+    /// handler may refuse to serve the connection by returning an
+    /// error.  In that case, the connection is closed, and the reason
+    /// is logged.
+    ///
+    /// This is called by the default implementation of
+    /// [`Handler::handle_connection`], if the connection can be served
+    /// directly from the socket, i.e., if it is not compressed, and
+    /// the client sent its capabilities in the handshake, which all
+    /// clients using this crate do.  Handlers that serve compressed
+    /// connections, see [`ServerBuilder::compression`], implement
+    /// [`Handler::handle_connection`] instead.  The default
+    /// implementation refuses the connection.
     fn handle(&self,
               network: capnp_rpc::twoparty::VatNetwork<tokio_util::compat::Compat<tokio::net::tcp::OwnedReadHalf>>)
-              -> RpcSystem<Side>;
-}
-
-/// A factory for handlers.
-pub type HandlerFactory = fn(
-    descriptor: Descriptor,
-    local: &tokio::task::LocalSet
-) -> Result<Box<dyn Handler>>;
-
-/// A descriptor is used to connect to a service.
-#[derive(Clone)]
-pub struct Descriptor {
-    ctx: core::Context,
-    rendezvous: PathBuf,
-    executable: PathBuf,
-    factory: HandlerFactory,
-}
+              -> Result<RpcSystem<Side>>
+    {
+        let _ = network;
+        Err(anyhow!("The handler doesn't implement Handler::handle"))
+    }
 
-impl std::fmt::Debug for Descriptor {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Descriptor")
-            .field("rendezvous", &self.rendezvous)
-            .field("executable", &self.executable)
-            .finish()
+    /// Called on every connection.
+    ///
+    /// Like [`Handler::handle`], but for any connection.  Use
+    /// [`Connection::into_network`] to get the network, which
+    /// decompresses the data if the peers agreed on compression.  The
+    /// default implementation passes connections that can be served
+    /// directly from the socket to [`Handler::handle`], and refuses
+    /// the others.
+    fn handle_connection(&self, connection: Connection)
+                         -> Result<RpcSystem<Side>>
+    {
+        match connection.into_plain_network() {
+            Ok(network) => self.handle(network),
+            Err(_) => Err(anyhow!("The connection can't be served from the \
+                                   socket, and the handler doesn't \
+                                   implement Handler::handle_connection")),
+        }
     }
-}
 
-impl Descriptor {
-    /// Create a descriptor given its rendez-vous point, the path to
-    /// the servers executable file, and a handler factory.
-    pub fn new(ctx: &core::Context, rendezvous: PathBuf,
-               executable: PathBuf, factory: HandlerFactory)
-               -> Self {
-        Descriptor {
-            ctx: ctx.clone(),
-            rendezvous,
-            executable,
-            factory,
+    /// Called on every connection, with the service the client
+    /// selected.
+    ///
+    /// Clients select a service using [`DescriptorBuilder::service`].
+    /// `service` is `None` if the client didn't select one.  The
+    /// default implementation serves these connections using
+    /// [`Handler::handle_connection`], and refuses the others.  To
+    /// serve several services on one listener, use a [`Multiplexer`].
+    fn handle_service(&self, service: Option<&str>, connection: Connection)
+                      -> Result<RpcSystem<Side>>
+    {
+        match service {
+            None => self.handle_connection(connection),
+            Some(name) => Err(anyhow!("Unknown service {:?}", name)),
         }
     }
 
-    /// Returns the context.
-    pub fn context(&self) -> &core::Context {
-        &self.ctx
+    /// Called on every connection, with the server's context.
+    ///
+    /// `ctx` is the context of the server's descriptor, see
+    /// [`Descriptor::context`].  This way, the handler can consult
+    /// the configuration, e.g., [`Context::home`] or
+    /// [`Context::ipc_policy`], without stashing the context when it
+    /// is created.  This is what the server calls.  The default
+    /// implementation ignores `ctx`, and calls
+    /// [`Handler::handle_service`].
+    fn handle_with_context(&self, ctx: &Context, service: Option<&str>,
+                           connection: Connection)
+                           -> Result<RpcSystem<Side>>
+    {
+        let _ = ctx;
+        self.handle_service(service, connection)
     }
 
-    /// Returns the rendez-vous point.
-    pub fn rendez_vous(&self) -> &Path {
-        &self.rendezvous
+    /// Called when the server is asked to reload its configuration.
+    ///
+    /// See [`ServerBuilder::reload_on_sighup`].  This is called
+    /// while connections are being served, and doesn't interrupt
+    /// them.  If this returns an error, it is logged, and the server
+    /// keeps serving clients using the old configuration.  The default
+    /// implementation does nothing.
+    fn reload(&self) -> Result<()> {
+        Ok(())
     }
 
-    /// Connects to a descriptor, starting the server if necessary.
+    /// Returns the version and capabilities the server advertises.
     ///
-    /// # Panic
-    /// This will panic if called outside of the Tokio runtime context. See
-    /// See [`Handle::enter`] for more details.
+    /// Clients that ask for them, see
+    /// [`DescriptorBuilder::min_server_version`] and
+    /// [`DescriptorBuilder::require_capability`], receive them as
+    /// part of the handshake, before any RPC is made.  This is called
+    /// once when the server starts serving clients.  The default
+    /// implementation returns an empty version, and no capabilities.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+}
+
+/// Serves several services on one listener.
+///
+/// Every service has its own handler, and clients select the
+/// service using [`DescriptorBuilder::service`].  Connections that
+/// don't select a service are served by the default handler, if
+/// any.  This way, a daemon providing several Cap'n Proto interfaces
+/// needs only one socket and one rendezvous point.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use sequoia_ipc::{Descriptor, Handler, Multiplexer, Result};
+/// # fn keystore(_: &Descriptor) -> Result<Box<dyn Handler>> { unimplemented!() }
+/// # fn agent(_: &Descriptor) -> Result<Box<dyn Handler>> { unimplemented!() }
+/// fn factory(descriptor: Descriptor, _: &tokio::task::LocalSet)
+///            -> Result<Box<dyn Handler>>
+/// {
+///     Ok(Box::new(Multiplexer::new()
+///                 .service("keystore", keystore(&descriptor)?)
+///                 .service("agent", agent(&descriptor)?)))
+/// }
+/// ```
+#[derive(Default)]
+pub struct Multiplexer {
+    default: Option<Box<dyn Handler>>,
+    services: Vec<(String, Box<dyn Handler>)>,
+}
+
+impl Multiplexer {
+    /// Returns a multiplexer without any services.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a service.
     ///
-    /// [`Handle::enter`]: tokio::runtime::Handle::enter()
-    pub fn connect(&self) -> Result<RpcSystem<Side>> {
-        self.connect_with_policy(*self.ctx.ipc_policy())
+    /// Connections selecting the service `name` are served by
+    /// `handler`.  If the service already exists, its handler is
+    /// replaced.
+    pub fn service(mut self, name: &str, handler: Box<dyn Handler>) -> Self {
+        self.set_service(name, handler);
+        self
     }
 
-    /// Connects to a descriptor, starting the server if necessary.
+    /// Adds a service.
     ///
-    /// This function does not use the context's IPC policy, but uses
-    /// the given one.
+    /// See [`Multiplexer::service`].  Returns the old handler, if
+    /// any.
+    pub fn set_service(&mut self, name: &str, handler: Box<dyn Handler>)
+                       -> Option<Box<dyn Handler>>
+    {
+        if let Some((_, h)) = self.services.iter_mut().find(|(n, _)| n == name) {
+            Some(std::mem::replace(h, handler))
+        } else {
+            self.services.push((name.into(), handler));
+            None
+        }
+    }
+
+    /// Sets the handler for connections that don't select a service.
     ///
-    /// # Panic
-    /// This will panic if called outside of the Tokio runtime context. See
-    /// See [`Handle::enter`] for more details.
+    /// By default, these connections are refused.
+    pub fn default_handler(mut self, handler: Box<dyn Handler>) -> Self {
+        self.set_default_handler(handler);
+        self
+    }
+
+    /// Sets the handler for connections that don't select a service.
     ///
-    /// [`Handle::enter`]: tokio::runtime::Handle::enter()
-    pub fn connect_with_policy(&self, policy: core::IPCPolicy)
-                   -> Result<RpcSystem<Side>> {
-        let do_connect = |cookie: Cookie, mut s: TcpStream| {
-            cookie.send(&mut s)?;
+    /// See [`Multiplexer::default_handler`].  Returns the old
+    /// handler, if any.
+    pub fn set_default_handler(&mut self, handler: Box<dyn Handler>)
+                               -> Option<Box<dyn Handler>>
+    {
+        self.default.replace(handler)
+    }
 
-            /* Tokioize.  */
-            s.set_nonblocking(true)?;
-            let stream = tokio::net::TcpStream::from_std(s)?;
-            stream.set_nodelay(true)?;
+    /// Returns the handler for `service`.
+    fn lookup(&self, service: Option<&str>) -> Result<&dyn Handler> {
+        match service {
+            None => self.default.as_deref()
+                .ok_or_else(|| anyhow!("No service selected")),
+            Some(name) => self.services.iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, h)| &**h)
+                .ok_or_else(|| anyhow!("Unknown service {:?}", name)),
+        }
+    }
+}
 
-            let (reader, writer) = stream.into_split();
-            use tokio_util::compat::TokioAsyncReadCompatExt;
-            use tokio_util::compat::TokioAsyncWriteCompatExt;
-            let (reader, writer) = (reader.compat(), writer.compat_write());
+impl Handler for Multiplexer {
+    fn handle_connection(&self, connection: Connection)
+                         -> Result<RpcSystem<Side>>
+    {
+        self.handle_service(None, connection)
+    }
 
-            let network =
-                Box::new(twoparty::VatNetwork::new(reader, writer,
-                                                   Side::Client,
-                                                   Default::default()));
+    fn handle_service(&self, service: Option<&str>, connection: Connection)
+                      -> Result<RpcSystem<Side>>
+    {
+        self.lookup(service)?.handle_connection(connection)
+    }
 
-            Ok(RpcSystem::new(network, None))
-        };
+    fn handle_with_context(&self, ctx: &Context, service: Option<&str>,
+                           connection: Connection)
+                           -> Result<RpcSystem<Side>>
+    {
+        self.lookup(service)?.handle_with_context(ctx, None, connection)
+    }
 
-        fs::create_dir_all(self.ctx.home())?;
+    /// Reloads all services.
+    ///
+    /// Every handler is reloaded, even if another one fails.  The
+    /// first error is returned.
+    fn reload(&self) -> Result<()> {
+        let mut result = Ok(());
+        for handler in self.default.iter()
+            .chain(self.services.iter().map(|(_, h)| h))
+        {
+            let r = handler.reload();
+            if result.is_ok() {
+                result = r;
+            }
+        }
+        result
+    }
+}
 
-        let mut file = CookieFile::open(&self.rendezvous)?;
+/// A factory for handlers.
+pub type HandlerFactory = fn(
+    descriptor: Descriptor,
+    local: &tokio::task::LocalSet
+) -> Result<Box<dyn Handler>>;
 
-        if let Some((cookie, rest)) = file.read()? {
-            let stream = String::from_utf8(rest).map_err(drop)
-                .and_then(|rest| rest.parse::<SocketAddr>().map_err(drop))
-                .and_then(|addr| TcpStream::connect(addr).map_err(drop));
+/// Creates the server side of an RPC system for a connection.
+///
+/// This is meant to be used by [`Handler::handle`] and
+/// [`Handler::handle_connection`].  It serves `bootstrap` on
+/// `network`, and applies the limits configured in `ctx`, see
+/// [`Config::max_concurrent_calls`].  If an audit hook is configured,
+/// see [`Config::audit`], the calls on `bootstrap` are recorded.
+/// The network doesn't know which connection it is for, so the
+/// records don't identify the client.  Handlers implementing
+/// [`Handler::handle_connection`] should use
+/// [`Connection::into_rpc_system`] instead.
+pub fn server_rpc_system<R>(
+    ctx: &core::Context,
+    network: twoparty::VatNetwork<tokio_util::compat::Compat<R>>,
+    bootstrap: capnp::capability::Client)
+    -> RpcSystem<Side>
+where
+    R: tokio::io::AsyncRead + Unpin + 'static,
+{
+    rpc_system(ctx, network, bootstrap, None)
+}
 
-            if let Ok(s) = stream {
-                do_connect(cookie, s)
-            } else {
-                /* Failed to connect.  Invalidate the cookie and try again.  */
-                file.clear()?;
-                drop(file);
-                self.connect()
-            }
-        } else {
-            let cookie = Cookie::new();
+/// Creates the server side of an RPC system for `connection`.
+///
+/// See [`server_rpc_system`] and [`Connection::into_rpc_system`].
+fn rpc_system<R>(
+    ctx: &core::Context,
+    network: twoparty::VatNetwork<tokio_util::compat::Compat<R>>,
+    bootstrap: capnp::capability::Client,
+    connection: Option<ConnectionInfo>)
+    -> RpcSystem<Side>
+where
+    R: tokio::io::AsyncRead + Unpin + 'static,
+{
+    let bootstrap = match ctx.max_concurrent_calls() {
+        Some(limit) => limit_calls(bootstrap, limit),
+        None => bootstrap,
+    };
+    // Record the calls before they are limited, so that rejected
+    // calls are recorded as well.
+    let bootstrap = match ctx.audit() {
+        Some(hook) => capnp_rpc::new_client(CallAuditor {
+            inner: bootstrap,
+            hook: hook.clone(),
+            methods: ctx.audit_methods().clone(),
+            connection,
+        }),
+        None => bootstrap,
+    };
+    RpcSystem::new(Box::new(network), Some(bootstrap))
+}
 
-            let (addr, external, _join_handle) = match policy {
-                core::IPCPolicy::Internal => self.start(false)?,
-                core::IPCPolicy::External => self.start(true)?,
-                core::IPCPolicy::Robust => self.start(true)
-                    .or_else(|_| self.start(false))?
-            };
+/// Forwards calls to a capability, recording them using an audit
+/// hook.
+struct CallAuditor {
+    inner: capnp::capability::Client,
+    hook: core::AuditHook,
+    methods: core::AuditMethods,
+    connection: Option<ConnectionInfo>,
+}
 
-            /* XXX: It'd be nice not to waste this connection.  */
-            cookie.send(&mut TcpStream::connect(addr)?)?;
+impl capnp::capability::FromServer<CallAuditor> for capnp::capability::Client {
+    type Dispatch = CallAuditorDispatch;
 
-            if external {
-                /* Write connection information to file.  */
-                file.write(&cookie, format!("{}", addr).as_bytes())?;
-            }
-            drop(file);
+    fn from_server(s: CallAuditor) -> Self::Dispatch {
+        CallAuditorDispatch(s)
+    }
+}
 
-            do_connect(cookie, TcpStream::connect(addr)?)
-        }
+/// Dispatches calls to a [`CallAuditor`].
+struct CallAuditorDispatch(CallAuditor);
+
+impl std::ops::Deref for CallAuditorDispatch {
+    type Target = CallAuditor;
+
+    fn deref(&self) -> &CallAuditor {
+        &self.0
     }
+}
 
-    /// Start the service, either as an external process or as a
-    /// thread.
-    fn start(&self, external: bool)
-        -> Result<(SocketAddr, bool, Option<JoinHandle<Result<()>>>)>
+impl std::ops::DerefMut for CallAuditorDispatch {
+    fn deref_mut(&mut self) -> &mut CallAuditor {
+        &mut self.0
+    }
+}
+
+impl capnp::capability::Server for CallAuditorDispatch {
+    fn dispatch_call(&mut self, interface_id: u64, method_id: u16,
+                     params: capnp::capability::Params<capnp::any_pointer::Owned>,
+                     mut results: capnp::capability::Results<capnp::any_pointer::Owned>)
+                     -> capnp::capability::Promise<(), capnp::Error>
     {
-        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
-        let addr = listener.local_addr()?;
+        use capnp::any_pointer;
+        use capnp::capability::Promise;
 
-        /* Start the server, connect to it, and send the cookie.  */
-        let join_handle: Option<JoinHandle<Result<()>>> = if external {
-            self.fork(listener)?;
-            None
-        } else {
-            Some(self.spawn(listener)?)
-        };
+        let auditor = &self.0;
+        (auditor.hook)(&AuditRecord {
+            connection: auditor.connection.clone(),
+            interface_id,
+            method_id,
+            method: auditor.methods.get(&interface_id)
+                .and_then(|names| names.get(usize::from(method_id)))
+                .copied(),
+            timestamp: std::time::SystemTime::now(),
+        });
 
-        Ok((addr, external, join_handle))
-    }
+        let mut request = auditor.inner
+            .new_call::<any_pointer::Owned, any_pointer::Owned>(
+                interface_id, method_id, None);
+        capnp_rpc::pry!(request.get().set_as(capnp_rpc::pry!(params.get())));
 
-    fn fork(&self, listener: TcpListener) -> Result<()> {
-        let mut cmd = new_background_command(&self.executable);
-        cmd
-            .arg("--home")
-            .arg(self.ctx.home())
-            .arg("--lib")
-            .arg(self.ctx.lib())
-            .arg("--ephemeral")
-            .arg(self.ctx.ephemeral().to_string())
-            .arg("--socket").arg("0")
-            .stdout(Stdio::null())
-            .stderr(Stdio::null());
+        Promise::from_future(async move {
+            let response = request.send().promise.await?;
+            results.get().set_as(response.get()?)?;
+            Ok(())
+        })
+    }
+}
 
-        platform! {
-            unix => {
-                // Pass the listening TCP socket as child stdin.
-                cmd.stdin(unsafe { Stdio::from_raw_fd(listener.into_raw_fd()) });
-            },
-            windows => {
-                // Sockets for `TcpListener` are not inheritable by default, so
-                // let's make them so, since we'll pass them to a child process.
-                unsafe {
-                    match winapi::um::handleapi::SetHandleInformation(
-                        listener.as_raw_socket() as _,
-                        winapi::um::winbase::HANDLE_FLAG_INHERIT,
-                        winapi::um::winbase::HANDLE_FLAG_INHERIT,
-                    ) {
-                        0 => Err(std::io::Error::last_os_error()),
-                        _ => Ok(())
-                    }?
-                };
-                // We can't pass the socket to stdin directly on Windows, since
-                // non-overlapped (blocking) I/O handles can be redirected there.
-                // We use Tokio (async I/O), so we just pass it via env var rather
-                // than establishing a separate channel to pass the socket through.
-                cmd.env("SOCKET", format!("{}", listener.into_raw_socket()));
-            }
-        }
+/// An RPC call, as recorded for auditing.
+///
+/// See [`Config::audit`].  The record identifies the method by its
+/// name, if the interface's method names are known, see
+/// [`Config::audit_methods`].  Cap'n Proto identifies methods on the
+/// wire using the interface's ID and the method's ordinal.  For
+/// instance, the method declared as `hello @0 (name: Text)` is
+/// method `0` of its interface.  The record includes these as well.
+/// It never includes the parameters or the results.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditRecord {
+    connection: Option<ConnectionInfo>,
+    interface_id: u64,
+    method_id: u16,
+    method: Option<&'static str>,
+    timestamp: std::time::SystemTime,
+}
 
-        cmd.spawn()?;
-        Ok(())
+impl AuditRecord {
+    /// Returns the connection the call was made on.
+    ///
+    /// This identifies the client, see [`ConnectionInfo`].  Returns
+    /// `None` if the RPC system was not created using
+    /// [`Connection::into_rpc_system`].
+    pub fn connection(&self) -> Option<&ConnectionInfo> {
+        self.connection.as_ref()
     }
 
-    fn spawn(&self, l: TcpListener) -> Result<JoinHandle<Result<()>>> {
-        let descriptor = self.clone();
-        let join_handle = thread::spawn(move || -> Result<()> {
-            Server::new(descriptor)
-                .with_context(|| "Failed to spawn server".to_string())?
-                .serve_listener(l)
-                .with_context(|| "Failed to spawn server".to_string())?;
-            Ok(())
-        });
+    /// Returns the ID of the interface the method belongs to.
+    pub fn interface_id(&self) -> u64 {
+        self.interface_id
+    }
 
-        Ok(join_handle)
+    /// Returns the method's ordinal within its interface.
+    pub fn method_id(&self) -> u16 {
+        self.method_id
     }
 
-    /// Turn this process into a server.
+    /// Returns the method's name.
     ///
-    /// This checks if a server is running.  If not, it turns the
-    /// current process into a server.
-    ///
-    /// This function is for servers trying to start themselves.
-    /// Normally, servers are started by clients on demand.  A client
-    /// should never call this function.
-    pub fn bootstrap(&mut self) -> Result<Option<JoinHandle<Result<()>>>> {
-        let mut file = CookieFile::open(&self.rendezvous)?;
+    /// Returns `None` if the interface's method names are not known,
+    /// see [`Config::audit_methods`].
+    pub fn method(&self) -> Option<&'static str> {
+        self.method
+    }
 
-        // Try to connect to the server.  If it is already running,
-        // we're done.
-        if let Some((cookie, rest)) = file.read()? {
-            if let Ok(addr) = String::from_utf8(rest).map_err(drop)
-                .and_then(|rest| rest.parse::<SocketAddr>().map_err(drop))
-            {
-                let stream = TcpStream::connect(&addr).map_err(drop);
+    /// Returns when the call was made.
+    pub fn timestamp(&self) -> std::time::SystemTime {
+        self.timestamp
+    }
+}
 
-                if let Ok(mut s) = stream {
-                    if let Ok(()) = cookie.send(&mut s) {
-                        // There's already a server running.
-                        return Ok(None);
-                    }
-                }
-            }
-        }
+/// Limits the number of concurrent calls on a capability.
+///
+/// Returns a capability that forwards calls to `client`.  Calls made
+/// while `limit` calls are in flight fail immediately with an
+/// [`Overloaded`](capnp::ErrorKind::Overloaded) error.  Calls on
+/// capabilities returned by the forwarded calls are not limited.
+///
+/// To limit the calls per connection, wrap the bootstrap capability
+/// in [`Handler::handle_connection`].  [`server_rpc_system`] does that
+/// if a limit is configured.
+pub fn limit_calls(client: capnp::capability::Client, limit: usize)
+                   -> capnp::capability::Client
+{
+    capnp_rpc::new_client(CallLimiter {
+        inner: client,
+        in_flight: Rc::new(Cell::new(0)),
+        limit,
+    })
+}
 
-        // Create a new cookie.
-        let cookie = Cookie::new();
+/// Forwards calls to a capability, limiting the number of concurrent
+/// calls.
+struct CallLimiter {
+    inner: capnp::capability::Client,
+    in_flight: Rc<Cell<usize>>,
+    limit: usize,
+}
 
-        // Start an *internal* server.
-        let (addr, _external, join_handle) = self.start(false)?;
-        let join_handle = join_handle
-            .expect("start returns the join handle for in-process servers");
+/// Counts a call while it is in flight.
+struct InFlight(Rc<Cell<usize>>);
 
-        file.write(&cookie, format!("{}", addr).as_bytes())?;
-        // Release the lock.
-        drop(file);
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
 
-        // Send the cookie to the server.
-        let mut s = TcpStream::connect(addr)?;
-        cookie.send(&mut s)?;
+impl capnp::capability::FromServer<CallLimiter> for capnp::capability::Client {
+    type Dispatch = CallLimiterDispatch;
 
-        Ok(Some(join_handle))
+    fn from_server(s: CallLimiter) -> Self::Dispatch {
+        CallLimiterDispatch(s)
     }
 }
 
-/// A server.
-pub struct Server {
-    runtime: tokio::runtime::Runtime,
-    descriptor: Descriptor,
+/// Dispatches calls to a [`CallLimiter`].
+struct CallLimiterDispatch(CallLimiter);
+
+impl std::ops::Deref for CallLimiterDispatch {
+    type Target = CallLimiter;
+
+    fn deref(&self) -> &CallLimiter {
+        &self.0
+    }
 }
 
-impl Server {
-    /// Creates a new server for the descriptor.
-    pub fn new(descriptor: Descriptor) -> Result<Self> {
-        Ok(Server {
-            runtime: tokio::runtime::Runtime::new()?,
-            descriptor,
+impl std::ops::DerefMut for CallLimiterDispatch {
+    fn deref_mut(&mut self) -> &mut CallLimiter {
+        &mut self.0
+    }
+}
+
+impl capnp::capability::Server for CallLimiterDispatch {
+    fn dispatch_call(&mut self, interface_id: u64, method_id: u16,
+                     params: capnp::capability::Params<capnp::any_pointer::Owned>,
+                     mut results: capnp::capability::Results<capnp::any_pointer::Owned>)
+                     -> capnp::capability::Promise<(), capnp::Error>
+    {
+        use capnp::any_pointer;
+        use capnp::capability::Promise;
+
+        let limiter = &self.0;
+        if limiter.in_flight.get() >= limiter.limit {
+            return Promise::err(capnp::Error::overloaded(format!(
+                "More than {} concurrent calls", limiter.limit)));
+        }
+        limiter.in_flight.set(limiter.in_flight.get() + 1);
+        let in_flight = InFlight(limiter.in_flight.clone());
+
+        let mut request = limiter.inner
+            .new_call::<any_pointer::Owned, any_pointer::Owned>(
+                interface_id, method_id, None);
+        capnp_rpc::pry!(request.get().set_as(capnp_rpc::pry!(params.get())));
+
+        Promise::from_future(async move {
+            let _in_flight = in_flight;
+            let response = request.send().promise.await?;
+            results.get().set_as(response.get()?)?;
+            Ok(())
         })
     }
+}
 
-    /// Creates a Context from `env::args()`.
-    pub fn context() -> Result<core::Context> {
-        use std::env::args;
-        let args: Vec<String> = args().collect();
+/// Returns the path of the rendezvous point for the given service.
+///
+/// The rendezvous point of the service `service` is the file
+/// `<service>.v<version>.cookie` in the context's home directory, or,
+/// if a prefix is configured, `<prefix><service>.v<version>.cookie`,
+/// see [`Config::rendezvous_prefix`].  `version` is the version of
+/// the handshake the client speaks, currently 1, so that clients and
+/// servers speaking incompatible versions don't use each other's
+/// rendezvous points.  Services that change their own protocol
+/// incompatibly should change their name, e.g. `keystore2`.
+///
+/// This is the layout used by Sequoia's services, and can be used to
+/// locate the rendezvous point without creating a [`Descriptor`],
+/// e.g. to inspect or remove it.  The names don't collide with
+/// GnuPG's sockets, which are named `S.<component>`, so the home
+/// directory can be shared with GnuPG.
+///
+/// `service` is used as a file name, and should not contain path
+/// separators.
+///
+/// # Examples
+///
+/// ```
+/// # use sequoia_ipc::{Context, Result};
+/// # fn main() -> Result<()> {
+/// use sequoia_ipc::rendezvous_path;
+///
+/// let ctx = Context::configure().ephemeral().build()?;
+/// let path = rendezvous_path(&ctx, "keystore");
+/// assert_eq!(path, ctx.home().join("keystore.v1.cookie"));
+/// # Ok(()) }
+/// ```
+pub fn rendezvous_path(ctx: &core::Context, service: &str) -> PathBuf {
+    ctx.home().join(format!("{}{}{}", ctx.rendezvous_prefix(), service,
+                            rendezvous_suffix()))
+}
 
-        if args.len() != 7 || args[1] != "--home"
-            || args[3] != "--lib" || args[5] != "--ephemeral" {
-                return Err(anyhow!(
-                    "Usage: {} --home <HOMEDIR> --lib <LIBDIR> \
-                     --ephemeral true|false", args[0]));
-            }
+/// Returns the default rendezvous backend for the file at `path`.
+///
+/// The backend uses the context's directory mode and lock timeout.
+fn file_rendezvous(ctx: &core::Context, path: &Path) -> FileRendezvous {
+    let mut backend = FileRendezvous::new(path).dir_mode(ctx.home_mode());
+    if let Some(timeout) = ctx.lock_timeout() {
+        backend.set_lock_timeout(timeout);
+    }
+    backend
+}
 
-        let mut cfg = core::Context::configure()
-            .home(&args[2]).lib(&args[4]);
+/// Returns the suffix of the rendezvous points' file names.
+///
+/// See [`rendezvous_path`].
+fn rendezvous_suffix() -> String {
+    format!(".v{}.cookie", HANDSHAKE_VERSION)
+}
 
-        if let Ok(ephemeral) = args[6].parse() {
-            if ephemeral {
-                cfg.set_ephemeral();
-            }
+/// Returns the environment variable overriding a server's
+/// executable.
+///
+/// The service is named after the executable's file name, without
+/// extension and without a `sequoia-` prefix.  It is upper-cased, and
+/// every character that is not alphanumeric is replaced by an
+/// underscore.  The variable is only consulted if
+/// [`Config::executable_override`] is enabled.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::Path;
+/// use sequoia_ipc::executable_override_var;
+///
+/// assert_eq!(executable_override_var(Path::new("/usr/lib/sequoia-keystore")),
+///            "SEQUOIA_KEYSTORE_SERVER");
+/// assert_eq!(executable_override_var(Path::new("public-key-store.exe")),
+///            "SEQUOIA_PUBLIC_KEY_STORE_SERVER");
+/// ```
+pub fn executable_override_var(executable: &Path) -> String {
+    let name = executable.file_stem()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let service = name.strip_prefix("sequoia-").unwrap_or(&name);
+    let service: String = service.chars()
+        .map(|c| if c.is_ascii_alphanumeric() {
+            c.to_ascii_uppercase()
         } else {
-            return Err(anyhow!(
-                "Expected 'true' or 'false' for --ephemeral, got: {}",
-                args[6]));
-        }
+            '_'
+        })
+        .collect();
+    format!("SEQUOIA_{}_SERVER", service)
+}
+
+/// What [`Descriptor::connect`] would do.
+///
+/// This is returned by [`Descriptor::plan_connect`].
+///
+/// Note: This enum cannot be exhaustively matched to allow future
+/// extensions.
+#[non_exhaustive]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectPlan {
+    /// Connect to the server listening on the given address.
+    Connect(SocketAddr),
+    /// Start an external server using the given executable.
+    StartExternal(PathBuf),
+    /// Start an external server using the given executable, and fall
+    /// back to starting a thread if that fails.
+    StartExternalOrInternal(PathBuf),
+    /// Start the server in a thread.
+    StartInternal,
+}
 
-        cfg.build()
+impl std::fmt::Display for ConnectPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectPlan::Connect(addr) =>
+                write!(f, "connect to existing server at {}", addr),
+            ConnectPlan::StartExternal(exe) =>
+                write!(f, "start external server via {}", exe.display()),
+            ConnectPlan::StartExternalOrInternal(exe) =>
+                write!(f, "start external server via {}, \
+                           or an internal thread if that fails",
+                       exe.display()),
+            ConnectPlan::StartInternal =>
+                write!(f, "start internal thread"),
+        }
     }
+}
 
-    /// Turns this process into a server.
-    ///
-    /// External servers must call this early on.
+/// The status of a service.
+///
+/// This is returned by [`Context::discover_services`].
+#[derive(Clone, Debug)]
+pub struct ServiceStatus {
+    name: String,
+    rendezvous: PathBuf,
+    address: Option<SocketAddr>,
+    live: bool,
+}
+
+impl ServiceStatus {
+    /// Returns the service's name.
     ///
-    /// On Linux expects 'stdin' to be a listening TCP socket.
-    /// On Windows this expects `SOCKET` env var to be set to a listening socket
-    /// of the Windows Sockets API `SOCKET` value.
-    pub fn serve(&mut self) -> Result<()> {
-        let listener = platform! {
-            unix => unsafe { TcpListener::from_raw_fd(0) },
-            windows => {
-                let socket = std::env::var("SOCKET")?.parse()?;
-                unsafe { TcpListener::from_raw_socket(socket) }
-            }
-        };
-        self.serve_listener(listener)
+    /// This is the name passed to [`rendezvous_path`].
+    pub fn name(&self) -> &str {
+        &self.name
     }
 
-    fn serve_listener(&mut self, l: TcpListener) -> Result<()> {
-        // The protocol is:
-        //
-        // - The first client exclusively locks the cookie file.
-        //
-        // - The client allocates a TCP socket, and generates a
-        //   cookie.
-        //
-        // - The client starts the server, and passes the listener to
-        //   it.
-        //
-        // - The client connects to the server via the socket, and
-        //   sends it the cookie.
-        //
-        // - The client drops the connection and unlocks the cookie
-        //   file thereby allowing other clients to connect.
-        //
-        // - The server waits for the cookie on the first connection.
-        //
-        // - The server starts serving clients.
-        //
-        // Note: this initial connection cannot (currently) be used
-        // for executing RPCs; the server closes it immediately after
-        // receiving the cookie.
-
-        // The first client sends us the cookie.
-        let cookie = {
-            let mut i = l.accept()?;
-            Cookie::receive(&mut i.0)?
-        };
-
-        /* Tokioize.  */
-        let local = tokio::task::LocalSet::new();
-        let handler = (self.descriptor.factory)(self.descriptor.clone(), &local)?;
+    /// Returns the rendez-vous point.
+    pub fn rendez_vous(&self) -> &Path {
+        &self.rendezvous
+    }
 
-        let server = async move {
-            l.set_nonblocking(true)?;
-            let socket = tokio::net::TcpListener::from_std(l).unwrap();
+    /// Returns the server's address, if the rendezvous point
+    /// contains one.
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.address
+    }
 
-            loop {
-                let (mut socket, _) = socket.accept().await?;
+    /// Returns whether something listens on the server's address.
+    ///
+    /// The service is considered live if connecting to the address
+    /// and sending the cookie succeeds.  Servers don't acknowledge
+    /// the cookie, so this doesn't tell whether the server accepted
+    /// it: if another program reused the port, the service is
+    /// reported as live nevertheless, and clients will fail to
+    /// connect.  If connecting times out, because the server doesn't
+    /// accept connections, the service is reported as live, too.
+    ///
+    /// If this is `false`, the rendezvous point is stale: the next
+    /// client will start a new server.
+    pub fn is_live(&self) -> bool {
+        self.live
+    }
+}
 
-                let _ = socket.set_nodelay(true);
-                let received_cookie = match Cookie::receive_async(&mut socket).await {
-                    Err(_) => continue, // XXX: Log the error?
-                    Ok(received_cookie) => received_cookie,
-                };
-                if received_cookie != cookie {
-                    continue;   // XXX: Log the error?
-                }
+/// Returns whether `entry` looks like a rendezvous point.
+///
+/// See [`rendezvous_path`].
+fn is_rendezvous(ctx: &core::Context, entry: &fs::DirEntry) -> bool {
+    service_name(ctx, &entry.path()).is_some()
+        && entry.file_type().map(|t| t.is_file()).unwrap_or(false)
+}
 
-                let (reader, writer) = socket.into_split();
+/// Returns the name of the service whose rendezvous point is `path`.
+///
+/// See [`rendezvous_path`].
+fn service_name<'a>(ctx: &core::Context, path: &'a Path) -> Option<&'a str> {
+    path.file_name()?.to_str()?
+        .strip_prefix(ctx.rendezvous_prefix())?
+        .strip_suffix(&rendezvous_suffix())
+        .filter(|name| ! name.is_empty())
+}
 
-                use tokio_util::compat::TokioAsyncReadCompatExt;
-                use tokio_util::compat::TokioAsyncWriteCompatExt;
-                let (reader, writer) = (reader.compat(), writer.compat_write());
+/// Implements [`Context::discover_services`].
+fn discover_services(ctx: &core::Context) -> Result<Vec<ServiceStatus>> {
+    let entries = match fs::read_dir(ctx.home()) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound =>
+            return Ok(Vec::new()),
+        Err(err) => return Err(anyhow::Error::from(err).context(
+            format!("Reading {}", ctx.home().display()))),
+    };
 
-                let network =
-                    twoparty::VatNetwork::new(reader, writer,
-                                            Side::Server, Default::default());
+    let mut services = Vec::new();
+    for entry in entries {
+        let entry = entry
+            .with_context(|| format!("Reading {}", ctx.home().display()))?;
+        let path = entry.path();
+        if ! is_rendezvous(ctx, &entry) {
+            continue;
+        }
+        let name = match service_name(ctx, &path) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
 
-                let rpc_system = handler.handle(network);
-                let _ = tokio::task::spawn_local(rpc_system).await;
+        // We don't lock the rendezvous point: a client starting the
+        // server holds the lock until the server is up, and we don't
+        // want to wait for that.  If we read a partially written
+        // file, the service is reported as not live.
+        let mut file = fs::File::open(&path)
+            .with_context(|| format!("Reading {}", path.display()))?;
+        let content = match read_rendezvous_file(&mut file, &path) {
+            Ok(content) => content,
+            Err(err) if matches!(err.downcast_ref::<Error>(),
+                                 Some(Error::MalformedRendezvous(..))) =>
+                Vec::new(),
+            Err(err) => return Err(err),
+        };
+        let (cookie, rest) = if let Some(cookie) = ctx.out_of_band_cookie() {
+            (Cookie::from(&cookie[..]), content)
+        } else {
+            match Cookie::extract(content) {
+                Some((cookie, rest)) => (Some(cookie), rest),
+                None => (None, Vec::new()),
             }
         };
+        let address = parse_address(rest);
+        let live = match (&cookie, address) {
+            (Some(cookie), Some(addr)) => is_live(&probe(cookie, addr)),
+            _ => false,
+        };
 
-        local.block_on(&self.runtime, server)
+        services.push(ServiceStatus {
+            name,
+            rendezvous: path,
+            address,
+            live,
+        });
     }
+
+    services.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(services)
 }
 
-/// Cookies are used to authenticate clients.
-struct Cookie(Vec<u8>);
+/// Implements [`Context::gc_rendezvous`].
+fn gc_rendezvous(ctx: &core::Context) -> Result<usize> {
+    let entries = match fs::read_dir(ctx.home()) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound =>
+            return Ok(0),
+        Err(err) => return Err(anyhow::Error::from(err).context(
+            format!("Reading {}", ctx.home().display()))),
+    };
 
-use rand::RngCore;
-use rand::rngs::OsRng;
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry
+            .with_context(|| format!("Reading {}", ctx.home().display()))?;
+        let path = entry.path();
+        if ! is_rendezvous(ctx, &entry) {
+            continue;
+        }
 
-impl Cookie {
-    const SIZE: usize = 32;
+        // Hold the lock while we decide, so that we don't race a
+        // client.  If a client holds the lock, it is using the
+        // rendezvous point, for instance, because it is starting the
+        // server.  Leave it alone.
+        let mut file = match CookieFile::try_open(&path)? {
+            Some(file) => file,
+            None => continue,
+        };
+        let rendezvous = if let Some(cookie) = ctx.out_of_band_cookie() {
+            file.read_address().map(|addr| addr.map(|addr| {
+                (Cookie::from(&cookie[..]).expect("cookie has the right size"),
+                 addr)
+            }))
+        } else {
+            file.read()
+        };
+        let rendezvous = match rendezvous {
+            Ok(rendezvous) => rendezvous,
+            // Malformed rendezvous points are stale.
+            Err(err) if matches!(err.downcast_ref::<Error>(),
+                                 Some(Error::MalformedRendezvous(..))) =>
+                None,
+            Err(err) => return Err(err),
+        };
+        let live = rendezvous.and_then(|(cookie, rest)| {
+            Some(is_live(&probe(&cookie, parse_address(rest)?)))
+        }).unwrap_or(false);
+        if live {
+            continue;
+        }
 
-    /// Make a new cookie.
-    fn new() -> Self {
-        let mut c = vec![0; Cookie::SIZE];
-        OsRng.fill_bytes(&mut c);
-        Cookie(c)
+        // Clients waiting for the lock notice that the file has been
+        // removed once they get it, see `CookieFile::open`.  On
+        // Windows, files that are open can't be removed, but an
+        // empty rendezvous point is as good as none.  As it is still
+        // there, it is not counted.
+        file.clear()?;
+        removed += platform! {
+            unix => {
+                fs::remove_file(&path)
+                    .with_context(|| format!("Removing {}", path.display()))?;
+                tracing::debug!(path = %path.display(),
+                                "Removed stale rendezvous point");
+                1
+            },
+            windows => {
+                tracing::debug!(path = %path.display(),
+                                "Cleared stale rendezvous point");
+                0
+            }
+        };
     }
 
-    /// Make a new cookie from a slice.
-    fn from(buf: &[u8]) -> Option<Self> {
-        if buf.len() == Cookie::SIZE {
-            let mut c = Vec::with_capacity(Cookie::SIZE);
-            c.extend_from_slice(buf);
-            Some(Cookie(c))
-        } else {
-            None
-        }
+    Ok(removed)
+}
+
+/// Binds the listener for a new server.
+///
+/// The server listens on the configured address, see
+/// [`Config::bind_address`].  If a debug port is configured, and it
+/// is free, the server listens on it.  Otherwise, the server listens
+/// on an ephemeral port.  See [`Config::debug_port`].
+fn bind_listener(ctx: &core::Context) -> io::Result<TcpListener> {
+    let address = ctx.bind_address();
+    if ! address.is_loopback() {
+        tracing::warn!(%address, "Starting the server on a non-loopback \
+                                  address.  It is exposed to the network");
     }
 
-    /// Given a vector starting with a cookie, extract it and return
-    /// the rest.
-    fn extract(mut buf: Vec<u8>) -> Option<(Self, Vec<u8>)> {
-        if buf.len() >= Cookie::SIZE {
-            let r = buf.split_off(Cookie::SIZE);
-            Some((Cookie(buf), r))
-        } else {
-            None
+    if let Some(port) = ctx.debug_port() {
+        match TcpListener::bind((address, port)) {
+            Ok(listener) => {
+                tracing::warn!(port, "Starting the server on the debug port.  \
+                                      This is insecure, use it for \
+                                      debugging only");
+                return Ok(listener);
+            },
+            Err(err) => tracing::warn!(
+                port, "Debug port is not available, using an ephemeral \
+                       port instead: {}", err),
         }
     }
 
-    /// Read a cookie from 'from'.
-    fn receive<R: Read>(from: &mut R) -> Result<Self> {
-        let mut buf = vec![0; Cookie::SIZE];
-        from.read_exact(&mut buf)?;
-        Ok(Cookie(buf))
+    bind_retrying(|| TcpListener::bind((address, 0)))
+}
+
+/// How often to try binding the listener, see [`bind_retrying`].
+const BIND_ATTEMPTS: u32 = 5;
+
+/// How long to wait between attempts to bind the listener.
+const BIND_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Binds a listener using `bind`.
+///
+/// Binding to an ephemeral port can fail transiently, for instance,
+/// if we race with other processes for the last free ports, or run
+/// out of file descriptors.  In that case, `bind` is retried a few
+/// times.  Other errors, and the last error, are returned.
+fn bind_retrying<T>(mut bind: impl FnMut() -> io::Result<T>)
+                    -> io::Result<T>
+{
+    let mut attempt = 1;
+    loop {
+        let err = match bind() {
+            Ok(listener) => return Ok(listener),
+            Err(err) => err,
+        };
+
+        if attempt == BIND_ATTEMPTS || ! is_transient_bind_error(&err) {
+            return Err(err);
+        }
+        tracing::debug!(attempt, "Failed to bind the listener, retrying in \
+                                  {:?}: {}", BIND_BACKOFF, err);
+        thread::sleep(BIND_BACKOFF);
+        attempt += 1;
     }
+}
 
-    /// Asynchronously read a cookie from 'socket'.
-    async fn receive_async(socket: &mut tokio::net::TcpStream) -> io::Result<Cookie> {
-        use tokio::io::AsyncReadExt;
+/// Returns whether binding a listener may succeed if retried.
+fn is_transient_bind_error(err: &io::Error) -> bool {
+    use io::ErrorKind::*;
 
-        let mut buf = vec![0; Cookie::SIZE];
-        socket.read_exact(&mut buf).await?;
-        Ok(Cookie::from(&buf).expect("enough bytes read"))
+    match err.kind() {
+        AddrInUse | AddrNotAvailable | Interrupted | OutOfMemory =>
+            return true,
+        _ => (),
     }
 
+    let transient: &[i32] = platform! {
+        unix => &[libc::EMFILE, libc::ENFILE, libc::ENOBUFS, libc::ENOMEM],
+        // WSAEMFILE and WSAENOBUFS.
+        windows => &[10024, 10055]
+    };
+    err.raw_os_error().map(|errno| transient.contains(&errno))
+        .unwrap_or(false)
+}
+
+/// How long connecting to a server and sending it the cookie may
+/// take, when probing for a server, or delivering the cookie to a
+/// server that was just started.
+///
+/// The server's listener is listening before the server is started,
+/// so connecting completes in the kernel's backlog, even if the
+/// server hasn't started to accept connections yet.  Only if the
+/// backlog is full, for instance, because the server is wedged, does
+/// this expire.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
 
-    /// Write a cookie to 'to'.
-    fn send<W: Write>(&self, to: &mut W) -> io::Result<()> {
-        to.write_all(&self.0)
+/// How long clients wait to connect to a remote server, by default.
+///
+/// See [`DescriptorBuilder::connect_timeout`].
+const REMOTE_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long [`Descriptor::hand_off`] waits for the old server to give
+/// up its listener.
+///
+/// The old server hands off its listener once the connection it is
+/// serving ends.
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long clients wait for the server's capabilities.
+///
+/// The server only responds once it accepted the connection, which it
+/// doesn't do while it is serving another client.  Hence, this is
+/// generous, it only guards against servers that never respond.
+const CAPABILITIES_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How much data an in-memory stream buffers in each direction.
+///
+/// See [`Config::disable_internal_cookie_auth`].
+const IN_PROCESS_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Checks whether a server is listening on `addr`, and sends it the
+/// cookie.
+///
+/// See [`send_cookie`] for the errors.  Use [`is_live`] to interpret
+/// the result.
+fn probe(cookie: &Cookie, addr: SocketAddr) -> Result<()> {
+    send_cookie(cookie, addr)
+}
+
+/// Returns whether the result of [`probe`] shows that a server is
+/// running.
+///
+/// If connecting times out, see [`ConnectError::TimedOut`], the
+/// server is running, but doesn't accept connections.
+fn is_live(probe: &Result<()>) -> bool {
+    match probe {
+        Ok(()) => true,
+        Err(err) => is_timeout(err),
     }
 }
 
-impl PartialEq for Cookie {
-    fn eq(&self, other: &Cookie) -> bool {
-        // First, compare the length.
-        self.0.len() == other.0.len()
-            // The length is not a secret, hence we can use && here.
-            && unsafe {
-                ::memsec::memeq(self.0.as_ptr(),
-                                other.0.as_ptr(),
-                                self.0.len())
-            }
+/// Returns whether the server at `addr` is running, see [`probe`].
+///
+/// If there is a server, but it doesn't accept connections, this
+/// fails with [`ConnectError::TimedOut`], so that the caller doesn't
+/// start another one.
+fn probe_running(cookie: &Cookie, addr: SocketAddr) -> Result<bool> {
+    match probe(cookie, addr) {
+        Ok(()) => Ok(true),
+        Err(err) if is_timeout(&err) => Err(err),
+        Err(_) => Ok(false),
     }
 }
 
-/// Wraps a cookie file.
-struct CookieFile {
-    path: PathBuf,
-    file: fs::File,
+/// What [`Descriptor::start_unless_running`] did.
+enum Startup<R, S> {
+    /// The server published at the rendezvous point is running.
+    Running(R),
+    /// A new server was started.
+    Started(S),
 }
 
-impl CookieFile {
-    /// Opens the specified cookie.
-    ///
-    /// The file is opened, and immediately locked.  (The lock is
-    /// dropped when the file is closed.)
-    fn open(path: &Path) -> Result<CookieFile> {
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent)
-                .with_context(|| format!("Creating {}", parent.display()))?;
+/// Connects to the server at `addr`, and sends it the cookie.
+///
+/// Connecting and sending the cookie each take at most
+/// [`PROBE_TIMEOUT`].  If either expires, this fails with
+/// [`ConnectError::TimedOut`].
+fn send_cookie(cookie: &Cookie, addr: SocketAddr) -> Result<()> {
+    let classify = |err: io::Error, kind: ConnectError| {
+        if matches!(err.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock)
+        {
+            anyhow::Error::from(err).context(ConnectError::TimedOut(addr))
+        } else {
+            anyhow::Error::from(err).context(kind)
         }
+    };
 
-        let mut file = fs::OpenOptions::new();
-        file
-            .read(true)
-            .write(true)
-            .create(true);
-        #[cfg(unix)]
-        file.mode(0o600);
-        let file = file.open(path)
-            .with_context(|| format!("Opening {}", path.display()))?;
-        file.lock_exclusive()
-            .with_context(|| format!("Locking {}", path.display()))?;
-
-        Ok(Self {
-            path: path.to_path_buf(),
-            file,
-        })
+    let mut s = TcpStream::connect_timeout(&addr, PROBE_TIMEOUT)
+        .map_err(|err| classify(err, ConnectError::Connect(addr)))?;
+    s.set_write_timeout(Some(PROBE_TIMEOUT))
+        .context(ConnectError::Connect(addr))?;
+    cookie.send(&mut s)
+        .map_err(|err| classify(err, ConnectError::Handshake))
+}
+
+/// How long [`Descriptor::wait_ready`] waits after the first failed
+/// attempt.
+const WAIT_READY_MIN_DELAY: Duration = Duration::from_millis(10);
+
+/// How long [`Descriptor::wait_ready`] waits between attempts at
+/// most.
+const WAIT_READY_MAX_DELAY: Duration = Duration::from_millis(500);
+
+/// A descriptor is used to connect to a service.
+///
+/// Descriptors are cheap to clone: the clones share the context and
+/// the rest of the state, which is immutable once the descriptor has
+/// been built.  Servers hand a clone to the handler factory for every
+/// connection, see [`HandlerFactory`].
+#[derive(Clone)]
+pub struct Descriptor {
+    inner: Arc<DescriptorInner>,
+}
+
+/// The state of a [`Descriptor`], shared by all clones.
+struct DescriptorInner {
+    ctx: core::Context,
+    rendezvous: PathBuf,
+    executable: PathBuf,
+    factory: HandlerFactory,
+    args: ServerArgs,
+    /// Arguments passed to external servers before the
+    /// [`ServerArgs`], see the `test_util` module.
+    executable_args: Vec<std::ffi::OsString>,
+    /// The address of a remote server, see [`DescriptorBuilder::remote`].
+    remote: Option<SocketAddr>,
+    /// See [`DescriptorBuilder::connect_timeout`].
+    connect_timeout: Duration,
+    /// The service to select, see [`DescriptorBuilder::service`].
+    service: Option<String>,
+    /// Where the rendezvous point is stored, see
+    /// [`DescriptorBuilder::rendezvous`].
+    backend: Box<dyn Rendezvous>,
+    /// File descriptors passed to external servers, see
+    /// [`DescriptorBuilder::pass_fd`].
+    #[cfg(unix)]
+    fds: Vec<(String, std::os::unix::io::OwnedFd)>,
+    /// What the client advertises, see
+    /// [`DescriptorBuilder::capabilities`].
+    capabilities: Option<Capabilities>,
+    /// See [`DescriptorBuilder::min_server_version`].
+    min_server_version: Option<String>,
+    /// See [`DescriptorBuilder::require_capability`].
+    required_capabilities: Vec<String>,
+    /// What the server advertised on the last connection, see
+    /// [`Descriptor::server_capabilities`].
+    server_capabilities: std::sync::Mutex<Option<Capabilities>>,
+    /// The server serving the descriptor in-process, once started,
+    /// see [`Config::disable_internal_cookie_auth`].
+    in_process: std::sync::Mutex<Option<InProcessServer>>,
+}
+
+/// A server running on a thread of this process, and serving
+/// connections over in-memory streams.
+///
+/// See [`Config::disable_internal_cookie_auth`].
+struct InProcessServer {
+    /// Passes new connections to the server.
+    connections: tokio::sync::mpsc::UnboundedSender<tokio::io::DuplexStream>,
+    /// What the handler advertises, see [`Handler::capabilities`].
+    capabilities: Capabilities,
+}
+
+impl std::fmt::Debug for Descriptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Descriptor")
+            .field("rendezvous", &self.inner.rendezvous)
+            .field("executable", &self.inner.executable)
+            .field("executable_args", &self.inner.executable_args)
+            .field("args", &self.inner.args)
+            .field("remote", &self.inner.remote)
+            .field("connect_timeout", &self.inner.connect_timeout)
+            .field("service", &self.inner.service)
+            .field("backend", &self.inner.backend)
+            .field("capabilities", &self.inner.capabilities)
+            .field("min_server_version", &self.inner.min_server_version)
+            .field("required_capabilities", &self.inner.required_capabilities)
+            .finish()
     }
+}
 
-    /// Reads the cookie file.
+/// Builds a [`Descriptor`].
+///
+/// In addition to what [`Descriptor::new`] takes, this allows passing
+/// additional arguments to external servers.  They are passed on the
+/// command line, and the server can retrieve them using
+/// [`ServerArgs::from_env`].
+///
+/// # Examples
+///
+/// ```no_run
+/// # use sequoia_ipc::{Context, Descriptor, DescriptorBuilder, Result};
+/// # use sequoia_ipc::rendezvous_path;
+/// # fn factory(_: Descriptor, _: &tokio::task::LocalSet)
+/// #            -> Result<Box<dyn sequoia_ipc::Handler>> { unimplemented!() }
+/// # fn main() -> Result<()> {
+/// let ctx = Context::configure().build()?;
+/// let descriptor = DescriptorBuilder::new(
+///     &ctx, rendezvous_path(&ctx, "keystore"),
+///     ctx.lib().join("sequoia-keystore"), factory)
+///     .arg("tls-cert", "/etc/keystore/cert.pem")?
+///     .build();
+/// # Ok(()) }
+/// ```
+pub struct DescriptorBuilder(DescriptorInner);
+
+impl DescriptorBuilder {
+    /// Starts building a descriptor.
     ///
-    /// If the file contains a cookie, returns it and any other data.
+    /// See [`Descriptor::new`].
+    pub fn new(ctx: &core::Context, rendezvous: PathBuf,
+               executable: PathBuf, factory: HandlerFactory)
+               -> Self {
+        DescriptorBuilder(DescriptorInner {
+            ctx: ctx.clone(),
+            backend: Box::new(file_rendezvous(ctx, &rendezvous)),
+            rendezvous,
+            executable,
+            factory,
+            args: ServerArgs::new(ctx),
+            executable_args: Vec::new(),
+            remote: None,
+            connect_timeout: REMOTE_CONNECT_TIMEOUT,
+            service: None,
+            #[cfg(unix)]
+            fds: Vec::new(),
+            capabilities: None,
+            min_server_version: None,
+            required_capabilities: Vec::new(),
+            server_capabilities: Default::default(),
+            in_process: Default::default(),
+        })
+    }
+
+    /// Adds an argument passed to external servers.
     ///
-    /// Returns `None` if the file does not contain a cookie.
-    fn read(&mut self) -> Result<Option<(Cookie, Vec<u8>)>> {
-        let mut content = vec![];
-        self.file.read_to_end(&mut content)
-            .with_context(|| format!("Opening {}", self.path.display()))?;
-        Ok(Cookie::extract(content))
+    /// See [`ServerArgs::arg`].
+    pub fn arg<S: AsRef<std::ffi::OsStr>>(mut self, name: &str, value: S)
+                                          -> Result<Self>
+    {
+        self.set_arg(name, value)?;
+        Ok(self)
     }
 
-    /// Writes the specified cookie to the cookie file followed by the
-    /// specified data.
+    /// Adds an argument passed to external servers.
     ///
-    /// The contents of the cookie file are replaced.
-    fn write(&mut self, cookie: &Cookie, data: &[u8]) -> Result<()> {
-        self.file.rewind()
-            .with_context(|| format!("Rewinding {}", self.path.display()))?;
-        self.file.set_len(0)
-            .with_context(|| format!("Truncating {}", self.path.display()))?;
-        self.file.write_all(&cookie.0)
-            .with_context(|| format!("Updating {}", self.path.display()))?;
-        self.file.write_all(data)
-            .with_context(|| format!("Updating {}", self.path.display()))?;
+    /// See [`ServerArgs::arg`].  Returns the old value, if any.
+    pub fn set_arg<S: AsRef<std::ffi::OsStr>>(&mut self, name: &str, value: S)
+                                              -> Result<Option<std::ffi::OsString>>
+    {
+        self.0.args.set_arg(name, value)
+    }
 
-        Ok(())
+    /// Passes a file descriptor to external servers.
+    ///
+    /// Every external server started using the descriptor inherits
+    /// `fd`, for instance, a log file or a notification pipe.  It is
+    /// passed as `--fd <name>=<fd>`, and the server finds it using
+    /// [`ServerArgs::fd`].  `name` is subject to the same rules as
+    /// the names of additional arguments, see [`ServerArgs::arg`].
+    /// If a file descriptor with that name is already present, it is
+    /// replaced.
+    ///
+    /// The descriptor keeps `fd` open for as long as it lives.
+    /// Servers started as threads share the process's file
+    /// descriptors, and don't need this.  This is only supported on
+    /// Unix.
+    #[cfg(unix)]
+    pub fn pass_fd(mut self, name: &str, fd: std::os::unix::io::OwnedFd)
+                   -> Result<Self>
+    {
+        self.set_pass_fd(name, fd)?;
+        Ok(self)
     }
 
-    /// Clears the cookie file.
+    /// Passes a file descriptor to external servers.
     ///
-    /// The cookie file is truncated.
-    fn clear(&mut self) -> Result<()> {
-        self.file.set_len(0)
-            .with_context(|| format!("Truncating {}", self.path.display()))?;
-        Ok(())
+    /// See [`DescriptorBuilder::pass_fd`].  Returns the old file
+    /// descriptor, if any.
+    #[cfg(unix)]
+    pub fn set_pass_fd(&mut self, name: &str, fd: std::os::unix::io::OwnedFd)
+                       -> Result<Option<std::os::unix::io::OwnedFd>>
+    {
+        crate::args::check_name(name)?;
+        if let Some((_, f)) = self.0.fds.iter_mut().find(|(n, _)| n == name) {
+            Ok(Some(std::mem::replace(f, fd)))
+        } else {
+            self.0.fds.push((name.into(), fd));
+            Ok(None)
+        }
     }
-}
 
-#[derive(thiserror::Error, Debug)]
-/// Errors returned from the network routines.
-pub enum Error {
-    /// Connection closed unexpectedly.
-    #[error("Connection closed unexpectedly.")]
-    ConnectionClosed(Vec<u8>),
-}
+    /// Connects to the server at the given address, never starts one.
+    ///
+    /// This is for servers that are not started on demand, for
+    /// instance, a server running on another machine whose socket is
+    /// forwarded over SSH (`ssh -L 127.0.0.1:PORT:127.0.0.1:PORT`).
+    /// [`Descriptor::connect`] connects to `addr`, and fails with
+    /// [`ConnectError::Connect`] if it is unreachable, regardless of
+    /// the IPC policy.  It never starts a server, nor does it modify
+    /// the rendezvous point.
+    ///
+    /// The cookie is the out-of-band cookie, if configured (see
+    /// [`Config::cookie_fd`]).  Otherwise, it is read from the
+    /// rendezvous point, for instance, a copy of the remote server's
+    /// rendezvous point.  The address stored there is ignored.
+    pub fn remote(mut self, addr: SocketAddr) -> Self {
+        self.set_remote(addr);
+        self
+    }
 
-/// Result type specialization.
-pub type Result<T> = ::std::result::Result<T, anyhow::Error>;
+    /// Connects to the server at the given address, never starts one.
+    ///
+    /// See [`DescriptorBuilder::remote`].  Returns the old value, if
+    /// any.
+    pub fn set_remote(&mut self, addr: SocketAddr) -> Option<SocketAddr> {
+        std::mem::replace(&mut self.0.remote, Some(addr))
+    }
 
-// Global initialization and cleanup of the Windows Sockets API (WSA) module.
-// NOTE: This has to be top-level in order for `ctor::{ctor, dtor}` to work.
-#[cfg(windows)]
-use std::sync::atomic::{AtomicBool, Ordering};
-#[cfg(windows)]
-static WSA_INITED: AtomicBool = AtomicBool::new(false);
+    /// Bounds how long connecting to the remote server takes.
+    ///
+    /// If the remote server can't be reached within `timeout`,
+    /// [`Descriptor::connect`] fails with [`ConnectError::Connect`].
+    /// The default is ten seconds.  See [`DescriptorBuilder::remote`].
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.set_connect_timeout(timeout);
+        self
+    }
 
-#[cfg(windows)]
-#[ctor::ctor]
-fn wsa_startup() {
-    unsafe {
-        let ret = winsock2::WSAStartup(
-            0x202, // version 2.2
-            &mut std::mem::zeroed(),
-        );
-        WSA_INITED.store(ret != 0, Ordering::SeqCst);
+    /// Bounds how long connecting to the remote server takes.
+    ///
+    /// See [`DescriptorBuilder::connect_timeout`].  Returns the old
+    /// value.
+    pub fn set_connect_timeout(&mut self, timeout: Duration) -> Duration {
+        std::mem::replace(&mut self.0.connect_timeout, timeout)
     }
-}
 
-#[cfg(windows)]
-#[ctor::dtor]
-fn wsa_cleanup() {
-    if WSA_INITED.load(Ordering::SeqCst) {
-        let _ = unsafe { winsock2::WSACleanup() };
+    /// Selects a service on the server.
+    ///
+    /// This is for servers serving several services on one listener,
+    /// see [`Multiplexer`].  The server passes `name` to
+    /// [`Handler::handle_service`].  Descriptors for different
+    /// services of the same server share the rendezvous point and the
+    /// executable, and only differ in the service.
+    ///
+    /// `name` must be between 1 and 255 bytes long.  Servers that
+    /// predate service selection close the connection.
+    pub fn service(mut self, name: &str) -> Result<Self> {
+        self.set_service(name)?;
+        Ok(self)
     }
-}
 
-pub(crate) fn new_background_command<S>(program: S) -> Command
-where
-    S: AsRef<std::ffi::OsStr>,
-{
-    let command = Command::new(program);
+    /// Selects a service on the server.
+    ///
+    /// See [`DescriptorBuilder::service`].  Returns the old value, if
+    /// any.
+    pub fn set_service(&mut self, name: &str) -> Result<Option<String>> {
+        if name.is_empty() || name.len() > u8::MAX.into() {
+            return Err(anyhow!("Service names must be between 1 and {} \
+                                bytes long, got {:?}", u8::MAX, name));
+        }
+        Ok(self.0.service.replace(name.into()))
+    }
 
-    #[cfg(windows)]
+    /// Stores the rendezvous point using the given backend.
+    ///
+    /// By default, the rendezvous point is the file passed to
+    /// [`DescriptorBuilder::new`], see [`FileRendezvous`].  If
+    /// another backend is used, the path only identifies the
+    /// service in errors and log messages.  Note that
+    /// [`Context::discover_services`] and
+    /// [`Context::gc_rendezvous`] only know about files.
+    ///
+    /// [`Context::discover_services`]: core::Context::discover_services()
+    /// [`Context::gc_rendezvous`]: core::Context::gc_rendezvous()
+    pub fn rendezvous<R: Rendezvous + 'static>(mut self, backend: R) -> Self {
+        self.set_rendezvous(backend);
+        self
+    }
+
+    /// Stores the rendezvous point using the given backend.
+    ///
+    /// See [`DescriptorBuilder::rendezvous`].  Returns the old
+    /// backend.
+    pub fn set_rendezvous<R: Rendezvous + 'static>(&mut self, backend: R)
+                                                   -> Box<dyn Rendezvous>
+    {
+        std::mem::replace(&mut self.0.backend, Box::new(backend))
+    }
+
+    /// Advertises the client's version and capabilities.
+    ///
+    /// They are sent to the server as part of the handshake, and the
+    /// server responds with its own, see [`Handler::capabilities`].
+    /// The server records what the client advertised, see
+    /// [`ConnectionInfo::capabilities`].  Servers that predate the
+    /// exchange close the connection.
+    pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.set_capabilities(capabilities);
+        self
+    }
+
+    /// Advertises the client's version and capabilities.
+    ///
+    /// See [`DescriptorBuilder::capabilities`].  Returns the old
+    /// value, if any.
+    pub fn set_capabilities(&mut self, capabilities: Capabilities)
+                            -> Option<Capabilities>
+    {
+        self.0.capabilities.replace(capabilities)
+    }
+
+    /// Refuses to talk to servers older than `version`.
+    ///
+    /// `version` is a dotted version like `1.2.3`, see
+    /// [`Capabilities::new`] for how versions are compared.  The
+    /// client asks the server for its version as part of the
+    /// handshake, see [`Handler::capabilities`].  If it is older,
+    /// connecting fails with [`ConnectError::Incompatible`], before
+    /// any RPC is made.  Servers that predate the exchange close the
+    /// connection.
+    pub fn min_server_version(mut self, version: &str) -> Result<Self> {
+        self.set_min_server_version(version)?;
+        Ok(self)
+    }
+
+    /// Refuses to talk to servers older than `version`.
+    ///
+    /// See [`DescriptorBuilder::min_server_version`].  Returns the
+    /// old value, if any.
+    pub fn set_min_server_version(&mut self, version: &str)
+                                  -> Result<Option<String>>
+    {
+        if parse_version(version).is_none() {
+            return Err(anyhow!("Invalid version {:?}, expected a dotted \
+                                version like 1.2.3", version));
+        }
+        Ok(self.0.min_server_version.replace(version.into()))
+    }
+
+    /// Refuses to talk to servers that don't advertise the
+    /// capability `name`.
+    ///
+    /// Like [`DescriptorBuilder::min_server_version`], this is
+    /// checked as part of the handshake, and connecting fails with
+    /// [`ConnectError::Incompatible`] if the server doesn't advertise
+    /// the capability.  This can be called several times to require
+    /// several capabilities.
+    pub fn require_capability(mut self, name: &str) -> Self {
+        if ! self.0.required_capabilities.iter().any(|n| n == name) {
+            self.0.required_capabilities.push(name.into());
+        }
+        self
+    }
+
+    /// Finalizes the descriptor.
+    pub fn build(self) -> Descriptor {
+        Descriptor {
+            inner: Arc::new(self.0),
+        }
+    }
+}
+
+impl Descriptor {
+    /// Create a descriptor given its rendez-vous point, the path to
+    /// the servers executable file, and a handler factory.
+    pub fn new(ctx: &core::Context, rendezvous: PathBuf,
+               executable: PathBuf, factory: HandlerFactory)
+               -> Self {
+        DescriptorBuilder::new(ctx, rendezvous, executable, factory).build()
+    }
+
+    /// Returns the context.
+    pub fn context(&self) -> &core::Context {
+        &self.inner.ctx
+    }
+
+    /// Returns the arguments passed to external servers.
+    ///
+    /// The socket and the cookie file descriptor are only set when
+    /// the server is started.
+    pub fn server_args(&self) -> &ServerArgs {
+        &self.inner.args
+    }
+
+    /// Returns the rendez-vous point.
+    pub fn rendez_vous(&self) -> &Path {
+        &self.inner.rendezvous
+    }
+
+    /// Returns the address of the remote server, if any.
+    ///
+    /// See [`DescriptorBuilder::remote`].
+    pub fn remote(&self) -> Option<SocketAddr> {
+        self.inner.remote
+    }
+
+    /// Returns how long connecting to the remote server may take.
+    ///
+    /// See [`DescriptorBuilder::connect_timeout`].
+    pub fn connect_timeout(&self) -> Duration {
+        self.inner.connect_timeout
+    }
+
+    /// Returns the service selected on the server, if any.
+    ///
+    /// See [`DescriptorBuilder::service`].
+    pub fn service(&self) -> Option<&str> {
+        self.inner.service.as_deref()
+    }
+
+    /// Returns the version and capabilities the server advertised.
+    ///
+    /// The client checks them against its requirements, see
+    /// [`DescriptorBuilder::min_server_version`], and
+    /// [`DescriptorBuilder::require_capability`].  This returns what
+    /// the server sent on the last successful connection, and `None`
+    /// if the client hasn't connected yet.  Clones of the descriptor
+    /// share this.
+    pub fn server_capabilities(&self) -> Option<Capabilities> {
+        self.inner.server_capabilities.lock().expect("not poisoned").clone()
+    }
+
+    /// Connects to a descriptor, starting the server if necessary.
+    ///
+    /// If the server is running, this doesn't take the lock on the
+    /// rendezvous point, see [`Rendezvous::read_unlocked`].
+    ///
+    /// # Errors
+    ///
+    /// This must be called from within a Tokio runtime context.
+    /// Otherwise, [`Error::NoTokioRuntime`] is returned.  See
+    /// [`Handle::enter`] for more details.
+    ///
+    /// Errors can be downcast to [`ConnectError`] to find out what
+    /// went wrong.
+    ///
+    /// [`Handle::enter`]: tokio::runtime::Handle::enter()
+    pub fn connect(&self) -> Result<RpcSystem<Side>> {
+        self.connect_with_policy(*self.inner.ctx.ipc_policy())
+    }
+
+    /// Connects to a descriptor, starting the server if necessary.
+    ///
+    /// This function does not use the context's IPC policy, but uses
+    /// the given one.  If the `no-subprocess` feature is enabled, the
+    /// policy is ignored, and [`IPCPolicy::Internal`] is used.
+    ///
+    /// # Errors
+    ///
+    /// This must be called from within a Tokio runtime context.
+    /// Otherwise, [`Error::NoTokioRuntime`] is returned.  See
+    /// [`Handle::enter`] for more details.
+    ///
+    /// Errors can be downcast to [`ConnectError`] to find out what
+    /// went wrong.
+    ///
+    /// [`Handle::enter`]: tokio::runtime::Handle::enter()
+    pub fn connect_with_policy(&self, policy: core::IPCPolicy)
+                   -> Result<RpcSystem<Side>> {
+        // Registering the socket with the reactor below would panic
+        // outside of a runtime context.  Check before we do anything
+        // else.
+        tokio::runtime::Handle::try_current()
+            .map_err(|_| Error::NoTokioRuntime)
+            .context(ConnectError::NoTokioRuntime)?;
+
+        // Without support for subprocesses, we can only start
+        // internal servers.
+        let policy = if cfg!(feature = "no-subprocess") {
+            core::IPCPolicy::Internal
+        } else {
+            policy
+        };
+
+        let rendezvous_error =
+            || ConnectError::Rendezvous(self.inner.rendezvous.clone());
+
+        if let Some(addr) = self.inner.remote {
+            let cookie = if let Some(cookie) = self.out_of_band_cookie() {
+                cookie
+            } else {
+                let cookie = ConnectError::classify(
+                    self.inner.backend.read().map(Cookie::extract),
+                    rendezvous_error)?;
+                cookie.ok_or_else(|| anyhow!(
+                    "No cookie for the remote server at {}", addr))
+                    .with_context(rendezvous_error)?
+                    .0
+            };
+            let s = TcpStream::connect_timeout(&addr, self.connect_timeout())
+                .context(ConnectError::Connect(addr))?;
+            return self.connect_stream(cookie, s, addr);
+        }
+
+        if policy == core::IPCPolicy::Internal
+            && ! self.inner.ctx.internal_cookie_auth()
+        {
+            return self.connect_in_process();
+        }
+
+        // Fast path: if the server is running, connect to it without
+        // taking the lock, so that clients don't contend for it.  This
+        // is safe, because the rendezvous point is only modified under
+        // the lock, and connecting is idempotent.  If we read it while
+        // it is being written, the address is incomplete, and we read
+        // it twice to make sure that it is not.  If we read a stale
+        // address, connecting fails, and we retry holding the lock.
+        // If the server is gone, but the port has already been reused,
+        // the handshake fails, just like it would have had we held
+        // the lock: all we check is that we can connect.
+        match self.connect_unlocked() {
+            Ok(Some((cookie, s, addr))) =>
+                return self.connect_stream(cookie, s, addr),
+            Ok(None) => (),
+            Err(err) => tracing::debug!(
+                rendezvous = %self.inner.rendezvous.display(),
+                "Reading the rendezvous point without the lock: {}", err),
+        }
+
+        let running = |cookie: Cookie, addr: SocketAddr|
+                       -> Result<Option<RpcSystem<Side>>>
+        {
+            match TcpStream::connect(addr) {
+                Ok(s) => self.connect_stream(cookie, s, addr).map(Some),
+                Err(err) => {
+                    tracing::debug!("Connecting to {}: {}", addr, err);
+                    Ok(None)
+                },
+            }
+        };
+        let start = |file: &mut dyn RendezvousLock, cookie: Cookie|
+                     -> Result<(Cookie, SocketAddr)>
+        {
+            let (addr, external, _join_handle) =
+                self.start_with_policy(policy, &cookie)?;
+
+            if external {
+                /* Write connection information to file.  */
+                self.write_rendezvous(file, &cookie, addr)
+                    .with_context(rendezvous_error)?;
+            }
+            Ok((cookie, addr))
+        };
+        let (cookie, addr) = match self.start_unless_running(running, start)? {
+            Startup::Running(rpc_system) => return Ok(rpc_system),
+            Startup::Started(server) => server,
+        };
+
+        let s = TcpStream::connect(addr).context(ConnectError::Connect(addr))?;
+        self.connect_stream(cookie, s, addr)
+    }
+
+    /// Starts the server, if it isn't running, without connecting to
+    /// it.
+    ///
+    /// This is for launchers that start servers eagerly, for
+    /// instance, at login, so that the first call to
+    /// [`Descriptor::connect`] is fast.  If a server is running, this
+    /// does nothing.  Otherwise, a server is started according to the
+    /// context's IPC policy, like [`Descriptor::connect`] would, and
+    /// published at the rendezvous point.  Unlike
+    /// [`Descriptor::connect`], this also publishes servers started
+    /// on a thread, so that subsequent calls to
+    /// [`Descriptor::connect`] attach to them.  Such a server
+    /// runs until the process exits.
+    ///
+    /// This doesn't need a Tokio runtime.  Remote servers can't be
+    /// started.  Like [`Descriptor::bootstrap`], this fails with
+    /// [`ConnectError::TimedOut`] if the server is running, but
+    /// doesn't accept connections.
+    ///
+    /// Errors can be downcast to [`ConnectError`] to find out what
+    /// went wrong.
+    pub fn ensure_started(&self) -> Result<()> {
+        if let Some(addr) = self.inner.remote {
+            return Err(anyhow!("Cannot start the remote server at {}", addr));
+        }
+
+        let policy = if cfg!(feature = "no-subprocess") {
+            core::IPCPolicy::Internal
+        } else {
+            *self.inner.ctx.ipc_policy()
+        };
+
+        let running = |cookie: Cookie, addr| -> Result<Option<()>> {
+            Ok(probe_running(&cookie, addr)?.then_some(()))
+        };
+        let start = |file: &mut dyn RendezvousLock, cookie: Cookie|
+                     -> Result<()>
+        {
+            let (addr, _external, _join_handle) =
+                self.start_with_policy(policy, &cookie)?;
+            self.write_rendezvous(file, &cookie, addr)
+                .with_context(|| ConnectError::Rendezvous(
+                    self.inner.rendezvous.clone()))
+        };
+        match self.start_unless_running(running, start)? {
+            Startup::Running(()) | Startup::Started(()) => Ok(()),
+        }
+    }
+
+    /// Starts a server, unless the one published at the rendezvous
+    /// point is running.
+    ///
+    /// This takes the lock on the rendezvous point, and holds it
+    /// while checking for and starting the server, so that only one
+    /// server is started.  If the rendezvous point names a server,
+    /// `running` is called with its cookie and address.  It returns
+    /// `Some` if the server is running, which is then returned, and
+    /// `None` if it is gone.  Errors returned by `running` are
+    /// returned as is, for instance, if the server is running, but
+    /// doesn't accept connections.
+    ///
+    /// Otherwise, the rendezvous point is invalidated, and `start` is
+    /// called with the lock, and a new cookie.  It starts the server,
+    /// and publishes it at the rendezvous point, if appropriate.
+    fn start_unless_running<R, S>(
+        &self,
+        running: impl FnOnce(Cookie, SocketAddr) -> Result<Option<R>>,
+        start: impl FnOnce(&mut dyn RendezvousLock, Cookie) -> Result<S>)
+        -> Result<Startup<R, S>>
+    {
+        let rendezvous_error =
+            || ConnectError::Rendezvous(self.inner.rendezvous.clone());
+        let mut file = ConnectError::classify(
+            self.inner.backend.lock(), rendezvous_error)?;
+
+        if let Some((cookie, rest)) = self.read_rendezvous(&mut *file)
+            .with_context(rendezvous_error)?
+        {
+            match parse_address(rest) {
+                Some(addr) => match running(cookie, addr)? {
+                    Some(r) => return Ok(Startup::Running(r)),
+                    None => tracing::info!(
+                        rendezvous = %self.inner.rendezvous.display(),
+                        "Server at {} is gone, starting a new one", addr),
+                },
+                None => tracing::warn!(
+                    rendezvous = %self.inner.rendezvous.display(),
+                    "Rendezvous point is malformed, a client may have \
+                     crashed while starting the server.  Starting a new \
+                     one"),
+            }
+
+            // Invalidate the cookie.  We still hold the lock, so we
+            // can start the server right away.
+            file.write(&[]).with_context(rendezvous_error)?;
+        }
+
+        let cookie = self.new_cookie()?;
+        start(&mut *file, cookie).map(Startup::Started)
+    }
+
+    /// Starts a server according to `policy`.
+    ///
+    /// See [`Descriptor::start`].
+    fn start_with_policy(&self, policy: core::IPCPolicy, cookie: &Cookie)
+        -> Result<(SocketAddr, bool, Option<JoinHandle<Result<()>>>)>
+    {
+        match policy {
+            core::IPCPolicy::Internal => self.start(false, cookie),
+            core::IPCPolicy::External => self.start(true, cookie),
+            core::IPCPolicy::Robust => self.start(true, cookie)
+                .or_else(|_| self.start(false, cookie)),
+        }
+    }
+
+    /// Authenticates to the server on `s`, and sets up the RPC
+    /// system.
+    ///
+    /// `addr` is the server's address, and only used for errors.
+    fn connect_stream(&self, cookie: Cookie, mut s: TcpStream,
+                      addr: SocketAddr)
+                      -> Result<RpcSystem<Side>>
+    {
+        set_keepalive(&s, &self.inner.ctx)
+            .context(ConnectError::Connect(addr))?;
+
+        // The server replies to our capabilities only if it accepts
+        // the cookie.  Otherwise, it closes the connection.
+        let handshake_error = |err: io::Error| {
+            let kind = match err.kind() {
+                io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe =>
+                    ConnectError::CookieRejected(addr),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock =>
+                    ConnectError::TimedOut(addr),
+                _ => ConnectError::Handshake,
+            };
+            anyhow::Error::from(err).context(kind)
+        };
+        cookie.send(&mut s).map_err(handshake_error)?;
+        if let Some(id) = self.inner.ctx.trace_id() {
+            send_trace_id(&mut s, id).map_err(handshake_error)?;
+        }
+        if let Some(name) = &self.inner.service {
+            send_service(&mut s, name).map_err(handshake_error)?;
+        }
+
+        // The capabilities are always exchanged, and read in full by
+        // the server, so that it doesn't read anything that belongs
+        // to the RPC system, see [`Handler::handle`].
+        let mut ours = self.inner.capabilities.clone().unwrap_or_default()
+            .without_reserved();
+        if self.inner.ctx.compression() {
+            ours = ours.advertise_compression();
+        }
+        let theirs = exchange_capabilities(&mut s, &ours, CAPABILITIES_TIMEOUT)
+            .map_err(handshake_error)?;
+        let compressing = ours.supports(COMPRESSION_CAPABILITY)
+            && theirs.supports(COMPRESSION_CAPABILITY);
+        let theirs = theirs.without_reserved();
+        self.check_server_capabilities(&theirs)
+            .context(ConnectError::Incompatible)?;
+        *self.inner.server_capabilities.lock().expect("not poisoned") =
+            Some(theirs);
+
+        let compression = if compressing {
+            negotiate_compression(&mut s, CAPABILITIES_TIMEOUT)
+                .context(ConnectError::Handshake)?
+        } else {
+            Compression::None
+        };
+
+        /* Tokioize.  */
+        let stream = s.set_nonblocking(true)
+            .and_then(|()| tokio::net::TcpStream::from_std(s))
+            .and_then(|stream| stream.set_nodelay(true).map(|()| stream))
+            .context(ConnectError::Connect(addr))?;
+
+        let (reader, writer) = stream.into_split();
+        let reader = ConnectionReader::new(reader, Vec::new(), compression);
+        let writer = ConnectionWriter::new(writer, compression);
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+        use tokio_util::compat::TokioAsyncWriteCompatExt;
+        let (reader, writer) = (reader.compat(), writer.compat_write());
+
+        let network =
+            Box::new(twoparty::VatNetwork::new(reader, writer,
+                                               Side::Client,
+                                               *self.inner.ctx.reader_options()));
+
+        Ok(RpcSystem::new(network, None))
+    }
+
+    /// Connects to the server serving the descriptor in-process,
+    /// starting it if necessary.
+    ///
+    /// There is no handshake: the stream can only be reached from
+    /// within the process, so no cookie is needed, and the server's
+    /// capabilities are known.  See
+    /// [`Config::disable_internal_cookie_auth`].
+    fn connect_in_process(&self) -> Result<RpcSystem<Side>> {
+        let mut server = self.inner.in_process.lock().expect("not poisoned");
+        let (client, stream) = tokio::io::duplex(IN_PROCESS_BUFFER_SIZE);
+        let stream = match &*server {
+            Some(running) => match running.connections.send(stream) {
+                Ok(()) => None,
+                // The server's thread is gone, start a new one.
+                Err(err) => Some(err.0),
+            },
+            None => Some(stream),
+        };
+        if let Some(stream) = stream {
+            let started = self.spawn_in_process().context(ConnectError::Start)?;
+            started.connections.send(stream)
+                .map_err(|_| anyhow!("The in-process server stopped"))
+                .context(ConnectError::Start)?;
+            *server = Some(started);
+        }
+        let theirs = server.as_ref().expect("started above")
+            .capabilities.clone();
+        drop(server);
+
+        self.check_server_capabilities(&theirs)
+            .context(ConnectError::Incompatible)?;
+        *self.inner.server_capabilities.lock().expect("not poisoned") =
+            Some(theirs);
+
+        let (reader, writer) = tokio::io::split(client);
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+        use tokio_util::compat::TokioAsyncWriteCompatExt;
+        let network =
+            Box::new(twoparty::VatNetwork::new(reader.compat(),
+                                               writer.compat_write(),
+                                               Side::Client,
+                                               *self.inner.ctx.reader_options()));
+
+        Ok(RpcSystem::new(network, None))
+    }
+
+    /// Starts a server serving the descriptor in-process.
+    ///
+    /// The server runs on a thread, and serves the connections it
+    /// receives concurrently.  It stops once all clones of the
+    /// descriptor are gone, unless the handler keeps one.
+    fn spawn_in_process(&self) -> Result<InProcessServer> {
+        let (connections, mut incoming) =
+            tokio::sync::mpsc::unbounded_channel::<tokio::io::DuplexStream>();
+        let (started, is_started) = std::sync::mpsc::channel();
+
+        let descriptor = self.clone();
+        thread::Builder::new()
+            .name(SERVER_THREAD_NAME.into())
+            .spawn(move || {
+                let ctx = descriptor.inner.ctx.clone();
+                let service = descriptor.inner.service.clone();
+                let setup = || -> Result<_> {
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()?;
+                    let local = tokio::task::LocalSet::new();
+                    let handler =
+                        (descriptor.inner.factory)(descriptor.clone(), &local)?;
+                    Ok((runtime, local, handler))
+                };
+                let (runtime, local, handler) = match setup() {
+                    Ok(server) => server,
+                    Err(err) => {
+                        let _ = started.send(Err(err));
+                        return;
+                    },
+                };
+                // Don't keep the descriptor alive, so that the
+                // server stops once the clients are gone.
+                drop(descriptor);
+                let _ = started.send(
+                    Ok(handler.capabilities().without_reserved()));
+
+                local.block_on(&runtime, async move {
+                    while let Some(stream) = incoming.recv().await {
+                        let span = tracing::info_span!("connection", in_process = true);
+                        let (reader, writer) = tokio::io::split(stream);
+                        let connection = Connection {
+                            reader: ConnectionReader(
+                                ConnectionReaderInner::InProcess(reader)),
+                            writer: ConnectionWriter::InProcess(writer),
+                            reader_options: *ctx.reader_options(),
+                            info: ConnectionInfo::new(
+                                (Ipv4Addr::UNSPECIFIED, 0).into(),
+                                ctx.trace_id().map(Into::into), None),
+                        };
+                        let rpc_system = match handler.handle_with_context(
+                            &ctx, service.as_deref(), connection)
+                        {
+                            Ok(rpc_system) => rpc_system,
+                            Err(err) => {
+                                tracing::warn!(parent: &span,
+                                               "Handler refused connection: {:#}",
+                                               err);
+                                continue;
+                            },
+                        };
+                        use tracing::Instrument;
+                        tokio::task::spawn_local(async move {
+                            if let Err(err) = rpc_system.await {
+                                tracing::debug!("Connection failed: {}", err);
+                            }
+                        }.instrument(span));
+                    }
+                });
+            })
+            .context("Failed to spawn server")?;
+
+        let capabilities = is_started.recv()
+            .map_err(|_| anyhow!("The in-process server failed to start"))??;
+        Ok(InProcessServer {
+            connections,
+            capabilities,
+        })
+    }
+
+    /// Checks that the server meets our requirements.
+    ///
+    /// See [`DescriptorBuilder::min_server_version`] and
+    /// [`DescriptorBuilder::require_capability`].
+    fn check_server_capabilities(&self, theirs: &Capabilities) -> Result<()> {
+        if let Some(min) = &self.inner.min_server_version {
+            if ! theirs.is_at_least(min) {
+                return Err(Error::IncompatibleServer(format!(
+                    "the server's version {:?} is older than {}",
+                    theirs.version(), min)).into());
+            }
+        }
+        for name in &self.inner.required_capabilities {
+            if ! theirs.supports(name) {
+                return Err(Error::IncompatibleServer(format!(
+                    "the server doesn't support {:?}", name)).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns what [`Descriptor::connect`] would do.
+    ///
+    /// This inspects the rendezvous point, and, based on the IPC
+    /// policy, reports whether `connect` would connect to a running
+    /// server, or start a new one.  It does not connect to the server,
+    /// start one, or modify the rendezvous point.  In particular, it
+    /// doesn't check whether the server is still alive: if it is not,
+    /// `connect` starts a new one.
+    pub fn plan_connect(&self) -> Result<ConnectPlan> {
+        if let Some(addr) = self.inner.remote {
+            return Ok(ConnectPlan::Connect(addr));
+        }
+
+        let content: Protected = self.inner.backend.read()?.into();
+        if let Some((_cookie, rest)) = self.parse_rendezvous(&content) {
+            if let Some(addr) = parse_address(rest) {
+                return Ok(ConnectPlan::Connect(addr));
+            }
+        }
+
+        let policy = if cfg!(feature = "no-subprocess") {
+            core::IPCPolicy::Internal
+        } else {
+            *self.inner.ctx.ipc_policy()
+        };
+        Ok(match policy {
+            core::IPCPolicy::Internal => ConnectPlan::StartInternal,
+            core::IPCPolicy::External =>
+                ConnectPlan::StartExternal(self.executable()),
+            core::IPCPolicy::Robust =>
+                ConnectPlan::StartExternalOrInternal(self.executable()),
+        })
+    }
+
+    /// Connects to the server, and runs `f` on the bootstrap
+    /// capability.
+    ///
+    /// This is a convenience function for programs that just want to
+    /// make a few calls, and don't otherwise need an async runtime.
+    /// It creates a single-threaded Tokio runtime and a
+    /// [`LocalSet`], connects to the server (starting it, if
+    /// necessary), drives the RPC system, and passes the server's
+    /// bootstrap capability to `f`.  Once the future returned by `f`
+    /// has completed, the connection is closed and the runtime is
+    /// torn down.
+    ///
+    /// # Errors
+    ///
+    /// This creates a new runtime.  When called from within a Tokio
+    /// runtime context, [`Error::NestedRuntime`] is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use sequoia_ipc::{Context, Descriptor, Handler, Result};
+    /// # use sequoia_ipc::rendezvous_path;
+    /// # struct Keystore;
+    /// # impl Handler for Keystore {}
+    /// # fn factory(_: Descriptor, _: &tokio::task::LocalSet)
+    /// #            -> Result<Box<dyn Handler>> { Ok(Box::new(Keystore)) }
+    /// # fn main() -> Result<()> {
+    /// let ctx = Context::configure().build()?;
+    /// let descriptor = Descriptor::new(
+    ///     &ctx, rendezvous_path(&ctx, "keystore"),
+    ///     ctx.lib().join("sequoia-keystore"), factory);
+    ///
+    /// // Typically, the client is of a type generated by `capnpc`.
+    /// descriptor.with_client(|keystore: capnp::capability::Client| async move {
+    ///     keystore.when_resolved().await?;
+    ///     Ok(())
+    /// })?;
+    /// # Ok(()) }
+    /// ```
+    ///
+    /// [`LocalSet`]: tokio::task::LocalSet
+    pub fn with_client<C, F, Fut, T>(&self, f: F) -> Result<T>
+    where
+        C: capnp::capability::FromClientHook,
+        F: FnOnce(C) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Err(Error::NestedRuntime.into());
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let local = tokio::task::LocalSet::new();
+
+        local.block_on(&runtime, async move {
+            let mut rpc_system = self.connect()?;
+            let client: C = rpc_system.bootstrap(Side::Server);
+            let disconnector = rpc_system.get_disconnector();
+            tokio::task::spawn_local(rpc_system);
+
+            let result = f(client).await;
+
+            // Shut down the connection cleanly.
+            let _ = disconnector.await;
+            result
+        })
+    }
+
+    /// Connects to the server, and returns its bootstrap capability.
+    ///
+    /// This connects like [`Descriptor::connect`] (starting the
+    /// server, if necessary), spawns the RPC system on the current
+    /// [`LocalSet`], and casts the server's bootstrap capability to
+    /// `C`, which is typically a client type generated by `capnpc`.
+    /// Whether the server actually implements `C` is only checked
+    /// once a call is made.
+    ///
+    /// The spawned RPC system owns the connection, the returned
+    /// [`ClientConnection`] dereferences to the client.  The
+    /// connection is closed when the `ClientConnection` is dropped,
+    /// or, cleanly, using [`ClientConnection::disconnect`].  It is
+    /// also closed when the server closes it or the `LocalSet` is
+    /// dropped.  Once the connection is closed, the client and any
+    /// capabilities obtained through it are broken, and calls on them
+    /// fail.  Use [`ReconnectingClient`] to reconnect transparently.
+    ///
+    /// # Errors
+    ///
+    /// See [`Descriptor::connect`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a [`LocalSet`].
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let hello = descriptor.connect_client::<hello::Client>()?;
+    /// let mut request = hello.hello_request();
+    /// request.get().set_name("world");
+    /// let response = request.send().promise.await?;
+    /// hello.disconnect().await?;
+    /// ```
+    ///
+    /// [`LocalSet`]: tokio::task::LocalSet
+    pub fn connect_client<C>(&self) -> Result<ClientConnection<C>>
+    where
+        C: capnp::capability::FromClientHook,
+    {
+        let mut rpc_system = self.connect()?;
+        let client: C = rpc_system.bootstrap(Side::Server);
+        let disconnector = rpc_system.get_disconnector();
+
+        let disconnected = Rc::new(Cell::new(false));
+        let flag = disconnected.clone();
+        let rpc_system = tokio::task::spawn_local(async move {
+            let _ = rpc_system.await;
+            flag.set(true);
+        });
+
+        Ok(ClientConnection {
+            client,
+            disconnector: Some(disconnector),
+            disconnected,
+            rpc_system,
+        })
+    }
+
+    /// Waits until the server accepts RPCs.
+    ///
+    /// This doesn't start the server.  Use it after starting the
+    /// server, for instance, using [`Descriptor::bootstrap`], or if it
+    /// is started by someone else, like a supervisor.
+    ///
+    /// The server is polled.  Every attempt reads the rendezvous
+    /// point, connects to the server, authenticates, and waits for the
+    /// server to answer Cap'n Proto's bootstrap request.  No method
+    /// is called on the bootstrap capability.  If the attempt fails,
+    /// we retry after a delay, which starts at 10 milliseconds, and is
+    /// doubled after every attempt, up to half a second.  The
+    /// rendezvous point is read without taking the lock, because a
+    /// client starting the server holds it until the server is up.
+    /// Servers serve one connection at a time, so a server that is
+    /// busy with another client is not ready.
+    ///
+    /// # Errors
+    ///
+    /// If the server is not ready within `timeout`, the reason the
+    /// last attempt failed is returned.
+    ///
+    /// This creates a new runtime.  When called from within a Tokio
+    /// runtime context, [`Error::NestedRuntime`] is returned.
+    pub fn wait_ready(&self, timeout: Duration) -> Result<()> {
+        use std::time::Instant;
+
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Err(Error::NestedRuntime.into());
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let local = tokio::task::LocalSet::new();
+
+        let deadline = Instant::now() + timeout;
+        let mut delay = WAIT_READY_MIN_DELAY;
+        loop {
+            // Neither a zero connect timeout, nor a zero read timeout
+            // is allowed.
+            let remaining = deadline.saturating_duration_since(Instant::now())
+                .max(Duration::from_millis(1));
+            let err = match local.block_on(&runtime, async {
+                tokio::time::timeout(remaining, self.ping(remaining)).await
+                    .unwrap_or_else(|_| Err(anyhow!("Timed out")))
+            }) {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(err.context(format!(
+                    "Server for {} not ready after {:?}",
+                    self.inner.rendezvous.display(), timeout)));
+            }
+            tracing::trace!(rendezvous = %self.inner.rendezvous.display(),
+                            "Server not ready, retrying in {:?}: {:#}",
+                            delay.min(remaining), err);
+            thread::sleep(delay.min(remaining));
+            delay = (delay * 2).min(WAIT_READY_MAX_DELAY);
+        }
+    }
+
+    /// Connects to the running server, and waits for it to answer
+    /// the bootstrap request.
+    ///
+    /// Used by [`Descriptor::wait_ready`].  `timeout` bounds connecting,
+    /// and the handshake.
+    async fn ping(&self, timeout: Duration) -> Result<()> {
+        let (cookie, addr) = self.running_server()?
+            .ok_or_else(|| anyhow!("No server is running"))?;
+        let s = TcpStream::connect_timeout(&addr, timeout)
+            .context(ConnectError::Connect(addr))?;
+        // Don't hang if the server doesn't answer the compression
+        // offer.
+        s.set_read_timeout(Some(timeout))
+            .context(ConnectError::Connect(addr))?;
+        let mut rpc_system = self.connect_stream(cookie, s, addr)?;
+        let client: capnp::capability::Client =
+            rpc_system.bootstrap(Side::Server);
+        let disconnector = rpc_system.get_disconnector();
+        tokio::task::spawn_local(rpc_system);
+
+        let resolved = client.when_resolved().await;
+        drop(client);
+        let _ = disconnector.await;
+        resolved?;
+        Ok(())
+    }
+
+    /// Returns the cookie and the address of the running server, if
+    /// any.
+    ///
+    /// The rendezvous point is read without taking the lock.
+    fn running_server(&self) -> Result<Option<(Cookie, SocketAddr)>> {
+        if let Some(addr) = self.inner.remote {
+            let cookie = match self.out_of_band_cookie() {
+                Some(cookie) => Some(cookie),
+                None => Cookie::extract(self.inner.backend.read_unlocked()?)
+                    .map(|(cookie, _)| cookie),
+            };
+            return Ok(cookie.map(|cookie| (cookie, addr)));
+        }
+
+        let content: Protected = self.inner.backend.read_unlocked()?.into();
+        Ok(self.parse_rendezvous(&content).and_then(
+            |(cookie, rest)| Some((cookie, parse_address(rest)?))))
+    }
+
+    /// Returns the out-of-band cookie, if one is configured.
+    fn out_of_band_cookie(&self) -> Option<Cookie> {
+        self.inner.ctx.out_of_band_cookie()
+            .map(|c| Cookie::from(&c[..]).expect("cookie has the right size"))
+    }
+
+    /// Returns the cookie for a new server.
+    ///
+    /// This is the out-of-band cookie, if one is configured, or a
+    /// fresh one, see [`Cookie::generate`].
+    fn new_cookie(&self) -> Result<Cookie> {
+        match self.out_of_band_cookie() {
+            Some(cookie) => Ok(cookie),
+            None => Cookie::generate(&self.inner.ctx),
+        }
+    }
+
+    /// Reads the cookie and the address from the rendezvous point.
+    ///
+    /// If an out-of-band cookie is configured, the rendezvous point
+    /// only contains the address.
+    fn read_rendezvous(&self, file: &mut dyn RendezvousLock)
+                       -> Result<Option<(Cookie, Vec<u8>)>>
+    {
+        let content: Protected = file.read()?.into();
+        Ok(self.parse_rendezvous(&content))
+    }
+
+    /// Connects to the server without taking the lock.
+    ///
+    /// Returns `None` if the rendezvous point doesn't name a server,
+    /// changed while we read it, or if the server is gone.
+    fn connect_unlocked(&self)
+                        -> Result<Option<(Cookie, TcpStream, SocketAddr)>>
+    {
+        // Both reads may contain the cookie.  Wrap them, so that they
+        // are cleared when dropped.
+        let content: Protected = self.inner.backend.read_unlocked()?.into();
+        let again: Protected = self.inner.backend.read_unlocked()?.into();
+        if content.is_empty() || content[..] != again[..] {
+            return Ok(None);
+        }
+        drop(again);
+
+        let (cookie, rest) = match self.parse_rendezvous(&content) {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let addr = match parse_address(rest) {
+            Some(addr) => addr,
+            None => return Ok(None),
+        };
+        match TcpStream::connect(addr) {
+            Ok(s) => Ok(Some((cookie, s, addr))),
+            Err(err) => {
+                tracing::debug!(
+                    rendezvous = %self.inner.rendezvous.display(),
+                    "Server at {} is not reachable: {}", addr, err);
+                Ok(None)
+            },
+        }
+    }
+
+    /// Splits the contents of the rendezvous point into the cookie
+    /// and the address.
+    ///
+    /// See [`Descriptor::read_rendezvous`].
+    fn parse_rendezvous(&self, content: &[u8])
+                        -> Option<(Cookie, Vec<u8>)>
+    {
+        if let Some(cookie) = self.out_of_band_cookie() {
+            Some(content).filter(|c| ! c.is_empty())
+                .map(|addr| (cookie, addr.to_vec()))
+        } else {
+            Cookie::split(content)
+                .map(|(cookie, rest)| (cookie, rest.to_vec()))
+        }
+    }
+
+    /// Writes the cookie and the address to the rendezvous point.
+    ///
+    /// If an out-of-band cookie is configured, only the address is
+    /// written.
+    fn write_rendezvous(&self, file: &mut dyn RendezvousLock,
+                        cookie: &Cookie, addr: SocketAddr)
+                        -> Result<()>
+    {
+        let addr = format!("{}", addr);
+        if self.inner.ctx.out_of_band_cookie().is_some() {
+            file.write(addr.as_bytes())
+        } else {
+            let mut content = Vec::with_capacity(Cookie::SIZE + addr.len());
+            content.extend_from_slice(&cookie.0[..]);
+            content.extend_from_slice(addr.as_bytes());
+            let r = file.write(&content);
+            unsafe {
+                memsec::memzero(content.as_mut_ptr(), content.len());
+            }
+            r
+        }
+    }
+
+    /// Start the service, either as an external process or as a
+    /// thread.
+    ///
+    /// External servers are sent the cookie, unless they already know
+    /// it.  Threads are handed the cookie directly.  If configured,
+    /// this waits for external servers to signal that they are ready,
+    /// see [`Config::ready_timeout`].
+    ///
+    /// This can't deadlock, even if the server is slow to start
+    /// accepting connections: the listener is listening before the
+    /// server is started, so connecting to it completes in the
+    /// kernel's backlog, and the cookie fits into the socket's
+    /// buffer.  Callers publish the address only after this returns,
+    /// so the cookie is the first thing an external server receives,
+    /// unless a client connects to the address by chance.  Threads
+    /// don't read the cookie from a connection at all, so they can't
+    /// be confused by such clients, or by this process connecting to
+    /// itself.  Delivering the cookie is bounded by
+    /// [`PROBE_TIMEOUT`] nevertheless.
+    ///
+    /// Errors are classified using [`ConnectError`].
+    fn start(&self, external: bool, cookie: &Cookie)
+        -> Result<(SocketAddr, bool, Option<JoinHandle<Result<()>>>)>
+    {
+        let listener = bind_listener(&self.inner.ctx)
+            .context(ConnectError::Bind)?;
+        let addr = listener.local_addr().context(ConnectError::Bind)?;
+
+        /* Start the server, connect to it, and send the cookie.  */
+        let (join_handle, ready) = if external {
+            let out_of_band = self.inner.ctx.out_of_band_cookie()
+                .map(|cookie| &cookie[..]);
+            (None, ConnectError::classify(self.fork(listener, out_of_band),
+                                          || ConnectError::Start)?)
+        } else {
+            (Some(self.spawn(listener, cookie).context(ConnectError::Start)?),
+             None)
+        };
+
+        if external && self.inner.ctx.out_of_band_cookie().is_none() {
+            /* XXX: It'd be nice not to waste this connection.  */
+            send_cookie(cookie, addr)?;
+        }
+
+        #[cfg(all(unix, not(feature = "no-subprocess")))]
+        if let (Some((ready, child)), Some(timeout)) =
+            (ready, self.inner.ctx.ready_timeout())
+        {
+            wait_child_ready(child, ready, timeout)
+                .with_context(|| format!(
+                    "Starting {}", self.executable().display()))
+                .context(ConnectError::Start)?;
+        }
+        #[cfg(not(all(unix, not(feature = "no-subprocess"))))]
+        let _ = ready;
+
+        Ok((addr, external, join_handle))
+    }
+
+    /// Returns the executable external servers are started with.
+    ///
+    /// This is the executable recorded in the descriptor, unless it
+    /// is overridden, see [`Config::executable_override`].
+    fn executable(&self) -> PathBuf {
+        if self.inner.ctx.executable_override() {
+            let var = executable_override_var(&self.inner.executable);
+            if let Some(executable) = std::env::var_os(&var) {
+                if ! executable.is_empty() {
+                    return PathBuf::from(executable);
+                }
+            }
+        }
+        self.inner.executable.clone()
+    }
+
+    /// Returns the log file of external servers.
+    ///
+    /// If [`Config::server_log`] is enabled, the standard output and
+    /// error of external servers are appended to this file.  It is
+    /// located in the home directory, and named after the server's
+    /// executable.
+    ///
+    /// [`Config::server_log`]: core::Config::server_log()
+    pub fn log_file(&self) -> PathBuf {
+        let name = self.inner.executable.file_stem()
+            .unwrap_or_else(|| self.inner.executable.as_os_str());
+        let mut name = name.to_os_string();
+        name.push(".log");
+        self.inner.ctx.home().join(name)
+    }
+
+    /// Opens the log file for appending, see [`Descriptor::log_file`].
+    #[cfg(not(feature = "no-subprocess"))]
+    fn open_log_file(&self) -> Result<fs::File> {
+        let path = self.log_file();
+        create_dir_all(self.inner.ctx.home(), self.inner.ctx.home_mode())
+            .with_context(|| format!("Creating {}",
+                                     self.inner.ctx.home().display()))?;
+        let mut file = fs::OpenOptions::new();
+        file.append(true).create(true);
+        #[cfg(unix)]
+        file.mode(0o600);
+        file.open(&path)
+            .with_context(|| format!("Opening {}", path.display()))
+    }
+
+    #[cfg(feature = "no-subprocess")]
+    fn fork(&self, _listener: TcpListener, _cookie: Option<&[u8]>)
+            -> Result<Option<(fs::File, std::process::Child)>>
+    {
+        Err(anyhow!("Starting external servers is disabled \
+                     (feature no-subprocess)"))
+    }
+
+    /// Starts the external server.
+    ///
+    /// If `cookie` is given, it is passed to the server out of band.
+    /// Otherwise, the server waits for the cookie on the first
+    /// connection.
+    ///
+    /// If the client waits for the server to become ready, returns
+    /// the read end of the pipe the server signals readiness on, and
+    /// the server's process.
+    #[cfg(not(feature = "no-subprocess"))]
+    fn fork(&self, listener: TcpListener, cookie: Option<&[u8]>)
+            -> Result<Option<(fs::File, std::process::Child)>>
+    {
+        let mut args = self.inner.args.clone();
+        args.socket = Some(0);
+        #[cfg(unix)]
+        for (name, fd) in &self.inner.fds {
+            use std::os::unix::io::AsRawFd;
+            args.set_fd(name, fd.as_raw_fd());
+        }
+
+        let executable = self.executable();
+        if executable != self.inner.executable {
+            tracing::warn!("Starting {} instead of {}, as requested by {}",
+                           executable.display(),
+                           self.inner.executable.display(),
+                           executable_override_var(&self.inner.executable));
+        }
+        let mut cmd = new_background_command(&executable);
+        cmd
+            .args(&self.inner.executable_args)
+            .args(args.to_args());
+        if self.inner.ctx.server_log() {
+            let log = self.open_log_file().context(ConnectError::Start)?;
+            cmd.stdout(log.try_clone().context(ConnectError::Start)?)
+                .stderr(log);
+        } else {
+            cmd.stdout(Stdio::null())
+                .stderr(Stdio::null());
+        }
+
+        #[cfg(unix)]
+        for (_, fd) in &self.inner.fds {
+            use std::os::unix::io::AsRawFd;
+            inherit_fd(&mut cmd, fd.as_raw_fd());
+        }
+
+        // The pipe the server signals readiness on.  We must close
+        // our copy of the write end once the child has been spawned,
+        // so that we notice when the child exits.
+        #[cfg(unix)]
+        let ready = if self.inner.ctx.ready_timeout().is_some() {
+            use std::os::unix::io::AsRawFd;
+
+            let (reader, writer) = pipe()?;
+            let fd = writer.as_raw_fd();
+            cmd.arg("--ready-fd").arg(fd.to_string());
+            inherit_fd(&mut cmd, fd);
+            Some((reader, writer))
+        } else {
+            None
+        };
+        #[cfg(not(unix))]
+        let ready: Option<(fs::File, fs::File)> = None;
+
+        // The file carrying the out-of-band cookie must stay open
+        // until the child has been spawned.
+        let _cookie_pipe = platform! {
+            unix => {
+                // Pass the listening TCP socket as child stdin.
+                cmd.stdin(unsafe { Stdio::from_raw_fd(listener.into_raw_fd()) });
+
+                // Pass the out-of-band cookie over a memfd or a pipe.
+                if let Some(cookie) = cookie {
+                    use std::os::unix::io::AsRawFd;
+
+                    let pipe = cookie_file(cookie)?;
+                    let fd = pipe.as_raw_fd();
+                    cmd.arg("--cookie-fd").arg(fd.to_string());
+                    // Let the child inherit the read end.
+                    inherit_fd(&mut cmd, fd);
+                    Some(pipe)
+                } else {
+                    None
+                }
+            },
+            windows => {
+                if cookie.is_some() {
+                    return Err(anyhow!("Passing an out-of-band cookie to \
+                                        external servers is not supported \
+                                        on Windows"));
+                }
+
+                // Sockets for `TcpListener` are not inheritable by default, so
+                // let's make them so, since we'll pass them to a child process.
+                unsafe {
+                    match winapi::um::handleapi::SetHandleInformation(
+                        listener.as_raw_socket() as _,
+                        winapi::um::winbase::HANDLE_FLAG_INHERIT,
+                        winapi::um::winbase::HANDLE_FLAG_INHERIT,
+                    ) {
+                        0 => Err(std::io::Error::last_os_error()),
+                        _ => Ok(())
+                    }?
+                };
+                // We can't pass the socket to stdin directly on Windows, since
+                // non-overlapped (blocking) I/O handles can be redirected there.
+                // We use Tokio (async I/O), so we just pass it via env var rather
+                // than establishing a separate channel to pass the socket through.
+                cmd.env("SOCKET", format!("{}", listener.into_raw_socket()));
+            }
+        };
+
+        let child = cmd.spawn().map_err(|err| {
+            let kind = if err.kind() == io::ErrorKind::NotFound {
+                ConnectError::ExecutableNotFound(executable.clone())
+            } else {
+                ConnectError::Start
+            };
+            anyhow::Error::from(err).context(kind)
+        })?;
+        Ok(ready.map(|(reader, _writer)| (reader, child)))
+    }
+
+    /// Starts a server on a thread.
+    ///
+    /// The server authenticates clients using `cookie`.
+    fn spawn(&self, l: TcpListener, cookie: &Cookie)
+             -> Result<JoinHandle<Result<()>>>
+    {
+        let descriptor = self.clone();
+        let cookie = Cookie::from(&cookie.0[..])
+            .expect("cookie has the right size");
+        let join_handle = thread::spawn(move || -> Result<()> {
+            Server::new(descriptor)
+                .with_context(|| "Failed to spawn server".to_string())?
+                .serve_connections(l, cookie)
+                .with_context(|| "Failed to spawn server".to_string())?;
+            Ok(())
+        });
+
+        Ok(join_handle)
+    }
+
+    /// Hands the listener of a running server off to a new server.
+    ///
+    /// This restarts a server without a gap in which clients can't
+    /// connect, for instance, to upgrade the server's executable.
+    /// The old server must have handed out `handle`, see
+    /// [`Server::handoff_handle`].  The old server stops accepting
+    /// connections once the connection it is currently serving
+    /// ends, and gives up its listener.  Then, a new server is
+    /// started on the same listener, and with the same cookie.
+    /// Hence, the rendezvous point remains valid.  Clients connecting
+    /// in the meantime are queued in the listener's backlog, and are
+    /// served by the new server.  The backlog's size is limited by the
+    /// operating system.  Once it is full, further clients are
+    /// refused, or time out connecting, depending on the platform.
+    ///
+    /// The rendezvous point is locked during the handoff, so that
+    /// only one server owns the address at any time.  If the old
+    /// server doesn't give up its listener within ten seconds, for
+    /// instance, because the connection it
+    /// is serving doesn't end, this fails with
+    /// [`ConnectError::TimedOut`], and the old server keeps serving.
+    ///
+    /// With [`IPCPolicy::External`], the new server is started as
+    /// external process, and with [`IPCPolicy::Internal`], as a
+    /// thread.  With [`IPCPolicy::Robust`], an external server is
+    /// tried first.  If a thread is started, its join handle is
+    /// returned.
+    ///
+    /// External servers receive the cookie using `--cookie-fd`, which
+    /// is not supported on Windows.  Servers started by the init
+    /// system publish a cookie at the rendezvous point, and require
+    /// clients to send it (see [`Server::from_socket_activation`]).
+    /// Hence, they are handed off like any other server, and the new
+    /// server continues to use their cookie.
+    pub fn hand_off(&self, handle: &HandoffHandle, policy: core::IPCPolicy)
+                    -> Result<Option<JoinHandle<Result<()>>>>
+    {
+        let policy = if cfg!(feature = "no-subprocess") {
+            core::IPCPolicy::Internal
+        } else {
+            policy
+        };
+
+        let rendezvous_error =
+            || ConnectError::Rendezvous(self.inner.rendezvous.clone());
+        let _file = ConnectError::classify(
+            self.inner.backend.lock(), rendezvous_error)?;
+
+        let Handoff { listener, cookie } = handle.request(HANDOFF_TIMEOUT)?;
+
+        // Clients may already be waiting in the listener's backlog,
+        // hence we pass the cookie out of band.
+        let external = || -> Result<()> {
+            let listener = listener.try_clone().context(ConnectError::Start)?;
+            let ready = ConnectError::classify(
+                self.fork(listener, Some(&cookie.0[..])),
+                || ConnectError::Start)?;
+
+            #[cfg(all(unix, not(feature = "no-subprocess")))]
+            if let (Some((ready, child)), Some(timeout)) =
+                (ready, self.inner.ctx.ready_timeout())
+            {
+                wait_child_ready(child, ready, timeout)
+                    .with_context(|| format!(
+                        "Starting {}", self.executable().display()))
+                    .context(ConnectError::Start)?;
+            }
+            #[cfg(not(all(unix, not(feature = "no-subprocess"))))]
+            let _ = ready;
+            Ok(())
+        };
+        let internal = |listener: TcpListener, cookie: Cookie| {
+            let descriptor = self.clone();
+            thread::spawn(move || -> Result<()> {
+                Server::new(descriptor)
+                    .context("Failed to spawn server")?
+                    .serve_connections(listener, cookie)
+                    .context("Failed to spawn server")
+            })
+        };
+
+        match policy {
+            core::IPCPolicy::External => external().map(|()| None),
+            core::IPCPolicy::Internal =>
+                Ok(Some(internal(listener, cookie))),
+            core::IPCPolicy::Robust => match external() {
+                Ok(()) => Ok(None),
+                Err(err) => {
+                    tracing::debug!("Starting the external server failed, \
+                                     starting a thread: {:#}", err);
+                    Ok(Some(internal(listener, cookie)))
+                },
+            },
+        }
+    }
+
+    /// Turn this process into a server.
+    ///
+    /// This checks if a server is running.  If not, it turns the
+    /// current process into a server.
+    ///
+    /// This function is for servers trying to start themselves.
+    /// Normally, servers are started by clients on demand.  A client
+    /// should never call this function.
+    ///
+    /// Probing for a running server takes at most a second for
+    /// connecting, and as long for sending the cookie.  If the
+    /// server is running, but doesn't accept connections, this fails
+    /// with [`ConnectError::TimedOut`] instead of hanging, and no
+    /// new server is started.
+    ///
+    /// Errors can be downcast to [`ConnectError`] to find out what
+    /// went wrong.
+    pub fn bootstrap(&mut self) -> Result<Option<JoinHandle<Result<()>>>> {
+        if let Some(addr) = self.inner.remote {
+            return Err(anyhow!("Cannot start the remote server at {}", addr));
+        }
+
+        // Try to connect to the server.  If it is already running,
+        // we're done.
+        let running = |cookie: Cookie, addr| -> Result<Option<()>> {
+            Ok(probe_running(&cookie, addr)?.then_some(()))
+        };
+        let start = |file: &mut dyn RendezvousLock, cookie: Cookie|
+                     -> Result<JoinHandle<Result<()>>>
+        {
+            // Start an *internal* server, and send it the cookie.
+            let (addr, _external, join_handle) = self.start(false, &cookie)?;
+            let join_handle = join_handle
+                .expect("start returns the join handle for in-process servers");
+
+            self.write_rendezvous(file, &cookie, addr)
+                .with_context(|| ConnectError::Rendezvous(
+                    self.inner.rendezvous.clone()))?;
+            Ok(join_handle)
+        };
+        match self.start_unless_running(running, start)? {
+            Startup::Running(()) => Ok(None),
+            Startup::Started(join_handle) => Ok(Some(join_handle)),
+        }
+    }
+
+    /// Turn this process into a server, asynchronously.
+    ///
+    /// This is like [`Descriptor::bootstrap`], but it can be called
+    /// from within a tokio runtime.  Probing for a running server
+    /// uses tokio's IO.  Taking the rendezvous point's lock, which
+    /// may block until another process releases it, and starting the
+    /// server are done on tokio's blocking thread pool.
+    ///
+    /// Errors can be downcast to [`ConnectError`] to find out what
+    /// went wrong.
+    pub async fn bootstrap_async(&self)
+                                 -> Result<Option<JoinHandle<Result<()>>>>
+    {
+        if let Some(addr) = self.inner.remote {
+            return Err(anyhow!("Cannot start the remote server at {}", addr));
+        }
+
+        let rendezvous_error =
+            || ConnectError::Rendezvous(self.inner.rendezvous.clone());
+        let descriptor = self.clone();
+        let (mut file, rendezvous) =
+            tokio::task::spawn_blocking(move || -> Result<_> {
+                let mut file = descriptor.inner.backend.lock()?;
+                let rendezvous = descriptor.read_rendezvous(&mut *file)?;
+                Ok((file, rendezvous))
+            }).await?
+            .with_context(rendezvous_error)?;
+
+        // Try to connect to the server.  If it is already running,
+        // we're done.
+        if let Some((cookie, rest)) = rendezvous {
+            if let Some(addr) = parse_address(rest) {
+                let probe = async {
+                    let mut s = tokio::net::TcpStream::connect(addr).await?;
+                    cookie.send_async(&mut s).await
+                };
+                match tokio::time::timeout(PROBE_TIMEOUT, probe).await {
+                    // There's already a server running.
+                    Ok(Ok(())) => return Ok(None),
+                    Ok(Err(_)) => (),
+                    // There is a server, but it doesn't accept
+                    // connections.  Don't start another one.
+                    Err(_) => return Err(ConnectError::TimedOut(addr).into()),
+                }
+                tracing::info!(
+                    rendezvous = %self.inner.rendezvous.display(),
+                    "Server at {} is gone, starting a new one", addr);
+            } else {
+                tracing::warn!(
+                    rendezvous = %self.inner.rendezvous.display(),
+                    "Rendezvous point is malformed, a client may have \
+                     crashed while starting the server.  Starting a new \
+                     one");
+            }
+        }
+
+        // Start an *internal* server, send it a new cookie, and
+        // publish it.  We still hold the lock.
+        let descriptor = self.clone();
+        tokio::task::spawn_blocking(move || -> Result<_> {
+            let cookie = descriptor.new_cookie()?;
+            let (addr, _external, join_handle) =
+                descriptor.start(false, &cookie)?;
+            let join_handle = join_handle
+                .expect("start returns the join handle for in-process servers");
+
+            descriptor.write_rendezvous(&mut *file, &cookie, addr)
+                .with_context(|| ConnectError::Rendezvous(
+                    descriptor.inner.rendezvous.clone()))?;
+            // Release the lock.
+            drop(file);
+
+            Ok(Some(join_handle))
+        }).await?
+    }
+}
+
+/// A client that reconnects when its connection breaks.
+///
+/// Long-lived clients may outlive their connection to the server,
+/// for instance, if the server is restarted, or closes the
+/// connection because it was idle.  `ReconnectingClient` keeps track
+/// of the connection's [`RpcSystem`], and once it has terminated,
+/// [`ReconnectingClient::client`] transparently calls
+/// [`Descriptor::connect`] again, which starts a new server, if
+/// necessary, and re-resolves the bootstrap capability.
+///
+/// Calls that are in flight when the connection breaks still fail,
+/// typically with [`capnp::ErrorKind::Disconnected`].  They are not
+/// retried, because they may or may not have been executed by the
+/// server.  Also, capabilities obtained from an earlier connection
+/// remain broken; get a fresh bootstrap capability using
+/// [`ReconnectingClient::client`] before making a call.
+///
+/// The RPC system is driven by a task spawned using
+/// [`tokio::task::spawn_local`].  Hence, `ReconnectingClient` must be
+/// used from within a [`LocalSet`].
+///
+/// [`LocalSet`]: tokio::task::LocalSet
+///
+/// # Examples
+///
+/// ```ignore
+/// let mut client = ReconnectingClient::<hello::Client>::new(descriptor);
+/// loop {
+///     let mut request = client.client()?.hello_request();
+///     request.get().set_name("world");
+///     request.send().promise.await?;
+///     tokio::time::sleep(Duration::from_secs(60)).await;
+/// }
+/// ```
+pub struct ReconnectingClient<C> {
+    descriptor: Descriptor,
+    connection: Option<LiveConnection<C>>,
+}
+
+/// A connection of a [`ReconnectingClient`].
+struct LiveConnection<C> {
+    client: C,
+    disconnector: capnp_rpc::Disconnector<Side>,
+    /// Set once the RPC system has terminated.
+    disconnected: Rc<Cell<bool>>,
+}
+
+impl<C> ReconnectingClient<C>
+where
+    C: capnp::capability::FromClientHook + Clone,
+{
+    /// Returns a client for the descriptor.
+    ///
+    /// The connection is established lazily, by the first call to
+    /// [`ReconnectingClient::client`].
+    pub fn new(descriptor: Descriptor) -> Self {
+        ReconnectingClient {
+            descriptor,
+            connection: None,
+        }
+    }
+
+    /// Returns the descriptor.
+    pub fn descriptor(&self) -> &Descriptor {
+        &self.descriptor
+    }
+
+    /// Returns whether the client is connected to the server.
+    ///
+    /// A connection is considered broken once its RPC system has
+    /// terminated.  Note that this is only noticed while the
+    /// [`LocalSet`] is running.
+    ///
+    /// [`LocalSet`]: tokio::task::LocalSet
+    pub fn is_connected(&self) -> bool {
+        self.connection.as_ref()
+            .map(|c| ! c.disconnected.get())
+            .unwrap_or(false)
+    }
+
+    /// Returns the bootstrap capability.
+    ///
+    /// If the client is not connected, this connects to the server
+    /// (starting it, if necessary) first.
+    ///
+    /// # Errors
+    ///
+    /// See [`Descriptor::connect`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a [`LocalSet`].
+    ///
+    /// [`LocalSet`]: tokio::task::LocalSet
+    pub fn client(&mut self) -> Result<C> {
+        if ! self.is_connected() {
+            if self.connection.is_some() {
+                tracing::debug!(rendezvous = %self.descriptor.rendez_vous().display(),
+                                "Connection broken, reconnecting");
+            }
+            self.connection = Some(self.connect()?);
+        }
+
+        Ok(self.connection.as_ref().expect("connected").client.clone())
+    }
+
+    /// Connects to the server.
+    fn connect(&self) -> Result<LiveConnection<C>> {
+        let mut rpc_system = self.descriptor.connect()?;
+        let client: C = rpc_system.bootstrap(Side::Server);
+        let disconnector = rpc_system.get_disconnector();
+
+        let disconnected = Rc::new(Cell::new(false));
+        let flag = disconnected.clone();
+        tokio::task::spawn_local(async move {
+            let _ = rpc_system.await;
+            flag.set(true);
+        });
+
+        Ok(LiveConnection {
+            client,
+            disconnector,
+            disconnected,
+        })
+    }
+
+    /// Closes the connection, if any.
+    ///
+    /// The next call to [`ReconnectingClient::client`] connects
+    /// again.
+    pub async fn disconnect(&mut self) -> Result<()> {
+        if let Some(c) = self.connection.take() {
+            if ! c.disconnected.get() {
+                c.disconnector.await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A connection to a server.
+///
+/// This is returned by [`Descriptor::connect_client`], and
+/// dereferences to the server's bootstrap capability.  Dropping it
+/// closes the connection, after which the capability and any
+/// capabilities obtained through it are broken.
+pub struct ClientConnection<C> {
+    client: C,
+    disconnector: Option<capnp_rpc::Disconnector<Side>>,
+    /// Set once the RPC system has terminated.
+    disconnected: Rc<Cell<bool>>,
+    rpc_system: tokio::task::JoinHandle<()>,
+}
+
+impl<C> ClientConnection<C> {
+    /// Returns the server's bootstrap capability.
+    pub fn client(&self) -> &C {
+        &self.client
+    }
+
+    /// Returns whether the connection is still open.
+    ///
+    /// Note that a closed connection is only noticed while the
+    /// [`LocalSet`] is running.
+    ///
+    /// [`LocalSet`]: tokio::task::LocalSet
+    pub fn is_connected(&self) -> bool {
+        ! self.disconnected.get()
+    }
+
+    /// Closes the connection cleanly.
+    ///
+    /// Unlike dropping the `ClientConnection`, this waits until the
+    /// RPC system shut down.
+    pub async fn disconnect(mut self) -> Result<()> {
+        if let Some(disconnector) = self.disconnector.take() {
+            if ! self.disconnected.get() {
+                disconnector.await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<C> std::ops::Deref for ClientConnection<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.client
+    }
+}
+
+impl<C> Drop for ClientConnection<C> {
+    fn drop(&mut self) {
+        // Dropping the RPC system closes the connection.
+        self.rpc_system.abort();
+    }
+}
+
+/// The name of the threads the server's runtime spawns on demand.
+const SERVER_THREAD_NAME: &str = "sequoia-ipc";
+
+/// How long the server waits for the first client to send the cookie.
+///
+/// The client that started the server sends the cookie right after
+/// connecting.  If another process connects first and stalls, we
+/// give up on it after this long, and accept the next connection.
+const FIRST_COOKIE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long clients have to complete the handshake.
+///
+/// This is a fixed window for the whole handshake, not a timeout for
+/// each read: a client that trickles in the cookie byte by byte is
+/// dropped when it expires, no matter how active it is.  If
+/// configured, the idle timeout is used instead, if it is shorter.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The maximum number of concurrent handshakes.
+///
+/// If this many clients are in the middle of the handshake, the
+/// server stops accepting connections until one of them completes
+/// the handshake, or is dropped.  Together with
+/// [`HANDSHAKE_TIMEOUT`], this bounds the resources clients that
+/// don't authenticate can tie up.
+const MAX_PENDING_HANDSHAKES: usize = 64;
+
+/// How long dropping a server waits for its blocking tasks, see
+/// [`ServerBuilder::shutdown_timeout`].
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A server.
+///
+/// When the server is dropped, its runtime is shut down.  This waits
+/// for at most [`ServerBuilder::shutdown_timeout`] for the runtime's
+/// blocking tasks, so that dropping a server never hangs.
+pub struct Server {
+    /// The server's runtime, until the server is dropped.
+    runtime: Option<tokio::runtime::Runtime>,
+    descriptor: Descriptor,
+    /// A listener handed to us by the init system.
+    activated: Option<TcpListener>,
+    /// Called once the server is ready.
+    on_ready: Option<Box<dyn FnOnce() + Send>>,
+    /// The file descriptor to signal readiness on.
+    #[cfg(unix)]
+    ready_fd: Option<std::os::unix::io::OwnedFd>,
+    /// How long connections may be idle, see
+    /// [`ServerBuilder::idle_timeout`].
+    idle_timeout: Option<Duration>,
+    /// Whether connections may be compressed, see
+    /// [`ServerBuilder::compression`].
+    compression: bool,
+    /// The options used to read RPC messages.
+    reader_options: ReaderOptions,
+    /// How long to wait for the first client to send the cookie.
+    cookie_timeout: Duration,
+    /// How long clients have to complete the handshake, see
+    /// [`HANDSHAKE_TIMEOUT`].
+    handshake_timeout: Duration,
+    /// Whether to reload on `SIGHUP`, see
+    /// [`ServerBuilder::reload_on_sighup`].
+    #[cfg_attr(not(unix), allow(dead_code))]
+    reload_on_sighup: bool,
+    /// How long dropping the server waits for blocking tasks, see
+    /// [`ServerBuilder::shutdown_timeout`].
+    shutdown_timeout: Duration,
+    /// Coordinates handing off the listener and shutting down, see
+    /// [`Server::handoff_handle`] and [`Server::shutdown_handle`].
+    control: Option<Arc<ControlState>>,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        // The server only serves while `Server::serve` borrows it, so
+        // there is no accept loop to stop.  But dropping the runtime
+        // would wait for its blocking tasks for as long as they run.
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_timeout(self.shutdown_timeout);
+        }
+    }
+}
+
+/// Builds a [`Server`].
+///
+/// This collects the server's options in one place.  The defaults are
+/// taken from the descriptor's context.  Use [`ServerBuilder::build`]
+/// to create the server, or [`ServerBuilder::serve`] to also serve
+/// clients right away.
+///
+/// # Examples
+///
+/// ```
+/// # use std::time::Duration;
+/// # use sequoia_ipc::{Context, Descriptor, Handler, Result, Server};
+/// # use sequoia_ipc::rendezvous_path;
+/// # struct Keystore;
+/// # impl Handler for Keystore {}
+/// # fn factory(_: Descriptor, _: &tokio::task::LocalSet)
+/// #            -> Result<Box<dyn Handler>> { Ok(Box::new(Keystore)) }
+/// # fn main() -> Result<()> {
+/// let ctx = Context::configure().ephemeral().build()?;
+/// let descriptor = Descriptor::new(
+///     &ctx, rendezvous_path(&ctx, "keystore"),
+///     ctx.lib().join("sequoia-keystore"), factory);
+///
+/// let server = Server::builder(descriptor)
+///     .idle_timeout(Duration::from_secs(300))
+///     .compression()
+///     .on_ready(|| eprintln!("Ready"))
+///     .build()?;
+/// // Serve clients using `server.serve()`, or use
+/// // `ServerBuilder::serve` instead of `ServerBuilder::build`.
+/// # drop(server);
+/// # Ok(()) }
+/// ```
+pub struct ServerBuilder {
+    descriptor: Descriptor,
+    socket_activation: bool,
+    on_ready: Option<Box<dyn FnOnce() + Send>>,
+    #[cfg(unix)]
+    ready_fd: Option<std::os::unix::io::OwnedFd>,
+    idle_timeout: Option<Duration>,
+    compression: bool,
+    reader_options: ReaderOptions,
+    reload_on_sighup: bool,
+    shutdown_timeout: Duration,
+}
+
+impl ServerBuilder {
+    /// Starts building a server for the descriptor.
+    ///
+    /// See [`Server::builder`].
+    pub fn new(descriptor: Descriptor) -> Self {
+        let ctx = &descriptor.inner.ctx;
+        ServerBuilder {
+            socket_activation: false,
+            on_ready: None,
+            #[cfg(unix)]
+            ready_fd: ctx.take_ready_fd(),
+            idle_timeout: ctx.idle_timeout(),
+            compression: ctx.compression(),
+            reader_options: *ctx.reader_options(),
+            reload_on_sighup: false,
+            shutdown_timeout: SHUTDOWN_TIMEOUT,
+            descriptor,
+        }
+    }
+
+    /// Uses the socket passed by the init system.
+    ///
+    /// See [`Server::from_socket_activation`].
+    pub fn socket_activation(mut self) -> Self {
+        self.set_socket_activation();
+        self
+    }
+
+    /// Uses the socket passed by the init system.
+    pub fn set_socket_activation(&mut self) -> bool {
+        std::mem::replace(&mut self.socket_activation, true)
+    }
+
+    /// Calls `f` once the server is ready.
+    ///
+    /// See [`Server::on_ready`].
+    pub fn on_ready<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.set_on_ready(f);
+        self
+    }
+
+    /// Calls `f` once the server is ready.
+    ///
+    /// Returns the old callback, if any.
+    pub fn set_on_ready<F>(&mut self, f: F) -> Option<Box<dyn FnOnce() + Send>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.on_ready.replace(Box::new(f))
+    }
+
+    /// Signals readiness on the given file descriptor.
+    ///
+    /// The default is taken from the context, see
+    /// [`Config::ready_fd`].
+    ///
+    /// This is only supported on Unix.
+    #[cfg(unix)]
+    pub fn ready_fd(mut self, fd: std::os::unix::io::OwnedFd) -> Self {
+        self.set_ready_fd(fd);
+        self
+    }
+
+    /// Signals readiness on the given file descriptor.
+    ///
+    /// Returns the old file descriptor, if any.
+    #[cfg(unix)]
+    pub fn set_ready_fd(&mut self, fd: std::os::unix::io::OwnedFd)
+                        -> Option<std::os::unix::io::OwnedFd>
+    {
+        self.ready_fd.replace(fd)
+    }
+
+    /// Closes connections that are idle for longer than `timeout`.
+    ///
+    /// The default is taken from the context, see
+    /// [`Config::idle_timeout`].
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.set_idle_timeout(timeout);
+        self
+    }
+
+    /// Closes connections that are idle for longer than `timeout`.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) -> Option<Duration> {
+        std::mem::replace(&mut self.idle_timeout, Some(timeout))
+    }
+
+    /// Accepts clients' offers to compress connections.
+    ///
+    /// The default is taken from the context, see
+    /// [`Config::compression`].
+    pub fn compression(mut self) -> Self {
+        self.set_compression();
+        self
+    }
+
+    /// Accepts clients' offers to compress connections.
+    pub fn set_compression(&mut self) -> bool {
+        std::mem::replace(&mut self.compression, true)
+    }
+
+    /// Sets the options used to read RPC messages.
+    ///
+    /// The default is taken from the context, see
+    /// [`Config::reader_options`].
+    pub fn reader_options(mut self, options: ReaderOptions) -> Self {
+        self.set_reader_options(options);
+        self
+    }
+
+    /// Sets the options used to read RPC messages.
+    pub fn set_reader_options(&mut self, options: ReaderOptions)
+                              -> ReaderOptions
+    {
+        std::mem::replace(&mut self.reader_options, options)
+    }
+
+    /// Reloads the configuration when the process receives `SIGHUP`.
+    ///
+    /// While the server is serving clients, `SIGHUP` calls
+    /// [`Handler::reload`] instead of terminating the process.
+    /// Clients stay connected.  When the server stops, the previous
+    /// signal disposition is restored.
+    ///
+    /// Signal handlers are process-wide, so this is meant for
+    /// external servers, and only one server per process may do
+    /// this at a time.  This is only supported on Unix, on other
+    /// platforms, [`ServerBuilder::build`] fails.
+    pub fn reload_on_sighup(mut self) -> Self {
+        self.set_reload_on_sighup();
+        self
+    }
+
+    /// Reloads the configuration when the process receives `SIGHUP`.
+    ///
+    /// See [`ServerBuilder::reload_on_sighup`].
+    pub fn set_reload_on_sighup(&mut self) -> bool {
+        std::mem::replace(&mut self.reload_on_sighup, true)
+    }
+
+    /// Limits how long dropping the server waits for blocking tasks.
+    ///
+    /// When the server is dropped, it shuts down its runtime.
+    /// Handlers may have started blocking tasks, e.g. using
+    /// [`tokio::task::spawn_blocking`], which can't be cancelled.
+    /// Dropping the server waits for them for at most `timeout`, and
+    /// then leaves them running in the background.  The default is
+    /// one second.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.set_shutdown_timeout(timeout);
+        self
+    }
+
+    /// Limits how long dropping the server waits for blocking tasks.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) -> Duration {
+        std::mem::replace(&mut self.shutdown_timeout, timeout)
+    }
+
+    /// Creates the server.
+    ///
+    /// See [`Server::new`] for the restrictions.
+    pub fn build(self) -> Result<Server> {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Err(Error::NestedRuntime.into());
+        }
+
+        if self.reload_on_sighup && ! cfg!(unix) {
+            return Err(anyhow!("Reloading on SIGHUP is only supported on Unix"));
+        }
+
+        let activated = if self.socket_activation {
+            Some(socket_activation::listener()?)
+        } else {
+            None
+        };
+
+        Ok(Server {
+            runtime: Some(tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .thread_name(SERVER_THREAD_NAME)
+                .build()?),
+            descriptor: self.descriptor,
+            activated,
+            on_ready: self.on_ready,
+            #[cfg(unix)]
+            ready_fd: self.ready_fd,
+            idle_timeout: self.idle_timeout,
+            compression: self.compression,
+            reader_options: self.reader_options,
+            cookie_timeout: FIRST_COOKIE_TIMEOUT,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            reload_on_sighup: self.reload_on_sighup,
+            shutdown_timeout: self.shutdown_timeout,
+            control: None,
+        })
+    }
+
+    /// Creates the server, and serves clients.
+    ///
+    /// See [`Server::serve`].
+    pub fn serve(self) -> Result<()> {
+        self.build()?.serve()
+    }
+}
+
+impl Server {
+    /// Returns a builder for a server for the descriptor.
+    ///
+    /// See [`ServerBuilder`].
+    pub fn builder(descriptor: Descriptor) -> ServerBuilder {
+        ServerBuilder::new(descriptor)
+    }
+
+    /// Creates a new server for the descriptor.
+    ///
+    /// This uses the default options, see [`Server::builder`] to
+    /// change them.
+    ///
+    /// The server runs on its own Tokio runtime, and [`Server::serve`]
+    /// blocks until the server exits.  Hence, this must not be called
+    /// from within a Tokio runtime context, not even from a blocking
+    /// task, as that would panic when the server is started or
+    /// dropped.  In that case, [`Error::NestedRuntime`] is returned.
+    /// Instead, create the server on a dedicated thread.
+    ///
+    /// All of the server's work happens on a [`LocalSet`], which is
+    /// driven by the thread calling [`Server::serve`].  Hence, the
+    /// runtime is a single-threaded (`current_thread`) runtime:
+    /// Tokio's default multi-threaded runtime would start one worker
+    /// thread per CPU, each with its own stack, that would sit idle.
+    /// On an eight core machine, that saves eight threads and their
+    /// stacks (2 MiB of address space each, by default).  Note that
+    /// this means that a handler blocking in an RPC blocks the whole
+    /// server, which was also the case with the multi-threaded
+    /// runtime, since local tasks only ever run on the thread calling
+    /// [`Server::serve`].
+    ///
+    /// [`LocalSet`]: tokio::task::LocalSet
+    pub fn new(descriptor: Descriptor) -> Result<Self> {
+        Server::builder(descriptor).build()
+    }
+
+    /// Calls `f` once the server is ready.
+    ///
+    /// The server is ready once it has received the cookie from the
+    /// client that started it (or, if the cookie was supplied out of
+    /// band, or the server was started by the init system, once it
+    /// starts listening), and the handler has been created.  This is
+    /// the moment the readiness file descriptor is signaled, see
+    /// [`Config::ready_fd`].  Use this, for instance, to notify a
+    /// supervisor.
+    pub fn on_ready<F>(&mut self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.on_ready = Some(Box::new(f));
+    }
+
+    /// Returns a handle to hand off the listener to a new server.
+    ///
+    /// Pass the handle to [`Descriptor::hand_off`], for instance,
+    /// when the server is asked to restart.  Once the listener has
+    /// been handed off, [`Server::serve`] returns.
+    pub fn handoff_handle(&mut self) -> HandoffHandle {
+        HandoffHandle(self.control.get_or_insert_with(Default::default).clone())
+    }
+
+    /// Returns a handle to shut down the server.
+    ///
+    /// Once [`ShutdownHandle::shutdown`] has been called,
+    /// [`Server::serve`] stops accepting connections, and returns
+    /// `Ok(())` once the connection it is currently serving, if any,
+    /// ends.
+    pub fn shutdown_handle(&mut self) -> ShutdownHandle {
+        ShutdownHandle(self.control.get_or_insert_with(Default::default).clone())
+    }
+
+    /// Returns a handle to list and cancel the server's connections.
+    pub fn connections_handle(&mut self) -> ConnectionsHandle {
+        ConnectionsHandle(
+            self.control.get_or_insert_with(Default::default).clone())
+    }
+
+    /// Returns whether a shutdown has been requested.
+    fn shutdown_requested(&self) -> bool {
+        self.control.as_ref().map(|state| state.lock().shutdown)
+            .unwrap_or(false)
+    }
+
+    /// Signals that the server is ready.
+    fn signal_ready(&mut self) {
+        tracing::debug!("Server is ready");
+
+        if let Some(f) = self.on_ready.take() {
+            f();
+        }
+
+        #[cfg(unix)]
+        if let Some(fd) = self.ready_fd.take() {
+            use std::os::unix::io::AsRawFd;
+
+            let raw = fd.as_raw_fd();
+            let mut file = fs::File::from(fd);
+            if let Err(err) = file.write_all(b"R") {
+                tracing::warn!("Failed to signal readiness on fd {}: {}",
+                               raw, err);
+            }
+        }
+    }
+
+    /// Creates a new server using a socket passed by the init
+    /// system.
+    ///
+    /// Under systemd's socket activation, the listening socket is
+    /// passed using the `LISTEN_FDS` and `LISTEN_PID` protocol (see
+    /// `sd_listen_fds(3)`).  The first passed file descriptor is
+    /// used, and the environment variables are removed so that they
+    /// are not inherited by our children.  On macOS, the socket named
+    /// `Listeners` in the launchd job's `Sockets` dictionary is used.
+    ///
+    /// The passed file descriptor must be a listening TCP socket.
+    /// When [`Server::serve`] is called, the server publishes a new
+    /// cookie and the socket's address at the rendezvous point, so
+    /// clients find and authenticate to it like to any other server.
+    pub fn from_socket_activation(descriptor: Descriptor) -> Result<Self> {
+        Server::builder(descriptor).socket_activation().build()
+    }
+
+    /// Creates a Context from `env::args()`.
+    ///
+    /// This parses the command line passed to external servers.  Use
+    /// [`ServerArgs::from_env`] to also access the additional
+    /// arguments.
+    pub fn context() -> Result<core::Context> {
+        ServerArgs::from_env()?.context()
+    }
+
+    /// Turns this process into a server.
+    ///
+    /// External servers must call this early on.
+    ///
+    /// On Linux expects 'stdin' to be a listening TCP socket.
+    /// On Windows this expects `SOCKET` env var to be set to a listening socket
+    /// of the Windows Sockets API `SOCKET` value.  The variable is
+    /// removed from the environment so that it is not inherited by
+    /// processes the server starts.
+    ///
+    /// If the server was created using
+    /// [`Server::from_socket_activation`], the socket passed by the
+    /// init system is used instead.
+    pub fn serve(&mut self) -> Result<()> {
+        if let Some(listener) = self.activated.take() {
+            let cookie = self.publish(&listener)?;
+            return self.serve_connections(listener, cookie);
+        }
+
+        let listener = platform! {
+            unix => unsafe { TcpListener::from_raw_fd(0) },
+            windows => {
+                let socket = take_socket_var()?;
+                unsafe { TcpListener::from_raw_socket(socket) }
+            }
+        };
+        self.serve_listener(listener)
+    }
+
+    /// Publishes the server listening on `l` at the rendezvous
+    /// point.
+    ///
+    /// This is used for servers started by the init system, see
+    /// [`Server::from_socket_activation`].  No client started them,
+    /// so they write the rendezvous point themselves.  Returns the
+    /// cookie clients have to send.
+    fn publish(&self, l: &TcpListener) -> Result<Cookie> {
+        let descriptor = &self.descriptor;
+        let mut addr = l.local_addr()?;
+        if addr.ip().is_unspecified() {
+            // Clients connect to the loopback interface.
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+
+        let rendezvous_error =
+            || ConnectError::Rendezvous(descriptor.inner.rendezvous.clone());
+        let cookie = descriptor.new_cookie()?;
+        let mut file = ConnectError::classify(
+            descriptor.inner.backend.lock(), rendezvous_error)?;
+        descriptor.write_rendezvous(&mut *file, &cookie, addr)
+            .with_context(rendezvous_error)?;
+        Ok(cookie)
+    }
+
+    fn serve_listener(&mut self, l: TcpListener) -> Result<()> {
+        // The protocol is:
+        //
+        // - The first client exclusively locks the cookie file.
+        //
+        // - The client allocates a TCP socket, and generates a
+        //   cookie.
+        //
+        // - The client starts the server, and passes the listener to
+        //   it.
+        //
+        // - The client connects to the server via the socket, and
+        //   sends it the cookie.
+        //
+        // - The client drops the connection and unlocks the cookie
+        //   file thereby allowing other clients to connect.
+        //
+        // - The server waits for the cookie on the first connection.
+        //
+        // - The server starts serving clients.
+        //
+        // Note: this initial connection cannot (currently) be used
+        // for executing RPCs; the server closes it immediately after
+        // receiving the cookie.
+        //
+        // If the cookie is supplied out of band, there is no initial
+        // connection.  Neither is there one for servers started on a
+        // thread, which are handed the cookie directly, see
+        // `Descriptor::start`.
+        if let Some(cookie) = self.descriptor.inner.ctx.out_of_band_cookie() {
+            let cookie = Cookie::from(&cookie[..])
+                .expect("cookie has the right size");
+            return self.serve_connections(l, cookie);
+        }
+
+        // The first client sends us the cookie.
+        if let Some(state) = &self.control {
+            state.lock().addr = Some(l.local_addr()?);
+        }
+        if self.shutdown_requested() {
+            self.stopped();
+            return Ok(());
+        }
+        let cookie = loop {
+            let mut i = loop {
+                match l.accept() {
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted =>
+                        continue,
+                    r => break r?,
+                }
+            };
+            if self.shutdown_requested() {
+                tracing::debug!("Shutting down");
+                self.stopped();
+                return Ok(());
+            }
+
+            // Don't let a client that doesn't send anything, or
+            // trickles it in, stall us.
+            match Cookie::receive(
+                &mut DeadlineReader::new(&i.0, self.cookie_timeout))
+            {
+                Ok(cookie) => break cookie,
+                Err(err) if err.downcast_ref::<io::Error>()
+                    .map(|e| matches!(e.kind(),
+                                      io::ErrorKind::WouldBlock
+                                      | io::ErrorKind::TimedOut))
+                    .unwrap_or(false) =>
+                {
+                    tracing::warn!(peer = %i.1,
+                                   "Timed out waiting for the cookie, \
+                                    accepting the next connection");
+                },
+                Err(err) => return Err(err),
+            }
+        };
+
+        self.serve_connections(l, cookie)
+    }
+
+    /// Serves clients connecting to `l`.
+    ///
+    /// Clients authenticate using `cookie`.
+    fn serve_connections(&mut self, l: TcpListener, cookie: Cookie)
+                         -> Result<()>
+    {
+        /* Tokioize.  */
+        let local = tokio::task::LocalSet::new();
+        let handler: Rc<dyn Handler> =
+            (self.descriptor.inner.factory)(self.descriptor.clone(), &local)?
+            .into();
+        // Install the signal handler before we are ready, so that no
+        // signal is lost.  The guard restores the old handler when we
+        // are done.
+        #[cfg(unix)]
+        let (_sighup, sighup) = if self.reload_on_sighup {
+            let (guard, reader) = sighup::Guard::install()?;
+            (Some(guard), Some(reader))
+        } else {
+            (None, None)
+        };
+        self.signal_ready();
+        let reader_options = self.reader_options;
+        let idle_timeout = self.idle_timeout;
+        let handshake_timeout = self.handshake_timeout;
+        let compression_enabled = self.compression;
+        let ctx = self.descriptor.inner.ctx.clone();
+        let control = self.control.clone();
+        if let Some(state) = &control {
+            state.lock().addr = Some(l.local_addr()?);
+        }
+
+        let server = async move {
+            l.set_nonblocking(true)?;
+            let listener = tokio::net::TcpListener::from_std(l)?;
+
+            #[cfg(unix)]
+            if let Some(reader) = sighup {
+                let reader = tokio::net::UnixStream::from_std(reader)?;
+                let handler = handler.clone();
+                tokio::task::spawn_local(async move {
+                    loop {
+                        if let Err(err) = sighup::recv(&reader).await {
+                            tracing::warn!("Waiting for SIGHUP failed: {}", err);
+                            return;
+                        }
+                        tracing::info!("Received SIGHUP, reloading");
+                        if let Err(err) = handler.reload() {
+                            tracing::warn!("Failed to reload: {:#}", err);
+                        }
+                    }
+                });
+            }
+
+            // The handshakes in progress.  They run concurrently, so
+            // that clients that are slow to authenticate don't hold up
+            // the others.
+            let mut handshakes = Handshakes::default();
+            let handshake_timeout = idle_timeout
+                .map(|t| t.min(handshake_timeout))
+                .unwrap_or(handshake_timeout);
+            let cookie = Rc::new(cookie);
+            let mut capabilities = handler.capabilities().without_reserved();
+            if compression_enabled {
+                capabilities = capabilities.advertise_compression();
+            }
+            let capabilities = Rc::new(capabilities);
+
+            loop {
+                if let Some(state) = &control {
+                    if state.lock().shutdown {
+                        // Connections are served one at a time, so
+                        // there is nothing left in flight.
+                        tracing::debug!("Shutting down");
+                        return Ok(());
+                    }
+                }
+
+                let next = {
+                    let accept =
+                        std::pin::pin!(accept_retrying(|| listener.accept()));
+                    handshakes.next(accept).await
+                };
+                let authenticated = match next {
+                    Next::Authenticated(Some(authenticated)) => authenticated,
+                    Next::Authenticated(None) => continue,
+                    Next::Accepted(connection) => {
+                        let (socket, peer) = connection?;
+
+                        if let Some(state) = &control {
+                            let mut inner = state.lock();
+                            if inner.shutdown {
+                                tracing::debug!("Shutting down");
+                                return Ok(());
+                            }
+                            if inner.requester == Some(peer) {
+                                tracing::debug!("Handing off the listener");
+                                drop(socket);
+                                let listener = listener.into_std()?;
+                                listener.set_nonblocking(false)?;
+                                let cookie = Cookie::from(&cookie.0[..])
+                                    .expect("cookie has the right size");
+                                inner.handoff = Some(Handoff { listener, cookie });
+                                state.cond.notify_all();
+                                return Ok(());
+                            }
+                        }
+
+                        let _ = socket.set_nodelay(true);
+                        if let Err(err) = set_keepalive(&socket, &ctx) {
+                            tracing::warn!(%peer,
+                                           "Failed to enable keepalive: {}", err);
+                        }
+
+                        handshakes.push(tokio::task::spawn_local(handshake(
+                            socket, peer, cookie.clone(),
+                            capabilities.clone(), handshake_timeout)));
+                        continue;
+                    },
+                };
+                let Authenticated {
+                    socket, buffered, peer, trace_id, service,
+                    capabilities: peer_capabilities, compression,
+                } = authenticated;
+                let span = match &trace_id {
+                    Some(id) =>
+                        tracing::info_span!("connection", %peer, trace_id = %id),
+                    None => tracing::info_span!("connection", %peer),
+                };
+                tracing::debug!(parent: &span, ?service, "Accepted connection");
+
+                let info = ConnectionInfo::new(
+                    peer, trace_id.clone(), peer_capabilities);
+
+                // Track the connection, so that it can be cancelled.
+                let (socket, _tracked) = if let Some(state) = &control {
+                    let socket = socket.into_std()?;
+                    let tracked = TrackedConnection::new(
+                        state.clone(), info.clone(), socket.try_clone()?);
+                    (tokio::net::TcpStream::from_std(socket)?, Some(tracked))
+                } else {
+                    (socket, None)
+                };
+
+                let (reader, writer) = socket.into_split();
+                let last_activity =
+                    Rc::new(Cell::new(tokio::time::Instant::now()));
+                let writer = ActivityWriter {
+                    inner: writer,
+                    last_activity: last_activity.clone(),
+                };
+                let connection = Connection {
+                    reader: ConnectionReader::new(reader, buffered, compression),
+                    writer: ConnectionWriter::new(writer, compression),
+                    reader_options,
+                    info,
+                };
+
+                let rpc_system = match handler.handle_with_context(
+                    &ctx, service.as_deref(), connection)
+                {
+                    Ok(rpc_system) => rpc_system,
+                    Err(err) => {
+                        // Dropping the network closes the connection.
+                        tracing::warn!(parent: &span,
+                                       "Handler refused connection: {:#}", err);
+                        continue;
+                    },
+                };
+                use tracing::Instrument;
+                let rpc_system =
+                    tokio::task::spawn_local(rpc_system.instrument(span.clone()));
+                drive(rpc_system, idle_timeout, last_activity, &span).await;
+            }
+        };
+
+        let r = local.block_on(
+            self.runtime.as_ref().expect("only taken when dropped"), server);
+        self.stopped();
+        r
+    }
+
+    /// Records that the server stopped serving.
+    fn stopped(&self) {
+        if let Some(state) = &self.control {
+            state.lock().stopped = true;
+            state.cond.notify_all();
+        }
+    }
+}
+
+/// A server's listener, handed off to a new server.
+struct Handoff {
+    listener: TcpListener,
+    /// The cookie clients authenticate with.
+    cookie: Cookie,
+}
+
+/// A handle to hand off a server's listener to a new server.
+///
+/// See [`Server::handoff_handle`] and [`Descriptor::hand_off`].
+#[derive(Clone)]
+pub struct HandoffHandle(Arc<ControlState>);
+
+impl HandoffHandle {
+    /// Asks the server to stop serving, and waits for its listener.
+    ///
+    /// The server notices the request once the connection it is
+    /// currently serving ends.  If that doesn't happen within
+    /// `timeout`, the request is withdrawn, and this fails with
+    /// [`ConnectError::TimedOut`].
+    fn request(&self, timeout: Duration) -> Result<Handoff> {
+        let mut inner = self.0.lock();
+        if inner.requester.is_some() {
+            return Err(anyhow!("The server has already been handed off"));
+        }
+        if inner.stopped {
+            return Err(anyhow!("The server stopped"));
+        }
+        let addr = inner.addr
+            .ok_or_else(|| anyhow!("The server is not serving yet"))?;
+
+        // Wake up the server.  It recognizes our connection by its
+        // address.
+        let s = TcpStream::connect_timeout(&addr, PROBE_TIMEOUT)
+            .with_context(|| format!("Connecting to {}", addr))?;
+        inner.requester = Some(s.local_addr()?);
+
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            if let Some(handoff) = inner.handoff.take() {
+                return Ok(handoff);
+            }
+            if inner.stopped {
+                return Err(anyhow!("The server stopped"));
+            }
+            let now = std::time::Instant::now();
+            if now >= deadline {
+                // The server handles our connection, which we close,
+                // like any other failed handshake.
+                inner.requester = None;
+                return Err(ConnectError::TimedOut(addr).into());
+            }
+            inner = self.0.cond.wait_timeout(inner, deadline - now)
+                .expect("the server doesn't panic while holding the lock")
+                .0;
+        }
+    }
+}
+
+/// A handle to shut down a server.
+///
+/// See [`Server::shutdown_handle`].
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<ControlState>);
+
+impl ShutdownHandle {
+    /// Asks the server to shut down.
+    ///
+    /// This doesn't wait for the server to stop: the server notices
+    /// the request once the connection it is currently serving ends,
+    /// and [`Server::serve`] then returns `Ok(())`.  If the server is
+    /// not serving yet, it returns as soon as it starts.  Calling
+    /// this after the server stopped does nothing.
+    pub fn shutdown(&self) -> Result<()> {
+        let mut inner = self.0.lock();
+        if inner.shutdown || inner.stopped {
+            return Ok(());
+        }
+        inner.shutdown = true;
+
+        // Wake up the server, if it is waiting for a connection.
+        if let Some(addr) = inner.addr {
+            TcpStream::connect(addr)
+                .with_context(|| format!("Connecting to {}", addr))?;
+        }
+        Ok(())
+    }
+}
+
+/// A connection served by a server.
+///
+/// See [`ConnectionsHandle::list`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    id: u64,
+    peer: SocketAddr,
+    connected_at: std::time::SystemTime,
+    trace_id: Option<String>,
+    capabilities: Option<Capabilities>,
+}
+
+impl ConnectionInfo {
+    /// Records a connection that completed the handshake.
+    fn new(peer: SocketAddr, trace_id: Option<String>,
+           capabilities: Option<Capabilities>)
+           -> Self
+    {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        static LAST_ID: AtomicU64 = AtomicU64::new(0);
+
+        ConnectionInfo {
+            id: LAST_ID.fetch_add(1, Ordering::Relaxed) + 1,
+            peer,
+            connected_at: std::time::SystemTime::now(),
+            trace_id,
+            capabilities,
+        }
+    }
+
+    /// Returns the connection's ID.
+    ///
+    /// IDs are unique within the process, and thus for the lifetime
+    /// of the server.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the client's address.
+    ///
+    /// For clients connected in-process (see
+    /// [`Config::disable_internal_cookie_auth`]), this is the
+    /// unspecified address, `0.0.0.0:0`.
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Returns when the client completed the handshake.
+    pub fn connected_at(&self) -> std::time::SystemTime {
+        self.connected_at
+    }
+
+    /// Returns the trace ID the client sent, if any.
+    ///
+    /// See [`Config::trace_id`].
+    ///
+    /// [`Config::trace_id`]: core::Config::trace_id()
+    pub fn trace_id(&self) -> Option<&str> {
+        self.trace_id.as_deref()
+    }
+
+    /// Returns the version and capabilities the client advertised,
+    /// if any.
+    ///
+    /// See [`DescriptorBuilder::capabilities`].  Clients that don't
+    /// set any advertise an empty version, and no capabilities.
+    /// Clients that predate the exchange don't advertise anything.
+    pub fn capabilities(&self) -> Option<&Capabilities> {
+        self.capabilities.as_ref()
+    }
+}
+
+/// A handle to list and cancel a server's connections.
+///
+/// See [`Server::connections_handle`].
+#[derive(Clone)]
+pub struct ConnectionsHandle(Arc<ControlState>);
+
+impl ConnectionsHandle {
+    /// Returns the connections the server is serving.
+    ///
+    /// The server serves one connection at a time, so this returns
+    /// at most one connection.  Clients waiting to be served are not
+    /// listed.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        self.0.lock().connection.iter()
+            .map(|(info, _)| info.clone())
+            .collect()
+    }
+
+    /// Closes the connection with the given ID.
+    ///
+    /// The connection is shut down, which fails the client's
+    /// outstanding and future calls.  Then, the server serves the
+    /// next client.  Returns whether the connection was found.
+    pub fn cancel(&self, id: u64) -> Result<bool> {
+        let mut inner = self.0.lock();
+        match inner.connection.take() {
+            Some((info, socket)) if info.id == id => {
+                tracing::info!(peer = %info.peer, "Cancelling connection");
+                socket.shutdown(std::net::Shutdown::Both)
+                    .with_context(|| format!(
+                        "Closing the connection to {}", info.peer))?;
+                Ok(true)
+            },
+            other => {
+                inner.connection = other;
+                Ok(false)
+            },
+        }
+    }
+}
+
+/// Records the connection a server is serving.
+///
+/// The record is removed when this is dropped.
+struct TrackedConnection(Arc<ControlState>);
+
+impl TrackedConnection {
+    fn new(state: Arc<ControlState>, info: ConnectionInfo, socket: TcpStream)
+           -> Self
+    {
+        state.lock().connection = Some((info, socket));
+        TrackedConnection(state)
+    }
+}
+
+impl Drop for TrackedConnection {
+    fn drop(&mut self) {
+        self.0.lock().connection = None;
+    }
+}
+
+/// The state shared by a [`Server`] and its [`HandoffHandle`]s,
+/// [`ShutdownHandle`]s, and [`ConnectionsHandle`]s.
+#[derive(Default)]
+struct ControlState {
+    inner: std::sync::Mutex<ControlInner>,
+    /// Signaled when the listener is handed off, or the server
+    /// stops.
+    cond: std::sync::Condvar,
+}
+
+impl ControlState {
+    fn lock(&self) -> std::sync::MutexGuard<'_, ControlInner> {
+        self.inner.lock()
+            .expect("the server doesn't panic while holding the lock")
+    }
+}
+
+#[derive(Default)]
+struct ControlInner {
+    /// The address the server listens on, once it serves.
+    addr: Option<SocketAddr>,
+    /// The address of the connection requesting the handoff.
+    requester: Option<SocketAddr>,
+    /// The listener, once it has been handed off.
+    handoff: Option<Handoff>,
+    /// Whether a shutdown has been requested.
+    shutdown: bool,
+    /// Whether the server stopped serving.
+    stopped: bool,
+    /// The connection the server is serving, and a clone of its
+    /// socket to cancel it.
+    connection: Option<(ConnectionInfo, TcpStream)>,
+}
+
+/// How long to wait before accepting connections again, if we ran out
+/// of file descriptors or memory.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// What to do about an error returned when accepting a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AcceptErrorAction {
+    /// The listener is unusable, stop serving.
+    Fatal,
+    /// The error concerns a single connection, accept the next one.
+    Retry,
+    /// We ran out of resources, retry after [`ACCEPT_BACKOFF`].
+    Backoff,
+}
+
+impl AcceptErrorAction {
+    /// Classifies an error returned when accepting a connection.
+    fn classify(err: &io::Error) -> Self {
+        use io::ErrorKind::*;
+
+        match err.kind() {
+            // The client went away before we accepted the connection,
+            // or we were interrupted by a signal.
+            Interrupted | WouldBlock | ConnectionAborted
+                | ConnectionReset | TimedOut =>
+                return AcceptErrorAction::Retry,
+            OutOfMemory => return AcceptErrorAction::Backoff,
+            _ => (),
+        }
+
+        let backoff: &[i32] = platform! {
+            unix => &[libc::EMFILE, libc::ENFILE, libc::ENOBUFS, libc::ENOMEM],
+            // WSAEMFILE and WSAENOBUFS.
+            windows => &[10024, 10055]
+        };
+        let retry: &[i32] = platform! {
+            // See accept(2): Linux passes errors pending on the new
+            // connection to accept.
+            unix => &[libc::EPROTO, libc::EPERM, libc::ENETDOWN,
+                      libc::ENOPROTOOPT, libc::EHOSTDOWN,
+                      libc::EHOSTUNREACH, libc::EOPNOTSUPP,
+                      libc::ENETUNREACH],
+            windows => &[]
+        };
+        match err.raw_os_error() {
+            Some(errno) if backoff.contains(&errno) =>
+                AcceptErrorAction::Backoff,
+            Some(errno) if retry.contains(&errno) =>
+                AcceptErrorAction::Retry,
+            _ => AcceptErrorAction::Fatal,
+        }
+    }
+}
+
+/// Accepts a connection using `accept`.
+///
+/// Transient errors are logged, and `accept` is retried.  Fatal
+/// errors, e.g. if the listener is unusable, are returned.
+async fn accept_retrying<F, Fut, T>(mut accept: F) -> io::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    loop {
+        let err = match accept().await {
+            Ok(connection) => return Ok(connection),
+            Err(err) => err,
+        };
+
+        match AcceptErrorAction::classify(&err) {
+            AcceptErrorAction::Fatal => return Err(err),
+            AcceptErrorAction::Retry => {
+                tracing::debug!("Failed to accept connection: {}", err);
+            },
+            AcceptErrorAction::Backoff => {
+                tracing::warn!("Failed to accept connection, retrying in {:?}: {}",
+                               ACCEPT_BACKOFF, err);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+            },
+        }
+    }
+}
+
+/// A client that completed the handshake.
+struct Authenticated {
+    socket: tokio::net::TcpStream,
+    /// What the client sent after the handshake, see
+    /// [`HandshakeStream`].
+    buffered: Vec<u8>,
+    peer: SocketAddr,
+    trace_id: Option<String>,
+    service: Option<String>,
+    capabilities: Option<Capabilities>,
+    compression: Compression,
+}
+
+/// Authenticates a client, gets the trace ID and the service,
+/// exchanges capabilities, and negotiates compression.
+///
+/// `capabilities` are the server's, see [`Handler::capabilities`].
+/// If they include [`COMPRESSION_CAPABILITY`], clients that advertise
+/// it too offer compression.  Clients authenticate using `cookie`.
+/// The client has `timeout` to complete the handshake.  If it fails
+/// to, or is rejected, this logs why, and returns `None`.
+async fn handshake(socket: tokio::net::TcpStream, peer: SocketAddr,
+                   cookie: Rc<Cookie>, capabilities: Rc<Capabilities>,
+                   timeout: Duration)
+                   -> Option<Authenticated>
+{
+    let mut stream = HandshakeStream::new(socket);
+    let handshake = async {
+        // The cookie is always sent, and comes first, so it is read
+        // directly.
+        let received_cookie =
+            Cookie::receive_async(&mut stream.socket).await
+            .map_err(|err| format!("Failed to read cookie: {}", err))?;
+        if received_cookie != *cookie {
+            return Err("bad cookie".to_string());
+        }
+
+        let trace_id = receive_trace_id(&mut stream).await
+            .map_err(|err| format!("Failed to read trace ID: {}", err))?;
+        let service = receive_service(&mut stream).await
+            .map_err(|err| format!("Failed to read service: {}", err))?;
+        let peer_capabilities =
+            receive_capabilities(&mut stream, &capabilities).await
+            .map_err(|err| format!("Failed to exchange capabilities: {}",
+                                   err))?;
+        let compressing = capabilities.supports(COMPRESSION_CAPABILITY)
+            && peer_capabilities.as_ref()
+            .is_some_and(|c| c.supports(COMPRESSION_CAPABILITY));
+        let compression =
+            receive_compression_offer(&mut stream, compressing)
+            .await
+            .map_err(|err| format!("Failed to negotiate compression: {}", err))?;
+        let peer_capabilities =
+            peer_capabilities.map(Capabilities::without_reserved);
+        Ok((trace_id, service, peer_capabilities, compression))
+    };
+    let handshake = tokio::time::timeout(timeout, handshake).await
+        .unwrap_or_else(|_| Err("handshake timed out".into()));
+
+    match handshake {
+        Ok((trace_id, service, capabilities, compression)) =>
+            Some(Authenticated {
+                socket: stream.socket, buffered: stream.buffer,
+                peer, trace_id, service, capabilities, compression,
+            }),
+        Err(reason) => {
+            tracing::warn!(%peer, "Rejecting connection: {}", reason);
+            None
+        },
+    }
+}
+
+/// The handshakes in progress.
+///
+/// See [`MAX_PENDING_HANDSHAKES`].  The handshakes are aborted when
+/// this is dropped.
+#[derive(Default)]
+struct Handshakes(Vec<tokio::task::JoinHandle<Option<Authenticated>>>);
+
+/// What [`Handshakes::next`] returns.
+enum Next<A> {
+    /// A connection was accepted.
+    Accepted(A),
+    /// A handshake completed.
+    Authenticated(Option<Authenticated>),
+}
+
+impl Handshakes {
+    /// Adds a handshake.
+    fn push(&mut self, handshake: tokio::task::JoinHandle<Option<Authenticated>>) {
+        self.0.push(handshake);
+    }
+
+    /// Waits until a handshake completes, or `accept` accepts a
+    /// connection.
+    ///
+    /// Completed handshakes are returned first.  `accept` is only
+    /// polled if there is room for another handshake.
+    async fn next<F: Future>(&mut self, mut accept: Pin<&mut F>)
+                             -> Next<F::Output>
+    {
+        std::future::poll_fn(|cx| {
+            for i in 0..self.0.len() {
+                if let task::Poll::Ready(r) = Pin::new(&mut self.0[i]).poll(cx) {
+                    self.0.swap_remove(i);
+                    // The task is only aborted when we are dropped.
+                    // If it panicked, treat the client as rejected.
+                    return task::Poll::Ready(
+                        Next::Authenticated(r.ok().flatten()));
+                }
+            }
+
+            if self.0.len() < MAX_PENDING_HANDSHAKES {
+                if let task::Poll::Ready(r) = accept.as_mut().poll(cx) {
+                    return task::Poll::Ready(Next::Accepted(r));
+                }
+            }
+            task::Poll::Pending
+        }).await
+    }
+}
+
+impl Drop for Handshakes {
+    fn drop(&mut self) {
+        for handshake in &self.0 {
+            handshake.abort();
+        }
+    }
+}
+
+/// Enables TCP keepalive on `socket`, if configured.
+///
+/// See [`Config::keepalive`].
+///
+/// [`Config::keepalive`]: core::Config::keepalive()
+fn set_keepalive<S>(socket: &S, ctx: &core::Context) -> io::Result<()>
+where
+    for<'s> socket2::SockRef<'s>: From<&'s S>,
+{
+    let (idle, interval, retries) = match ctx.keepalive() {
+        Some(keepalive) => keepalive,
+        None => return Ok(()),
+    };
+
+    let keepalive = socket2::TcpKeepalive::new().with_time(idle);
+    #[cfg(any(target_os = "linux", target_os = "android",
+              target_os = "macos", target_os = "ios",
+              target_os = "freebsd", target_os = "netbsd",
+              windows))]
+    let keepalive = keepalive.with_interval(interval);
+    #[cfg(any(target_os = "linux", target_os = "android",
+              target_os = "macos", target_os = "ios",
+              target_os = "freebsd", target_os = "netbsd"))]
+    let keepalive = keepalive.with_retries(retries);
+    let _ = (interval, retries);
+
+    socket2::SockRef::from(socket).set_tcp_keepalive(&keepalive)
+}
+
+/// Waits for the connection's RPC system to finish.
+///
+/// If `idle_timeout` is given, the connection is closed once nothing
+/// has been written to it for that long.
+async fn drive<T>(mut rpc_system: tokio::task::JoinHandle<T>,
+                  idle_timeout: Option<Duration>,
+                  last_activity: Rc<Cell<tokio::time::Instant>>,
+                  span: &tracing::Span)
+{
+    let idle_timeout = if let Some(t) = idle_timeout {
+        t
+    } else {
+        let _ = rpc_system.await;
+        return;
+    };
+
+    loop {
+        let deadline = last_activity.get() + idle_timeout;
+        if tokio::time::timeout_at(deadline, &mut rpc_system).await.is_ok() {
+            return;
+        }
+
+        if last_activity.get() + idle_timeout <= tokio::time::Instant::now() {
+            tracing::debug!(parent: span, "Closing idle connection");
+            // Dropping the RPC system closes the connection.
+            rpc_system.abort();
+            return;
+        }
+    }
+}
+
+/// Records when data was last written.
+struct ActivityWriter<W> {
+    inner: W,
+    last_activity: Rc<Cell<tokio::time::Instant>>,
+}
+
+impl<W: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for ActivityWriter<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>,
+                  buf: &[u8])
+                  -> task::Poll<io::Result<usize>>
+    {
+        let r = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let task::Poll::Ready(Ok(n)) = r {
+            if n > 0 {
+                self.last_activity.set(tokio::time::Instant::now());
+            }
+        }
+        r
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>)
+                  -> task::Poll<io::Result<()>>
+    {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>)
+                     -> task::Poll<io::Result<()>>
+    {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Support for reloading on `SIGHUP`.
+///
+/// The signal handler writes to a socket pair, which the server
+/// watches, see [`ServerBuilder::reload_on_sighup`].
+#[cfg(unix)]
+mod sighup {
+    use super::*;
+
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+    /// The end of the socket pair the signal handler writes to.
+    ///
+    /// The socket pair is created once, and never closed, so that
+    /// the signal handler never writes to a file descriptor that has
+    /// been closed, and maybe reused, in the meantime.
+    static WRITER: AtomicI32 = AtomicI32::new(-1);
+
+    /// The socket pair, created on first use.
+    static PAIR: Mutex<Option<(UnixStream, UnixStream)>> = Mutex::new(None);
+
+    /// Whether a server reloads on `SIGHUP`.
+    static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn on_sighup(_: libc::c_int) {
+        let fd = WRITER.load(Ordering::SeqCst);
+        if fd >= 0 {
+            // Safety: write is async-signal-safe.  The socket is
+            // non-blocking.  If it is full, a reload is pending
+            // anyway, and we drop the notification.
+            unsafe {
+                libc::write(fd, b"\0".as_ptr().cast(), 1);
+            }
+        }
+    }
+
+    /// Reloads on `SIGHUP` while it is alive.
+    pub(super) struct Guard {
+        old: libc::sigaction,
+    }
+
+    impl Guard {
+        /// Installs the signal handler.
+        ///
+        /// Returns the guard, and a socket that becomes readable when
+        /// the process receives `SIGHUP`, see [`recv`].  Fails if
+        /// another server reloads on `SIGHUP`.
+        pub(super) fn install() -> Result<(Guard, UnixStream)> {
+            if INSTALLED.swap(true, Ordering::SeqCst) {
+                return Err(anyhow!("Another server already reloads on SIGHUP"));
+            }
+            let r = Guard::install_internal();
+            if r.is_err() {
+                INSTALLED.store(false, Ordering::SeqCst);
+            }
+            r
+        }
+
+        fn install_internal() -> Result<(Guard, UnixStream)> {
+            let mut pair = PAIR.lock().expect("not poisoned");
+            if pair.is_none() {
+                let (reader, writer) = UnixStream::pair()?;
+                reader.set_nonblocking(true)?;
+                writer.set_nonblocking(true)?;
+                WRITER.store(writer.as_raw_fd(), Ordering::SeqCst);
+                *pair = Some((reader, writer));
+            }
+            let reader = pair.as_ref().expect("created above").0.try_clone()?;
+            drop(pair);
+
+            // Discard notifications meant for earlier servers.
+            let mut buf = [0; 16];
+            while matches!((&reader).read(&mut buf), Ok(n) if n > 0) {}
+
+            // Safety: we initialize the fields sigaction looks at.
+            let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+            action.sa_sigaction = on_sighup as extern "C" fn(libc::c_int)
+                as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            let mut old: libc::sigaction = unsafe { std::mem::zeroed() };
+            let r = unsafe {
+                libc::sigemptyset(&mut action.sa_mask);
+                libc::sigaction(libc::SIGHUP, &action, &mut old)
+            };
+            if r != 0 {
+                return Err(io::Error::last_os_error())
+                    .context("Installing the SIGHUP handler");
+            }
+
+            Ok((Guard { old }, reader))
+        }
+    }
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            // Safety: `old` was returned by sigaction.
+            unsafe {
+                libc::sigaction(libc::SIGHUP, &self.old, std::ptr::null_mut());
+            }
+            INSTALLED.store(false, Ordering::SeqCst);
+        }
+    }
+
+    /// Waits until the process receives `SIGHUP`.
+    ///
+    /// Signals received in the meantime are coalesced.
+    pub(super) async fn recv(reader: &tokio::net::UnixStream)
+                             -> io::Result<()>
+    {
+        let mut buf = [0; 16];
+        let mut received = false;
+        loop {
+            if ! received {
+                reader.readable().await?;
+            }
+            match reader.try_read(&mut buf) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => received = true,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    if received {
+                        return Ok(());
+                    }
+                },
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Support for socket activation.
+mod socket_activation {
+    use super::*;
+
+    /// The first file descriptor passed by systemd.
+    ///
+    /// See `SD_LISTEN_FDS_START` in `sd-daemon.h`.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    const LISTEN_FDS_START: i32 = 3;
+
+    /// Returns the listener passed by the init system.
+    pub(super) fn listener() -> Result<TcpListener> {
+        platform! {
+            unix => {
+                let fd = if let Some(fd) = launchd()? {
+                    fd
+                } else {
+                    let fd = systemd_fd(
+                        std::env::var("LISTEN_PID").ok().as_deref(),
+                        std::env::var("LISTEN_FDS").ok().as_deref(),
+                        std::process::id())?;
+                    // Don't leak the variables to our children.
+                    std::env::remove_var("LISTEN_PID");
+                    std::env::remove_var("LISTEN_FDS");
+                    std::env::remove_var("LISTEN_FDNAMES");
+                    fd
+                };
+
+                check_listener(fd)?;
+                Ok(unsafe { TcpListener::from_raw_fd(fd) })
+            },
+            windows => {
+                Err(anyhow!("Socket activation is not supported on Windows"))
+            }
+        }
+    }
+
+    /// Checks that `fd` is a listening TCP socket.
+    ///
+    /// We take ownership of `fd` only if it is, so that we don't
+    /// close a file descriptor the init system passed for another
+    /// purpose.
+    #[cfg(unix)]
+    fn check_listener(fd: i32) -> Result<()> {
+        use std::os::unix::io::BorrowedFd;
+
+        if fd < 0 {
+            return Err(anyhow!("Invalid file descriptor {}", fd));
+        }
+        // Safety: fd is not -1, and it is only borrowed for the
+        // duration of this function.
+        let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+        let socket = socket2::SockRef::from(&borrowed);
+
+        let ty = socket.r#type().with_context(
+            || format!("File descriptor {} is not a socket", fd))?;
+        if ty != socket2::Type::STREAM {
+            return Err(anyhow!("File descriptor {} is not a stream socket",
+                               fd));
+        }
+        let addr = socket.local_addr().with_context(
+            || format!("Getting the address of file descriptor {}", fd))?;
+        if addr.as_socket().is_none() {
+            return Err(anyhow!("File descriptor {} is not a TCP socket", fd));
+        }
+
+        let mut listening: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // Safety: listening and len are valid for writes, and len is
+        // the size of listening.
+        let r = unsafe {
+            libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN,
+                             &mut listening as *mut libc::c_int
+                             as *mut libc::c_void,
+                             &mut len)
+        };
+        if r != 0 {
+            return Err(io::Error::last_os_error()).with_context(
+                || format!("Checking whether file descriptor {} listens", fd));
+        }
+        if listening == 0 {
+            return Err(anyhow!("File descriptor {} is not listening", fd));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the file descriptor passed by systemd.
+    ///
+    /// `listen_pid` and `listen_fds` are the values of the
+    /// `LISTEN_PID` and `LISTEN_FDS` environment variables, `pid` is
+    /// the id of the current process.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(super) fn systemd_fd(listen_pid: Option<&str>,
+                             listen_fds: Option<&str>,
+                             pid: u32)
+                             -> Result<i32>
+    {
+        let listen_pid = listen_pid
+            .ok_or_else(|| anyhow!("Not socket activated: LISTEN_PID not set"))?;
+        let listen_pid: u32 = listen_pid.parse()
+            .with_context(|| format!("Invalid LISTEN_PID: {:?}", listen_pid))?;
+        if listen_pid != pid {
+            return Err(anyhow!("Not socket activated: LISTEN_PID is {}, \
+                                but we are {}", listen_pid, pid));
+        }
+
+        let listen_fds = listen_fds
+            .ok_or_else(|| anyhow!("Not socket activated: LISTEN_FDS not set"))?;
+        let listen_fds: u32 = listen_fds.parse()
+            .with_context(|| format!("Invalid LISTEN_FDS: {:?}", listen_fds))?;
+        if listen_fds == 0 {
+            return Err(anyhow!("Not socket activated: LISTEN_FDS is 0"));
+        }
+
+        Ok(LISTEN_FDS_START)
+    }
+
+    /// Returns the file descriptor passed by launchd, if any.
+    #[cfg(target_os = "macos")]
+    fn launchd() -> Result<Option<i32>> {
+        use std::ffi::CStr;
+
+        extern "C" {
+            fn launch_activate_socket(name: *const libc::c_char,
+                                      fds: *mut *mut libc::c_int,
+                                      cnt: *mut libc::size_t)
+                                      -> libc::c_int;
+        }
+
+        let name = CStr::from_bytes_with_nul(b"Listeners\0")
+            .expect("valid C string");
+        let mut fds: *mut libc::c_int = std::ptr::null_mut();
+        let mut cnt: libc::size_t = 0;
+        let r = unsafe {
+            launch_activate_socket(name.as_ptr(), &mut fds, &mut cnt)
+        };
+        if r != 0 {
+            // ESRCH means we are not managed by launchd.  ENOENT
+            // means there is no such socket.  Either way, fall back
+            // to systemd's protocol.
+            return Ok(None);
+        }
+
+        let fd = if cnt > 0 {
+            // We only use the first socket.  Close the others.
+            let fds_ = unsafe { std::slice::from_raw_parts(fds, cnt) };
+            for &fd in &fds_[1..] {
+                unsafe { libc::close(fd) };
+            }
+            Some(fds_[0])
+        } else {
+            None
+        };
+        unsafe { libc::free(fds as *mut libc::c_void) };
+
+        Ok(fd)
+    }
+
+    /// Returns the file descriptor passed by launchd, if any.
+    #[cfg(not(target_os = "macos"))]
+    #[cfg_attr(not(unix), allow(dead_code))]
+    fn launchd() -> Result<Option<i32>> {
+        Ok(None)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn systemd() {
+            let pid = std::process::id();
+            let pid_str = pid.to_string();
+
+            assert_eq!(systemd_fd(Some(&pid_str), Some("1"), pid).unwrap(),
+                       LISTEN_FDS_START);
+            assert_eq!(systemd_fd(Some(&pid_str), Some("2"), pid).unwrap(),
+                       LISTEN_FDS_START);
+
+            // Not activated.
+            assert!(systemd_fd(None, None, pid).is_err());
+            assert!(systemd_fd(Some(&pid_str), None, pid).is_err());
+            assert!(systemd_fd(None, Some("1"), pid).is_err());
+            // The variables are meant for another process.
+            assert!(systemd_fd(Some(&pid_str), Some("1"), pid + 1).is_err());
+            // Malformed.
+            assert!(systemd_fd(Some("foo"), Some("1"), pid).is_err());
+            assert!(systemd_fd(Some(&pid_str), Some("bar"), pid).is_err());
+            assert!(systemd_fd(Some(&pid_str), Some("0"), pid).is_err());
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn listener_check() -> Result<()> {
+            use std::net::Ipv4Addr;
+            use std::os::unix::io::AsRawFd;
+
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+            check_listener(listener.as_raw_fd())?;
+
+            // A connected socket is not a listener.
+            let stream = TcpStream::connect(listener.local_addr()?)?;
+            assert!(check_listener(stream.as_raw_fd()).is_err());
+
+            // Neither is a datagram socket.
+            let udp = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+            assert!(check_listener(udp.as_raw_fd()).is_err());
+
+            // Nor a file.
+            let file = tempfile::tempfile()?;
+            assert!(check_listener(file.as_raw_fd()).is_err());
+            assert!(check_listener(-1).is_err());
+            Ok(())
+        }
+    }
+}
+
+/// Precedes the cookie in the handshake.
+///
+/// See [`Cookie::send`].
+const HANDSHAKE_MAGIC: [u8; 4] = *b"SQHS";
+
+/// The version of the handshake.
+///
+/// Version 1 consists of [`HANDSHAKE_MAGIC`], the version, and the
+/// cookie.  Version 0 is the legacy handshake, which consists of just
+/// the cookie.
+const HANDSHAKE_VERSION: u8 = 1;
+
+/// Cookies are used to authenticate clients.
+///
+/// Cookies are always [`Cookie::SIZE`] bytes long, hence, comparing
+/// them cannot be short-circuited by a cookie of a different length.
+/// They are stored on the heap, so that moving them doesn't leave
+/// copies behind.
+struct Cookie(Box<[u8; Cookie::SIZE]>);
+
+impl Cookie {
+    const SIZE: usize = 32;
+
+    /// Make a new cookie.
+    ///
+    /// The cookie is drawn from the crypto backend, or from the
+    /// context's random number generator, if one was set using
+    /// `Config::rng`.
+    fn generate(ctx: &core::Context) -> Result<Self> {
+        let mut c = Cookie::zero();
+        ctx.random(&mut c.0[..])?;
+        Ok(c)
+    }
+
+    /// Make a new cookie using the crypto backend.
+    #[cfg(test)]
+    fn new() -> Self {
+        let mut c = Cookie::zero();
+        sequoia_openpgp::crypto::random(&mut c.0[..])
+            .expect("the crypto backend provides randomness");
+        c
+    }
+
+    /// Returns a cookie consisting of zeros, to be filled in.
+    fn zero() -> Self {
+        Cookie(Box::new([0; Cookie::SIZE]))
+    }
+
+    /// Make a new cookie from a slice.
+    ///
+    /// Returns `None` if `buf` is not [`Cookie::SIZE`] bytes long.
+    fn from(buf: &[u8]) -> Option<Self> {
+        if buf.len() == Cookie::SIZE {
+            let mut c = Cookie::zero();
+            c.0.copy_from_slice(buf);
+            Some(c)
+        } else {
+            None
+        }
+    }
+
+    /// Given a buffer starting with a cookie, extract it and return
+    /// the rest.
+    fn split(buf: &[u8]) -> Option<(Self, &[u8])> {
+        // This may be a truncated cookie.
+        buf.get(Cookie::SIZE..).map(|rest| {
+            let cookie = Cookie::from(&buf[..Cookie::SIZE])
+                .expect("cookie has the right size");
+            (cookie, rest)
+        })
+    }
+
+    /// Given a vector starting with a cookie, extract it and return
+    /// the rest.
+    ///
+    /// `buf` is erased.
+    fn extract(mut buf: Vec<u8>) -> Option<(Self, Vec<u8>)> {
+        let r = Cookie::split(&buf)
+            .map(|(cookie, rest)| (cookie, rest.to_vec()));
+        unsafe {
+            memsec::memzero(buf.as_mut_ptr(), buf.len());
+        }
+        r
+    }
+
+    /// Read a cookie from 'from'.
+    ///
+    /// See [`Cookie::send`] for the format.
+    fn receive<R: Read>(from: &mut R) -> Result<Self> {
+        let mut cookie = Cookie::zero();
+        from.read_exact(&mut cookie.0[..HANDSHAKE_MAGIC.len()])?;
+        if cookie.0[..HANDSHAKE_MAGIC.len()] == HANDSHAKE_MAGIC {
+            let mut version = [0; 1];
+            from.read_exact(&mut version)?;
+            if version[0] != HANDSHAKE_VERSION {
+                return Err(Error::UnsupportedHandshakeVersion(version[0])
+                           .into());
+            }
+            from.read_exact(&mut cookie.0[..])?;
+        } else {
+            // A legacy client that only sends the cookie.
+            from.read_exact(&mut cookie.0[HANDSHAKE_MAGIC.len()..])?;
+        }
+        Ok(cookie)
+    }
+
+    /// Asynchronously read a cookie from 'socket'.
+    ///
+    /// See [`Cookie::send`] for the format.
+    async fn receive_async(socket: &mut tokio::net::TcpStream) -> io::Result<Cookie> {
+        use tokio::io::AsyncReadExt;
+
+        let mut cookie = Cookie::zero();
+        socket.read_exact(&mut cookie.0[..HANDSHAKE_MAGIC.len()]).await?;
+        if cookie.0[..HANDSHAKE_MAGIC.len()] == HANDSHAKE_MAGIC {
+            let version = socket.read_u8().await?;
+            if version != HANDSHAKE_VERSION {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    Error::UnsupportedHandshakeVersion(version)));
+            }
+            socket.read_exact(&mut cookie.0[..]).await?;
+        } else {
+            // A legacy client that only sends the cookie.
+            socket.read_exact(&mut cookie.0[HANDSHAKE_MAGIC.len()..]).await?;
+        }
+        Ok(cookie)
+    }
+
+    /// Write a cookie to 'to'.
+    ///
+    /// The cookie is preceded by [`HANDSHAKE_MAGIC`] and
+    /// [`HANDSHAKE_VERSION`], so that the handshake can evolve.
+    /// Servers also accept the legacy handshake (version 0), which
+    /// consists of just the cookie.  A legacy cookie that happens to
+    /// start with the magic is misinterpreted, which happens with a
+    /// probability of 2^-32.
+    fn send<W: Write>(&self, to: &mut W) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(
+            HANDSHAKE_MAGIC.len() + 1 + Cookie::SIZE);
+        buf.extend_from_slice(&HANDSHAKE_MAGIC);
+        buf.push(HANDSHAKE_VERSION);
+        buf.extend_from_slice(&self.0[..]);
+        let r = to.write_all(&buf);
+        unsafe {
+            memsec::memzero(buf.as_mut_ptr(), buf.len());
+        }
+        r
+    }
+
+    /// Asynchronously write a cookie to 'socket'.
+    ///
+    /// See [`Cookie::send`] for the format.
+    async fn send_async(&self, socket: &mut tokio::net::TcpStream)
+                        -> io::Result<()>
+    {
+        use tokio::io::AsyncWriteExt;
+
+        let mut buf = Vec::with_capacity(
+            HANDSHAKE_MAGIC.len() + 1 + Cookie::SIZE);
+        buf.extend_from_slice(&HANDSHAKE_MAGIC);
+        buf.push(HANDSHAKE_VERSION);
+        buf.extend_from_slice(&self.0[..]);
+        let r = socket.write_all(&buf).await;
+        unsafe {
+            memsec::memzero(buf.as_mut_ptr(), buf.len());
+        }
+        r
+    }
+
+    /// Securely erases the cookie.
+    fn zeroize(&mut self) {
+        unsafe {
+            memsec::memzero(self.0.as_mut_ptr(), self.0.len());
+        }
+    }
+}
+
+/// Cookies are erased when they are dropped, in particular when they
+/// are invalidated or rejected.
+impl Drop for Cookie {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+/// Returns the listening socket passed by the client, and removes
+/// the variable from the environment.
+///
+/// The variable is only meaningful to us.  Processes the server
+/// starts would otherwise inherit it.
+#[cfg(windows)]
+fn take_socket_var() -> Result<u64> {
+    let socket = std::env::var("SOCKET")?;
+    std::env::remove_var("SOCKET");
+    Ok(socket.parse()?)
+}
+
+/// Announces a trace ID following the cookie.
+///
+/// Interpreted as the first word of a Cap'n Proto message, this would
+/// announce over a billion segments, which is far more than any
+/// implementation accepts.  Hence, there is no ambiguity with clients
+/// that don't send a trace ID.
+const TRACE_ID_MAGIC: [u8; 4] = *b"TRID";
+
+/// Sends the trace ID to the server.
+///
+/// The trace ID follows the cookie.  It is preceded by
+/// [`TRACE_ID_MAGIC`] and a length byte.  IDs longer than 255 bytes
+/// are truncated.
+fn send_trace_id<W: Write>(to: &mut W, id: &str) -> io::Result<()> {
+    let id = &id.as_bytes()[..id.len().min(u8::MAX.into())];
+    let mut buf = Vec::with_capacity(TRACE_ID_MAGIC.len() + 1 + id.len());
+    buf.extend_from_slice(&TRACE_ID_MAGIC);
+    buf.push(id.len() as u8);
+    buf.extend_from_slice(id);
+    to.write_all(&buf)
+}
+
+/// Receives the trace ID, if the client sent one.
+///
+/// If the client didn't send a trace ID, nothing is consumed, and the
+/// data is left for the RPC system.
+async fn receive_trace_id(stream: &mut HandshakeStream)
+                          -> io::Result<Option<String>>
+{
+    if ! stream.peek_magic(&TRACE_ID_MAGIC).await? {
+        return Ok(None);
+    }
+    stream.read(TRACE_ID_MAGIC.len()).await?;
+
+    let len = stream.read_u8().await?;
+    let id = stream.read(len.into()).await?;
+    Ok(Some(String::from_utf8_lossy(&id).into_owned()))
+}
+
+/// Selects the service on a server serving several services.
+///
+/// This follows the trace ID, if any, and precedes the capabilities
+/// and the compression offer.  Like [`TRACE_ID_MAGIC`], it cannot be
+/// mistaken for the start of a Cap'n Proto message.  The magic is
+/// followed by a length byte, and the service's name, see
+/// [`DescriptorBuilder::service`].  The server doesn't respond: if it
+/// doesn't serve the service, it closes the connection.
+const SERVICE_MAGIC: [u8; 4] = *b"SRVC";
+
+/// Sends the name of the service to the server.
+///
+/// The name is at most 255 bytes long, see
+/// [`DescriptorBuilder::set_service`].
+fn send_service<W: Write>(to: &mut W, name: &str) -> io::Result<()> {
+    let len = u8::try_from(name.len()).map_err(
+        |_| io::Error::new(io::ErrorKind::InvalidInput,
+                           "service name too long"))?;
+    let mut buf = Vec::with_capacity(SERVICE_MAGIC.len() + 1 + name.len());
+    buf.extend_from_slice(&SERVICE_MAGIC);
+    buf.push(len);
+    buf.extend_from_slice(name.as_bytes());
+    to.write_all(&buf)
+}
+
+/// Receives the name of the service, if the client selected one.
+///
+/// If the client didn't select a service, nothing is consumed.
+async fn receive_service(stream: &mut HandshakeStream)
+                         -> io::Result<Option<String>>
+{
+    if ! stream.peek_magic(&SERVICE_MAGIC).await? {
+        return Ok(None);
+    }
+    stream.read(SERVICE_MAGIC.len()).await?;
+
+    let len = stream.read_u8().await?;
+    let name = stream.read(len.into()).await?;
+    String::from_utf8(name).map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// A connection during the server's side of the handshake.
+///
+/// Most stages of the handshake are optional, so the server has to
+/// look at the next bytes to find out whether the client sent a
+/// stage.  They are read into a buffer, which is kept across the
+/// stages.  Bytes that turn out not to belong to the handshake are
+/// passed on to the RPC system, see [`ConnectionReader`].
+struct HandshakeStream {
+    socket: tokio::net::TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl HandshakeStream {
+    fn new(socket: tokio::net::TcpStream) -> Self {
+        HandshakeStream {
+            socket,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Reads more data, so that the buffer holds up to `want` bytes.
+    ///
+    /// This never buffers more than `want` bytes, so that we don't
+    /// read data following the handshake unless we have to.  Returns
+    /// false if the client closed the connection.
+    async fn read_more(&mut self, want: usize) -> io::Result<bool> {
+        let mut chunk = [0; 512];
+        let len = want.saturating_sub(self.buffer.len()).min(chunk.len());
+        loop {
+            self.socket.readable().await?;
+            match self.socket.try_read(&mut chunk[..len]) {
+                Ok(n) => {
+                    self.buffer.extend_from_slice(&chunk[..n]);
+                    return Ok(n > 0);
+                },
+                // The readiness was a false positive.
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Returns whether the next bytes are `magic`.
+    ///
+    /// Nothing is consumed.  If the client closes the connection
+    /// before sending enough bytes to tell, this returns false.
+    async fn peek_magic(&mut self, magic: &[u8; 4]) -> io::Result<bool> {
+        loop {
+            let n = self.buffer.len().min(magic.len());
+            if self.buffer[..n] != magic[..n] {
+                return Ok(false);
+            }
+            if n == magic.len() {
+                return Ok(true);
+            }
+            // Only a prefix of the magic arrived so far.
+            if ! self.read_more(magic.len()).await? {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Consumes the next `n` bytes.
+    async fn read(&mut self, n: usize) -> io::Result<Vec<u8>> {
+        while self.buffer.len() < n {
+            if ! self.read_more(n).await? {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          "client closed the connection"));
+            }
+        }
+        Ok(self.buffer.drain(..n).collect())
+    }
+
+    /// Consumes the next byte.
+    async fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.read(1).await?[0])
+    }
+
+    /// Sends `buf` to the client.
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        self.socket.write_all(buf).await
+    }
+}
+
+/// Exchanges the peers' versions and capabilities.
+///
+/// This follows the service, if any, and precedes the compression
+/// offer.  Like [`TRACE_ID_MAGIC`], it cannot be mistaken for the
+/// start of a Cap'n Proto message.  The client sends the magic,
+/// [`CAPABILITIES_VERSION`], a two byte length, and its
+/// [`Capabilities`], see [`Capabilities::encode`].  The server
+/// responds in the same format with its own.  Servers that predate
+/// the exchange close the connection.
+const CAPABILITIES_MAGIC: [u8; 4] = *b"CAPS";
+
+/// The version of the capabilities' format.
+const CAPABILITIES_VERSION: u8 = 1;
+
+/// Advertised by peers that negotiate compression.
+///
+/// The client only sends its compression offer, see
+/// [`COMPRESSION_MAGIC`], if both peers advertise this.  It is
+/// reserved: it is removed from the capabilities the peers pass on,
+/// see [`Descriptor::server_capabilities`] and
+/// [`ConnectionInfo::capabilities`].
+const COMPRESSION_CAPABILITY: &str = "sequoia-ipc.compression";
+
+/// A peer's version and capabilities.
+///
+/// Servers advertise them using [`Handler::capabilities`], clients
+/// using [`DescriptorBuilder::capabilities`].  This allows a client
+/// to refuse to talk to an incompatible server before issuing any
+/// RPC, see [`DescriptorBuilder::min_server_version`] and
+/// [`DescriptorBuilder::require_capability`].
+///
+/// # Examples
+///
+/// ```
+/// # fn main() -> sequoia_ipc::Result<()> {
+/// use sequoia_ipc::Capabilities;
+///
+/// let capabilities = Capabilities::new("1.4.2")?
+///     .capability("streaming")?;
+/// assert!(capabilities.is_at_least("1.4"));
+/// assert!(! capabilities.is_at_least("1.10"));
+/// assert!(capabilities.supports("streaming"));
+/// # Ok(()) }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    version: String,
+    names: Vec<String>,
+}
+
+impl Capabilities {
+    /// Returns capabilities with the given version, and no
+    /// capabilities.
+    ///
+    /// Versions are dotted numbers like `1.2.3`, and are compared
+    /// component by component, see [`Capabilities::is_at_least`].
+    /// Any suffix of a component, like `-beta` in `1.2.3-beta`, is
+    /// ignored.  The version must be at most 255 bytes long.
+    pub fn new(version: &str) -> Result<Self> {
+        if version.len() > u8::MAX.into() {
+            return Err(anyhow!("Versions must be at most {} bytes long, \
+                                got {:?}", u8::MAX, version));
+        }
+        Ok(Capabilities {
+            version: version.into(),
+            names: Vec::new(),
+        })
+    }
+
+    /// Adds a capability.
+    ///
+    /// `name` must be between 1 and 255 bytes long, and there may be
+    /// at most 255 capabilities.
+    pub fn capability(mut self, name: &str) -> Result<Self> {
+        if name.is_empty() || name.len() > u8::MAX.into() {
+            return Err(anyhow!("Capabilities must be between 1 and {} \
+                                bytes long, got {:?}", u8::MAX, name));
+        }
+        if ! self.supports(name) {
+            if self.names.len() == u8::MAX.into() {
+                return Err(anyhow!("At most {} capabilities are supported",
+                                   u8::MAX));
+            }
+            self.names.push(name.into());
+        }
+        Ok(self)
+    }
+
+    /// Returns the version.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Returns whether the version is at least `min`.
+    ///
+    /// Missing components count as zero, so `1.2` is the same as
+    /// `1.2.0`.  Versions that don't start with a number, like the
+    /// empty version, are older than any other version.
+    pub fn is_at_least(&self, min: &str) -> bool {
+        let min = parse_version(min).unwrap_or_default();
+        let version = match parse_version(&self.version) {
+            Some(version) => version,
+            None => return min.iter().all(|c| *c == 0),
+        };
+        let len = version.len().max(min.len());
+        let pad = |v: Vec<u64>| v.into_iter()
+            .chain(std::iter::repeat(0)).take(len).collect::<Vec<_>>();
+        pad(version) >= pad(min)
+    }
+
+    /// Returns whether the capability `name` is advertised.
+    pub fn supports(&self, name: &str) -> bool {
+        self.names.iter().any(|n| n == name)
+    }
+
+    /// Returns the capabilities.
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.names.iter().map(|n| n.as_str())
+    }
+
+    /// Returns the capabilities without the ones reserved for the
+    /// handshake, see [`COMPRESSION_CAPABILITY`].
+    fn without_reserved(mut self) -> Self {
+        self.names.retain(|n| n != COMPRESSION_CAPABILITY);
+        self
+    }
+
+    /// Adds [`COMPRESSION_CAPABILITY`], if we support compression.
+    ///
+    /// If there is no room left, compression is not advertised.
+    fn advertise_compression(self) -> Self {
+        if Compression::SUPPORTED.is_empty() {
+            return self;
+        }
+        self.clone().capability(COMPRESSION_CAPABILITY).unwrap_or(self)
+    }
+
+    /// Encodes the message sent in the handshake.
+    ///
+    /// The magic, the version of the format, and the two byte length
+    /// are followed by the length of the version and the version,
+    /// then the number of capabilities, and each capability preceded
+    /// by its length.  Given the limits, the body fits into 64 KiB.
+    fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.push(self.version.len() as u8);
+        body.extend_from_slice(self.version.as_bytes());
+        body.push(self.names.len() as u8);
+        for name in &self.names {
+            body.push(name.len() as u8);
+            body.extend_from_slice(name.as_bytes());
+        }
+
+        let mut buf = Vec::with_capacity(CAPABILITIES_MAGIC.len() + 3
+                                         + body.len());
+        buf.extend_from_slice(&CAPABILITIES_MAGIC);
+        buf.push(CAPABILITIES_VERSION);
+        buf.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&body);
+        buf
+    }
+
+    /// Parses the body of the message, see [`Capabilities::encode`].
+    fn parse(body: &[u8]) -> io::Result<Self> {
+        fn string(body: &mut &[u8]) -> Option<String> {
+            let (len, rest) = body.split_first()?;
+            let s = rest.get(..usize::from(*len))?;
+            *body = &rest[s.len()..];
+            String::from_utf8(s.to_vec()).ok()
+        }
+        let parse = |mut body: &[u8]| {
+            let version = string(&mut body)?;
+            let (n, rest) = body.split_first()?;
+            body = rest;
+            let names = (0..*n).map(|_| string(&mut body))
+                .collect::<Option<Vec<_>>>()?;
+            body.is_empty().then_some(Capabilities { version, names })
+        };
+        parse(body).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+                                                 "malformed capabilities"))
+    }
+}
+
+/// Parses a dotted version like `1.2.3`.
+///
+/// Only the leading digits of each component are used, and parsing
+/// stops at the first component without any.  Returns `None` if the
+/// first component doesn't start with a digit.
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let components = version.split('.')
+        .map_while(|c| {
+            let digits = c.bytes().take_while(u8::is_ascii_digit).count();
+            c[..digits].parse::<u64>().ok()
+        })
+        .collect::<Vec<_>>();
+    (! components.is_empty()).then_some(components)
+}
+
+/// Checks the header of a capabilities message, and returns the
+/// length of the body.
+fn read_capabilities_header(header: &[u8; CAPABILITIES_MAGIC.len() + 3])
+                            -> io::Result<usize>
+{
+    if header[..CAPABILITIES_MAGIC.len()] != CAPABILITIES_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "malformed capabilities"));
+    }
+    let version = header[CAPABILITIES_MAGIC.len()];
+    if version != CAPABILITIES_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported capabilities version {}", version)));
+    }
+    Ok(u16::from_be_bytes([header[5], header[6]]).into())
+}
+
+/// Sends our capabilities, and returns the server's.
+///
+/// Servers that reject the cookie, and servers that predate the
+/// exchange close the connection.  The server has `timeout` to
+/// respond.
+fn exchange_capabilities(s: &mut TcpStream, ours: &Capabilities,
+                         timeout: Duration)
+                         -> io::Result<Capabilities>
+{
+    s.write_all(&ours.encode())?;
+
+    let mut reader = DeadlineReader::new(s, timeout);
+    let mut header = [0; CAPABILITIES_MAGIC.len() + 3];
+    reader.read_exact(&mut header).map_err(|err| if
+        err.kind() == io::ErrorKind::UnexpectedEof
+    {
+        io::Error::new(io::ErrorKind::UnexpectedEof,
+                       "the server closed the connection")
+    } else {
+        err
+    })?;
+    let len = read_capabilities_header(&header)?;
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    s.set_read_timeout(None)?;
+    Capabilities::parse(&body)
+}
+
+/// Receives the client's capabilities, if it sent any, and responds
+/// with `ours`.
+///
+/// If the client didn't send its capabilities, nothing is consumed,
+/// and nothing is sent.
+async fn receive_capabilities(stream: &mut HandshakeStream,
+                              ours: &Capabilities)
+                              -> io::Result<Option<Capabilities>>
+{
+    if ! stream.peek_magic(&CAPABILITIES_MAGIC).await? {
+        return Ok(None);
+    }
+    let mut header = [0; CAPABILITIES_MAGIC.len() + 3];
+    header.copy_from_slice(&stream.read(header.len()).await?);
+    let len = read_capabilities_header(&header)?;
+    let body = stream.read(len).await?;
+    let theirs = Capabilities::parse(&body)?;
+
+    stream.write_all(&ours.encode()).await?;
+    Ok(Some(theirs))
+}
+
+/// Announces the compression algorithms the client supports.
+///
+/// This follows the capabilities, and is only sent if both peers
+/// advertised [`COMPRESSION_CAPABILITY`], so the server knows to
+/// expect it.  The magic is followed by the number of algorithms, and
+/// their identifiers, in order of preference.  The server responds
+/// with the magic, and the identifier of the algorithm it picked,
+/// which may be [`Compression::None`].  Everything after that is
+/// compressed.
+const COMPRESSION_MAGIC: [u8; 4] = *b"CMPR";
+
+/// A compression algorithm for a connection.
+///
+/// See [`Config::compression`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Compression {
+    /// No compression.
+    None = 0,
+    /// Raw deflate.
+    #[cfg(feature = "compression")]
+    Deflate = 1,
+}
+
+impl Compression {
+    /// The algorithms we support, in order of preference.
+    const SUPPORTED: &'static [Compression] = &[
+        #[cfg(feature = "compression")]
+        Compression::Deflate,
+    ];
+
+    /// Returns the algorithm with the given identifier, if we
+    /// support it.
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            #[cfg(feature = "compression")]
+            1 => Some(Compression::Deflate),
+            _ => None,
+        }
+    }
+}
+
+/// Offers the compression algorithms we support, and returns the
+/// one the server picked.
+///
+/// Only call this if the server advertised
+/// [`COMPRESSION_CAPABILITY`].  Servers that don't expect the offer
+/// close the connection.  The server has `timeout` to respond.
+fn negotiate_compression(s: &mut TcpStream, timeout: Duration)
+                         -> io::Result<Compression>
+{
+    let mut offer = Vec::with_capacity(COMPRESSION_MAGIC.len() + 1
+                                       + Compression::SUPPORTED.len());
+    offer.extend_from_slice(&COMPRESSION_MAGIC);
+    offer.push(Compression::SUPPORTED.len() as u8);
+    offer.extend(Compression::SUPPORTED.iter().map(|c| *c as u8));
+    s.write_all(&offer)?;
+
+    let mut response = [0; COMPRESSION_MAGIC.len() + 1];
+    let mut reader = DeadlineReader::new(s, timeout);
+    reader.read_exact(&mut response).map_err(|err| if
+        err.kind() == io::ErrorKind::UnexpectedEof
+    {
+        io::Error::new(io::ErrorKind::UnexpectedEof,
+                       "the server doesn't support compression")
+    } else {
+        err
+    })?;
+    s.set_read_timeout(None)?;
+    if response[..COMPRESSION_MAGIC.len()] != COMPRESSION_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "malformed compression response"));
+    }
+    Compression::from_id(response[COMPRESSION_MAGIC.len()])
+        .filter(|c| *c == Compression::None
+                || Compression::SUPPORTED.contains(c))
+        .ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the server picked the unknown compression algorithm {}",
+                    response[COMPRESSION_MAGIC.len()])))
+}
+
+/// Receives the client's compression offer, and responds.
+///
+/// The client only sends an offer if both peers advertised
+/// [`COMPRESSION_CAPABILITY`], which `expected` says.  If it is
+/// false, nothing is consumed, and nothing is sent.  If the client
+/// didn't offer an algorithm we support, [`Compression::None`] is
+/// picked.
+async fn receive_compression_offer(stream: &mut HandshakeStream,
+                                   expected: bool)
+                                   -> io::Result<Compression>
+{
+    if ! expected {
+        return Ok(Compression::None);
+    }
+    if stream.read(COMPRESSION_MAGIC.len()).await? != COMPRESSION_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "malformed compression offer"));
+    }
+
+    let n = stream.read_u8().await?;
+    let offer = stream.read(n.into()).await?;
+
+    let picked = offer.into_iter().find_map(Compression::from_id)
+        .unwrap_or(Compression::None);
+
+    let mut response = COMPRESSION_MAGIC.to_vec();
+    response.push(picked as u8);
+    stream.write_all(&response).await?;
+    Ok(picked)
+}
+
+/// An authenticated connection.
+///
+/// This is passed to [`Handler::handle_connection`].  Use
+/// [`Connection::into_rpc_system`] or [`Connection::into_network`] to
+/// serve it.
+pub struct Connection {
+    reader: ConnectionReader,
+    writer: ConnectionWriter<ActivityWriter<tokio::net::tcp::OwnedWriteHalf>>,
+    reader_options: ReaderOptions,
+    info: ConnectionInfo,
+}
+
+impl Connection {
+    /// Returns information about the connection.
+    ///
+    /// This identifies the client, see [`ConnectionInfo`].
+    pub fn info(&self) -> &ConnectionInfo {
+        &self.info
+    }
+
+    /// Creates the server side of an RPC system for the connection.
+    ///
+    /// Like [`server_rpc_system`], but the calls recorded by the audit
+    /// hook (see [`Config::audit`]) identify this connection, see
+    /// [`AuditRecord::connection`].
+    pub fn into_rpc_system(self, ctx: &core::Context,
+                           bootstrap: capnp::capability::Client)
+                           -> RpcSystem<Side>
+    {
+        let info = self.info.clone();
+        rpc_system(ctx, self.into_network(), bootstrap, Some(info))
+    }
+
+    /// Returns the network for the connection.
+    ///
+    /// The RPC system runs on top of this, see [`server_rpc_system`].
+    /// The network uses the server's reader options, see
+    /// [`ServerBuilder::reader_options`].
+    pub fn into_network(self)
+        -> twoparty::VatNetwork<tokio_util::compat::Compat<ConnectionReader>>
+    {
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+        use tokio_util::compat::TokioAsyncWriteCompatExt;
+        twoparty::VatNetwork::new(self.reader.compat(),
+                                  self.writer.compat_write(),
+                                  Side::Server, self.reader_options)
+    }
+
+    /// Returns the network for the connection, reading directly from
+    /// the socket.
+    ///
+    /// This is what [`Handler::handle`] is given.  It fails, and
+    /// returns the connection, if the connection is compressed, or
+    /// the server read data during the handshake that belongs to the
+    /// RPC system.
+    fn into_plain_network(self)
+        -> std::result::Result<
+            twoparty::VatNetwork<
+                tokio_util::compat::Compat<tokio::net::tcp::OwnedReadHalf>>,
+            Self>
+    {
+        let reader = match self.reader.into_plain() {
+            Ok(reader) => reader,
+            Err(reader) => return Err(Connection { reader, ..self }),
+        };
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+        use tokio_util::compat::TokioAsyncWriteCompatExt;
+        Ok(twoparty::VatNetwork::new(reader.compat(),
+                                     self.writer.compat_write(),
+                                     Side::Server, self.reader_options))
+    }
+}
+
+/// The reading end of a connection.
+///
+/// This is part of the network returned by
+/// [`Connection::into_network`].  If the peers agreed to compress the
+/// connection (see [`Config::compression`]), it decompresses the
+/// data.
+pub struct ConnectionReader(ConnectionReaderInner);
+
+enum ConnectionReaderInner {
+    Plain(Prefixed<tokio::net::tcp::OwnedReadHalf>),
+    /// See [`Config::disable_internal_cookie_auth`].
+    InProcess(tokio::io::ReadHalf<tokio::io::DuplexStream>),
+    #[cfg(feature = "compression")]
+    Deflate(compression::DeflateReader<Prefixed<tokio::net::tcp::OwnedReadHalf>>),
+}
+
+impl ConnectionReader {
+    /// Returns a reader for the connection.
+    ///
+    /// `buffered` is what the server read from `reader` during the
+    /// handshake, but doesn't belong to it, see [`HandshakeStream`].
+    fn new(reader: tokio::net::tcp::OwnedReadHalf, buffered: Vec<u8>,
+           compression: Compression)
+           -> Self
+    {
+        let reader = Prefixed {
+            prefix: buffered,
+            inner: reader,
+        };
+        ConnectionReader(match compression {
+            Compression::None => ConnectionReaderInner::Plain(reader),
+            #[cfg(feature = "compression")]
+            Compression::Deflate => ConnectionReaderInner::Deflate(
+                compression::DeflateReader::new(reader)),
+        })
+    }
+
+    /// Returns the socket, if the connection isn't compressed, and
+    /// nothing was buffered.
+    fn into_plain(self) -> std::result::Result<tokio::net::tcp::OwnedReadHalf, Self> {
+        match self.0 {
+            ConnectionReaderInner::Plain(r) if r.prefix.is_empty() =>
+                Ok(r.inner),
+            inner => Err(ConnectionReader(inner)),
+        }
+    }
+}
+
+impl tokio::io::AsyncRead for ConnectionReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>,
+                 buf: &mut tokio::io::ReadBuf<'_>)
+                 -> task::Poll<io::Result<()>>
+    {
+        match &mut self.0 {
+            ConnectionReaderInner::Plain(r) => Pin::new(r).poll_read(cx, buf),
+            ConnectionReaderInner::InProcess(r) => Pin::new(r).poll_read(cx, buf),
+            #[cfg(feature = "compression")]
+            ConnectionReaderInner::Deflate(r) => Pin::new(r).poll_read(cx, buf),
+        }
+    }
+}
+
+/// A reader that returns `prefix` before reading from `inner`.
+struct Prefixed<R> {
+    prefix: Vec<u8>,
+    inner: R,
+}
+
+impl<R: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for Prefixed<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>,
+                 buf: &mut tokio::io::ReadBuf<'_>)
+                 -> task::Poll<io::Result<()>>
+    {
+        if self.prefix.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        let n = self.prefix.len().min(buf.remaining());
+        buf.put_slice(&self.prefix[..n]);
+        self.prefix.drain(..n);
+        task::Poll::Ready(Ok(()))
+    }
+}
+
+/// The writing end of a connection.
+///
+/// If the peers agreed to compress the connection, it compresses the
+/// data.
+enum ConnectionWriter<W> {
+    Plain(W),
+    /// See [`Config::disable_internal_cookie_auth`].
+    InProcess(tokio::io::WriteHalf<tokio::io::DuplexStream>),
+    #[cfg(feature = "compression")]
+    Deflate(compression::DeflateWriter<W>),
+}
+
+impl<W> ConnectionWriter<W> {
+    fn new(writer: W, compression: Compression) -> Self {
+        match compression {
+            Compression::None => ConnectionWriter::Plain(writer),
+            #[cfg(feature = "compression")]
+            Compression::Deflate => ConnectionWriter::Deflate(
+                compression::DeflateWriter::new(writer)),
+        }
+    }
+}
+
+impl<W: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for ConnectionWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>,
+                  buf: &[u8])
+                  -> task::Poll<io::Result<usize>>
+    {
+        match self.get_mut() {
+            ConnectionWriter::Plain(w) => Pin::new(w).poll_write(cx, buf),
+            ConnectionWriter::InProcess(w) => Pin::new(w).poll_write(cx, buf),
+            #[cfg(feature = "compression")]
+            ConnectionWriter::Deflate(w) => Pin::new(w).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>)
+                  -> task::Poll<io::Result<()>>
+    {
+        match self.get_mut() {
+            ConnectionWriter::Plain(w) => Pin::new(w).poll_flush(cx),
+            ConnectionWriter::InProcess(w) => Pin::new(w).poll_flush(cx),
+            #[cfg(feature = "compression")]
+            ConnectionWriter::Deflate(w) => Pin::new(w).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>)
+                     -> task::Poll<io::Result<()>>
+    {
+        match self.get_mut() {
+            ConnectionWriter::Plain(w) => Pin::new(w).poll_shutdown(cx),
+            ConnectionWriter::InProcess(w) => Pin::new(w).poll_shutdown(cx),
+            #[cfg(feature = "compression")]
+            ConnectionWriter::Deflate(w) => Pin::new(w).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Creates a pipe.
+///
+/// Returns the read end and the write end.  Both ends are created
+/// with the close-on-exec flag set.
+#[cfg(all(unix, not(feature = "no-subprocess")))]
+fn pipe() -> Result<(fs::File, fs::File)> {
+    let mut fds = [0; 2];
+
+    // Where possible, set the flag atomically, so that a process
+    // spawned by another thread in the meantime doesn't inherit the
+    // pipe.
+    #[cfg(any(target_os = "linux", target_os = "android",
+              target_os = "freebsd", target_os = "netbsd",
+              target_os = "openbsd", target_os = "dragonfly"))]
+    {
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(unsafe {
+            (fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1]))
+        })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android",
+                  target_os = "freebsd", target_os = "netbsd",
+                  target_os = "openbsd", target_os = "dragonfly")))]
+    {
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let ends = unsafe {
+            (fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1]))
+        };
+        for fd in fds {
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        Ok(ends)
+    }
+}
+
+/// Creates a pipe containing `cookie`.
+///
+/// Returns the read end.  The write end is closed, so that the reader
+/// sees the end of file after the cookie.  Both ends are created with
+/// the close-on-exec flag set.
+#[cfg(all(unix, not(feature = "no-subprocess")))]
+fn cookie_pipe(cookie: &[u8]) -> Result<fs::File> {
+    let (reader, mut writer) = pipe()?;
+
+    // The pipe's buffer is much larger than the cookie, hence this
+    // does not block.
+    writer.write_all(cookie)?;
+    Ok(reader)
+}
+
+/// Creates a sealed, anonymous file containing `cookie`.
+///
+/// The file only lives in memory, and is positioned at the start.  It
+/// is sealed, so that it can neither be modified, nor resized.  It is
+/// created with the close-on-exec flag set.
+#[cfg(all(target_os = "linux", not(feature = "no-subprocess")))]
+fn cookie_memfd(cookie: &[u8]) -> io::Result<fs::File> {
+    // Safety: the name is NUL-terminated.
+    let fd = unsafe {
+        libc::memfd_create(b"sequoia-ipc-cookie\0".as_ptr().cast(),
+                           libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING)
+    };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safety: we just created the file descriptor, and own it.
+    let mut file = unsafe { fs::File::from_raw_fd(fd) };
+    file.write_all(cookie)?;
+    file.rewind()?;
+
+    let seals = libc::F_SEAL_SEAL | libc::F_SEAL_SHRINK | libc::F_SEAL_GROW
+        | libc::F_SEAL_WRITE;
+    if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, seals) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+/// Returns a file the server reads `cookie` from.
+///
+/// On Linux, this is a sealed memfd, see [`cookie_memfd`].  On other
+/// platforms, or if the kernel doesn't support memfds, this is a
+/// pipe, see [`cookie_pipe`].  Either way, the cookie never touches
+/// the file system.  The server reads it using
+/// [`Config::cookie_fd`].
+#[cfg(all(unix, not(feature = "no-subprocess")))]
+fn cookie_file(cookie: &[u8]) -> Result<fs::File> {
+    #[cfg(target_os = "linux")]
+    match cookie_memfd(cookie) {
+        Ok(file) => return Ok(file),
+        Err(err) => tracing::debug!(
+            "Creating a memfd failed, passing the cookie over a pipe: {}",
+            err),
+    }
+    cookie_pipe(cookie)
+}
+
+/// Lets the child spawned by `cmd` inherit the file descriptor `fd`.
+///
+/// Clears the close-on-exec flag in the child.
+#[cfg(all(unix, not(feature = "no-subprocess")))]
+fn inherit_fd(cmd: &mut Command, fd: std::os::unix::io::RawFd) {
+    use std::os::unix::process::CommandExt;
+
+    // Safety: fcntl is async-signal-safe.
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// A reader that fails once a deadline passed.
+///
+/// Unlike a read timeout, which applies to each read, this bounds the
+/// time all reads take together, so that a peer that sends one byte
+/// at a time cannot stall us.
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: std::time::Instant,
+}
+
+impl<'a> DeadlineReader<'a> {
+    /// Returns a reader that reads from `stream` for at most
+    /// `timeout`.
+    fn new(stream: &'a TcpStream, timeout: Duration) -> Self {
+        DeadlineReader {
+            stream,
+            deadline: std::time::Instant::now() + timeout,
+        }
+    }
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline
+            .saturating_duration_since(std::time::Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                      "deadline exceeded"));
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        let mut stream = self.stream;
+        stream.read(buf)
+    }
+}
+
+/// Waits for the server to signal readiness on `reader`.
+///
+/// Fails if the server closes its end of the pipe without signaling
+/// readiness, which it does when it exits, or if it doesn't signal
+/// readiness within `timeout`.
+#[cfg(all(unix, not(feature = "no-subprocess")))]
+fn wait_ready(mut reader: fs::File, timeout: Duration) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    use std::time::Instant;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let mut pfd = libc::pollfd {
+            fd: reader.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ms = remaining.as_millis().min(libc::c_int::MAX as u128)
+            as libc::c_int;
+        match unsafe { libc::poll(&mut pfd, 1, ms) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err.into());
+            }
+            0 => return Err(anyhow!(
+                "Server did not signal readiness within {:?}", timeout)),
+            _ => break,
+        }
+    }
+
+    let mut byte = [0; 1];
+    match reader.read(&mut byte)? {
+        0 => Err(anyhow!("Server exited before signaling readiness")),
+        _ => Ok(()),
+    }
+}
+
+/// Waits for the server `child` to signal readiness on `reader`.
+///
+/// See [`wait_ready`].  If the server doesn't signal readiness, it
+/// is killed and reaped, so that it neither lingers, nor remains a
+/// zombie.
+#[cfg(all(unix, not(feature = "no-subprocess")))]
+fn wait_child_ready(mut child: std::process::Child, reader: fs::File,
+                    timeout: Duration)
+    -> Result<()>
+{
+    let result = wait_ready(reader, timeout);
+    if result.is_err() {
+        if let Err(err) = child.kill() {
+            tracing::debug!("Killing server {}: {}", child.id(), err);
+        }
+        if let Err(err) = child.wait() {
+            tracing::debug!("Reaping server {}: {}", child.id(), err);
+        }
+    }
+    result
+}
+
+/// Reads the out-of-band cookie from the file descriptor `fd`.
+///
+/// The file descriptor is closed afterwards.
+#[cfg(unix)]
+pub(crate) fn read_cookie_fd(fd: std::os::unix::io::OwnedFd) -> Result<Protected> {
+    use std::os::unix::io::AsRawFd;
+
+    let raw = fd.as_raw_fd();
+    let mut file = fs::File::from(fd);
+    let mut cookie: Protected = vec![0; Cookie::SIZE].into();
+    file.read_exact(&mut cookie)
+        .with_context(|| format!("Reading cookie from fd {}", raw))?;
+    Ok(cookie)
+}
+
+/// Cookies are compared in constant time.
+impl PartialEq for Cookie {
+    fn eq(&self, other: &Cookie) -> bool {
+        // Both cookies are `Cookie::SIZE` bytes long.
+        unsafe {
+            ::memsec::memeq(self.0.as_ptr(), other.0.as_ptr(), Cookie::SIZE)
+        }
+    }
+}
+
+/// Parses the address stored in a rendezvous point.
+///
+/// Returns `None` if the address is malformed, for instance, because
+/// it was only partially written.
+fn parse_address(rest: Vec<u8>) -> Option<SocketAddr> {
+    String::from_utf8(rest).ok()?.parse().ok()
+}
+
+/// Creates `dir` and any missing parents using `mode`.
+///
+/// Existing directories are not changed.  On Windows, `mode` is
+/// ignored.
+fn create_dir_all(dir: &Path, mode: u32) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    builder.create(dir)
+}
+
+/// Wraps a cookie file.
+///
+/// The cookie file, or rendezvous point, is protected by an
+/// exclusive, advisory lock, which is taken when the file is opened,
+/// and released when it is dropped.  The invariants are:
+///
+///   - Only the holder of the lock writes to the rendezvous point.
+///
+///   - A client holds the lock from reading the rendezvous point
+///     until it has connected to the server, or, if it has to start
+///     a new server, until the server has been started, has received
+///     the cookie, and the rendezvous point has been updated.  Hence,
+///     concurrent clients wait for the first one to start the
+///     server, and then connect to it.
+///
+///   - If a client crashes while holding the lock, the lock is
+///     released by the operating system, but the rendezvous point
+///     may be empty, or only partially written.  The next client
+///     notices that, and starts a new server.  The same is true if
+///     the server is gone.
+///
+///   - Once the lock is released, the rendezvous point is either
+///     empty, or contains the cookie and the address of a server
+///     that was running when it was written.
+///
+///   - [`Context::gc_rendezvous`] only removes rendezvous points it
+///     holds the lock for.  Clients waiting for the lock notice that
+///     the file was removed, see [`CookieFile::open`].
+///
+/// Readers that don't modify the rendezvous point, like
+/// [`Context::discover_services`], may read it without holding the
+/// lock.  They must cope with partially written files.
+///
+/// [`Context::gc_rendezvous`]: core::Context::gc_rendezvous()
+/// [`Context::discover_services`]: core::Context::discover_services()
+struct CookieFile {
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl CookieFile {
+    /// Opens the specified cookie.
+    ///
+    /// The file is opened, and immediately locked.  (The lock is
+    /// dropped when the file is closed.)  If another process holds
+    /// the lock, this blocks, and emits a tracing event recording how
+    /// long we waited.
+    ///
+    /// Symbolic links are not followed: if the rendezvous point is
+    /// located in a directory writable by others, an attacker could
+    /// otherwise redirect us to a file under their control.  It is
+    /// also an error if `path` is a directory.
+    fn open(path: &Path) -> Result<CookieFile> {
+        Self::open_timeout(path, None)
+    }
+
+    /// Opens the specified cookie, waiting at most `timeout` for
+    /// the lock.
+    ///
+    /// Like [`CookieFile::open`], but if `timeout` is given, and
+    /// another process holds the lock for longer, this fails with
+    /// [`ConnectError::LockTimedOut`].
+    fn open_timeout(path: &Path, timeout: Option<Duration>)
+                    -> Result<CookieFile>
+    {
+        Ok(Self::open_internal(path, true, timeout)?
+           .expect("blocking open returns the file"))
+    }
+
+    /// Opens the specified cookie, unless it is locked.
+    ///
+    /// Like [`CookieFile::open`], but returns `None` instead of
+    /// blocking if another process holds the lock.
+    fn try_open(path: &Path) -> Result<Option<CookieFile>> {
+        Self::open_internal(path, false, None)
+    }
+
+    /// Implements [`CookieFile::open_timeout`] and
+    /// [`CookieFile::try_open`].
+    ///
+    /// The directory must exist.  It is created by
+    /// [`FileRendezvous::lock`] using the configured mode, see
+    /// [`Config::home_mode`].
+    fn open_internal(path: &Path, block: bool, timeout: Option<Duration>)
+                     -> Result<Option<CookieFile>>
+    {
+        loop {
+            match fs::symlink_metadata(path) {
+                Ok(m) if m.file_type().is_symlink() =>
+                    return Err(anyhow!("Opening {}: is a symbolic link",
+                                       path.display())),
+                Ok(m) if m.is_dir() =>
+                    return Err(anyhow!("Opening {}: is a directory",
+                                       path.display())),
+                // If it doesn't exist, we create it below.
+                _ => (),
+            }
+
+            let mut file = fs::OpenOptions::new();
+            file
+                .read(true)
+                .write(true)
+                .create(true);
+            #[cfg(unix)]
+            file.mode(0o600)
+                // Close the race between the above check and the open.
+                .custom_flags(libc::O_NOFOLLOW);
+            let file = file.open(path)
+                .with_context(|| format!("Opening {}", path.display()))?;
+
+            // Try to get the lock without blocking first, so that we
+            // know whether we had to wait for another client.  When
+            // many clients start at the same time, this explains
+            // latency spikes.
+            match file.try_lock_exclusive() {
+                Ok(()) => (),
+                Err(err) if err.kind() == fs2::lock_contended_error().kind() => {
+                    if ! block {
+                        return Ok(None);
+                    }
+
+                    let start = std::time::Instant::now();
+                    if let Some(timeout) = timeout {
+                        // There is no way to bound a blocking lock,
+                        // hence we poll.
+                        loop {
+                            thread::sleep(LOCK_POLL_INTERVAL);
+                            match file.try_lock_exclusive() {
+                                Ok(()) => break,
+                                Err(err) if err.kind()
+                                    == fs2::lock_contended_error().kind() =>
+                                    if start.elapsed() >= timeout {
+                                        return Err(anyhow::Error::from(err)
+                                            .context(ConnectError::LockTimedOut(
+                                                path.to_path_buf())));
+                                    },
+                                Err(err) => return Err(lock_error(path, err)),
+                            }
+                        }
+                    } else {
+                        file.lock_exclusive()
+                            .map_err(|err| lock_error(path, err))?;
+                    }
+                    let waited = start.elapsed();
+                    tracing::debug!(path = %path.display(),
+                                    waited_ms = waited.as_millis() as u64,
+                                    "Waited for the lock on the rendezvous point");
+                },
+                Err(err) => return Err(lock_error(path, err)),
+            }
+
+            // While we waited for the lock, the file may have been
+            // removed, e.g. by `Context::gc_rendezvous`.  Then,
+            // nobody would ever read what we write to it.  Try again.
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+
+                let locked = file.metadata()
+                    .with_context(|| format!("Reading {}", path.display()))?;
+                match fs::symlink_metadata(path) {
+                    Ok(m) if m.dev() == locked.dev()
+                        && m.ino() == locked.ino() => (),
+                    _ => continue,
+                }
+            }
+
+            return Ok(Some(Self {
+                path: path.to_path_buf(),
+                file,
+            }));
+        }
+    }
+
+    /// Reads the cookie file.
+    ///
+    /// If the file contains a cookie, returns it and any other data.
+    ///
+    /// Returns `None` if the file does not contain a cookie.
+    fn read(&mut self) -> Result<Option<(Cookie, Vec<u8>)>> {
+        let content = read_rendezvous_file(&mut self.file, &self.path)?;
+        Ok(Cookie::extract(content))
+    }
+
+    /// Writes the specified cookie to the cookie file followed by the
+    /// specified data.
+    ///
+    /// The contents of the cookie file are replaced.  Outside of the
+    /// tests, [`Descriptor::write_rendezvous`] is used.
+    #[cfg(test)]
+    fn write(&mut self, cookie: &Cookie, data: &[u8]) -> Result<()> {
+        self.replace(&[&cookie.0[..], data])
+    }
+
+    /// Reads the cookie file containing only an address.
+    ///
+    /// This is used if the cookie is supplied out of band.
+    ///
+    /// Returns `None` if the file is empty.
+    fn read_address(&mut self) -> Result<Option<Vec<u8>>> {
+        let content = read_rendezvous_file(&mut self.file, &self.path)?;
+        Ok(Some(content).filter(|c| ! c.is_empty()))
+    }
+
+    /// Replaces the contents of the cookie file with the
+    /// concatenation of `data`.
+    fn replace(&mut self, data: &[&[u8]]) -> Result<()> {
+        self.file.rewind()
+            .with_context(|| format!("Rewinding {}", self.path.display()))?;
+        self.file.set_len(0)
+            .with_context(|| format!("Truncating {}", self.path.display()))?;
+        for d in data {
+            self.file.write_all(d)
+                .with_context(|| format!("Updating {}", self.path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Clears the cookie file.
+    ///
+    /// The cookie file is truncated.
+    fn clear(&mut self) -> Result<()> {
+        self.file.set_len(0)
+            .with_context(|| format!("Truncating {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+impl RendezvousLock for CookieFile {
+    fn read(&mut self) -> Result<Vec<u8>> {
+        read_rendezvous_file(&mut self.file, &self.path)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.replace(&[data])
+    }
+}
+
+/// Returns the error for a failed attempt to lock the rendezvous
+/// point at `path`.
+///
+/// Some file systems, notably some network file systems, don't
+/// support advisory locks.  Without them, access to the rendezvous
+/// point is not serialized, and concurrent clients would corrupt it.
+/// We don't proceed, and return [`Error::LockUnsupported`] instead.
+fn lock_error(path: &Path, err: io::Error) -> anyhow::Error {
+    let unsupported: &[i32] = platform! {
+        unix => &[libc::ENOLCK, libc::EOPNOTSUPP, libc::ENOTSUP,
+                  libc::ENOSYS],
+        // ERROR_INVALID_FUNCTION and ERROR_NOT_SUPPORTED.
+        windows => &[1, 50]
+    };
+    let is_unsupported = err.kind() == io::ErrorKind::Unsupported
+        || err.raw_os_error().map(|errno| unsupported.contains(&errno))
+            .unwrap_or(false);
+
+    if is_unsupported {
+        anyhow::Error::from(err)
+            .context(Error::LockUnsupported(path.to_path_buf()))
+    } else {
+        anyhow::Error::from(err)
+            .context(format!("Locking {}", path.display()))
+    }
+}
+
+/// The maximum size of a rendezvous point.
+///
+/// A rendezvous point holds a cookie and an address, which is much
+/// smaller.  Larger files are not ours, and we don't read them.
+const MAX_RENDEZVOUS_SIZE: u64 = 1024;
+
+/// How often [`CookieFile::open_timeout`] tries to take the lock.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Reads a rendezvous point.
+///
+/// Files larger than [`MAX_RENDEZVOUS_SIZE`] are rejected with
+/// [`Error::MalformedRendezvous`] without reading them.
+fn read_rendezvous_file(file: &mut fs::File, path: &Path) -> Result<Vec<u8>> {
+    let too_large = |len: u64| Error::MalformedRendezvous(
+        path.to_path_buf(),
+        format!("{} bytes, at most {} expected", len, MAX_RENDEZVOUS_SIZE));
+
+    let len = file.metadata()
+        .with_context(|| format!("Opening {}", path.display()))?
+        .len();
+    if len > MAX_RENDEZVOUS_SIZE {
+        return Err(too_large(len).into());
+    }
+
+    // Avoid reallocations, which would leave copies of the cookie
+    // behind.  If the file grows in the meantime, don't read more
+    // than we allocated.
+    let mut content = Vec::with_capacity(MAX_RENDEZVOUS_SIZE as usize + 1);
+    file.take(MAX_RENDEZVOUS_SIZE + 1).read_to_end(&mut content)
+        .with_context(|| format!("Opening {}", path.display()))?;
+    if content.len() as u64 > MAX_RENDEZVOUS_SIZE {
+        return Err(too_large(content.len() as u64).into());
+    }
+    Ok(content)
+}
+
+/// Reads the rendezvous point at `path` without taking the lock.
+///
+/// If the file doesn't exist, this returns an empty vector.  Like
+/// [`CookieFile::open`], this doesn't follow symbolic links.
+pub(crate) fn read_rendezvous_unlocked(path: &Path) -> Result<Vec<u8>> {
+    let mut file = fs::OpenOptions::new();
+    file.read(true);
+    #[cfg(unix)]
+    file.custom_flags(libc::O_NOFOLLOW);
+    let mut file = match file.open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound =>
+            return Ok(Vec::new()),
+        Err(err) => return Err(anyhow::Error::from(err)
+                               .context(format!("Opening {}", path.display()))),
+    };
+    read_rendezvous_file(&mut file, path)
+}
+
+#[derive(thiserror::Error, Debug)]
+/// Errors returned from the network routines.
+pub enum Error {
+    /// Connection closed unexpectedly.
+    #[error("Connection closed unexpectedly.")]
+    ConnectionClosed(Vec<u8>),
+
+    /// An invalid IPC policy string was given.
+    #[error("Invalid IPC policy {0:?}, \
+             expected one of \"external\", \"internal\", or \"robust\"")]
+    InvalidIPCPolicy(String),
+
+    /// The function must be called from within a Tokio runtime.
+    #[error("Not called from within a Tokio runtime context")]
+    NoTokioRuntime,
+
+    /// The function must not be called from within a Tokio runtime.
+    #[error("Called from within a Tokio runtime context, \
+             start the server on a dedicated thread instead")]
+    NestedRuntime,
+
+    /// The peer uses a version of the handshake we don't support.
+    #[error("Unsupported handshake version {0}")]
+    UnsupportedHandshakeVersion(u8),
+
+    /// The server doesn't meet the client's requirements.
+    ///
+    /// See [`DescriptorBuilder::min_server_version`] and
+    /// [`DescriptorBuilder::require_capability`].
+    #[error("Incompatible server: {0}")]
+    IncompatibleServer(String),
+
+    /// The home directory cannot be determined.
+    ///
+    /// No home directory was set, and there is no default, for
+    /// instance because the user has no home directory.  See
+    /// [`Config::home`] and [`Config::default_home`].
+    #[error("Cannot determine the home directory.  Set $HOME, or use \
+             Config::home or Config::default_home")]
+    NoHome,
+
+    /// The rendezvous point is malformed.
+    ///
+    /// For instance, it is too large to be a rendezvous point.
+    #[error("Malformed rendezvous point {}: {}", .0.display(), .1)]
+    MalformedRendezvous(PathBuf, String),
+
+    /// The rendezvous point cannot be locked.
+    ///
+    /// The file system doesn't support advisory locks, which are
+    /// needed to serialize access to the rendezvous point.  This
+    /// happens on some network file systems.
+    #[error("Cannot lock the rendezvous point {}, the file system doesn't \
+             support file locking.  Put the home directory on a local \
+             file system", .0.display())]
+    LockUnsupported(PathBuf),
+
+    /// Servers would be exposed to the network.
+    ///
+    /// The configured bind address is not a loopback address, but
+    /// exposing servers to the network wasn't allowed.  See
+    /// [`Config::bind_address`] and [`Config::allow_network_exposure`].
+    #[error("Refusing to start servers on {0}, which is reachable from the \
+             network.  Use Config::allow_network_exposure to allow it")]
+    NetworkExposed(std::net::IpAddr),
+}
+
+/// Why connecting to, or starting a server failed.
+///
+/// The errors returned by [`Descriptor::connect`],
+/// [`Descriptor::connect_with_policy`], and [`Descriptor::bootstrap`]
+/// can be downcast to this type.  The underlying error is available
+/// as the error's source, so that the original error (for instance,
+/// an [`std::io::Error`], or an [`Error`]) can also be downcast to.
+///
+/// # Examples
+///
+/// ```no_run
+/// # fn f(descriptor: sequoia_ipc::Descriptor) -> anyhow::Result<()> {
+/// use sequoia_ipc::ConnectError;
+///
+/// match descriptor.connect() {
+///     Ok(_rpc_system) => (),
+///     Err(err) => match err.downcast_ref::<ConnectError>() {
+///         Some(ConnectError::ExecutableNotFound(path)) =>
+///             eprintln!("Please install {}", path.display()),
+///         _ => return Err(err),
+///     },
+/// }
+/// # Ok(()) }
+/// ```
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConnectError {
+    /// Not called from within a Tokio runtime context.
+    #[error("Not called from within a Tokio runtime context")]
+    NoTokioRuntime,
+
+    /// Accessing the rendezvous point failed.
+    #[error("Failed to access the rendezvous point {}", .0.display())]
+    Rendezvous(PathBuf),
+
+    /// Another client held the lock on the rendezvous point for too
+    /// long.
+    ///
+    /// See [`Config::lock_timeout`].
+    ///
+    /// [`Config::lock_timeout`]: core::Config::lock_timeout()
+    #[error("Timed out waiting for the lock on the rendezvous point {}",
+            .0.display())]
+    LockTimedOut(PathBuf),
+
+    /// Binding the server's socket failed.
+    #[error("Failed to bind the server's socket")]
+    Bind,
+
+    /// The server's executable does not exist.
+    #[error("Server executable {} not found", .0.display())]
+    ExecutableNotFound(PathBuf),
+
+    /// Starting the server failed.
+    #[error("Failed to start the server")]
+    Start,
+
+    /// Connecting to the server failed.
+    ///
+    /// This is the server's address.
+    #[error("Failed to connect to the server at {0}")]
+    Connect(SocketAddr),
+
+    /// Sending the cookie or the trace ID to the server failed.
+    ///
+    /// If the server closes the connection instead of completing
+    /// the handshake, this is a [`ConnectError::CookieRejected`].
+    #[error("Failed to authenticate to the server")]
+    Handshake,
+
+    /// The server closed the connection during the handshake.
+    ///
+    /// This is the server's address.  Servers close the connection
+    /// if the cookie doesn't match, for instance, because a different
+    /// server now listens on the address recorded in a stale
+    /// rendezvous point.  Servers that predate the capability
+    /// exchange close the connection, too.
+    #[error("The server at {0} rejected the cookie")]
+    CookieRejected(SocketAddr),
+
+    /// Connecting to the server, or sending it the cookie, timed out.
+    ///
+    /// This is the server's address.  The server is running, but
+    /// doesn't accept connections, see [`Descriptor::bootstrap`], or
+    /// doesn't complete the handshake.
+    #[error("Timed out connecting to the server at {0}")]
+    TimedOut(SocketAddr),
+
+    /// The server doesn't meet the client's requirements.
+    ///
+    /// The server's version or capabilities don't match what the
+    /// client requires, see [`DescriptorBuilder::min_server_version`]
+    /// and [`DescriptorBuilder::require_capability`].  The underlying
+    /// error is an [`Error::IncompatibleServer`].
+    #[error("The server is incompatible")]
+    Incompatible,
+}
+
+/// Returns whether `err` is a [`ConnectError::TimedOut`].
+fn is_timeout(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<ConnectError>(),
+             Some(ConnectError::TimedOut(_)))
+}
+
+impl ConnectError {
+    /// Classifies `r`'s error as `kind`, unless it is already
+    /// classified.
+    fn classify<T>(r: Result<T>, kind: impl FnOnce() -> ConnectError)
+                   -> Result<T>
+    {
+        r.map_err(|err| if err.is::<ConnectError>() {
+            err
+        } else {
+            err.context(kind())
+        })
+    }
+}
+
+/// Result type specialization.
+pub type Result<T> = ::std::result::Result<T, anyhow::Error>;
+
+// Global initialization and cleanup of the Windows Sockets API (WSA) module.
+// NOTE: This has to be top-level in order for `ctor::{ctor, dtor}` to work.
+#[cfg(windows)]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(windows)]
+static WSA_INITED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(windows)]
+#[ctor::ctor]
+fn wsa_startup() {
+    unsafe {
+        let ret = winsock2::WSAStartup(
+            0x202, // version 2.2
+            &mut std::mem::zeroed(),
+        );
+        WSA_INITED.store(ret != 0, Ordering::SeqCst);
+    }
+}
+
+#[cfg(windows)]
+#[ctor::dtor]
+fn wsa_cleanup() {
+    if WSA_INITED.load(Ordering::SeqCst) {
+        let _ = unsafe { winsock2::WSACleanup() };
+    }
+}
+
+/// Returns a command for starting a background process.
+///
+/// On Windows, the process does not get a console window.  On Unix,
+/// the process is started in a new session, and therefore in a new
+/// process group.  This way, it is not killed when the user hits
+/// `Ctrl-C` in the terminal the client was started from, nor when
+/// the terminal is closed.  It can still be terminated explicitly
+/// using its process id.
+#[cfg(not(feature = "no-subprocess"))]
+pub(crate) fn new_background_command<S>(program: S) -> Command
+where
+    S: AsRef<std::ffi::OsStr>,
+{
+    let command = Command::new(program);
+
+    #[cfg(unix)]
+    let command = {
+        use std::os::unix::process::CommandExt;
+
+        let mut command = command;
+        // Safety: setsid is async-signal-safe.
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        command
+    };
+
+    #[cfg(windows)]
     let command = {
         use std::os::windows::process::CommandExt;
 
-        // see https://docs.microsoft.com/en-us/windows/win32/procthread/process-creation-flags
-        const CREATE_NO_WINDOW: u32 = 0x08000000;
-        let mut command = command;
-        command.creation_flags(CREATE_NO_WINDOW);
-        command
-    };
+        // see https://docs.microsoft.com/en-us/windows/win32/procthread/process-creation-flags
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        let mut command = command;
+        command.creation_flags(CREATE_NO_WINDOW);
+        command
+    };
+
+    command
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    /// A handler factory for descriptors whose server is never
+    /// started.
+    fn unused_factory(_: Descriptor, _: &tokio::task::LocalSet)
+                      -> Result<Box<dyn Handler>>
+    {
+        Err(anyhow!("This server should not have been started"))
+    }
+
+    /// Returns a descriptor for a server that is never started.
+    fn unused_descriptor(ctx: &core::Context) -> Descriptor {
+        Descriptor::new(ctx, rendezvous_path(ctx, "unused"),
+                        ctx.lib().join("unused"), unused_factory)
+    }
+
+    #[test]
+    fn rendezvous_path() -> Result<()> {
+        let ctx = core::Context::configure().ephemeral().build()?;
+        let path = super::rendezvous_path(&ctx, "keystore");
+        assert!(path.starts_with(ctx.home()));
+        assert_eq!(path.parent(), Some(ctx.home()));
+        assert_eq!(path.file_name().unwrap(), "keystore.v1.cookie");
+        assert_eq!(service_name(&ctx, &path), Some("keystore"));
+
+        // Descriptors using the default rendezvous points of
+        // different services don't share them.
+        let a = Descriptor::new(&ctx, super::rendezvous_path(&ctx, "a"),
+                                ctx.lib().join("a"), unused_factory);
+        let b = Descriptor::new(&ctx, super::rendezvous_path(&ctx, "b"),
+                                ctx.lib().join("b"), unused_factory);
+        assert_ne!(a.rendez_vous(), b.rendez_vous());
+        assert_eq!(a.rendez_vous(), super::rendezvous_path(&ctx, "a"));
+
+        // Nor do the versions of the handshake.
+        for other in ["keystore.cookie", "keystore.v0.cookie",
+                      "keystore.v2.cookie"]
+        {
+            assert_ne!(path.file_name().unwrap(), other);
+            assert_eq!(service_name(&ctx, &ctx.home().join(other)), None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rendezvous_prefix() -> Result<()> {
+        // The files GnuPG keeps in its home directory.
+        const GNUPG: &[&str] = &[
+            "S.gpg-agent", "S.gpg-agent.extra", "S.gpg-agent.browser",
+            "S.gpg-agent.ssh", "S.dirmngr", "S.keyboxd", "S.scdaemon",
+            "S.uiserver", "pubring.kbx", "pubring.gpg", "secring.gpg",
+            "trustdb.gpg", "random_seed", "sshcontrol", "private-keys-v1.d",
+            "openpgp-revocs.d", "public-keys.d", "gpg.conf",
+            "gpg-agent.conf", "common.conf", "tofu.db",
+        ];
+        let services = ["keystore", "gpg-agent", "dirmngr", "keyboxd",
+                        "scdaemon", "pubring", "S"];
+
+        let ctx = core::Context::configure().ephemeral().build()?;
+        let prefixed = ctx.reconfigure().rendezvous_prefix("sequoia-")?.build()?;
+        assert_eq!(prefixed.rendezvous_prefix(), "sequoia-");
+        for ctx in [&ctx, &prefixed] {
+            for service in services {
+                let path = super::rendezvous_path(ctx, service);
+                assert_eq!(path.parent(), Some(ctx.home()));
+                let name = path.file_name().unwrap().to_str().unwrap();
+                assert!(! GNUPG.contains(&name), "{} collides", name);
+                assert!(! name.starts_with("S."), "{} collides", name);
+            }
+        }
+        assert_eq!(super::rendezvous_path(&prefixed, "keystore"),
+                   ctx.home().join("sequoia-keystore.v1.cookie"));
+
+        // Discovery only considers files with the prefix.
+        fs::write(super::rendezvous_path(&ctx, "plain"), b"")?;
+        fs::write(super::rendezvous_path(&prefixed, "keystore"), b"")?;
+        let names = |ctx: &core::Context| -> Result<Vec<String>> {
+            Ok(ctx.discover_services()?.iter()
+               .map(|s| s.name().to_string()).collect())
+        };
+        assert_eq!(names(&prefixed)?, ["keystore"]);
+        assert_eq!(names(&ctx)?, ["plain", "sequoia-keystore"]);
+
+        for invalid in [".hidden", "a/b", "nul\0"] {
+            assert!(core::Context::configure()
+                    .rendezvous_prefix(invalid).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn seeded_cookies() -> Result<()> {
+        use rand::{RngCore, SeedableRng};
+        use rand::rngs::StdRng;
+
+        let seeded = |seed| -> Result<core::Context> {
+            core::Context::configure()
+                .ephemeral()
+                .ipc_policy(core::IPCPolicy::Internal)
+                .rng(StdRng::seed_from_u64(seed))
+                .build()
+        };
+
+        let mut expected = [0; Cookie::SIZE];
+        StdRng::seed_from_u64(42).fill_bytes(&mut expected);
+        let ctx = seeded(42)?;
+        assert_eq!(*Cookie::generate(&ctx)?.0, expected);
+        assert_eq!(*Cookie::generate(&seeded(42)?)?.0, expected);
+        assert_ne!(*Cookie::generate(&seeded(43)?)?.0, expected);
+
+        // Clones share the generator.
+        let second = Cookie::generate(&ctx.clone())?;
+        assert_ne!(*second.0, expected);
+
+        // Servers get the generator's cookies.
+        let ctx = seeded(42)?;
+        let mut descriptor = hello_descriptor(&ctx);
+        descriptor.bootstrap()?.expect("server started");
+        assert_eq!(&fs::read(descriptor.rendez_vous())?[..Cookie::SIZE],
+                   &expected[..]);
+
+        // By default, the crypto backend is used.
+        let ctx = core::Context::configure().ephemeral().build()?;
+        assert_ne!(Cookie::generate(&ctx)?.0, Cookie::generate(&ctx)?.0);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn home_mode() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir()?;
+        let mode = |p: &Path| -> Result<u32> {
+            Ok(fs::metadata(p)?.permissions().mode() & 0o777)
+        };
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .home(tmp.path().join("parent").join("home"))
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        assert_eq!(ctx.home_mode(), 0o700);
+        let mut descriptor = hello_descriptor(&ctx);
+        descriptor.bootstrap()?.expect("server started");
+        assert_eq!(mode(ctx.home())?, 0o700);
+        assert_eq!(mode(&tmp.path().join("parent"))?, 0o700);
+
+        // Existing directories are not changed.
+        let existing = tmp.path().join("existing");
+        fs::create_dir(&existing)?;
+        fs::set_permissions(&existing, fs::Permissions::from_mode(0o755))?;
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .home(&existing)
+            .home_mode(0o711)
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        hello_descriptor(&ctx).bootstrap()?.expect("server started");
+        assert_eq!(mode(&existing)?, 0o755);
+
+        // A custom mode.
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .home(tmp.path().join("custom"))
+            .home_mode(0o711)
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        hello_descriptor(&ctx).bootstrap()?.expect("server started");
+        assert_eq!(mode(ctx.home())?, 0o711);
+
+        // The mode also applies to the rendezvous point's directory,
+        // if it is not the home directory.
+        let nested = tmp.path().join("nested");
+        let mut descriptor = Descriptor::new(
+            &ctx, nested.join("hello.cookie"), ctx.lib().join("hello"),
+            Hello::factory);
+        descriptor.bootstrap()?.expect("server started");
+        assert_eq!(mode(&nested)?, 0o711);
+        Ok(())
+    }
+
+    #[test]
+    fn connect_without_runtime() -> Result<()> {
+        let ctx = core::Context::configure().ephemeral().build()?;
+        let descriptor = unused_descriptor(&ctx);
+
+        let err = descriptor.connect().err().expect("no runtime");
+        assert!(matches!(err.downcast_ref::<Error>(),
+                         Some(Error::NoTokioRuntime)));
+        assert_eq!(err.downcast_ref::<ConnectError>(),
+                   Some(&ConnectError::NoTokioRuntime));
+
+        // The rendezvous point was not touched.
+        assert!(! descriptor.rendez_vous().exists());
+        Ok(())
+    }
+
+    #[test]
+    fn connect_errors() -> Result<()> {
+        let ctx = core::Context::configure().ephemeral().build()?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let _guard = runtime.enter();
+
+        // The rendezvous point's parent is not a directory.
+        let file = ctx.home().join("file");
+        fs::write(&file, b"")?;
+        let rendezvous = file.join("rendezvous");
+        let mut descriptor = Descriptor::new(
+            &ctx, rendezvous.clone(), ctx.lib().join("unused"),
+            unused_factory);
+        let err = descriptor.connect().err().expect("invalid rendezvous");
+        assert_eq!(err.downcast_ref::<ConnectError>(),
+                   Some(&ConnectError::Rendezvous(rendezvous.clone())));
+        // The underlying error is still accessible.
+        assert!(err.chain().any(|e| e.is::<io::Error>()), "{:?}", err);
+
+        let err = descriptor.bootstrap().err().expect("invalid rendezvous");
+        assert_eq!(err.downcast_ref::<ConnectError>(),
+                   Some(&ConnectError::Rendezvous(rendezvous)));
+
+        #[cfg(not(feature = "no-subprocess"))]
+        {
+            let executable = ctx.lib().join("does-not-exist");
+            let descriptor = Descriptor::new(
+                &ctx, rendezvous_path(&ctx, "missing"), executable.clone(),
+                unused_factory);
+            let err = descriptor.connect_with_policy(core::IPCPolicy::External)
+                .err().expect("missing executable");
+            assert_eq!(err.downcast_ref::<ConnectError>(),
+                       Some(&ConnectError::ExecutableNotFound(executable)));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "no-subprocess"))]
+    #[test]
+    fn executable_override() -> Result<()> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let _guard = runtime.enter();
+
+        let overridden = |ctx: &core::Context| Descriptor::new(
+            ctx, rendezvous_path(ctx, "overridden"),
+            ctx.lib().join("sequoia-override-test"), unused_factory);
+        let var = executable_override_var(Path::new("sequoia-override-test"));
+        assert_eq!(var, "SEQUOIA_OVERRIDE_TEST_SERVER");
+
+        // The variable is only used by tests in this function.
+        let debug = std::env::temp_dir().join("does-not-exist")
+            .join("debug-server");
+        std::env::set_var(&var, &debug);
+
+        // The override is ignored by default.
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::External)
+            .build()?;
+        let descriptor = overridden(&ctx);
+        let executable = ctx.lib().join("sequoia-override-test");
+        assert_eq!(descriptor.plan_connect()?,
+                   ConnectPlan::StartExternal(executable.clone()));
+        let err = descriptor.connect().err().expect("missing executable");
+        assert_eq!(err.downcast_ref::<ConnectError>(),
+                   Some(&ConnectError::ExecutableNotFound(executable)));
+
+        // If enabled, the override is started instead.
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::External)
+            .executable_override()
+            .build()?;
+        let descriptor = overridden(&ctx);
+        assert_eq!(descriptor.plan_connect()?,
+                   ConnectPlan::StartExternal(debug.clone()));
+        let err = descriptor.connect().err().expect("missing executable");
+        assert_eq!(err.downcast_ref::<ConnectError>(),
+                   Some(&ConnectError::ExecutableNotFound(debug)));
+
+        std::env::remove_var(&var);
+        assert_eq!(descriptor.plan_connect()?,
+                   ConnectPlan::StartExternal(
+                       ctx.lib().join("sequoia-override-test")));
+        Ok(())
+    }
+
+    #[test]
+    fn server_in_runtime() -> Result<()> {
+        let ctx = core::Context::configure().ephemeral().build()?;
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let err = runtime.block_on(async {
+            Server::new(unused_descriptor(&ctx)).err()
+        }).expect("nested runtime");
+        assert!(matches!(err.downcast_ref::<Error>(),
+                         Some(Error::NestedRuntime)));
+
+        // Blocking tasks are also inside the runtime context.
+        let descriptor = unused_descriptor(&ctx);
+        let err = runtime.block_on(async move {
+            tokio::task::spawn_blocking(move || Server::new(descriptor).err())
+                .await
+        })?.expect("nested runtime");
+        assert!(matches!(err.downcast_ref::<Error>(),
+                         Some(Error::NestedRuntime)));
+
+        // But a dedicated thread is fine.
+        let descriptor = unused_descriptor(&ctx);
+        let _guard = runtime.enter();
+        std::thread::spawn(move || Server::new(descriptor).map(|_| ()))
+            .join().expect("thread panicked")?;
+        Ok(())
+    }
+
+    #[test]
+    fn with_client_in_runtime() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+        let greet = |descriptor: &Descriptor| descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "nested").await
+            });
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let err = runtime.block_on(async { greet(&descriptor).err() })
+            .expect("nested runtime");
+        assert!(matches!(err.downcast_ref::<Error>(),
+                         Some(Error::NestedRuntime)));
+
+        // Blocking tasks are also inside the runtime context.
+        let d = descriptor.clone();
+        let err = runtime.block_on(async move {
+            tokio::task::spawn_blocking(move || greet(&d).err()).await
+        })?.expect("nested runtime");
+        assert!(matches!(err.downcast_ref::<Error>(),
+                         Some(Error::NestedRuntime)));
+        // We didn't start a server.
+        assert_eq!(descriptor.plan_connect()?, ConnectPlan::StartInternal);
+
+        // Outside of the runtime context, it works.
+        assert_eq!(greet(&descriptor)?, "Hello nested!");
+        Ok(())
+    }
+
+    use crate::hello_protocol_capnp::hello;
+
+    /// A handler for the hello protocol.
+    struct Hello {
+        c: hello::Client,
+        ctx: core::Context,
+    }
+
+    impl Hello {
+        fn factory(descriptor: Descriptor, _: &tokio::task::LocalSet)
+                   -> Result<Box<dyn Handler>>
+        {
+            Ok(Box::new(Hello {
+                c: capnp_rpc::new_client(HelloServer {}),
+                ctx: descriptor.context().clone(),
+            }))
+        }
+    }
+
+    impl Handler for Hello {
+        fn handle_connection(&self, connection: Connection)
+                             -> Result<RpcSystem<Side>>
+        {
+            Ok(connection.into_rpc_system(&self.ctx, self.c.clone().client))
+        }
+    }
+
+    struct HelloServer {}
+
+    impl hello::Server for HelloServer {
+        fn hello(&mut self,
+                 params: hello::HelloParams,
+                 mut results: hello::HelloResults)
+                 -> capnp::capability::Promise<(), capnp::Error>
+        {
+            let p = capnp_rpc::pry!(params.get());
+            let name: String = capnp_rpc::pry!(p.get_name()).to_string()
+                .expect("valid UTF-8");
+            results.get().set_response(&format!("Hello {}!", name));
+            capnp::capability::Promise::ok(())
+        }
+    }
+
+    /// Returns a descriptor for a server speaking the hello
+    /// protocol.
+    fn hello_descriptor(ctx: &core::Context) -> Descriptor {
+        Descriptor::new(ctx, rendezvous_path(ctx, "hello"),
+                        ctx.lib().join("hello"), Hello::factory)
+    }
+
+    /// Says hello to the server.
+    async fn say_hello(hello: hello::Client, name: &str) -> Result<String> {
+        let mut request = hello.hello_request();
+        request.get().set_name(name);
+        let response = request.send().promise.await?;
+        Ok(response.get()?.get_response()?.to_str()?.to_string())
+    }
+
+    /// Checks that the server runs on a current-thread runtime,
+    /// which doesn't start worker threads.
+    #[test]
+    fn server_is_single_threaded() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = Descriptor::new(
+            &ctx, rendezvous_path(&ctx, "flavor"), ctx.lib().join("flavor"),
+            FlavorServer::factory);
+
+        // The server reports the flavor of the runtime it runs on.
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "").await
+            })?;
+        assert_eq!(response, format!("{:?}",
+                                     tokio::runtime::RuntimeFlavor::CurrentThread));
+        Ok(())
+    }
+
+    /// Answers with the flavor of the runtime serving the request.
+    struct FlavorServer {}
+
+    impl FlavorServer {
+        fn factory(descriptor: Descriptor, _: &tokio::task::LocalSet)
+                   -> Result<Box<dyn Handler>>
+        {
+            Ok(Box::new(Hello {
+                c: capnp_rpc::new_client(FlavorServer {}),
+                ctx: descriptor.context().clone(),
+            }))
+        }
+    }
+
+    impl hello::Server for FlavorServer {
+        fn hello(&mut self,
+                 _: hello::HelloParams,
+                 mut results: hello::HelloResults)
+                 -> capnp::capability::Promise<(), capnp::Error>
+        {
+            let flavor = tokio::runtime::Handle::current().runtime_flavor();
+            results.get().set_response(&format!("{:?}", flavor));
+            capnp::capability::Promise::ok(())
+        }
+    }
+
+    #[test]
+    fn with_client() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "with_client").await
+            })?;
+        assert_eq!(response, "Hello with_client!");
+        Ok(())
+    }
+
+    /// A handler that refuses the first connection.
+    struct Picky {
+        hello: Hello,
+        refused: Cell<bool>,
+    }
+
+    impl Picky {
+        fn factory(descriptor: Descriptor, _: &tokio::task::LocalSet)
+                   -> Result<Box<dyn Handler>>
+        {
+            Ok(Box::new(Picky {
+                hello: Hello {
+                    c: capnp_rpc::new_client(HelloServer {}),
+                    ctx: descriptor.context().clone(),
+                },
+                refused: Cell::new(false),
+            }))
+        }
+    }
+
+    impl Handler for Picky {
+        fn handle_connection(&self, connection: Connection)
+                             -> Result<RpcSystem<Side>>
+        {
+            if ! self.refused.replace(true) {
+                return Err(anyhow!("Not on the guest list"));
+            }
+            self.hello.handle_connection(connection)
+        }
+    }
+
+    #[test]
+    fn handler_refuses_connection() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let mut descriptor = Descriptor::new(
+            &ctx, rendezvous_path(&ctx, "picky"), ctx.lib().join("picky"),
+            Picky::factory);
+        descriptor.bootstrap()?.expect("server started");
+
+        // The first connection is refused, the client sees a
+        // disconnect.
+        let err = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "refused").await
+            }).unwrap_err();
+        let err = err.downcast_ref::<capnp::Error>()
+            .unwrap_or_else(|| panic!("Expected a capnp error, got {}", err));
+        assert_eq!(err.kind, capnp::ErrorKind::Disconnected);
+
+        // The server continues to serve other connections.
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "accepted").await
+            })?;
+        assert_eq!(response, "Hello accepted!");
+        Ok(())
+    }
+
+    /// Collects the events emitted by all threads.
+    ///
+    /// Each event is recorded as its fields followed by the fields of
+    /// its explicit parent span.
+    mod capture {
+        use std::fmt::Write;
+        use std::sync::{Mutex, Once};
+
+        use tracing::{Event, Metadata, Subscriber};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+
+        static SPANS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        static EVENTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        struct Fields<'a>(&'a mut String);
+
+        impl Visit for Fields<'_> {
+            fn record_debug(&mut self, field: &Field,
+                            value: &dyn std::fmt::Debug) {
+                let _ = write!(self.0, "{}={:?} ", field.name(), value);
+            }
+        }
+
+        struct Capture;
+
+        impl Subscriber for Capture {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut fields = String::new();
+                span.record(&mut Fields(&mut fields));
+                let mut spans = SPANS.lock().unwrap();
+                spans.push(fields);
+                Id::from_u64(spans.len() as u64)
+            }
+
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = String::new();
+                event.record(&mut Fields(&mut fields));
+                if let Some(parent) = event.parent() {
+                    fields.push_str(
+                        &SPANS.lock().unwrap()[parent.into_u64() as usize - 1]);
+                }
+                EVENTS.lock().unwrap().push(fields);
+            }
+
+            fn enter(&self, _: &Id) {}
+
+            fn exit(&self, _: &Id) {}
+        }
+
+        /// Installs the global subscriber.
+        pub fn install() {
+            static INSTALL: Once = Once::new();
+            INSTALL.call_once(|| {
+                tracing::subscriber::set_global_default(Capture)
+                    .expect("no other global subscriber");
+            });
+        }
+
+        /// Returns the events recorded so far.
+        pub fn events() -> Vec<String> {
+            EVENTS.lock().unwrap().clone()
+        }
+    }
+
+    #[test]
+    fn trace_id() -> Result<()> {
+        capture::install();
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .trace_id("trace-331")
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "trace").await
+            })?;
+        assert_eq!(response, "Hello trace!");
+
+        let events = capture::events();
+        assert!(events.iter().any(|e| e.contains("Accepted connection")
+                                  && e.contains("trace_id=trace-331")),
+                "{:?}", events);
+        Ok(())
+    }
+
+    #[test]
+    fn lock_contention() -> Result<()> {
+        use std::sync::mpsc;
+
+        capture::install();
+
+        let ctx = core::Context::configure().ephemeral().build()?;
+        let path = rendezvous_path(&ctx, "contended");
+        let hold = Duration::from_millis(200);
+
+        // Hold the lock in one thread...
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let holder = {
+            let path = path.clone();
+            thread::spawn(move || -> Result<()> {
+                let file = CookieFile::open(&path)?;
+                locked_tx.send(()).expect("receiver alive");
+                thread::sleep(hold);
+                drop(file);
+                Ok(())
+            })
+        };
+        locked_rx.recv()?;
+
+        // ... and wait for it in another.
+        let waiter = {
+            let path = path.clone();
+            thread::spawn(move || CookieFile::open(&path).map(drop))
+        };
+        waiter.join().expect("waiter panicked")?;
+        holder.join().expect("holder panicked")?;
+
+        let events = capture::events();
+        let waited = events.iter()
+            .filter(|e| e.contains("Waited for the lock")
+                    && e.contains(&format!("{}", path.display())))
+            .filter_map(|e| e.split("waited_ms=").nth(1))
+            .filter_map(|w| w.split(' ').next()?.parse::<u64>().ok())
+            .next()
+            .unwrap_or_else(|| panic!("no contention reported: {:?}", events));
+        // Allow for some slack in the timing of the threads.
+        assert!(waited >= hold.as_millis() as u64 / 2, "{}", waited);
+
+        // Uncontended locks are not reported.
+        let n = capture::events().len();
+        CookieFile::open(&path)?;
+        assert!(capture::events()[n..].iter()
+                .all(|e| ! (e.contains("Waited for the lock")
+                            && e.contains(&format!("{}", path.display())))));
+        Ok(())
+    }
+
+    #[test]
+    fn idle_timeout() -> Result<()> {
+        use std::time::Instant;
+
+        let timeout = Duration::from_millis(200);
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .idle_timeout(timeout)
+            .build()?;
+        let mut descriptor = hello_descriptor(&ctx);
+        descriptor.bootstrap()?.expect("server started");
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let local = tokio::task::LocalSet::new();
+        local.block_on(&runtime, async {
+            // Complete the handshake and an RPC, and then go idle.
+            let hello = descriptor.connect_client::<hello::Client>()?;
+            assert_eq!(say_hello(hello.client().clone(), "idle").await?,
+                       "Hello idle!");
+            let idle = Instant::now();
+
+            // The server closes the connection.
+            while hello.is_connected() {
+                assert!(idle.elapsed() < Duration::from_secs(10),
+                        "idle connection not closed");
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert!(idle.elapsed() >= timeout);
+
+            // And serves the next client.
+            let hello = descriptor.connect_client::<hello::Client>()?;
+            assert_eq!(say_hello(hello.client().clone(), "again").await?,
+                       "Hello again!");
+            Ok(())
+        })
+    }
+
+    /// Says howdy instead of hello.
+    struct HowdyServer {}
+
+    impl hello::Server for HowdyServer {
+        fn hello(&mut self,
+                 params: hello::HelloParams,
+                 mut results: hello::HelloResults)
+                 -> capnp::capability::Promise<(), capnp::Error>
+        {
+            let p = capnp_rpc::pry!(params.get());
+            let name: String = capnp_rpc::pry!(p.get_name()).to_string()
+                .expect("valid UTF-8");
+            results.get().set_response(&format!("Howdy {}!", name));
+            capnp::capability::Promise::ok(())
+        }
+    }
+
+    /// Says hello from the server's home.
+    struct HomeServer(String);
+
+    impl hello::Server for HomeServer {
+        fn hello(&mut self,
+                 params: hello::HelloParams,
+                 mut results: hello::HelloResults)
+                 -> capnp::capability::Promise<(), capnp::Error>
+        {
+            let p = capnp_rpc::pry!(params.get());
+            let name: String = capnp_rpc::pry!(p.get_name()).to_string()
+                .expect("valid UTF-8");
+            results.get().set_response(
+                &format!("Hello {} from {}!", name, self.0));
+            capnp::capability::Promise::ok(())
+        }
+    }
+
+    #[test]
+    fn handler_context() -> Result<()> {
+        /// Doesn't keep the context, but is given it.
+        struct Homely {}
+
+        impl Handler for Homely {
+            fn handle_connection(&self, _: Connection)
+                                 -> Result<RpcSystem<Side>>
+            {
+                Err(anyhow!("The server passes the context"))
+            }
+
+            fn handle_with_context(&self, ctx: &Context, _: Option<&str>,
+                                   connection: Connection)
+                                   -> Result<RpcSystem<Side>>
+            {
+                let home = ctx.home().display().to_string();
+                let c: hello::Client = capnp_rpc::new_client(HomeServer(home));
+                Ok(connection.into_rpc_system(ctx, c.client))
+            }
+        }
+
+        fn factory(_: Descriptor, _: &tokio::task::LocalSet)
+                   -> Result<Box<dyn Handler>>
+        {
+            Ok(Box::new(Multiplexer::new()
+                        .default_handler(Box::new(Homely {}))))
+        }
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let mut descriptor = Descriptor::new(
+            &ctx, rendezvous_path(&ctx, "homely"), ctx.lib().join("homely"),
+            factory);
+        descriptor.bootstrap()?.expect("server started");
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "home").await
+            })?;
+        assert_eq!(response,
+                   format!("Hello home from {}!", ctx.home().display()));
+        Ok(())
+    }
+
+    #[test]
+    fn multiplexed_services() -> Result<()> {
+        fn factory(descriptor: Descriptor, _: &tokio::task::LocalSet)
+                   -> Result<Box<dyn Handler>>
+        {
+            let ctx = descriptor.context();
+            Ok(Box::new(Multiplexer::new()
+                        .service("hello", Box::new(Hello {
+                            c: capnp_rpc::new_client(HelloServer {}),
+                            ctx: ctx.clone(),
+                        }))
+                        .service("howdy", Box::new(Hello {
+                            c: capnp_rpc::new_client(HowdyServer {}),
+                            ctx: ctx.clone(),
+                        }))))
+        }
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = |service: Option<&str>| -> Result<Descriptor> {
+            let mut builder = DescriptorBuilder::new(
+                &ctx, rendezvous_path(&ctx, "multiplexed"),
+                ctx.lib().join("multiplexed"), factory);
+            if let Some(service) = service {
+                builder.set_service(service)?;
+            }
+            Ok(builder.build())
+        };
+
+        let mut hello = descriptor(Some("hello"))?;
+        assert_eq!(hello.service(), Some("hello"));
+        hello.bootstrap()?.expect("server started");
+        let addr = match hello.plan_connect()? {
+            ConnectPlan::Connect(addr) => addr,
+            plan => panic!("unexpected plan: {:?}", plan),
+        };
+        let response = hello.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "multiplexed").await
+            })?;
+        assert_eq!(response, "Hello multiplexed!");
+
+        // The other service is served by the same server.
+        let mut howdy = descriptor(Some("howdy"))?;
+        assert!(howdy.bootstrap()?.is_none());
+        assert_eq!(howdy.plan_connect()?, ConnectPlan::Connect(addr));
+        let response = howdy.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "multiplexed").await
+            })?;
+        assert_eq!(response, "Howdy multiplexed!");
+
+        // Unknown services, and connections that don't select one,
+        // are refused.
+        for service in [Some("nope"), None] {
+            let r = descriptor(service)?.with_client(
+                |hello: hello::Client| async move {
+                    say_hello(hello, "multiplexed").await
+                });
+            assert!(r.is_err(), "{:?}: {:?}", service, r);
+        }
+
+        // The server is still up.
+        let response = hello.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "again").await
+            })?;
+        assert_eq!(response, "Hello again!");
+
+        // Invalid names.
+        assert!(descriptor(Some("")).is_err());
+        assert!(descriptor(Some(&"x".repeat(256))).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn service_framing() -> Result<()> {
+        let mut buf = Vec::new();
+        send_service(&mut buf, "agent")?;
+        assert_eq!(&buf[..], b"SRVC\x05agent");
+        assert!(send_service(&mut buf, &"x".repeat(256)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn trace_id_framing() -> Result<()> {
+        let mut buf = Vec::new();
+        send_trace_id(&mut buf, "abc")?;
+        assert_eq!(&buf[..], b"TRID\x03abc");
+
+        // Long IDs are truncated.
+        let mut buf = Vec::new();
+        send_trace_id(&mut buf, &"x".repeat(300))?;
+        assert_eq!(buf.len(), 4 + 1 + 255);
+        assert_eq!(buf[4], 255);
+        Ok(())
+    }
+
+    #[test]
+    fn compression_negotiation() -> Result<()> {
+        let best = Compression::SUPPORTED.first().copied()
+            .unwrap_or(Compression::None);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        // The client only offers compression if both peers advertised
+        // it.
+        for (offer, expected) in [
+            (true, best),
+            (false, Compression::None),
+        ] {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+            let addr = listener.local_addr()?;
+            let client = thread::spawn(move || -> io::Result<_> {
+                let mut s = TcpStream::connect(addr)?;
+                let compression = if offer {
+                    negotiate_compression(&mut s, Duration::from_secs(10))?
+                } else {
+                    Compression::None
+                };
+                // What the RPC system would send.
+                s.write_all(&[0; 8])?;
+                Ok(compression)
+            });
+
+            let (picked, rest) = runtime.block_on(async {
+                listener.set_nonblocking(true)?;
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                let (socket, _) = listener.accept().await?;
+                let mut stream = HandshakeStream::new(socket);
+                let picked =
+                    receive_compression_offer(&mut stream, offer).await?;
+                let buffered = stream.buffer.len();
+                let rest = stream.read(8).await?;
+                Ok::<_, io::Error>((picked, (buffered, rest)))
+            })?;
+            assert_eq!(picked, expected);
+            assert_eq!(client.join().expect("no panic")?, expected);
+            // Nothing else is consumed, or read.
+            assert_eq!(rest.1, [0; 8]);
+            assert_eq!(rest.0, 0);
+        }
+
+        // If the server expects an offer, anything else is an error.
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let mut s = TcpStream::connect(addr)?;
+        s.write_all(&[0; 8])?;
+        let err = runtime.block_on(async {
+            listener.set_nonblocking(true)?;
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            let (socket, _) = listener.accept().await?;
+            let mut stream = HandshakeStream::new(socket);
+            Ok::<_, io::Error>(receive_compression_offer(&mut stream, true)
+                               .await.expect_err("not an offer"))
+        })?;
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Servers that don't know about compression close the
+        // connection.
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let server = thread::spawn(move || -> io::Result<()> {
+            let (mut s, _) = listener.accept()?;
+            // Read the whole offer, so that closing the connection
+            // doesn't reset it.
+            let mut offer = vec![0; 5 + Compression::SUPPORTED.len()];
+            s.read_exact(&mut offer)?;
+            Ok(())
+        });
+        let err = negotiate_compression(&mut TcpStream::connect(addr)?,
+                                        Duration::from_secs(10))
+            .expect_err("server closed the connection");
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        server.join().expect("no panic")?;
+
+        // Servers that don't respond don't stall the client.
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let (sender, receiver) = std::sync::mpsc::channel::<()>();
+        let server = thread::spawn(move || -> io::Result<()> {
+            let (_s, _) = listener.accept()?;
+            // Keep the connection open until the client gave up.
+            let _ = receiver.recv();
+            Ok(())
+        });
+        let err = negotiate_compression(&mut TcpStream::connect(addr)?,
+                                        Duration::from_millis(250))
+            .expect_err("timed out");
+        assert!(matches!(err.kind(),
+                         io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock),
+                "{}", err);
+        drop(sender);
+        server.join().expect("no panic")?;
+        Ok(())
+    }
+
+    /// Clients don't offer compression to servers that don't
+    /// advertise it.
+    #[test]
+    fn compression_capability() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .compression()
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let cookie = Cookie::new();
+        let copy = Cookie::from(&cookie.0[..]).expect("cookie has the right size");
+
+        let server = thread::spawn(move || -> Result<(Capabilities, Vec<u8>)> {
+            let (mut s, _) = listener.accept()?;
+            assert!(Cookie::receive(&mut s)? == cookie);
+            let mut header = [0; CAPABILITIES_MAGIC.len() + 3];
+            s.read_exact(&mut header)?;
+            let mut body = vec![0; read_capabilities_header(&header)?];
+            s.read_exact(&mut body)?;
+            s.write_all(&Capabilities::default().encode())?;
+
+            let mut rest = Vec::new();
+            s.read_to_end(&mut rest)?;
+            Ok((Capabilities::parse(&body)?, rest))
+        });
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let _guard = runtime.enter();
+        let s = TcpStream::connect(addr)?;
+        let rpc_system = descriptor.connect_stream(copy, s, addr)?;
+        // The server's capabilities are recorded.
+        assert_eq!(descriptor.server_capabilities(),
+                   Some(Capabilities::default()));
+        drop(rpc_system);
+
+        let (theirs, rest) = server.join().expect("no panic")?;
+        assert_eq!(theirs.supports(COMPRESSION_CAPABILITY),
+                   ! Compression::SUPPORTED.is_empty());
+        assert!(! rest.starts_with(&COMPRESSION_MAGIC));
+        Ok(())
+    }
+
+    /// Handlers that only implement [`Handler::handle`] serve
+    /// clients.
+    #[test]
+    fn plain_handler() -> Result<()> {
+        struct Plain(Hello);
+
+        impl Handler for Plain {
+            fn handle(&self,
+                      network: twoparty::VatNetwork<tokio_util::compat::Compat<tokio::net::tcp::OwnedReadHalf>>)
+                      -> Result<RpcSystem<Side>>
+            {
+                Ok(server_rpc_system(&self.0.ctx, network,
+                                     self.0.c.clone().client))
+            }
+        }
+
+        fn factory(descriptor: Descriptor, _: &tokio::task::LocalSet)
+                   -> Result<Box<dyn Handler>>
+        {
+            Ok(Box::new(Plain(Hello {
+                c: capnp_rpc::new_client(HelloServer {}),
+                ctx: descriptor.context().clone(),
+            })))
+        }
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .trace_id("plain")
+            .build()?;
+        let descriptor = DescriptorBuilder::new(
+            &ctx, rendezvous_path(&ctx, "plain"), ctx.lib().join("plain"),
+            factory)
+            .min_server_version("0")?
+            .build();
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "plain").await
+            })?;
+        assert_eq!(response, "Hello plain!");
+        Ok(())
+    }
+
+    /// Compressing and non-compressing peers interoperate.
+    #[test]
+    fn compression_interop() -> Result<()> {
+        let home = tempfile::tempdir()?;
+        let ctx = |compression: bool| {
+            let mut config = core::Context::configure()
+                .home(home.path())
+                .ipc_policy(core::IPCPolicy::Internal)
+                .trace_id("compression");
+            if compression {
+                config.set_compression();
+            }
+            config.build()
+        };
+        // Large, and compressible.
+        let name = "compressible ".repeat(10_000);
+
+        for (server, client) in [(true, true), (true, false),
+                                 (false, true), (false, false)] {
+            let server_ctx = ctx(server)?;
+            let path = rendezvous_path(
+                &server_ctx, &format!("compression-{}-{}", server, client));
+            let mut descriptor = Descriptor::new(
+                &server_ctx, path.clone(), server_ctx.lib().join("hello"),
+                Hello::factory);
+            descriptor.bootstrap()?.expect("server started");
+
+            let client_ctx = ctx(client)?;
+            let descriptor = Descriptor::new(
+                &client_ctx, path, client_ctx.lib().join("hello"),
+                Hello::factory);
+            assert!(matches!(descriptor.plan_connect()?,
+                             ConnectPlan::Connect(_)));
+            for _ in 0..2 {
+                let name = name.clone();
+                let response = descriptor.with_client(
+                    |hello: hello::Client| async move {
+                        say_hello(hello, &name).await
+                    })?;
+                assert_eq!(response, format!("Hello {}!", name));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn discover_services() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        assert!(ctx.discover_services()?.is_empty());
+
+        // A live server.
+        let mut descriptor = hello_descriptor(&ctx);
+        descriptor.bootstrap()?.expect("server started");
+
+        // A stale rendezvous point: nothing listens on the address.
+        let addr = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+            .local_addr()?;
+        let mut stale = Cookie::new().0.to_vec();
+        stale.extend_from_slice(addr.to_string().as_bytes());
+        fs::write(rendezvous_path(&ctx, "stale"), stale)?;
+
+        // This is not a rendezvous point.
+        fs::write(ctx.home().join("stale.txt"), b"")?;
+
+        let services = ctx.discover_services()?;
+        assert_eq!(services.len(), 2);
+
+        assert_eq!(services[0].name(), "hello");
+        assert_eq!(services[0].rendez_vous(), descriptor.rendez_vous());
+        assert!(services[0].address().is_some());
+        assert!(services[0].is_live());
+
+        assert_eq!(services[1].name(), "stale");
+        assert_eq!(services[1].address(), Some(addr));
+        assert!(! services[1].is_live());
+
+        // The server still works.
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "discover").await
+            })?;
+        assert_eq!(response, "Hello discover!");
+        Ok(())
+    }
+
+    #[test]
+    fn gc_rendezvous() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        assert_eq!(ctx.gc_rendezvous()?, 0);
+
+        // A live server.
+        let mut descriptor = hello_descriptor(&ctx);
+        descriptor.bootstrap()?.expect("server started");
+
+        // A stale rendezvous point: nothing listens on the address.
+        let addr = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+            .local_addr()?;
+        let mut stale = Cookie::new().0.to_vec();
+        stale.extend_from_slice(addr.to_string().as_bytes());
+        fs::write(rendezvous_path(&ctx, "stale"), &stale)?;
+
+        // Malformed rendezvous points.
+        fs::write(rendezvous_path(&ctx, "empty"), b"")?;
+        fs::write(rendezvous_path(&ctx, "garbage"), b"garbage")?;
+
+        // A stale rendezvous point that a client is using.
+        fs::write(rendezvous_path(&ctx, "locked"), &stale)?;
+        let locked = CookieFile::open(&rendezvous_path(&ctx, "locked"))?;
+
+        // This is not a rendezvous point.
+        fs::write(ctx.home().join("stale.txt"), b"")?;
+
+        // On Windows, they are truncated, which is not counted.
+        assert_eq!(ctx.gc_rendezvous()?, if cfg!(unix) { 3 } else { 0 });
+        for name in ["stale", "empty", "garbage"] {
+            let path = rendezvous_path(&ctx, name);
+            if cfg!(unix) {
+                assert!(! path.exists(), "{}", path.display());
+            } else {
+                assert_eq!(fs::read(&path)?, b"");
+            }
+        }
+        assert!(descriptor.rendez_vous().exists());
+        assert_eq!(fs::read(rendezvous_path(&ctx, "locked"))?, stale);
+        assert!(ctx.home().join("stale.txt").exists());
+
+        // The server still works.
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "gc").await
+            })?;
+        assert_eq!(response, "Hello gc!");
+
+        // Once the client is done, the rendezvous point is collected.
+        drop(locked);
+        #[cfg(unix)]
+        {
+            assert_eq!(ctx.gc_rendezvous()?, 1);
+            assert_eq!(ctx.gc_rendezvous()?, 0);
+        }
+        Ok(())
+    }
+
+    /// A client waiting for the lock on a rendezvous point that is
+    /// removed in the meantime must not use the removed file.
+    #[cfg(unix)]
+    #[test]
+    fn cookie_file_removed_while_waiting() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .build()?;
+        let path = rendezvous_path(&ctx, "removed");
+
+        let holder = CookieFile::open(&path)?;
+        let waiter = {
+            let path = path.clone();
+            std::thread::spawn(move || -> Result<()> {
+                let mut file = CookieFile::open(&path)?;
+                RendezvousLock::write(&mut file, b"after")?;
+                Ok(())
+            })
+        };
+
+        // Give the waiter a chance to block on the lock.
+        std::thread::sleep(Duration::from_millis(100));
+        fs::remove_file(&path)?;
+        drop(holder);
+
+        waiter.join().expect("no panic")?;
+        assert_eq!(fs::read(&path)?, b"after");
+        Ok(())
+    }
+
+    /// The number of servers `counting_factory` created.
+    static SERVERS_STARTED: std::sync::atomic::AtomicUsize =
+        std::sync::atomic::AtomicUsize::new(0);
+
+    /// Like `Hello::factory`, but counts the servers.
+    fn counting_factory(descriptor: Descriptor, local: &tokio::task::LocalSet)
+                        -> Result<Box<dyn Handler>>
+    {
+        use std::sync::atomic::Ordering::SeqCst;
+        SERVERS_STARTED.fetch_add(1, SeqCst);
+        Hello::factory(descriptor, local)
+    }
+
+    #[test]
+    fn concurrent_bootstrap() -> Result<()> {
+        use std::sync::atomic::Ordering::SeqCst;
+        const CLIENTS: usize = 32;
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = Descriptor::new(
+            &ctx, rendezvous_path(&ctx, "race"), ctx.lib().join("race"),
+            counting_factory);
+
+        let barrier = Arc::new(std::sync::Barrier::new(CLIENTS));
+        let race = |f: fn(Descriptor) -> Result<bool>| {
+            (0..CLIENTS).map(|_| {
+                let descriptor = descriptor.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    f(descriptor)
+                })
+            }).collect::<Vec<_>>().into_iter()
+                .map(|t| t.join().expect("no panic"))
+                .collect::<Result<Vec<bool>>>()
+        };
+
+        // Exactly one of the clients starts the server.
+        let started = race(|mut descriptor| {
+            Ok(descriptor.bootstrap()?.is_some())
+        })?;
+        assert_eq!(started.iter().filter(|s| **s).count(), 1);
+        assert_eq!(SERVERS_STARTED.load(SeqCst), 1);
+
+        // And all clients connect to it.
+        let said_hello = race(|descriptor| {
+            let response = descriptor.with_client(
+                |hello: hello::Client| async move {
+                    say_hello(hello, "race").await
+                })?;
+            Ok(response == "Hello race!")
+        })?;
+        assert!(said_hello.iter().all(|ok| *ok));
+        assert_eq!(SERVERS_STARTED.load(SeqCst), 1);
+        Ok(())
+    }
+
+    /// Like `Hello::factory`, but takes a while.
+    fn sleepy_factory(descriptor: Descriptor, local: &tokio::task::LocalSet)
+                      -> Result<Box<dyn Handler>>
+    {
+        thread::sleep(Duration::from_millis(200));
+        Hello::factory(descriptor, local)
+    }
+
+    #[test]
+    fn bootstrap_under_load() -> Result<()> {
+        const CLIENTS: usize = 16;
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let mut descriptor = Descriptor::new(
+            &ctx, rendezvous_path(&ctx, "sleepy"), ctx.lib().join("sleepy"),
+            sleepy_factory);
+
+        // Bootstrapping doesn't wait for the server to accept
+        // connections.
+        let _server = descriptor.bootstrap()?.expect("server started");
+        let addr = match descriptor.plan_connect()? {
+            ConnectPlan::Connect(addr) => addr,
+            plan => panic!("unexpected plan: {:?}", plan),
+        };
+
+        // A client that connects before the server accepts
+        // connections doesn't get to pick the cookie.
+        Cookie::new().send(&mut TcpStream::connect(addr)?)?;
+
+        let clients = (0..CLIENTS).map(|i| {
+            let descriptor = descriptor.clone();
+            thread::spawn(move || -> Result<bool> {
+                let name = format!("client {}", i);
+                let expected = format!("Hello {}!", name);
+                let mut d = descriptor.clone();
+                // Bootstrapping again finds the running server.
+                assert!(d.bootstrap()?.is_none());
+                let response = descriptor.with_client(
+                    |hello: hello::Client| async move {
+                        say_hello(hello, &name).await
+                    })?;
+                Ok(response == expected)
+            })
+        }).collect::<Vec<_>>();
+        for client in clients {
+            assert!(client.join().expect("no panic")?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ensure_started() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+
+        descriptor.ensure_started()?;
+        let addr = match descriptor.plan_connect()? {
+            ConnectPlan::Connect(addr) => addr,
+            plan => panic!("unexpected plan: {:?}", plan),
+        };
+        let rendezvous = fs::read(descriptor.rendez_vous())?;
+
+        // The server is running, so this does nothing.
+        descriptor.ensure_started()?;
+        assert_eq!(fs::read(descriptor.rendez_vous())?, rendezvous);
+
+        // Connecting attaches to the server.
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "warm").await
+            })?;
+        assert_eq!(response, "Hello warm!");
+        assert_eq!(fs::read(descriptor.rendez_vous())?, rendezvous);
+        assert_eq!(descriptor.plan_connect()?, ConnectPlan::Connect(addr));
+
+        // Remote servers can't be started.
+        let remote = DescriptorBuilder::new(
+            &ctx, rendezvous_path(&ctx, "remote"),
+            ctx.lib().join("does-not-exist"), unused_factory)
+            .remote(addr)
+            .build();
+        assert!(remote.ensure_started().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn disable_internal_cookie_auth() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .disable_internal_cookie_auth()
+            .build()?;
+        assert!(! ctx.internal_cookie_auth());
+        let hello = |descriptor: &Descriptor, name: &'static str|
+            descriptor.with_client(move |hello: hello::Client| async move {
+                say_hello(hello, name).await
+            });
+
+        // Internal servers are served in-process, and shared by the
+        // descriptor's clones.  Nothing is published at the
+        // rendezvous point.
+        let descriptor = hello_descriptor(&ctx);
+        assert_eq!(hello(&descriptor, "one")?, "Hello one!");
+        assert_eq!(hello(&descriptor.clone(), "two")?, "Hello two!");
+        assert!(descriptor.inner.in_process.lock().unwrap().is_some());
+        assert!(read_rendezvous_unlocked(descriptor.rendez_vous())?.is_empty());
+        assert_eq!(descriptor.server_capabilities(),
+                   Some(Capabilities::default()));
+
+        // Connections over TCP still require the cookie.
+        let tcp_hello = |descriptor: &Descriptor, name: &'static str| {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            let local = tokio::task::LocalSet::new();
+            local.block_on(&runtime, async move {
+                let mut rpc_system =
+                    descriptor.connect_with_policy(core::IPCPolicy::External)?;
+                let client: hello::Client = rpc_system.bootstrap(Side::Server);
+                tokio::task::spawn_local(rpc_system);
+                say_hello(client, name).await
+            })
+        };
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let cookie = Cookie::new();
+        let copy = Cookie::from(&cookie.0[..]).expect("cookie has the right size");
+        let mut server = Server::new(descriptor.clone())?;
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || {
+            server.serve_connections(listener, cookie)
+        });
+
+        descriptor.write_rendezvous(
+            &mut CookieFile::open(descriptor.rendez_vous())?,
+            &Cookie::new(), addr)?;
+        let err = tcp_hello(&descriptor, "rejected")
+            .expect_err("the wrong cookie is rejected");
+        assert!(matches!(err.downcast_ref::<ConnectError>(),
+                         Some(ConnectError::CookieRejected(a)) if *a == addr),
+                "{:?}", err);
+
+        descriptor.write_rendezvous(
+            &mut CookieFile::open(descriptor.rendez_vous())?, &copy, addr)?;
+        assert_eq!(tcp_hello(&descriptor, "tcp")?, "Hello tcp!");
+
+        shutdown.shutdown()?;
+        server.join().expect("no panic")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bootstrap_async() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+
+        let _server = descriptor.bootstrap_async().await?
+            .expect("server started");
+        // The second time around, the server is already running.
+        assert!(descriptor.bootstrap_async().await?.is_none());
+        assert!(matches!(descriptor.plan_connect()?, ConnectPlan::Connect(_)));
+
+        // `with_client` starts its own runtime, which it can't do
+        // from within ours.
+        let response = thread::spawn(move || {
+            descriptor.with_client(|hello: hello::Client| async move {
+                say_hello(hello, "async").await
+            })
+        }).join().expect("no panic")?;
+        assert_eq!(response, "Hello async!");
+        Ok(())
+    }
+
+    // Only Linux routes all of 127.0.0.0/8 to the loopback interface
+    // by default.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn bind_address() -> Result<()> {
+        let address = Ipv4Addr::new(127, 0, 0, 2);
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .bind_address(address.into())
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "interface").await
+            })?;
+        assert_eq!(response, "Hello interface!");
+        match descriptor.plan_connect()? {
+            ConnectPlan::Connect(addr) =>
+                assert_eq!(addr.ip(), std::net::IpAddr::from(address)),
+            plan => panic!("unexpected plan: {:?}", plan),
+        }
+        Ok(())
+    }
+
+    // Other systems may refuse connections if the backlog is full,
+    // instead of dropping them.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn bootstrap_timeout() -> Result<()> {
+        /// Held by the test to stall the server before it accepts
+        /// connections.
+        static GATE: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+        fn gated(descriptor: Descriptor, local: &tokio::task::LocalSet)
+                 -> Result<Box<dyn Handler>>
+        {
+            drop(GATE.lock().unwrap_or_else(|err| err.into_inner()));
+            Hello::factory(descriptor, local)
+        }
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let mut descriptor = Descriptor::new(
+            &ctx, rendezvous_path(&ctx, "gated"), ctx.lib().join("gated"),
+            gated);
+
+        // A wedged server: it is listening, but doesn't accept
+        // connections, and its backlog is full.
+        let gate = GATE.lock().unwrap_or_else(|err| err.into_inner());
+        descriptor.bootstrap()?.expect("server started");
+        let addr = match descriptor.plan_connect()? {
+            ConnectPlan::Connect(addr) => addr,
+            plan => panic!("unexpected plan: {:?}", plan),
+        };
+        // Each connection in the backlog takes a file descriptor.
+        unsafe {
+            let mut limit: libc::rlimit = std::mem::zeroed();
+            if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) == 0 {
+                limit.rlim_cur = limit.rlim_max;
+                libc::setrlimit(libc::RLIMIT_NOFILE, &limit);
+            }
+        }
+        let mut backlog = Vec::new();
+        loop {
+            match TcpStream::connect_timeout(&addr, Duration::from_millis(100)) {
+                Ok(s) => backlog.push(s),
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => break,
+                Err(err) => return Err(err.into()),
+            }
+            assert!(backlog.len() < 10_000, "the backlog is not bounded");
+        }
+        let rendezvous = fs::read(descriptor.rendez_vous())?;
+
+        let started = std::time::Instant::now();
+        let err = descriptor.bootstrap().err().expect("times out");
+        assert_eq!(err.downcast_ref::<ConnectError>(),
+                   Some(&ConnectError::TimedOut(addr)));
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let err = runtime.block_on(descriptor.bootstrap_async())
+            .err().expect("times out");
+        assert_eq!(err.downcast_ref::<ConnectError>(),
+                   Some(&ConnectError::TimedOut(addr)));
+
+        let err = descriptor.ensure_started().err().expect("times out");
+        assert_eq!(err.downcast_ref::<ConnectError>(),
+                   Some(&ConnectError::TimedOut(addr)));
+
+        // The server is running, so its rendezvous point is not
+        // stale.
+        let services = ctx.discover_services()?;
+        assert_eq!(services.len(), 1);
+        assert!(services[0].is_live());
+        assert_eq!(ctx.gc_rendezvous()?, 0);
+        assert!(started.elapsed() < 20 * PROBE_TIMEOUT);
+
+        // No server was started in its place.
+        assert_eq!(fs::read(descriptor.rendez_vous())?, rendezvous);
+
+        // Once the server accepts connections, it serves clients.
+        drop(backlog);
+        drop(gate);
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "patient").await
+            })?;
+        assert_eq!(response, "Hello patient!");
+        Ok(())
+    }
+
+    #[test]
+    fn memory_rendezvous() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let backend = MemoryRendezvous::new();
+        let mut descriptor = DescriptorBuilder::new(
+            &ctx, rendezvous_path(&ctx, "memory"), ctx.lib().join("hello"),
+            Hello::factory)
+            .rendezvous(backend.clone())
+            .build();
+
+        assert_eq!(descriptor.plan_connect()?, ConnectPlan::StartInternal);
+        descriptor.bootstrap()?.expect("server started");
+        let addr = match descriptor.plan_connect()? {
+            ConnectPlan::Connect(addr) => addr,
+            plan => panic!("unexpected plan: {:?}", plan),
+        };
+        assert!(descriptor.bootstrap()?.is_none());
+
+        // The rendezvous point is stored in memory, not in the file.
+        assert!(! descriptor.rendez_vous().exists());
+        let (_cookie, rest) = Cookie::extract(backend.read()?)
+            .expect("has a cookie");
+        assert_eq!(rest, addr.to_string().as_bytes());
+
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "memory").await
+            })?;
+        assert_eq!(response, "Hello memory!");
+        Ok(())
+    }
+
+    /// Checks that clients connect to a running server without taking
+    /// the lock.
+    #[test]
+    fn connect_unlocked() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// Counts how often the lock is taken.
+        #[derive(Debug, Default)]
+        struct Counting {
+            backend: MemoryRendezvous,
+            locks: Arc<AtomicUsize>,
+        }
+
+        impl Rendezvous for Counting {
+            fn lock(&self) -> Result<Box<dyn RendezvousLock>> {
+                self.locks.fetch_add(1, Ordering::SeqCst);
+                self.backend.lock()
+            }
+
+            fn read_unlocked(&self) -> Result<Vec<u8>> {
+                self.backend.read_unlocked()
+            }
+        }
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let backend = Counting::default();
+        let locks = backend.locks.clone();
+        let memory = backend.backend.clone();
+        let descriptor = DescriptorBuilder::new(
+            &ctx, rendezvous_path(&ctx, "unlocked"), ctx.lib().join("hello"),
+            Hello::factory)
+            .rendezvous(backend)
+            .build();
+
+        descriptor.bootstrap()?.expect("server started");
+        let before = locks.load(Ordering::SeqCst);
+
+        // The lock is held by another client, yet we can connect.
+        let guard = memory.lock()?;
+        for i in 0..10 {
+            let name = format!("unlocked {}", i);
+            let response = descriptor.with_client(
+                |hello: hello::Client| async move {
+                    say_hello(hello, &name).await
+                })?;
+            assert_eq!(response, format!("Hello unlocked {}!", i));
+        }
+        assert_eq!(locks.load(Ordering::SeqCst), before);
+
+        drop(guard);
+
+        // If the server is gone, we fall back to the lock.
+        let gone = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let mut content = Cookie::new().0.to_vec();
+        content.extend_from_slice(gone.to_string().as_bytes());
+        memory.write(&content)?;
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "locked").await
+            })?;
+        assert_eq!(response, "Hello locked!");
+        assert_eq!(locks.load(Ordering::SeqCst), before + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn lock_unsupported() {
+        let path = Path::new("/nfs/home/.sequoia/keystore.cookie");
+        let unsupported = |err: &anyhow::Error| matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::LockUnsupported(p)) if p == path);
+
+        let errno = platform! {
+            unix => libc::ENOLCK,
+            windows => 50
+        };
+        let err = lock_error(path, io::Error::from_raw_os_error(errno));
+        assert!(unsupported(&err), "{:?}", err);
+        // The original error is preserved.
+        assert_eq!(err.root_cause().downcast_ref::<io::Error>()
+                   .and_then(|e| e.raw_os_error()), Some(errno));
+
+        let err = lock_error(path, io::ErrorKind::Unsupported.into());
+        assert!(unsupported(&err), "{:?}", err);
+
+        // Other errors are passed through.
+        let err = lock_error(path, io::ErrorKind::PermissionDenied.into());
+        assert!(! unsupported(&err), "{:?}", err);
+        assert_eq!(err.root_cause().downcast_ref::<io::Error>()
+                   .map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+    }
+
+    /// A client crashed while writing the rendezvous point.
+    #[test]
+    fn half_written_rendezvous() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let mut descriptor = hello_descriptor(&ctx);
+        let mut half_written = Cookie::new().0.to_vec();
+        half_written.extend_from_slice(b"127.0.0.1:");
+
+        fs::create_dir_all(ctx.home())?;
+        fs::write(descriptor.rendez_vous(), &half_written)?;
+        assert_eq!(descriptor.plan_connect()?, ConnectPlan::StartInternal);
+        descriptor.bootstrap()?.expect("server started");
+        assert!(matches!(descriptor.plan_connect()?, ConnectPlan::Connect(_)));
+
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "healed").await
+            })?;
+        assert_eq!(response, "Hello healed!");
+
+        // Clients heal it, too.
+        let descriptor = DescriptorBuilder::new(
+            &ctx, rendezvous_path(&ctx, "client"), ctx.lib().join("hello"),
+            Hello::factory).build();
+        fs::write(descriptor.rendez_vous(), &half_written)?;
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "client").await
+            })?;
+        assert_eq!(response, "Hello client!");
+        // Internal servers started by clients are not recorded.
+        assert_eq!(fs::read(descriptor.rendez_vous())?, b"");
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_rendezvous() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let _guard = runtime.enter();
+        let descriptor = hello_descriptor(&ctx);
+
+        // A huge, sparse file.  If we tried to read it, we would
+        // allocate a gigabyte.
+        fs::create_dir_all(ctx.home())?;
+        let mut stale = Cookie::new().0.to_vec();
+        stale.extend_from_slice(b"127.0.0.1:1");
+        fs::write(descriptor.rendez_vous(), &stale)?;
+        fs::OpenOptions::new().write(true).open(descriptor.rendez_vous())?
+            .set_len(1 << 30)?;
+
+        let malformed = |err: &anyhow::Error| matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::MalformedRendezvous(p, _))
+                if p == descriptor.rendez_vous());
+        let err = CookieFile::open(descriptor.rendez_vous())?.read()
+            .err().expect("too large");
+        assert!(malformed(&err));
+        let err = CookieFile::open(descriptor.rendez_vous())?.read_address()
+            .err().expect("too large");
+        assert!(malformed(&err));
+
+        let err = descriptor.connect().err().expect("too large");
+        assert!(malformed(&err));
+        assert_eq!(err.downcast_ref::<ConnectError>(),
+                   Some(&ConnectError::Rendezvous(
+                       descriptor.rendez_vous().to_path_buf())));
+
+        // The service is reported as not live, and the rendezvous
+        // point is collected.
+        let services = ctx.discover_services()?;
+        assert_eq!(services.len(), 1);
+        assert_eq!(services[0].address(), None);
+        assert!(! services[0].is_live());
+        assert_eq!(ctx.gc_rendezvous()?, 1);
+
+        // Files up to the limit are read.
+        let mut large = stale.clone();
+        large.resize(MAX_RENDEZVOUS_SIZE as usize, b' ');
+        fs::write(descriptor.rendez_vous(), &large)?;
+        let (_, rest) = CookieFile::open(descriptor.rendez_vous())?.read()?
+            .expect("has a cookie");
+        assert_eq!(rest.len(), large.len() - Cookie::SIZE);
+        Ok(())
+    }
+
+    #[test]
+    fn hand_off() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+
+        // Start the old server by hand, so that we get a handoff
+        // handle.
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let cookie = Cookie::new();
+        descriptor.write_rendezvous(
+            &mut CookieFile::open(descriptor.rendez_vous())?, &cookie, addr)?;
+        let handshake = |cookie: &Cookie| -> Result<TcpStream> {
+            let mut s = TcpStream::connect(addr)?;
+            cookie.send(&mut s)?;
+            send_trace_id(&mut s, "")?;
+            Ok(s)
+        };
+        let copy = Cookie::from(&cookie.0[..]).expect("cookie has the right size");
+        let mut server = Server::new(descriptor.clone())?;
+        let handle = server.handoff_handle();
+        let old = thread::spawn(move || {
+            server.serve_connections(listener, cookie)
+        });
+
+        let hello = |name: &'static str| descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, name).await
+            });
+        assert_eq!(hello("old")?, "Hello old!");
+        let rendezvous = fs::read(descriptor.rendez_vous())?;
+
+        // The old server is busy serving a client, so the handoff has
+        // to wait.
+        let busy = handshake(&copy)?;
+        let new = {
+            let descriptor = descriptor.clone();
+            let handle = handle.clone();
+            thread::spawn(move || {
+                descriptor.hand_off(&handle, core::IPCPolicy::Internal)
+            })
+        };
+        std::thread::sleep(Duration::from_millis(100));
+
+        // A client that is waiting in the backlog during the handoff.
+        let mut waiting = handshake(&copy)?;
+
+        drop(busy);
+        let new = new.join().expect("no panic")?
+            .expect("server thread started");
+        // The old server exits once it has handed off the listener.
+        old.join().expect("no panic")?;
+        assert!(handle.request(HANDOFF_TIMEOUT).is_err());
+
+        // The waiting client is served by the new server: the server
+        // waits for our RPC, it didn't close the connection.
+        waiting.set_read_timeout(Some(Duration::from_millis(200)))?;
+        match waiting.read(&mut [0; 1]) {
+            Err(err) => assert!(matches!(err.kind(),
+                                         io::ErrorKind::WouldBlock
+                                         | io::ErrorKind::TimedOut)),
+            Ok(n) => panic!("unexpected read of {} bytes", n),
+        }
+        drop(waiting);
+
+        // The rendezvous point is still valid.
+        assert_eq!(fs::read(descriptor.rendez_vous())?, rendezvous);
+        assert_eq!(hello("new")?, "Hello new!");
+        assert!(! new.is_finished());
+        Ok(())
+    }
+
+    #[test]
+    fn hand_off_timeout() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let cookie = Cookie::new();
+        descriptor.write_rendezvous(
+            &mut CookieFile::open(descriptor.rendez_vous())?, &cookie, addr)?;
+        let copy = Cookie::from(&cookie.0[..]).expect("cookie has the right size");
+        let mut server = Server::new(descriptor.clone())?;
+        let handle = server.handoff_handle();
+        let connections = server.connections_handle();
+        let old = thread::spawn(move || {
+            server.serve_connections(listener, cookie)
+        });
+        let hello = |name: &'static str| descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, name).await
+            });
+        assert_eq!(hello("old")?, "Hello old!");
+
+        // The server is busy with a connection that doesn't end, so
+        // the request is withdrawn.  The byte following the trace ID
+        // starts the RPC stream.
+        let mut busy = TcpStream::connect(addr)?;
+        copy.send(&mut busy)?;
+        send_trace_id(&mut busy, "")?;
+        busy.write_all(&[0])?;
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while connections.list().is_empty() {
+            assert!(std::time::Instant::now() < deadline, "not served");
+            thread::yield_now();
+        }
+        let err = handle.request(Duration::from_millis(100))
+            .err().expect("timed out");
+        assert_eq!(err.downcast_ref::<ConnectError>(),
+                   Some(&ConnectError::TimedOut(addr)));
+
+        // The server keeps serving.
+        drop(busy);
+        assert_eq!(hello("still old")?, "Hello still old!");
+        assert!(! old.is_finished());
+
+        // And can still be handed off.
+        let handoff = handle.request(HANDOFF_TIMEOUT)?;
+        assert_eq!(handoff.listener.local_addr()?, addr);
+        old.join().expect("no panic")?;
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+
+        // Start the server by hand, so that we get a shutdown handle.
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let cookie = Cookie::new();
+        descriptor.write_rendezvous(
+            &mut CookieFile::open(descriptor.rendez_vous())?, &cookie, addr)?;
+        let mut server = Server::new(descriptor.clone())?;
+        let handle = server.shutdown_handle();
+        let server = thread::spawn(move || {
+            server.serve_connections(listener, cookie)
+        });
+
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "shutdown").await
+            })?;
+        assert_eq!(response, "Hello shutdown!");
+
+        // The server is busy serving a client, so it finishes that
+        // first: the client can still make calls after the shutdown
+        // has been requested.
+        let busy = handle.clone();
+        let responses = descriptor.with_client(
+            |hello: hello::Client| async move {
+                let before = say_hello(hello.clone(), "before").await?;
+                busy.shutdown()?;
+                let after = say_hello(hello, "after").await?;
+                Ok((before, after))
+            })?;
+        assert_eq!(responses,
+                   ("Hello before!".to_string(), "Hello after!".to_string()));
+
+        // Once the client is gone, the server stops.
+        server.join().expect("no panic")?;
+
+        // Shutting down a stopped server is fine.
+        handle.shutdown()?;
+        Ok(())
+    }
+
+    #[test]
+    fn server_builder() -> Result<()> {
+        use std::sync::mpsc;
+
+        // The context doesn't set any of the options.
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let cookie = Cookie::new();
+        descriptor.write_rendezvous(
+            &mut CookieFile::open(descriptor.rendez_vous())?, &cookie, addr)?;
+        let copy = Cookie::from(&cookie.0[..]).expect("cookie has the right size");
+        let (sender, receiver) = mpsc::channel();
+        let mut options = ReaderOptions::new();
+        options.traversal_limit_in_words(Some(1 << 20));
+        let mut server = Server::builder(descriptor.clone())
+            .on_ready(move || sender.send(()).unwrap())
+            .idle_timeout(Duration::from_millis(100))
+            .compression()
+            .reader_options(options)
+            .build()?;
+        let handle = server.shutdown_handle();
+        let server = thread::spawn(move || {
+            server.serve_connections(listener, cookie)
+        });
+        receiver.recv_timeout(Duration::from_secs(10))?;
+
+        // A compressed connection works.
+        let compressed = core::Context::configure()
+            .home(ctx.home())
+            .ipc_policy(core::IPCPolicy::Internal)
+            .compression()
+            .build()?;
+        let response = hello_descriptor(&compressed).with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "builder").await
+            })?;
+        assert_eq!(response, "Hello builder!");
+
+        // Idle connections are closed.
+        let mut idle = TcpStream::connect(addr)?;
+        copy.send(&mut idle)?;
+        send_trace_id(&mut idle, "")?;
+        idle.set_read_timeout(Some(Duration::from_secs(10)))?;
+        assert_eq!(idle.read(&mut [0; 1])?, 0);
+
+        handle.shutdown()?;
+        server.join().expect("no panic")?;
+        Ok(())
+    }
+
+    /// The builder's compression and reader options apply to the
+    /// connections.
+    #[test]
+    fn server_builder_settings() -> Result<()> {
+        use std::sync::Mutex;
+
+        /// Whether the connections were compressed, and their
+        /// traversal limits.
+        static SEEN: Mutex<Vec<(bool, Option<usize>)>> = Mutex::new(Vec::new());
+
+        struct Recording(Hello);
+
+        impl Handler for Recording {
+            fn handle_connection(&self, connection: Connection)
+                                 -> Result<RpcSystem<Side>>
+            {
+                #[cfg(feature = "compression")]
+                let compressed = matches!(connection.reader.0,
+                                          ConnectionReaderInner::Deflate(_));
+                #[cfg(not(feature = "compression"))]
+                let compressed = false;
+                SEEN.lock().unwrap().push(
+                    (compressed,
+                     connection.reader_options.traversal_limit_in_words));
+                self.0.handle_connection(connection)
+            }
+        }
+
+        fn factory(descriptor: Descriptor, _: &tokio::task::LocalSet)
+                   -> Result<Box<dyn Handler>>
+        {
+            Ok(Box::new(Recording(Hello {
+                c: capnp_rpc::new_client(HelloServer {}),
+                ctx: descriptor.context().clone(),
+            })))
+        }
+
+        // The context doesn't set any of the options.
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = Descriptor::new(
+            &ctx, rendezvous_path(&ctx, "hello"), ctx.lib().join("hello"),
+            factory);
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let cookie = Cookie::new();
+        descriptor.write_rendezvous(
+            &mut CookieFile::open(descriptor.rendez_vous())?, &cookie, addr)?;
+        let mut options = ReaderOptions::new();
+        options.traversal_limit_in_words(Some(1024));
+        let mut server = Server::builder(descriptor.clone())
+            .compression()
+            .reader_options(options)
+            .build()?;
+        let handle = server.shutdown_handle();
+        let server = thread::spawn(move || {
+            server.serve_connections(listener, cookie)
+        });
+
+        // Clients offering compression get it, if it is supported,
+        // others don't.
+        let compressed = core::Context::configure()
+            .home(ctx.home())
+            .ipc_policy(core::IPCPolicy::Internal)
+            .compression()
+            .build()?;
+        for (ctx, name) in [(&compressed, "compressed"), (&ctx, "plain")] {
+            let response = hello_descriptor(ctx).with_client(
+                |hello: hello::Client| async move {
+                    say_hello(hello, name).await
+                })?;
+            assert_eq!(response, format!("Hello {}!", name));
+        }
+        assert_eq!(*SEEN.lock().unwrap(),
+                   [(! Compression::SUPPORTED.is_empty(), Some(1024)),
+                    (false, Some(1024))]);
+
+        // Messages exceeding the traversal limit are rejected.
+        let name = "x".repeat(64 * 1024);
+        assert!(hello_descriptor(&ctx).with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, &name).await
+            }).is_err());
+
+        handle.shutdown()?;
+        server.join().expect("no panic")?;
+        Ok(())
+    }
+
+    #[test]
+    fn wait_ready() -> Result<()> {
+        use std::time::Instant;
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+
+        // Nothing starts the server.
+        let descriptor = Descriptor::new(
+            &ctx, rendezvous_path(&ctx, "never"), ctx.lib().join("never"),
+            Hello::factory);
+        let timeout = Duration::from_millis(300);
+        let start = Instant::now();
+        assert!(descriptor.wait_ready(timeout).is_err());
+        assert!(start.elapsed() >= timeout);
+        assert!(start.elapsed() < Duration::from_secs(10));
+        // We didn't start it either.
+        assert_eq!(descriptor.plan_connect()?, ConnectPlan::StartInternal);
+
+        // The server's socket accepts connections right away, but the
+        // server only answers RPCs after a while.
+        let descriptor = hello_descriptor(&ctx);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let cookie = Cookie::new();
+        let mut file = CookieFile::open(descriptor.rendez_vous())?;
+        descriptor.write_rendezvous(&mut file, &cookie, addr)?;
+        drop(file);
+
+        let delay = Duration::from_millis(200);
+        let mut server = Server::new(descriptor.clone())?;
+        let shutdown = server.shutdown_handle();
+        let start = Instant::now();
+        let server = thread::spawn(move || {
+            thread::sleep(delay);
+            server.serve_connections(listener, cookie)
+        });
+
+        descriptor.wait_ready(Duration::from_secs(10))?;
+        assert!(start.elapsed() >= delay);
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        // Once it is ready, it returns promptly.
+        let start = Instant::now();
+        descriptor.wait_ready(Duration::from_secs(10))?;
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "ready").await
+            })?;
+        assert_eq!(response, "Hello ready!");
+
+        shutdown.shutdown()?;
+        server.join().expect("no panic")?;
+        Ok(())
+    }
+
+    /// Checks that SIGHUP reloads the configuration, and that
+    /// clients stay connected.
+    ///
+    /// The signal is sent to the whole process, and the test
+    /// replaces the process's handler for it.  So that this doesn't
+    /// interfere with the tests running concurrently, the test
+    /// re-executes the test binary, and runs in the child.
+    #[cfg(unix)]
+    #[test]
+    fn reload_on_sighup() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Instant;
+
+        /// Set for the re-executed test binary.
+        const CHILD_VAR: &str = "SEQUOIA_IPC_TEST_SIGHUP_CHILD";
+
+        if std::env::var_os(CHILD_VAR).is_none() {
+            let output = std::process::Command::new(std::env::current_exe()?)
+                .args(["--exact", "test::reload_on_sighup",
+                       "--test-threads=1"])
+                .env(CHILD_VAR, "1")
+                .stdin(std::process::Stdio::null())
+                .output()?;
+            assert!(output.status.success(),
+                    "reload_on_sighup failed in the child: {}\n{}\n{}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stdout),
+                    String::from_utf8_lossy(&output.stderr));
+            // Make sure the test actually ran.
+            assert!(String::from_utf8_lossy(&output.stdout)
+                    .contains("1 passed"));
+            return Ok(());
+        }
+
+        static RELOADS: AtomicUsize = AtomicUsize::new(0);
+
+        /// Counts the reloads.
+        struct Reloading(Hello);
+
+        impl Reloading {
+            fn factory(descriptor: Descriptor, _: &tokio::task::LocalSet)
+                       -> Result<Box<dyn Handler>>
+            {
+                Ok(Box::new(Reloading(Hello {
+                    c: capnp_rpc::new_client(HelloServer {}),
+                    ctx: descriptor.context().clone(),
+                })))
+            }
+        }
+
+        impl Handler for Reloading {
+            fn handle_connection(&self, connection: Connection)
+                                 -> Result<RpcSystem<Side>>
+            {
+                self.0.handle_connection(connection)
+            }
+
+            fn reload(&self) -> Result<()> {
+                RELOADS.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        }
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = Descriptor::new(
+            &ctx, rendezvous_path(&ctx, "reload"), ctx.lib().join("reload"),
+            Reloading::factory);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let cookie = Cookie::new();
+        let mut file = CookieFile::open(descriptor.rendez_vous())?;
+        descriptor.write_rendezvous(&mut file, &cookie, addr)?;
+        drop(file);
+
+        let (ready, is_ready) = std::sync::mpsc::channel();
+        let mut server = Server::builder(descriptor.clone())
+            .reload_on_sighup()
+            .on_ready(move || {
+                let _ = ready.send(());
+            })
+            .build()?;
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || {
+            server.serve_connections(listener, cookie)
+        });
+        is_ready.recv_timeout(Duration::from_secs(10))?;
+
+        // Only one server may reload on SIGHUP.
+        assert!(sighup::Guard::install().is_err());
+
+        let responses = descriptor.with_client(
+            |hello: hello::Client| async move {
+                let before = say_hello(hello.clone(), "before").await?;
+
+                // Safety: kill is safe to call.
+                assert_eq!(unsafe {
+                    libc::kill(libc::getpid(), libc::SIGHUP)
+                }, 0);
+                let start = Instant::now();
+                while RELOADS.load(Ordering::SeqCst) == 0 {
+                    assert!(start.elapsed() < Duration::from_secs(10));
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+
+                // The connection survived the reload.
+                let after = say_hello(hello, "after").await?;
+                Ok((before, after))
+            })?;
+        assert_eq!(responses,
+                   ("Hello before!".to_string(), "Hello after!".to_string()));
+        assert_eq!(RELOADS.load(Ordering::SeqCst), 1);
+
+        shutdown.shutdown()?;
+        server.join().expect("no panic")?;
+
+        // Once the server stopped, another one may reload on SIGHUP.
+        drop(sighup::Guard::install()?);
+        Ok(())
+    }
+
+    #[test]
+    fn cancel_connection() -> Result<()> {
+        use std::time::Instant;
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let cookie = Cookie::new();
+        let copy = Cookie::from(&cookie.0[..]).expect("cookie has the right size");
+        let mut server = Server::new(descriptor)?;
+        let connections = server.connections_handle();
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || {
+            server.serve_connections(listener, cookie)
+        });
+
+        // Connects, and sends the start of an RPC message, so that
+        // the connection stays open.
+        let connect = |trace_id: &str| -> Result<TcpStream> {
+            let mut s = TcpStream::connect(addr)?;
+            copy.send(&mut s)?;
+            send_trace_id(&mut s, trace_id)?;
+            s.write_all(&[0])?;
+            Ok(s)
+        };
+        let wait_for = |trace_id: &str| -> Result<ConnectionInfo> {
+            let start = Instant::now();
+            loop {
+                let list = connections.list();
+                assert!(list.len() <= 1);
+                if let Some(c) = list.into_iter()
+                    .find(|c| c.trace_id() == Some(trace_id))
+                {
+                    return Ok(c);
+                }
+                assert!(start.elapsed() < Duration::from_secs(10));
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        let mut first = connect("first")?;
+        let info = wait_for("first")?;
+        assert_eq!(info.peer(), first.local_addr()?);
+
+        // The second client waits until the first one is done.
+        let mut second = connect("second")?;
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(connections.list(), vec![info.clone()]);
+
+        assert!(! connections.cancel(info.id() + 1)?);
+        assert!(connections.cancel(info.id())?);
+        assert!(! connections.cancel(info.id())?);
+        first.set_read_timeout(Some(Duration::from_secs(10)))?;
+        assert_eq!(first.read(&mut [0; 1])?, 0);
+
+        // Now, the second client is served, and its connection
+        // survives.
+        let second_info = wait_for("second")?;
+        assert_ne!(second_info.id(), info.id());
+        assert_eq!(second_info.peer(), second.local_addr()?);
+        second.set_read_timeout(Some(Duration::from_millis(100)))?;
+        let err = second.read(&mut [0; 1]).unwrap_err();
+        assert!(matches!(err.kind(), io::ErrorKind::WouldBlock
+                         | io::ErrorKind::TimedOut));
+
+        shutdown.shutdown()?;
+        drop(second);
+        server.join().expect("no panic")?;
+        assert!(connections.list().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn capabilities_versions() -> Result<()> {
+        let v = |version: &str| Capabilities::new(version).expect("valid");
+        assert!(v("1.2.3").is_at_least("1.2.3"));
+        assert!(v("1.2.3").is_at_least("1.2"));
+        assert!(v("1.2").is_at_least("1.2.0"));
+        assert!(v("1.10").is_at_least("1.9"));
+        assert!(v("2").is_at_least("1.99.99"));
+        assert!(v("1.2.3-beta").is_at_least("1.2.3"));
+        assert!(! v("1.2.3").is_at_least("1.2.4"));
+        assert!(! v("1.9").is_at_least("1.10"));
+        assert!(! v("").is_at_least("0.1"));
+        assert!(! v("unknown").is_at_least("1"));
+        assert!(v("").is_at_least("0"));
+
+        assert!(Capabilities::new(&"1".repeat(256)).is_err());
+        assert!(v("1").capability("").is_err());
+        assert!(v("1").capability(&"x".repeat(256)).is_err());
+
+        // Encoding roundtrips.
+        let caps = v("1.2.3").capability("a")?.capability("bc")?
+            .capability("a")?;
+        assert_eq!(caps.iter().collect::<Vec<_>>(), ["a", "bc"]);
+        let encoded = caps.encode();
+        assert_eq!(&encoded[..4], b"CAPS");
+        assert_eq!(read_capabilities_header(
+            encoded[..7].try_into().expect("seven bytes"))?,
+                   encoded.len() - 7);
+        assert_eq!(Capabilities::parse(&encoded[7..])?, caps);
+        assert_eq!(Capabilities::parse(&Capabilities::default().encode()[7..])?,
+                   Capabilities::default());
+        // Trailing garbage and truncation are rejected.
+        let mut garbage = encoded[7..].to_vec();
+        garbage.push(0);
+        assert!(Capabilities::parse(&garbage).is_err());
+        assert!(Capabilities::parse(&encoded[7..encoded.len() - 1]).is_err());
+
+        assert!(DescriptorBuilder::new(
+            &core::Context::configure().ephemeral().build()?,
+            PathBuf::from("rendezvous"), PathBuf::from("server"),
+            unused_factory)
+                .min_server_version("latest").is_err());
+        Ok(())
+    }
+
+    /// A handler advertising its version and capabilities.
+    struct Versioned(Hello);
+
+    impl Versioned {
+        fn factory(descriptor: Descriptor, _: &tokio::task::LocalSet)
+                   -> Result<Box<dyn Handler>>
+        {
+            Ok(Box::new(Versioned(Hello {
+                c: capnp_rpc::new_client(HelloServer {}),
+                ctx: descriptor.context().clone(),
+            })))
+        }
+
+        fn capabilities() -> Capabilities {
+            Capabilities::new("2.1.0")
+                .and_then(|c| c.capability("frobnicate"))
+                .expect("valid")
+        }
+    }
+
+    impl Handler for Versioned {
+        fn handle_connection(&self, connection: Connection)
+                             -> Result<RpcSystem<Side>>
+        {
+            self.0.handle_connection(connection)
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Versioned::capabilities()
+        }
+    }
+
+    #[test]
+    fn capabilities_exchange() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = |min: &str, capability: &str| -> Result<Descriptor> {
+            Ok(DescriptorBuilder::new(
+                &ctx, rendezvous_path(&ctx, "versioned"),
+                ctx.lib().join("versioned"), Versioned::factory)
+               .min_server_version(min)?
+               .require_capability(capability)
+               .build())
+        };
+
+        // A compatible server.
+        let compatible = descriptor("2.1", "frobnicate")?;
+        assert_eq!(compatible.server_capabilities(), None);
+        let response = compatible.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "compatible").await
+            })?;
+        assert_eq!(response, "Hello compatible!");
+        assert_eq!(compatible.server_capabilities(),
+                   Some(Versioned::capabilities()));
+
+        // Servers that are too old, or lack a capability, are refused
+        // before any RPC is made.
+        for (min, capability) in [("2.2", "frobnicate"), ("2", "defrost")] {
+            let incompatible = descriptor(min, capability)?;
+            let err = incompatible.with_client(
+                |hello: hello::Client| async move {
+                    say_hello(hello, "incompatible").await
+                }).unwrap_err();
+            assert_eq!(err.downcast_ref::<ConnectError>(),
+                       Some(&ConnectError::Incompatible));
+            assert!(err.chain().any(|e| matches!(
+                e.downcast_ref::<Error>(),
+                Some(Error::IncompatibleServer(_)))), "{:#}", err);
+            assert_eq!(incompatible.server_capabilities(), None);
+        }
+        Ok(())
+    }
+
+    /// The server records the capabilities the client advertised.
+    #[test]
+    fn capabilities_connection_info() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let cookie = Cookie::new();
+        let copy = Cookie::from(&cookie.0[..]).expect("cookie has the right size");
+        let mut server = Server::new(descriptor)?;
+        let connections = server.connections_handle();
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || {
+            server.serve_connections(listener, cookie)
+        });
+
+        let ours = Capabilities::new("1.0")?.capability("streaming")?;
+        let mut s = TcpStream::connect(addr)?;
+        copy.send(&mut s)?;
+        // The hello server doesn't advertise anything.
+        assert_eq!(exchange_capabilities(&mut s, &ours, Duration::from_secs(10))?,
+                   Capabilities::default());
+        // The start of an RPC message, so that the connection stays
+        // open.
+        s.write_all(&[0])?;
+
+        let start = std::time::Instant::now();
+        let info = loop {
+            if let Some(info) = connections.list().pop() {
+                break info;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(info.capabilities(), Some(&ours));
+
+        shutdown.shutdown()?;
+        connections.cancel(info.id())?;
+        drop(s);
+        server.join().expect("no panic")?;
+        Ok(())
+    }
+
+    /// Clients wait for the server's capabilities for a bounded
+    /// time, even if the server trickles them.
+    #[test]
+    fn capabilities_timeout() -> Result<()> {
+        use std::time::Instant;
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let trickler = thread::spawn(move || -> io::Result<()> {
+            let (mut s, _) = listener.accept()?;
+            let header = Capabilities::default().encode();
+            for byte in &header[..CAPABILITIES_MAGIC.len()] {
+                thread::sleep(Duration::from_millis(100));
+                if s.write_all(&[*byte]).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        });
+
+        let mut s = TcpStream::connect(addr)?;
+        let start = Instant::now();
+        let err = exchange_capabilities(&mut s, &Capabilities::default(),
+                                        Duration::from_millis(250))
+            .expect_err("timed out");
+        assert!(matches!(err.kind(),
+                         io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock),
+                "{}", err);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(s);
+        trickler.join().expect("no panic")?;
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_before_serving() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let mut server = Server::new(hello_descriptor(&ctx))?;
+        server.shutdown_handle().shutdown()?;
+
+        // The server returns instead of waiting for the first client.
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        server.serve_listener(listener)?;
+        Ok(())
+    }
+
+    #[test]
+    fn drop_server() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+
+        // A server that never served.
+        drop(Server::new(hello_descriptor(&ctx))?);
+
+        // A server whose runtime has a blocking task that doesn't
+        // finish anytime soon.
+        let server = Server::builder(hello_descriptor(&ctx))
+            .shutdown_timeout(Duration::from_millis(100))
+            .build()?;
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        server.runtime.as_ref().unwrap().spawn_blocking(move || {
+            let _ = started_tx.send(());
+            thread::sleep(Duration::from_secs(60));
+        });
+        started_rx.recv()?;
+
+        let start = std::time::Instant::now();
+        drop(server);
+        assert!(start.elapsed() < Duration::from_secs(30),
+                "dropping the server took {:?}", start.elapsed());
+        Ok(())
+    }
+
+    #[test]
+    fn keepalive() -> Result<()> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let socket = TcpStream::connect(listener.local_addr()?)?;
+
+        // By default, keepalive is off.
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        set_keepalive(&socket, &ctx)?;
+        assert!(! socket2::SockRef::from(&socket).keepalive()?);
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .keepalive(Duration::from_secs(30), Duration::from_secs(5), 3)
+            .build()?;
+        assert_eq!(ctx.keepalive(),
+                   Some((Duration::from_secs(30), Duration::from_secs(5), 3)));
+        set_keepalive(&socket, &ctx)?;
+        assert!(socket2::SockRef::from(&socket).keepalive()?);
+
+        // Clients and servers using it still talk to each other.
+        let response = hello_descriptor(&ctx).with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "alive").await
+            })?;
+        assert_eq!(response, "Hello alive!");
+        Ok(())
+    }
+
+    #[test]
+    fn first_cookie_timeout() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let cookie = Cookie::new();
+        descriptor.write_rendezvous(
+            &mut CookieFile::open(descriptor.rendez_vous())?, &cookie, addr)?;
+
+        // A client connects first, and never sends anything.
+        let stalled = TcpStream::connect(addr)?;
+
+        // Another one sends a bogus cookie, one byte at a time.  Each
+        // byte arrives before the timeout, but the whole cookie
+        // doesn't.
+        let mut trickling = TcpStream::connect(addr)?;
+        let trickling = thread::spawn(move || {
+            for _ in 0..HANDSHAKE_MAGIC.len() + 1 + Cookie::SIZE {
+                if trickling.write_all(&[0]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let mut server = Server::new(descriptor.clone())?;
+        server.cookie_timeout = Duration::from_millis(200);
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || server.serve_listener(listener));
+
+        // The client that started the server sends the cookie.
+        cookie.send(&mut TcpStream::connect(addr)?)?;
+
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "patient").await
+            })?;
+        assert_eq!(response, "Hello patient!");
+        drop(stalled);
+
+        shutdown.shutdown()?;
+        server.join().expect("no panic")?;
+        trickling.join().expect("no panic");
+        Ok(())
+    }
+
+    #[test]
+    fn slow_handshakes() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let cookie = Cookie::new();
+        descriptor.write_rendezvous(
+            &mut CookieFile::open(descriptor.rendez_vous())?, &cookie, addr)?;
+        let mut handshake = Vec::new();
+        cookie.send(&mut handshake)?;
+
+        let mut server = Server::new(descriptor.clone())?;
+        server.handshake_timeout = Duration::from_millis(500);
+        let shutdown = server.shutdown_handle();
+        let server =
+            thread::spawn(move || server.serve_connections(listener, cookie));
+
+        // More clients than can be handled concurrently trickle in
+        // the handshake, taking much longer than they may.
+        let slow = (0..MAX_PENDING_HANDSHAKES + 16)
+            .map(|_| TcpStream::connect(addr))
+            .collect::<io::Result<Vec<_>>>()?;
+        let trickler = thread::spawn(move || {
+            let mut slow = slow;
+            for byte in handshake {
+                for s in &mut slow {
+                    // The server may have closed the connection.
+                    let _ = s.write_all(&[byte]);
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+            slow
+        });
+
+        // A legitimate client is served nevertheless.
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "legitimate").await
+            })?;
+        assert_eq!(response, "Hello legitimate!");
+        assert!(! trickler.is_finished());
+
+        // The slow clients have been dropped.
+        for mut s in trickler.join().expect("no panic") {
+            s.set_read_timeout(Some(Duration::from_secs(10)))?;
+            match s.read(&mut [0; 1]) {
+                Ok(n) => assert_eq!(n, 0),
+                Err(err) => assert!(! matches!(err.kind(),
+                                               io::ErrorKind::WouldBlock
+                                               | io::ErrorKind::TimedOut),
+                                    "{}", err),
+            }
+        }
+
+        shutdown.shutdown()?;
+        server.join().expect("no panic")?;
+        Ok(())
+    }
+
+    #[test]
+    fn debug_port() -> Result<()> {
+        // Find a free port.
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+            .local_addr()?.port();
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .debug_port(port)
+            .build()?;
+
+        let address = |descriptor: &Descriptor| -> Result<SocketAddr> {
+            let (_, addr) = CookieFile::open(descriptor.rendez_vous())?
+                .read()?.expect("server started");
+            Ok(String::from_utf8(addr)?.parse()?)
+        };
+
+        let mut descriptor = hello_descriptor(&ctx);
+        descriptor.bootstrap()?.expect("server started");
+        assert_eq!(address(&descriptor)?.port(), port);
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "debug").await
+            })?;
+        assert_eq!(response, "Hello debug!");
+
+        // The port is taken now, the second server falls back to an
+        // ephemeral port.
+        let mut second = Descriptor::new(
+            &ctx, rendezvous_path(&ctx, "second"), ctx.lib().join("hello"),
+            Hello::factory);
+        second.bootstrap()?.expect("server started");
+        assert_ne!(address(&second)?.port(), port);
+        let response = second.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "fallback").await
+            })?;
+        assert_eq!(response, "Hello fallback!");
+        Ok(())
+    }
+
+    #[test]
+    fn out_of_band_cookie() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .out_of_band_cookie([0x42; Cookie::SIZE])
+            .build()?;
+        let mut descriptor = hello_descriptor(&ctx);
+        descriptor.bootstrap()?.expect("server started");
+
+        // The rendezvous point only contains the address, not the
+        // cookie.
+        let content = fs::read(descriptor.rendez_vous())?;
+        assert!(! content.windows(Cookie::SIZE)
+                .any(|w| w == [0x42; Cookie::SIZE]));
+        String::from_utf8(content)?.parse::<SocketAddr>()?;
+
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "out of band").await
+            })?;
+        assert_eq!(response, "Hello out of band!");
+        Ok(())
+    }
+
+    /// The number of calls `SlowHelloServer` is executing.
+    static SLOW_CALLS: std::sync::atomic::AtomicUsize =
+        std::sync::atomic::AtomicUsize::new(0);
+    /// The maximum of `SLOW_CALLS`.
+    static SLOW_CALLS_MAX: std::sync::atomic::AtomicUsize =
+        std::sync::atomic::AtomicUsize::new(0);
+
+    /// A hello server that takes its time to reply.
+    struct SlowHelloServer {}
+
+    impl hello::Server for SlowHelloServer {
+        fn hello(&mut self,
+                 _: hello::HelloParams,
+                 mut results: hello::HelloResults)
+                 -> capnp::capability::Promise<(), capnp::Error>
+        {
+            use std::sync::atomic::Ordering::SeqCst;
+
+            let n = SLOW_CALLS.fetch_add(1, SeqCst) + 1;
+            SLOW_CALLS_MAX.fetch_max(n, SeqCst);
+            capnp::capability::Promise::from_future(async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                SLOW_CALLS.fetch_sub(1, SeqCst);
+                results.get().set_response("Hello, eventually!");
+                Ok(())
+            })
+        }
+    }
+
+    fn slow_hello_factory(descriptor: Descriptor, _: &tokio::task::LocalSet)
+                          -> Result<Box<dyn Handler>>
+    {
+        Ok(Box::new(Hello {
+            c: capnp_rpc::new_client(SlowHelloServer {}),
+            ctx: descriptor.context().clone(),
+        }))
+    }
+
+    #[test]
+    fn max_concurrent_calls() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .max_concurrent_calls(2)
+            .build()?;
+        let descriptor = Descriptor::new(
+            &ctx, rendezvous_path(&ctx, "slow"), ctx.lib().join("slow"),
+            slow_hello_factory);
+
+        let results = descriptor.with_client(
+            |hello: hello::Client| async move {
+                let calls = (0..10).map(|_| {
+                    let hello = hello.clone();
+                    tokio::task::spawn_local(async move {
+                        say_hello(hello, "").await
+                    })
+                }).collect::<Vec<_>>();
+
+                let mut results = Vec::new();
+                for call in calls {
+                    results.push(call.await?);
+                }
+
+                // Once the calls completed, new calls are accepted.
+                assert_eq!(say_hello(hello, "").await?, "Hello, eventually!");
+                Ok(results)
+            })?;
+
+        let max = SLOW_CALLS_MAX.load(std::sync::atomic::Ordering::SeqCst);
+        assert!(max <= 2, "{} concurrent calls", max);
 
-    command
+        let ok = results.iter().filter(|r| r.is_ok()).count();
+        assert!(ok >= 2, "only {} calls succeeded", ok);
+        for r in results.iter().filter_map(|r| r.as_ref().err()) {
+            let err = r.downcast_ref::<capnp::Error>().expect("a capnp error");
+            assert_eq!(err.kind, capnp::ErrorKind::Overloaded);
+        }
+        assert!(ok < 10);
+        Ok(())
+    }
+
+    #[test]
+    fn audit() -> Result<()> {
+        let records = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = records.clone();
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .audit_methods(
+                <hello::Client as capnp::traits::HasTypeId>::TYPE_ID,
+                &["hello"])
+            .audit(move |record: &AuditRecord| {
+                recorded.lock().unwrap().push(record.clone());
+            })
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+
+        let before = std::time::SystemTime::now();
+        for _ in 0..2 {
+            descriptor.with_client(|hello: hello::Client| async move {
+                say_hello(hello.clone(), "secret").await?;
+                say_hello(hello, "secret").await?;
+                Ok(())
+            })?;
+        }
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 4);
+        for record in records.iter() {
+            assert_eq!(record.interface_id(),
+                       <hello::Client as capnp::traits::HasTypeId>::TYPE_ID);
+            assert_eq!(record.method_id(), 0);
+            assert_eq!(record.method(), Some("hello"));
+            assert!(record.timestamp() >= before);
+            // The parameters are not recorded.
+            assert!(! format!("{:?}", record).contains("secret"));
+        }
+
+        // The records identify the connection.
+        let connections = records.iter()
+            .map(|r| r.connection().expect("served by a server").id())
+            .collect::<Vec<_>>();
+        assert_eq!(connections[0], connections[1]);
+        assert_eq!(connections[2], connections[3]);
+        assert_ne!(connections[0], connections[2]);
+        assert!(records[0].connection().unwrap().peer().ip().is_loopback());
+        Ok(())
+    }
+
+    /// Starts a hello server on a thread, and records it in the
+    /// rendezvous point.
+    ///
+    /// Unlike the servers started by [`Descriptor::connect`], the
+    /// server can be killed: once `kill` is set, the thread exits,
+    /// closing the listener and all connections.
+    fn killable_hello_server(descriptor: &Descriptor,
+                             kill: std::sync::Arc<std::sync::atomic::AtomicBool>)
+                             -> Result<JoinHandle<Result<()>>>
+    {
+        use std::sync::atomic::Ordering;
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let cookie = Cookie::new();
+        CookieFile::open(descriptor.rendez_vous())?
+            .write(&cookie, listener.local_addr()?.to_string().as_bytes())?;
+
+        let descriptor = descriptor.clone();
+        Ok(thread::spawn(move || -> Result<()> {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            let local = tokio::task::LocalSet::new();
+            let handler = Hello::factory(descriptor, &local)?;
+
+            local.block_on(&runtime, async move {
+                listener.set_nonblocking(true)?;
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                tokio::task::spawn_local(async move {
+                    while let Ok((socket, peer)) = listener.accept().await {
+                        let mut stream = HandshakeStream::new(socket);
+                        match Cookie::receive_async(&mut stream.socket).await {
+                            Ok(c) if c == cookie => (),
+                            _ => continue,
+                        }
+                        if receive_capabilities(&mut stream,
+                                                &Capabilities::default())
+                            .await.is_err()
+                        {
+                            continue;
+                        }
+
+                        let (reader, writer) = stream.socket.into_split();
+                        let writer = ActivityWriter {
+                            inner: writer,
+                            last_activity: Rc::new(Cell::new(
+                                tokio::time::Instant::now())),
+                        };
+                        let connection = Connection {
+                            reader: ConnectionReader::new(
+                                reader, stream.buffer, Compression::None),
+                            writer: ConnectionWriter::new(
+                                writer, Compression::None),
+                            reader_options: Default::default(),
+                            info: ConnectionInfo::new(peer, None, None),
+                        };
+                        if let Ok(rpc_system) =
+                            handler.handle_connection(connection)
+                        {
+                            tokio::task::spawn_local(rpc_system);
+                        }
+                    }
+                });
+
+                while ! kill.load(Ordering::SeqCst) {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                Ok(())
+            })
+        }))
+    }
+
+    #[test]
+    fn connect_client() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let local = tokio::task::LocalSet::new();
+        local.block_on(&runtime, async move {
+            let hello = descriptor.connect_client::<hello::Client>()?;
+            assert_eq!(say_hello(hello.client().clone(), "first").await?,
+                       "Hello first!");
+            assert_eq!(say_hello((*hello).clone(), "second").await?,
+                       "Hello second!");
+            assert!(hello.is_connected());
+
+            // Dropping the connection closes it, and another client
+            // can connect to the same server.
+            drop(hello);
+            let hello = descriptor.connect_client::<hello::Client>()?;
+            assert_eq!(say_hello(hello.client().clone(), "third").await?,
+                       "Hello third!");
+
+            // Disconnecting closes it cleanly.
+            hello.disconnect().await?;
+            let hello = descriptor.connect_client::<hello::Client>()?;
+            assert_eq!(say_hello(hello.client().clone(), "fourth").await?,
+                       "Hello fourth!");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn reconnecting_client() -> Result<()> {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::Instant;
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+        let kill = Arc::new(AtomicBool::new(false));
+        let server = killable_hello_server(&descriptor, kill.clone())?;
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let local = tokio::task::LocalSet::new();
+        local.block_on(&runtime, async move {
+            let mut client = ReconnectingClient::<hello::Client>::new(descriptor);
+            assert!(! client.is_connected());
+            assert_eq!(say_hello(client.client()?, "first").await?,
+                       "Hello first!");
+            assert!(client.is_connected());
+
+            // Kill the server mid-session.
+            let stale = client.client()?;
+            kill.store(true, Ordering::SeqCst);
+            server.join().expect("server thread panicked")?;
+
+            // The client notices once its RPC system terminates.
+            let start = Instant::now();
+            while client.is_connected() {
+                assert!(start.elapsed() < Duration::from_secs(10));
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+
+            // Capabilities from the old connection are broken.
+            assert!(say_hello(stale, "stale").await.is_err());
+
+            // The next call transparently reconnects, starting a new
+            // server.
+            assert_eq!(say_hello(client.client()?, "second").await?,
+                       "Hello second!");
+            assert!(client.is_connected());
+
+            client.disconnect().await?;
+            assert!(! client.is_connected());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn clock_skew() -> Result<()> {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let mut descriptor = hello_descriptor(&ctx);
+        descriptor.bootstrap()?.expect("server started");
+        let content = fs::read(descriptor.rendez_vous())?;
+
+        // The age of the rendezvous point doesn't matter, even if
+        // the client's clock is way off.
+        for mtime in [
+            UNIX_EPOCH + Duration::from_secs(1),
+            SystemTime::now() - Duration::from_millis(500),
+            SystemTime::now() + Duration::from_millis(500),
+            SystemTime::now() + Duration::from_secs(365 * 24 * 60 * 60),
+        ] {
+            fs::File::options().write(true).open(descriptor.rendez_vous())?
+                .set_modified(mtime)?;
+            assert!(descriptor.bootstrap()?.is_none());
+            let response = descriptor.with_client(
+                |hello: hello::Client| async move {
+                    say_hello(hello, "skew").await
+                })?;
+            assert_eq!(response, "Hello skew!");
+            assert_eq!(fs::read(descriptor.rendez_vous())?, content);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn plan_connect() -> Result<()> {
+        let plan = |policy: core::IPCPolicy| -> Result<ConnectPlan> {
+            let ctx = core::Context::configure()
+                .ephemeral()
+                .ipc_policy(policy)
+                .build()?;
+            let descriptor = unused_descriptor(&ctx);
+            let plan = descriptor.plan_connect()?;
+            // The rendezvous point was not created.
+            assert!(! descriptor.rendez_vous().exists());
+            Ok(plan)
+        };
+
+        assert_eq!(plan(core::IPCPolicy::Internal)?, ConnectPlan::StartInternal);
+        if ! cfg!(feature = "no-subprocess") {
+            let ctx = core::Context::configure().ephemeral().build()?;
+            let exe = ctx.lib().join("unused");
+            assert_eq!(plan(core::IPCPolicy::External)?,
+                       ConnectPlan::StartExternal(exe.clone()));
+            assert_eq!(plan(core::IPCPolicy::Robust)?,
+                       ConnectPlan::StartExternalOrInternal(exe));
+        }
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = unused_descriptor(&ctx);
+
+        // A rendezvous point without an address.
+        fs::write(descriptor.rendez_vous(), b"")?;
+        assert_eq!(descriptor.plan_connect()?, ConnectPlan::StartInternal);
+        let mut garbage = Cookie::new().0.to_vec();
+        garbage.extend_from_slice(b"not an address");
+        fs::write(descriptor.rendez_vous(), &garbage)?;
+        assert_eq!(descriptor.plan_connect()?, ConnectPlan::StartInternal);
+
+        // A rendezvous point with an address.  Nothing is listening,
+        // and we don't check.
+        let addr: SocketAddr = "127.0.0.1:9".parse()?;
+        let mut content = Cookie::new().0.to_vec();
+        content.extend_from_slice(addr.to_string().as_bytes());
+        fs::write(descriptor.rendez_vous(), &content)?;
+        let plan = descriptor.plan_connect()?;
+        assert_eq!(plan, ConnectPlan::Connect(addr));
+        assert_eq!(plan.to_string(), "connect to existing server at 127.0.0.1:9");
+
+        // The rendezvous point was not modified.
+        assert_eq!(fs::read(descriptor.rendez_vous())?, content);
+        Ok(())
+    }
+
+    /// Checks that only cookies of the right size exist, and that
+    /// they are compared correctly.
+    #[test]
+    fn cookie_invariant() {
+        let cookie = Cookie::new();
+        assert_eq!(std::mem::size_of_val(&*cookie.0), Cookie::SIZE);
+
+        // Cookies of any other size are rejected.
+        for len in [0, 1, Cookie::SIZE - 1, Cookie::SIZE + 1, 2 * Cookie::SIZE] {
+            assert!(Cookie::from(&vec![0; len]).is_none(), "{}", len);
+        }
+        let copy = Cookie::from(&cookie.0[..]).expect("cookie has the right size");
+        assert!(copy == cookie);
+
+        // Truncated cookies are not extracted.
+        assert!(Cookie::extract(cookie.0[..Cookie::SIZE - 1].to_vec()).is_none());
+        let (extracted, rest) = Cookie::extract(cookie.0.to_vec())
+            .expect("has a cookie");
+        assert!(extracted == cookie);
+        assert!(rest.is_empty());
+        let mut content = cookie.0.to_vec();
+        content.extend_from_slice(b"127.0.0.1:1");
+        let (extracted, rest) = Cookie::extract(content).expect("has a cookie");
+        assert!(extracted == cookie);
+        assert_eq!(rest, b"127.0.0.1:1");
+
+        // Cookies that differ in a single bit are different.
+        for i in [0, Cookie::SIZE / 2, Cookie::SIZE - 1] {
+            let mut near = cookie.0.to_vec();
+            near[i] ^= 1;
+            let near = Cookie::from(&near).expect("cookie has the right size");
+            assert!(near != cookie, "{}", i);
+        }
+        assert!(Cookie::new() != cookie);
+
+        // A truncated handshake doesn't yield a cookie.
+        let mut handshake = Vec::new();
+        cookie.send(&mut handshake).expect("writing to a vector");
+        for len in [0, HANDSHAKE_MAGIC.len() + 1, handshake.len() - 1] {
+            assert!(Cookie::receive(&mut &handshake[..len]).is_err(), "{}", len);
+        }
+        let received = Cookie::receive(&mut &handshake[..])
+            .expect("complete handshake");
+        assert!(received == cookie);
+    }
+
+    #[test]
+    fn cookie_zeroize() -> Result<()> {
+        let mut cookie = Cookie::new();
+        assert!(cookie.0.iter().any(|&b| b != 0));
+        cookie.zeroize();
+        assert_eq!(*cookie.0, [0; Cookie::SIZE]);
+
+        // A stale rendezvous point is cleared, and a new server is
+        // started.
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+        let addr = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+            .local_addr()?;
+        let mut stale = Cookie::new().0.to_vec();
+        stale.extend_from_slice(addr.to_string().as_bytes());
+        fs::write(descriptor.rendez_vous(), stale)?;
+
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "stale").await
+            })?;
+        assert_eq!(response, "Hello stale!");
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn socket_var_is_not_inherited() -> Result<()> {
+        std::env::set_var("SOCKET", "1234");
+        assert_eq!(take_socket_var()?, 1234);
+        assert!(std::env::var_os("SOCKET").is_none());
+
+        // A process started by the server doesn't see it.
+        let output = std::process::Command::new("cmd")
+            .args(["/C", "if defined SOCKET (echo leaked) else (echo ok)"])
+            .output()?;
+        assert_eq!(String::from_utf8(output.stdout)?.trim(), "ok");
+        Ok(())
+    }
+
+    #[cfg(all(unix, not(feature = "no-subprocess")))]
+    #[test]
+    fn server_args() -> Result<()> {
+        use std::ffi::OsStr;
+        use std::os::unix::fs::PermissionsExt;
+
+        // The server checks that the lib directory exists.
+        let lib = tempfile::tempdir()?;
+        let ctx = core::Context::configure()
+            .ephemeral().lib(lib.path()).build()?;
+
+        // A stub server that records its command line.
+        let out = ctx.home().join("args");
+        let stub = ctx.home().join("stub");
+        fs::write(&stub, format!(
+            "#!/bin/sh\nprintf '%s\\n' \"$@\" > '{0}.tmp' && mv '{0}.tmp' '{0}'\n",
+            out.display()))?;
+        fs::set_permissions(&stub, fs::Permissions::from_mode(0o755))?;
+
+        let descriptor = DescriptorBuilder::new(
+            &ctx, rendezvous_path(&ctx, "stub"), stub, unused_factory)
+            .arg("tls-cert", "/etc/cert.pem")?
+            .arg("log-target", "syslog")?
+            .build();
+        assert_eq!(descriptor.server_args().get("tls-cert"),
+                   Some(OsStr::new("/etc/cert.pem")));
+
+        descriptor.fork(TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?, None)?;
+
+        let mut content = None;
+        for _ in 0..100 {
+            if let Ok(c) = fs::read_to_string(&out) {
+                content = Some(c);
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        let content = content.expect("the stub server ran");
+
+        let args = ServerArgs::parse(content.lines())?;
+        assert_eq!(args.home(), ctx.home());
+        assert_eq!(args.lib(), ctx.lib());
+        assert!(args.ephemeral());
+        assert_eq!(args.socket(), Some(0));
+        assert_eq!(args.cookie_fd(), None);
+        assert_eq!(args.get("tls-cert"), Some(OsStr::new("/etc/cert.pem")));
+        assert_eq!(args.get("log-target"), Some(OsStr::new("syslog")));
+        assert_eq!(args.context()?.home(), ctx.home());
+        Ok(())
+    }
+
+    #[cfg(all(unix, not(feature = "no-subprocess")))]
+    #[test]
+    fn cookie_fd() -> Result<()> {
+        use std::ffi::OsStr;
+        use std::os::unix::io::{AsRawFd, OwnedFd};
+
+        let cookie = Cookie::new();
+        let pipe = OwnedFd::from(cookie_pipe(&cookie.0[..])?);
+        let fd = pipe.as_raw_fd();
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .cookie_fd(pipe)?
+            .build()?;
+        assert_eq!(ctx.out_of_band_cookie().map(|c| &c[..]),
+                   Some(&cookie.0[..]));
+        // The file descriptor has been closed.
+        assert_eq!(unsafe { libc::fcntl(fd, libc::F_GETFD) }, -1);
+
+        // Standard input is the listener, and can't carry the cookie.
+        let args = ServerArgs::parse([
+            OsStr::new("--home"), ctx.home().as_os_str(),
+            OsStr::new("--lib"), ctx.lib().as_os_str(),
+            OsStr::new("--cookie-fd"), OsStr::new("0"),
+        ])?;
+        assert!(args.context().is_err());
+        Ok(())
+    }
+
+    #[cfg(all(unix, not(feature = "no-subprocess")))]
+    #[test]
+    fn pipe_cloexec() -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let (reader, writer) = pipe()?;
+        for end in [&reader, &writer] {
+            let flags = unsafe { libc::fcntl(end.as_raw_fd(), libc::F_GETFD) };
+            assert_ne!(flags, -1);
+            assert_ne!(flags & libc::FD_CLOEXEC, 0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn remote() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Robust)
+            .build()?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let _guard = runtime.enter();
+
+        // Stands in for the forwarded socket.
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+
+        let descriptor = DescriptorBuilder::new(
+            &ctx, rendezvous_path(&ctx, "remote"),
+            ctx.lib().join("does-not-exist"), unused_factory)
+            .remote(addr)
+            .build();
+        assert_eq!(descriptor.remote(), Some(addr));
+        assert_eq!(descriptor.connect_timeout(), REMOTE_CONNECT_TIMEOUT);
+        assert_eq!(descriptor.plan_connect()?, ConnectPlan::Connect(addr));
+
+        // We don't know the cookie.
+        let err = descriptor.connect().err().expect("no cookie");
+        assert_eq!(err.downcast_ref::<ConnectError>(),
+                   Some(&ConnectError::Rendezvous(
+                       descriptor.rendez_vous().to_path_buf())));
+        assert!(! descriptor.rendez_vous().exists());
+
+        // A copy of the remote rendezvous point.  The address is
+        // ignored.
+        let cookie = Cookie::new();
+        CookieFile::open(descriptor.rendez_vous())?
+            .write(&cookie, b"127.0.0.1:1")?;
+
+        // The forwarded server authenticates us, and answers our
+        // capabilities.
+        let copy = Cookie::from(&cookie.0[..]).expect("cookie has the right size");
+        let server = thread::spawn(move || -> Result<TcpStream> {
+            let (mut s, _) = listener.accept()?;
+            assert!(Cookie::receive(&mut s)? == copy);
+            let mut header = [0; CAPABILITIES_MAGIC.len() + 3];
+            s.read_exact(&mut header)?;
+            let mut body = vec![0; read_capabilities_header(&header)?];
+            s.read_exact(&mut body)?;
+            s.write_all(&Capabilities::default().encode())?;
+            Ok(s)
+        });
+        let _rpc_system = descriptor.connect()?;
+        let s = server.join().expect("no panic")?;
+
+        // The remote server is unreachable.  No local server is
+        // started, and the rendezvous point is not touched.
+        drop(s);
+        let err = descriptor.connect().err().expect("unreachable");
+        assert_eq!(err.downcast_ref::<ConnectError>(),
+                   Some(&ConnectError::Connect(addr)));
+        let (c, rest) = CookieFile::open(descriptor.rendez_vous())?
+            .read()?.expect("rendezvous point is intact");
+        assert!(c == cookie);
+        assert_eq!(rest, b"127.0.0.1:1");
+        Ok(())
+    }
+
+    /// Connecting to an unresponsive remote server times out.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn remote_connect_timeout() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .build()?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let _guard = runtime.enter();
+
+        // A wedged server: it is listening, but doesn't accept
+        // connections, and its backlog is full.
+        let socket = socket2::Socket::new(
+            socket2::Domain::IPV4, socket2::Type::STREAM, None)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into())?;
+        socket.listen(0)?;
+        let addr = socket.local_addr()?.as_socket().expect("an IP socket");
+        let mut backlog = Vec::new();
+        loop {
+            match TcpStream::connect_timeout(&addr, Duration::from_millis(100)) {
+                Ok(s) => backlog.push(s),
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => break,
+                Err(err) => return Err(err.into()),
+            }
+            assert!(backlog.len() < 100, "the backlog is not bounded");
+        }
+
+        let descriptor = DescriptorBuilder::new(
+            &ctx, rendezvous_path(&ctx, "remote"),
+            ctx.lib().join("does-not-exist"), unused_factory)
+            .remote(addr)
+            .connect_timeout(Duration::from_millis(100))
+            .build();
+        assert_eq!(descriptor.connect_timeout(), Duration::from_millis(100));
+        CookieFile::open(descriptor.rendez_vous())?
+            .write(&Cookie::new(), b"127.0.0.1:1")?;
+
+        let err = descriptor.connect().err().expect("times out");
+        assert_eq!(err.downcast_ref::<ConnectError>(),
+                   Some(&ConnectError::Connect(addr)));
+        Ok(())
+    }
+
+    #[test]
+    fn connect_errors() -> Result<()> {
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .lock_timeout(Duration::from_millis(100))
+            .build()?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let _guard = runtime.enter();
+
+        // Another client holds the lock.
+        let descriptor = hello_descriptor(&ctx);
+        let file = CookieFile::open(descriptor.rendez_vous())?;
+        let err = descriptor.ensure_started().err().expect("locked");
+        assert_eq!(err.downcast_ref::<ConnectError>(),
+                   Some(&ConnectError::LockTimedOut(
+                       descriptor.rendez_vous().to_path_buf())));
+        drop(file);
+
+        // The server doesn't know our cookie.
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let mut server = Server::new(descriptor.clone())?;
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(
+            move || server.serve_connections(listener, Cookie::new()));
+        let mut file = CookieFile::open(descriptor.rendez_vous())?;
+        descriptor.write_rendezvous(&mut file, &Cookie::new(), addr)?;
+        drop(file);
+        let err = descriptor.connect().err().expect("wrong cookie");
+        assert_eq!(err.downcast_ref::<ConnectError>(),
+                   Some(&ConnectError::CookieRejected(addr)));
+        shutdown.shutdown()?;
+        server.join().expect("no panic")?;
+
+        // The server's socket can't be bound.
+        let unroutable = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .bind_address("192.0.2.1".parse()?)
+            .allow_network_exposure()
+            .build()?;
+        let err = hello_descriptor(&unroutable).ensure_started()
+            .err().expect("can't bind");
+        assert_eq!(err.downcast_ref::<ConnectError>(),
+                   Some(&ConnectError::Bind));
+
+        // The server's executable doesn't exist.
+        #[cfg(not(feature = "no-subprocess"))]
+        {
+            let ctx = core::Context::configure()
+                .ephemeral()
+                .ipc_policy(core::IPCPolicy::External)
+                .build()?;
+            let executable = ctx.lib().join("does-not-exist");
+            let descriptor = Descriptor::new(
+                &ctx, rendezvous_path(&ctx, "missing"), executable.clone(),
+                unused_factory);
+            let err = descriptor.connect().err().expect("no executable");
+            assert_eq!(err.downcast_ref::<ConnectError>(),
+                       Some(&ConnectError::ExecutableNotFound(executable)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn descriptor_clone_is_shallow() -> Result<()> {
+        fn is_send_sync<T: Send + Sync>() {}
+        is_send_sync::<Descriptor>();
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .build()?;
+        let descriptor = DescriptorBuilder::new(
+            &ctx, rendezvous_path(&ctx, "clone"),
+            ctx.lib().join("does-not-exist"), unused_factory)
+            .arg("name", "value")?
+            .build();
+        let clone = descriptor.clone();
+
+        // The clone shares the state instead of copying it.
+        assert!(std::ptr::eq(descriptor.context(), clone.context()));
+        assert!(std::ptr::eq(descriptor.context().home(),
+                             clone.context().home()));
+        assert!(std::ptr::eq(descriptor.rendez_vous(), clone.rendez_vous()));
+        assert!(std::ptr::eq(descriptor.server_args(), clone.server_args()));
+        assert_eq!(Arc::strong_count(&descriptor.inner), 2);
+        drop(clone);
+        assert_eq!(Arc::strong_count(&descriptor.inner), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn accept_errors() -> Result<()> {
+        use std::collections::VecDeque;
+
+        fn errno(errno: i32) -> io::Error {
+            io::Error::from_raw_os_error(errno)
+        }
+
+        // A listener that fails a few times before accepting a
+        // connection.
+        let transient = platform! {
+            unix => vec![
+                io::Error::from(io::ErrorKind::Interrupted),
+                io::Error::from(io::ErrorKind::ConnectionAborted),
+                errno(libc::ECONNABORTED),
+                errno(libc::EPROTO),
+                errno(libc::EMFILE),
+                errno(libc::ENFILE),
+            ],
+            windows => vec![
+                io::Error::from(io::ErrorKind::Interrupted),
+                io::Error::from(io::ErrorKind::ConnectionAborted),
+                errno(10024),
+            ]
+        };
+        let backoffs = transient.iter()
+            .filter(|err| AcceptErrorAction::classify(err)
+                    == AcceptErrorAction::Backoff)
+            .count();
+        assert!(backoffs > 0);
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        let mut results: VecDeque<io::Result<u32>> =
+            transient.into_iter().map(Err).collect();
+        results.push_back(Ok(42));
+        let start = std::time::Instant::now();
+        let connection = runtime.block_on(accept_retrying(|| {
+            let r = results.pop_front().expect("stops after success");
+            async move { r }
+        }))?;
+        assert_eq!(connection, 42);
+        assert!(results.is_empty());
+        assert!(start.elapsed() >= ACCEPT_BACKOFF * backoffs as u32);
+
+        // Fatal errors stop the server.
+        let fatal = platform! {
+            unix => errno(libc::EBADF),
+            windows => errno(10038) // WSAENOTSOCK
+        };
+        let mut results: VecDeque<io::Result<u32>> =
+            vec![Err(fatal), Ok(42)].into();
+        let err = runtime.block_on(accept_retrying(|| {
+            let r = results.pop_front().expect("stops after error");
+            async move { r }
+        })).expect_err("fatal");
+        assert_eq!(AcceptErrorAction::classify(&err),
+                   AcceptErrorAction::Fatal);
+        assert_eq!(results.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn bind_errors() -> Result<()> {
+        use std::collections::VecDeque;
+
+        fn errno(errno: i32) -> io::Error {
+            io::Error::from_raw_os_error(errno)
+        }
+
+        // Transient errors are retried.
+        let transient = platform! {
+            unix => errno(libc::EMFILE),
+            windows => errno(10024)
+        };
+        let mut results: VecDeque<io::Result<u32>> = vec![
+            Err(io::Error::from(io::ErrorKind::AddrInUse)),
+            Err(transient),
+            Ok(42),
+        ].into();
+        assert_eq!(bind_retrying(|| results.pop_front().expect("retried"))?,
+                   42);
+        assert!(results.is_empty());
+
+        // But not forever.
+        let mut attempts = 0;
+        let err = bind_retrying(|| -> io::Result<()> {
+            attempts += 1;
+            Err(io::Error::from(io::ErrorKind::AddrInUse))
+        }).expect_err("persistent");
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert_eq!(attempts, BIND_ATTEMPTS);
+
+        // Other errors are returned immediately.
+        let mut attempts = 0;
+        let err = bind_retrying(|| -> io::Result<()> {
+            attempts += 1;
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        }).expect_err("fatal");
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(attempts, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn handshake_versions() -> Result<()> {
+        let cookie = Cookie::new();
+
+        // Legacy clients only send the cookie.
+        assert!(Cookie::receive(&mut &cookie.0[..])? == cookie);
+
+        // Current clients send the magic and the version first.
+        let mut handshake = Vec::new();
+        cookie.send(&mut handshake)?;
+        assert_eq!(&handshake[..4], &HANDSHAKE_MAGIC[..]);
+        assert_eq!(handshake[4], HANDSHAKE_VERSION);
+        assert!(Cookie::receive(&mut &handshake[..])? == cookie);
+
+        // Unknown versions are rejected.
+        handshake[4] = 2;
+        let err = Cookie::receive(&mut &handshake[..]).err()
+            .expect("unsupported version");
+        assert!(matches!(err.downcast_ref::<Error>(),
+                         Some(Error::UnsupportedHandshakeVersion(2))));
+        Ok(())
+    }
+
+    #[test]
+    fn handshake_peers() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+
+        // Start the server, and send it the first cookie using the
+        // legacy handshake.
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let d = descriptor.clone();
+        thread::spawn(move || Server::new(d)?.serve_listener(listener));
+        let cookie = Cookie::new();
+        TcpStream::connect(addr)?.write_all(&cookie.0[..])?;
+        CookieFile::open(descriptor.rendez_vous())?
+            .write(&cookie, addr.to_string().as_bytes())?;
+
+        // Current clients are served.
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "v1").await
+            })?;
+        assert_eq!(response, "Hello v1!");
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let local = tokio::task::LocalSet::new();
+        local.block_on(&runtime, async {
+            // Legacy clients are served.
+            let mut s = tokio::net::TcpStream::connect(addr).await?;
+            s.write_all(&cookie.0[..]).await?;
+            let (reader, writer) = s.into_split();
+            use tokio_util::compat::TokioAsyncReadCompatExt;
+            use tokio_util::compat::TokioAsyncWriteCompatExt;
+            let network = Box::new(twoparty::VatNetwork::new(
+                reader.compat(), writer.compat_write(),
+                Side::Client, Default::default()));
+            let mut rpc_system = RpcSystem::new(network, None);
+            let hello: hello::Client = rpc_system.bootstrap(Side::Server);
+            tokio::task::spawn_local(rpc_system);
+            assert_eq!(say_hello(hello, "v0").await?, "Hello v0!");
+
+            // Clients using an unknown version are rejected.
+            let mut s = tokio::net::TcpStream::connect(addr).await?;
+            s.write_all(&HANDSHAKE_MAGIC).await?;
+            s.write_all(&[HANDSHAKE_VERSION + 1]).await?;
+            let mut buf = [0; 1];
+            let n = tokio::time::timeout(Duration::from_secs(10),
+                                         s.read(&mut buf)).await??;
+            assert_eq!(n, 0);
+            Ok::<(), anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[cfg(all(unix, not(feature = "no-subprocess")))]
+    #[test]
+    fn server_signals_readiness() -> Result<()> {
+        use std::os::unix::io::OwnedFd;
+        use std::sync::mpsc;
+
+        let (reader, writer) = pipe()?;
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .ready_fd(OwnedFd::from(writer))
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let (sender, receiver) = mpsc::channel();
+        let d = descriptor.clone();
+        thread::spawn(move || -> Result<()> {
+            let mut server = Server::new(d)?;
+            server.on_ready(move || sender.send(()).unwrap());
+            server.serve_listener(listener)
+        });
+
+        // The server is not ready before it got the cookie.
+        assert!(wait_ready(reader.try_clone()?, Duration::from_millis(200))
+                .is_err());
+        assert!(receiver.try_recv().is_err());
+
+        let cookie = Cookie::new();
+        cookie.send(&mut TcpStream::connect(addr)?)?;
+        wait_ready(reader, Duration::from_secs(10))?;
+        receiver.recv_timeout(Duration::from_secs(10))?;
+
+        CookieFile::open(descriptor.rendez_vous())?
+            .write(&cookie, addr.to_string().as_bytes())?;
+        let response = descriptor.with_client(
+            |hello: hello::Client| async move {
+                say_hello(hello, "ready").await
+            })?;
+        assert_eq!(response, "Hello ready!");
+        Ok(())
+    }
+
+    #[cfg(all(unix, not(feature = "no-subprocess")))]
+    #[test]
+    fn server_builder_ready_fd() -> Result<()> {
+        use std::os::unix::io::OwnedFd;
+
+        let (ctx_reader, ctx_writer) = pipe()?;
+        let (reader, writer) = pipe()?;
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .ipc_policy(core::IPCPolicy::Internal)
+            .ready_fd(OwnedFd::from(ctx_writer))
+            .build()?;
+        let descriptor = hello_descriptor(&ctx);
+
+        // The builder takes the context's file descriptor, which is
+        // replaced by the one given to the builder.
+        let mut builder = Server::builder(descriptor.clone());
+        let old = builder.set_ready_fd(OwnedFd::from(writer));
+        assert!(old.is_some());
+        drop(old);
+        assert!(wait_ready(ctx_reader, Duration::from_secs(10)).is_err());
+
+        // Later servers don't get it.
+        assert!(Server::builder(descriptor.clone()).ready_fd.is_none());
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        thread::spawn(move || -> Result<()> {
+            builder.build()?.serve_listener(listener)
+        });
+
+        Cookie::new().send(&mut TcpStream::connect(addr)?)?;
+        wait_ready(reader, Duration::from_secs(10))?;
+        Ok(())
+    }
+
+    #[cfg(all(unix, not(feature = "no-subprocess")))]
+    #[test]
+    fn wait_for_readiness() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::Instant;
+
+        let lib = tempfile::tempdir()?;
+        let stub = |name: &str, body: &str| -> Result<Descriptor> {
+            let ctx = core::Context::configure()
+                .ephemeral()
+                .lib(lib.path())
+                .ready_timeout(Duration::from_millis(500))
+                .build()?;
+            let path = lib.path().join(name);
+            fs::write(&path, format!(
+                "#!/bin/sh\n\
+                 while [ $# -gt 0 ]; do\n\
+                 [ \"$1\" = --ready-fd ] && fd=$2\n\
+                 shift\n\
+                 done\n\
+                 {}\n", body))?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+            Ok(Descriptor::new(&ctx, rendezvous_path(&ctx, name), path,
+                               unused_factory))
+        };
+
+        let ready = stub("ready", "eval \"printf R >&$fd\"; sleep 2")?;
+        ready.start(true, &Cookie::new())?;
+
+        // Servers that don't signal readiness are reaped, and killed
+        // if need be.
+        let pid_file = lib.path().join("pid");
+        let reaped = || -> Result<bool> {
+            let pid: libc::pid_t = fs::read_to_string(&pid_file)?
+                .trim().parse()?;
+            Ok(unsafe { libc::kill(pid, 0) } == -1
+               && io::Error::last_os_error().raw_os_error()
+                   == Some(libc::ESRCH))
+        };
+
+        let exits = stub("exits", &format!(
+            "echo $$ > {}; exit 1", pid_file.display()))?;
+        assert!(exits.start(true, &Cookie::new()).is_err());
+        assert!(reaped()?);
+
+        let hangs = stub("hangs", &format!(
+            "echo $$ > {}; exec sleep 5", pid_file.display()))?;
+        let start = Instant::now();
+        assert!(hangs.start(true, &Cookie::new()).is_err());
+        assert!(start.elapsed() < Duration::from_secs(4));
+        assert!(reaped()?);
+        Ok(())
+    }
+
+    #[cfg(all(unix, not(feature = "no-subprocess")))]
+    #[test]
+    fn pass_fd() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let lib = tempfile::tempdir()?;
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .lib(lib.path())
+            .ready_timeout(Duration::from_secs(10))
+            .build()?;
+
+        // The server copies what it reads from the passed pipe to a
+        // file, and then signals readiness.
+        let output = lib.path().join("output");
+        let path = lib.path().join("reader");
+        fs::write(&path, format!(
+            "#!/bin/sh\n\
+             while [ $# -gt 0 ]; do\n\
+             [ \"$1\" = --ready-fd ] && ready=$2\n\
+             [ \"$1\" = --fd ] && fd=${{2#message=}}\n\
+             shift\n\
+             done\n\
+             eval \"cat <&$fd\" > {}\n\
+             eval \"printf R >&$ready\"\n",
+            output.display()))?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+
+        let (reader, mut writer) = pipe()?;
+        writer.write_all(b"hello from the client")?;
+        drop(writer);
+
+        let descriptor = DescriptorBuilder::new(
+            &ctx, rendezvous_path(&ctx, "reader"), path, unused_factory)
+            .pass_fd("message", reader.into())?
+            .build();
+        descriptor.start(true, &Cookie::new())?;
+        assert_eq!(fs::read(&output)?, b"hello from the client");
+
+        // Names are checked.
+        let (reader, _) = pipe()?;
+        assert!(DescriptorBuilder::new(
+            &ctx, rendezvous_path(&ctx, "invalid"), lib.path().join("x"),
+            unused_factory)
+                .pass_fd("ready-fd", reader.into()).is_err());
+        Ok(())
+    }
+
+    #[cfg(all(unix, not(feature = "no-subprocess")))]
+    #[test]
+    fn server_log() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        // The server complains, and then signals readiness.
+        let lib = tempfile::tempdir()?;
+        let path = lib.path().join("sequoia-complainer");
+        fs::write(&path,
+                  "#!/bin/sh\n\
+                   while [ $# -gt 0 ]; do\n\
+                   [ \"$1\" = --ready-fd ] && ready=$2\n\
+                   shift\n\
+                   done\n\
+                   echo out\n\
+                   echo 'thread main panicked' >&2\n\
+                   eval \"printf R >&$ready\"\n")?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+
+        let start = |ctx: &core::Context| -> Result<Descriptor> {
+            let descriptor = Descriptor::new(
+                ctx, rendezvous_path(ctx, "complainer"), path.clone(),
+                unused_factory);
+            descriptor.start(true, &Cookie::new())?;
+            Ok(descriptor)
+        };
+
+        // By default, the output is discarded.
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .lib(lib.path())
+            .ready_timeout(Duration::from_secs(10))
+            .build()?;
+        let descriptor = start(&ctx)?;
+        assert_eq!(descriptor.log_file(),
+                   ctx.home().join("sequoia-complainer.log"));
+        assert!(! descriptor.log_file().exists());
+
+        // The log is appended to.
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .lib(lib.path())
+            .ready_timeout(Duration::from_secs(10))
+            .server_log()
+            .build()?;
+        start(&ctx)?;
+        let descriptor = start(&ctx)?;
+        let log = fs::read_to_string(descriptor.log_file())?;
+        assert_eq!(log.matches("thread main panicked\n").count(), 2,
+                   "{:?}", log);
+        assert_eq!(log.matches("out\n").count(), 2, "{:?}", log);
+        Ok(())
+    }
+
+    #[cfg(all(target_os = "linux", not(feature = "no-subprocess")))]
+    #[test]
+    fn cookie_memfd() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        use std::os::unix::io::AsRawFd;
+
+        let cookie: Vec<u8> = (0..Cookie::SIZE as u8).collect();
+
+        // The memfd is sealed.
+        let mut file = super::cookie_memfd(&cookie)?;
+        let seals = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GET_SEALS) };
+        assert_eq!(seals, libc::F_SEAL_SEAL | libc::F_SEAL_SHRINK
+                   | libc::F_SEAL_GROW | libc::F_SEAL_WRITE);
+        assert!(file.write_all(b"x").is_err());
+        assert!(file.set_len(0).is_err());
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        assert_eq!(content, cookie);
+
+        // The server records what it got, and signals readiness.
+        let lib = tempfile::tempdir()?;
+        let path = lib.path().join("sequoia-memfd");
+        fs::write(&path,
+                  "#!/bin/sh\n\
+                   while [ $# -gt 0 ]; do\n\
+                   [ \"$1\" = --ready-fd ] && ready=$2\n\
+                   [ \"$1\" = --cookie-fd ] && cookie=$2\n\
+                   shift\n\
+                   done\n\
+                   readlink /proc/self/fd/$cookie > \"$0.link\"\n\
+                   eval \"cat <&$cookie\" > \"$0.cookie\"\n\
+                   eval \"printf R >&$ready\"\n")?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+
+        let ctx = core::Context::configure()
+            .ephemeral()
+            .lib(lib.path())
+            .ipc_policy(core::IPCPolicy::External)
+            .ready_timeout(Duration::from_secs(10))
+            .out_of_band_cookie(cookie.clone().try_into().expect("32 bytes"))
+            .build()?;
+        let descriptor = Descriptor::new(
+            &ctx, rendezvous_path(&ctx, "memfd"), path.clone(), unused_factory);
+        descriptor.ensure_started()?;
+
+        // The server read the cookie from a memfd.
+        let link = fs::read_to_string(lib.path().join("sequoia-memfd.link"))?;
+        assert!(link.starts_with("/memfd:sequoia-ipc-cookie"), "{:?}", link);
+        assert_eq!(fs::read(lib.path().join("sequoia-memfd.cookie"))?, cookie);
+
+        // The rendezvous point only contains the address, not the
+        // cookie.
+        let content = fs::read(descriptor.rendez_vous())?;
+        assert!(! content.windows(cookie.len()).any(|w| w == &cookie[..]));
+        String::from_utf8(content)?.parse::<SocketAddr>()?;
+        Ok(())
+    }
+
+    #[cfg(all(unix, not(feature = "no-subprocess")))]
+    #[test]
+    fn background_command_is_detached() -> Result<()> {
+        let mut child = new_background_command("sleep").arg("10").spawn()?;
+        let pid = child.id() as libc::pid_t;
+        let (pgid, sid) = unsafe { (libc::getpgid(pid), libc::getsid(pid)) };
+
+        // It can still be terminated explicitly.
+        child.kill()?;
+        child.wait()?;
+
+        // The child leads its own session and process group.
+        assert_eq!(pgid, pid);
+        assert_eq!(sid, pid);
+        assert_ne!(pgid, unsafe { libc::getpgid(0) });
+        assert_ne!(sid, unsafe { libc::getsid(0) });
+        Ok(())
+    }
+
+    #[test]
+    fn cookie_file_is_a_directory() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("rendezvous");
+        fs::create_dir(&path)?;
+
+        let err = CookieFile::open(&path).err().expect("is a directory");
+        let msg = format!("{:#}", err);
+        assert!(msg.contains("is a directory"), "{}", msg);
+        assert!(msg.contains(&path.display().to_string()), "{}", msg);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cookie_file_is_a_symlink() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let target = dir.path().join("target");
+        fs::write(&target, b"")?;
+        let path = dir.path().join("rendezvous");
+        std::os::unix::fs::symlink(&target, &path)?;
+
+        let err = CookieFile::open(&path).err().expect("is a symlink");
+        let msg = format!("{:#}", err);
+        assert!(msg.contains("symbolic link"), "{}", msg);
+        assert!(msg.contains(&path.display().to_string()), "{}", msg);
+
+        // A dangling symlink must not be followed either.
+        fs::remove_file(&target)?;
+        assert!(CookieFile::open(&path).is_err());
+        assert!(! target.exists());
+        Ok(())
+    }
 }
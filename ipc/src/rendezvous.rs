@@ -0,0 +1,355 @@
+//! Rendezvous backends.
+//!
+//! The rendezvous point is where clients find the server: it holds
+//! the server's cookie and address.  By default, it is a file in the
+//! context's home directory, see [`FileRendezvous`].  Embedders
+//! that have no shared, writable directory can provide their own
+//! backend, for instance, one storing the rendezvous point in a
+//! system keyring, and use it with
+//! [`DescriptorBuilder::rendezvous`].
+//!
+//! [`DescriptorBuilder::rendezvous`]: crate::DescriptorBuilder::rendezvous()
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use anyhow::Context as _;
+
+use crate::CookieFile;
+use crate::Result;
+
+/// A rendezvous backend.
+///
+/// The rendezvous point holds an opaque byte string, which is
+/// written by the client that starts the server.  Access is
+/// serialized using an exclusive lock, see [`Rendezvous::lock`].
+/// The invariants are described in the [module-level
+/// documentation], they are the same for all backends.
+///
+/// [module-level documentation]: crate#design
+pub trait Rendezvous: std::fmt::Debug + Send + Sync {
+    /// Takes the exclusive lock on the rendezvous point.
+    ///
+    /// If another client holds the lock, this blocks until it is
+    /// released.  The lock is released when the returned guard is
+    /// dropped.  If the process crashes, the lock must be released
+    /// as well, but the contents may be partially written.
+    fn lock(&self) -> Result<Box<dyn RendezvousLock>>;
+
+    /// Reads the rendezvous point.
+    ///
+    /// This takes the lock for the duration of the read.  If the
+    /// rendezvous point doesn't exist, this returns an empty
+    /// vector.
+    fn read(&self) -> Result<Vec<u8>> {
+        self.lock()?.read()
+    }
+
+    /// Reads the rendezvous point without taking the lock.
+    ///
+    /// This is used to connect to a running server without
+    /// contending for the lock, see [`Descriptor::connect`].  The
+    /// result may be stale, or, if a client is writing the rendezvous
+    /// point concurrently, partially written.  If the rendezvous point
+    /// doesn't exist, this returns an empty vector.
+    ///
+    /// The default implementation takes the lock.
+    ///
+    /// [`Descriptor::connect`]: crate::Descriptor::connect()
+    fn read_unlocked(&self) -> Result<Vec<u8>> {
+        self.read()
+    }
+
+    /// Replaces the contents of the rendezvous point.
+    ///
+    /// This takes the lock for the duration of the write.
+    fn write(&self, data: &[u8]) -> Result<()> {
+        self.lock()?.write(data)
+    }
+}
+
+/// A locked rendezvous point.
+///
+/// This is returned by [`Rendezvous::lock`].  The lock is released
+/// when it is dropped.
+pub trait RendezvousLock: Send {
+    /// Reads the rendezvous point.
+    ///
+    /// Returns an empty vector if the rendezvous point is empty.
+    fn read(&mut self) -> Result<Vec<u8>>;
+
+    /// Replaces the contents of the rendezvous point.
+    ///
+    /// Writing an empty slice clears it.
+    fn write(&mut self, data: &[u8]) -> Result<()>;
+}
+
+/// The default rendezvous backend, a file.
+///
+/// The file is protected by an advisory lock.  Symbolic links are
+/// not followed.  See [`rendezvous_path`] for where the file is
+/// stored by default.  Taking the lock creates the file's directory,
+/// see [`FileRendezvous::dir_mode`].  By default, taking the lock
+/// waits until it is released, see [`FileRendezvous::lock_timeout`].
+///
+/// [`rendezvous_path`]: crate::rendezvous_path()
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileRendezvous {
+    path: PathBuf,
+    dir_mode: u32,
+    lock_timeout: Option<Duration>,
+}
+
+impl FileRendezvous {
+    /// Returns a backend using the file at `path`.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        FileRendezvous {
+            path: path.as_ref().to_path_buf(),
+            dir_mode: crate::core::DEFAULT_HOME_MODE,
+            lock_timeout: None,
+        }
+    }
+
+    /// Sets the mode the file's directory is created with.
+    ///
+    /// If the directory doesn't exist, it is created along with any
+    /// missing parents when the lock is first taken, using `mode`,
+    /// subject to the umask.  The default is `0o700`.  Descriptors
+    /// use the context's mode, see [`Config::home_mode`].  This is
+    /// ignored on Windows.
+    ///
+    /// [`Config::home_mode`]: crate::Config::home_mode()
+    pub fn dir_mode(mut self, mode: u32) -> Self {
+        self.set_dir_mode(mode);
+        self
+    }
+
+    /// Sets the mode the file's directory is created with.
+    ///
+    /// Returns the old mode.
+    pub fn set_dir_mode(&mut self, mode: u32) -> u32 {
+        std::mem::replace(&mut self.dir_mode, mode)
+    }
+
+    /// Bounds how long taking the lock waits for another client to
+    /// release it.
+    ///
+    /// If the lock isn't released within `timeout`, taking it fails
+    /// with [`ConnectError::LockTimedOut`].  Descriptors use the
+    /// context's timeout, see [`Config::lock_timeout`].
+    ///
+    /// [`ConnectError::LockTimedOut`]: crate::ConnectError::LockTimedOut
+    /// [`Config::lock_timeout`]: crate::Config::lock_timeout()
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.set_lock_timeout(timeout);
+        self
+    }
+
+    /// Bounds how long taking the lock waits for another client to
+    /// release it.
+    ///
+    /// Returns the old timeout.
+    pub fn set_lock_timeout(&mut self, timeout: Duration)
+                            -> Option<Duration>
+    {
+        std::mem::replace(&mut self.lock_timeout, Some(timeout))
+    }
+
+    /// Returns the path of the rendezvous point.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Rendezvous for FileRendezvous {
+    fn lock(&self) -> Result<Box<dyn RendezvousLock>> {
+        if let Some(parent) = self.path.parent() {
+            crate::create_dir_all(parent, self.dir_mode)
+                .with_context(|| format!("Creating {}", parent.display()))?;
+        }
+        Ok(Box::new(CookieFile::open_timeout(&self.path, self.lock_timeout)?))
+    }
+
+    fn read(&self) -> Result<Vec<u8>> {
+        // Opening the rendezvous point creates it.
+        if self.path.exists() {
+            self.lock()?.read()
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    fn read_unlocked(&self) -> Result<Vec<u8>> {
+        crate::read_rendezvous_unlocked(&self.path)
+    }
+}
+
+/// An in-memory rendezvous backend.
+///
+/// The rendezvous point lives in the current process, and is shared
+/// by all clones.  This is useful for tests, and for processes that
+/// only start internal servers, see [`IPCPolicy::Internal`].
+///
+/// [`IPCPolicy::Internal`]: crate::IPCPolicy::Internal
+#[derive(Clone, Debug, Default)]
+pub struct MemoryRendezvous {
+    state: Arc<MemoryState>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    inner: Mutex<MemoryInner>,
+    cond: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct MemoryInner {
+    locked: bool,
+    content: Vec<u8>,
+}
+
+impl MemoryRendezvous {
+    /// Returns an empty in-memory rendezvous point.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Rendezvous for MemoryRendezvous {
+    fn lock(&self) -> Result<Box<dyn RendezvousLock>> {
+        let mut inner = self.state.inner.lock().expect("not poisoned");
+        while inner.locked {
+            inner = self.state.cond.wait(inner).expect("not poisoned");
+        }
+        inner.locked = true;
+
+        Ok(Box::new(MemoryLock {
+            state: self.state.clone(),
+        }))
+    }
+
+    fn read_unlocked(&self) -> Result<Vec<u8>> {
+        Ok(self.state.inner.lock().expect("not poisoned").content.clone())
+    }
+}
+
+/// A locked [`MemoryRendezvous`].
+struct MemoryLock {
+    state: Arc<MemoryState>,
+}
+
+impl RendezvousLock for MemoryLock {
+    fn read(&mut self) -> Result<Vec<u8>> {
+        Ok(self.state.inner.lock().expect("not poisoned").content.clone())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        let mut inner = self.state.inner.lock().expect("not poisoned");
+        // The old contents may include a cookie.
+        unsafe {
+            memsec::memzero(inner.content.as_mut_ptr(), inner.content.len());
+        }
+        inner.content = data.to_vec();
+        Ok(())
+    }
+}
+
+impl Drop for MemoryLock {
+    fn drop(&mut self) {
+        // Don't panic in drop, the lock is released even if another
+        // thread panicked while holding the mutex.
+        let mut inner = match self.state.inner.lock() {
+            Ok(inner) => inner,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        inner.locked = false;
+        self.state.cond.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn memory_rendezvous() -> Result<()> {
+        let r = MemoryRendezvous::new();
+        assert!(r.read()?.is_empty());
+        r.write(b"hello")?;
+        assert_eq!(r.read()?, b"hello");
+
+        // Clones share the rendezvous point.
+        let clone = r.clone();
+        assert_eq!(clone.read()?, b"hello");
+
+        // The lock is exclusive.
+        let mut guard = r.lock()?;
+        let waiter = thread::spawn(move || -> Result<Vec<u8>> {
+            clone.lock()?.read()
+        });
+        thread::sleep(Duration::from_millis(100));
+        assert!(! waiter.is_finished());
+        guard.write(b"")?;
+        drop(guard);
+        assert!(waiter.join().expect("no panic")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn file_rendezvous() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("service.cookie");
+        let r = FileRendezvous::new(&path);
+
+        // Reading doesn't create the file.
+        assert!(r.read()?.is_empty());
+        assert!(! path.exists());
+
+        r.write(b"hello")?;
+        assert_eq!(std::fs::read(&path)?, b"hello");
+        assert_eq!(r.read()?, b"hello");
+        assert_eq!(r.read_unlocked()?, b"hello");
+
+        // Reading without the lock works while it is held.
+        let guard = r.lock()?;
+        assert_eq!(r.read_unlocked()?, b"hello");
+        drop(guard);
+
+        r.lock()?.write(b"")?;
+        assert!(r.read()?.is_empty());
+
+        // Symbolic links are not followed.
+        #[cfg(unix)]
+        {
+            let link = dir.path().join("link.cookie");
+            std::os::unix::fs::symlink(&path, &link)?;
+            assert!(FileRendezvous::new(&link).read_unlocked().is_err());
+        }
+        Ok(())
+    }
+
+    /// Taking the lock creates the directory.
+    #[cfg(unix)]
+    #[test]
+    fn file_rendezvous_dir_mode() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir()?;
+        let home = dir.path().join("parent").join("home");
+        let r = FileRendezvous::new(home.join("service.cookie"))
+            .dir_mode(0o711);
+        assert!(r.read()?.is_empty());
+        assert!(! home.exists());
+
+        drop(r.lock()?);
+        for d in [&home, &dir.path().join("parent")] {
+            let mode = std::fs::metadata(d)?.permissions().mode();
+            assert_eq!(mode & 0o777, 0o711, "{}", d.display());
+        }
+        Ok(())
+    }
+}
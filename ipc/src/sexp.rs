@@ -8,6 +8,7 @@
 
 use std::convert::TryFrom;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::ops::Deref;
 
 #[cfg(test)]
@@ -21,11 +22,30 @@ use openpgp::Error;
 use openpgp::Result;
 
 mod parse;
+mod borrowed;
+pub use borrowed::{SexpRef, StringRef};
+
+/// The maximum nesting depth of lists accepted by the parsers.
+///
+/// Dropping, formatting, and converting expressions recurses into
+/// nested lists, so without a limit a small input like `((((...`
+/// could exhaust the stack.
+const MAX_DEPTH: usize = 128;
 
 /// An *S-Expression*.
 ///
 /// An *S-Expression* is either a string, or a list of *S-Expressions*.
-#[derive(Clone, PartialEq, Eq)]
+///
+/// Two *S-Expressions* are equal if they have the same canonical
+/// encoding once their display hints are removed, i.e., if they are
+/// the same tree of lists, and their strings have the same bytes.
+/// Display hints are ignored, and so is the way the expressions were
+/// written: whitespace, and whether a string is written as token,
+/// quoted string, hexadecimal, or verbatim, don't survive parsing.
+/// [`Hash`] is consistent with this, so expressions can be used to
+/// deduplicate or cache key structures.  Unlike this, [`String_`]'s
+/// equality takes the display hint into account.
+#[derive(Clone)]
 pub enum Sexp {
     /// Just a string.
     String(String_),
@@ -33,6 +53,35 @@ pub enum Sexp {
     List(Vec<Sexp>),
 }
 
+impl PartialEq for Sexp {
+    fn eq(&self, other: &Sexp) -> bool {
+        match (self, other) {
+            (Sexp::String(a), Sexp::String(b)) => a[..] == b[..],
+            (Sexp::List(a), Sexp::List(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Sexp {}
+
+impl Hash for Sexp {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Tag the variants, so that a string doesn't collide with a
+        // list.  Slices and vectors hash their length.
+        match self {
+            Sexp::String(s) => {
+                state.write_u8(0);
+                s[..].hash(state);
+            },
+            Sexp::List(l) => {
+                state.write_u8(1);
+                l.hash(state);
+            },
+        }
+    }
+}
+
 impl fmt::Debug for Sexp {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -82,6 +131,82 @@ impl Sexp {
         inner(self, true, &mut output);
         output
     }
+
+    /// Returns an object that formats the expression with secrets
+    /// redacted.
+    ///
+    /// The `Debug` implementation of `Sexp` shows everything,
+    /// including private key material.  Use this when the
+    /// expression may contain secrets and ends up in a log, or an
+    /// error message.  The result is formatted like the expression,
+    /// except that the strings in `(private-key ...)` and `(d ...)`
+    /// lists are replaced with `[redacted]`.  The names of the lists
+    /// are kept, so that the structure remains visible.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use sequoia_ipc::sexp::Sexp;
+    /// # fn main() -> sequoia_openpgp::Result<()> {
+    /// let sexp = Sexp::from_bytes(
+    ///     b"(11:private-key(3:ecc(5:curve7:Ed25519)(1:q2:QQ)(1:d2:DD)))")?;
+    /// assert_eq!(format!("{:?}", sexp.redacted()),
+    ///            "[b\"private-key\", [b\"ecc\", [b\"curve\", [redacted]], \
+    ///             [b\"q\", [redacted]], [b\"d\", [redacted]]]]");
+    /// # Ok(()) }
+    /// ```
+    pub fn redacted(&self) -> Redacted<'_> {
+        Redacted {
+            sexp: self,
+            secret: false,
+            head: false,
+        }
+    }
+}
+
+/// Formats an *S-Expression* with secrets redacted.
+///
+/// This is returned by [`Sexp::redacted`].
+pub struct Redacted<'a> {
+    sexp: &'a Sexp,
+    /// Whether the expression is part of a secret.
+    secret: bool,
+    /// Whether the expression is the first element of a list.
+    head: bool,
+}
+
+impl Redacted<'_> {
+    /// The lists whose strings are redacted.
+    const SECRETS: &'static [&'static [u8]] = &[b"private-key", b"d"];
+}
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.sexp {
+            // Only names, which are the first element of a list, are
+            // shown.  Names are short tokens, keys are not.
+            Sexp::String(s) if self.secret
+                && ! (self.head && s.len() <= 16 && s.iter().all(|c| {
+                    c.is_ascii_alphanumeric() || *c == b'-' || *c == b'_'
+                })) =>
+                f.write_str("[redacted]"),
+            Sexp::String(s) => s.fmt(f),
+            Sexp::List(l) => {
+                let secret = self.secret || match l.first() {
+                    Some(Sexp::String(name)) =>
+                        Self::SECRETS.contains(&&name[..]),
+                    _ => false,
+                };
+                f.debug_list()
+                    .entries(l.iter().enumerate().map(|(i, sexp)| Redacted {
+                        sexp,
+                        secret,
+                        head: i == 0,
+                    }))
+                    .finish()
+            },
+        }
+    }
 }
 
 impl Sexp {
@@ -103,7 +228,7 @@ impl Sexp {
         use openpgp::crypto::mpi::PublicKey;
         let not_a_session_key = || -> anyhow::Error {
             Error::MalformedMPI(
-                format!("Not a session key: {:?}", self)).into()
+                format!("Not a session key: {:?}", self.redacted())).into()
         };
 
         let value = self.get(b"value")?.ok_or_else(not_a_session_key)?
@@ -428,6 +553,85 @@ impl Sexp {
         }
     }
 
+    /// Returns an iterator over the elements of this list.
+    ///
+    /// Only the immediate children are returned, nested lists are
+    /// not descended into.  If this is a string, the iterator is
+    /// empty.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use sequoia_ipc::sexp::Sexp;
+    /// # fn main() -> sequoia_openpgp::Result<()> {
+    /// let sexp = Sexp::from_bytes(b"(7:keyinfo(4:grip2:ab)2:OK)")?;
+    /// for element in sexp.iter() {
+    ///     if let Some(s) = element.string() {
+    ///         println!("{:?}", s);
+    ///     }
+    /// }
+    /// # Ok(()) }
+    /// ```
+    pub fn iter(&self) -> std::slice::Iter<'_, Sexp> {
+        self.list().unwrap_or(&[]).iter()
+    }
+
+    /// Returns the raw value of the MPI called `name`.
+    ///
+    /// Searches this expression depth-first for a list of the form
+    /// `(name value)`, where both elements are strings, and returns
+    /// the value of the first one found.  For instance, given the
+    /// expression `(public-key (rsa (n #00C2...#) (e #010001#)))`,
+    /// `sexp.get_mpi(b"n")` returns the modulus.
+    ///
+    /// Unlike OpenPGP's MPIs, GnuPG's MPIs don't have a length
+    /// prefix inside the atom: the atom *is* the number, in
+    /// big-endian byte order, and the atom's length is the length of
+    /// the number.  Note that libgcrypt prepends a zero byte to
+    /// numbers whose most significant bit is set, so that they are
+    /// not mistaken for negative numbers, for instance, to RSA
+    /// moduli.  Elliptic curve points are not numbers, but opaque
+    /// octet strings, for instance, prefixed with `0x40` for
+    /// Ed25519, and are returned as is.  This function returns the
+    /// atom unmodified, see [`Sexp::get_openpgp_mpi`] for a
+    /// normalized representation.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use sequoia_ipc::sexp::Sexp;
+    /// # fn main() -> sequoia_openpgp::Result<()> {
+    /// let sexp = Sexp::from_bytes(
+    ///     b"(10:public-key(3:rsa(1:n3:\x00\xc1\x01)(1:e3:\x01\x00\x01)))")?;
+    /// assert_eq!(sexp.get_mpi(b"n"), Some(&b"\x00\xc1\x01"[..]));
+    /// assert_eq!(sexp.get_mpi(b"e"), Some(&b"\x01\x00\x01"[..]));
+    /// assert_eq!(sexp.get_mpi(b"d"), None);
+    /// # Ok(()) }
+    /// ```
+    pub fn get_mpi(&self, name: &[u8]) -> Option<&[u8]> {
+        let list = self.list()?;
+        if let [Sexp::String(key), Sexp::String(value)] = list {
+            if &key[..] == name {
+                return Some(&value[..]);
+            }
+        }
+
+        list.iter().find_map(|sexp| sexp.get_mpi(name))
+    }
+
+    /// Returns the MPI called `name`.
+    ///
+    /// Like [`Sexp::get_mpi`], but returns an OpenPGP MPI.  Leading
+    /// zeros, like the one libgcrypt prepends to numbers whose most
+    /// significant bit is set, are stripped.
+    ///
+    /// The MPI is not protected.  For secret key material, use
+    /// [`Sexp::get_mpi`] and convert the value to an
+    /// [`mpi::ProtectedMPI`] instead.
+    pub fn get_openpgp_mpi(&self, name: &[u8]) -> Option<mpi::MPI> {
+        self.get_mpi(name).map(mpi::MPI::new)
+    }
+
     /// Writes a serialized version of the object to `o`.
     pub fn serialize(&self, o: &mut dyn std::io::Write) -> Result<()> {
         match self {
@@ -443,6 +647,32 @@ impl Sexp {
         }
     }
 
+    /// Removes the display hints from all strings.
+    ///
+    /// Display hints are preserved when parsing any of the
+    /// encodings, and [`Sexp::serialize`] writes them out.  Use this
+    /// to drop them, e.g. to compare expressions while ignoring how
+    /// their strings should be displayed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use sequoia_ipc::sexp::Sexp;
+    /// # fn main() -> sequoia_openpgp::Result<()> {
+    /// let mut sexp = Sexp::from_bytes(b"(4:data[10:text/plain]5:Hello)")?;
+    /// sexp.strip_display_hints();
+    /// assert_eq!(sexp, Sexp::from_bytes(b"(4:data5:Hello)")?);
+    /// # Ok(()) }
+    /// ```
+    pub fn strip_display_hints(&mut self) {
+        match self {
+            Sexp::String(s) => {
+                s.take_display_hint();
+            },
+            Sexp::List(l) => l.iter_mut().for_each(Sexp::strip_display_hints),
+        }
+    }
+
     /// Given an alist, returns the key, i.e., the value of the first
     /// element.
     ///
@@ -456,7 +686,7 @@ impl Sexp {
         }
 
         Err(Error::InvalidArgument(
-            format!("Malformed alist: {:?}", self)).into())
+            format!("Malformed alist: {:?}", self.redacted())).into())
     }
 
     /// Given an alist, ignores the key (the first element) and
@@ -472,7 +702,7 @@ impl Sexp {
         }
 
         Err(Error::InvalidArgument(
-            format!("Malformed alist: {:?}", self)).into())
+            format!("Malformed alist: {:?}", self.redacted())).into())
     }
 
     /// Given an alist, checks that the key is equal to `key` and, if
@@ -680,6 +910,11 @@ impl String_ {
         self.1.as_ref().map(|b| b.as_ref())
     }
 
+    /// Removes this *String*'s display hint, and returns it.
+    pub fn take_display_hint(&mut self) -> Option<Box<[u8]>> {
+        self.1.take()
+    }
+
     /// Writes a serialized version of the object to `o`.
     pub fn serialize(&self, o: &mut dyn std::io::Write) -> Result<()> {
         if let Some(display) = self.display_hint() {
@@ -763,6 +998,18 @@ impl From<String_> for Sexp {
     }
 }
 
+/// Iterates over the elements of a list.
+///
+/// See [`Sexp::iter`].
+impl<'a> IntoIterator for &'a Sexp {
+    type Item = &'a Sexp;
+    type IntoIter = std::slice::Iter<'a, Sexp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
 impl From<Vec<Sexp>> for Sexp {
     fn from(v: Vec<Sexp>) -> Sexp {
         Sexp::List(v)
@@ -830,6 +1077,98 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn get_mpi() -> Result<()> {
+        let cert = Cert::from_bytes(
+            crate::tests::file("sexp/keys/rsa3072.pgp"))?;
+        for key in cert.keys().map(|ka| ka.key()) {
+            let (n, e) = match key.mpis() {
+                mpi::PublicKey::RSA { n, e } => (n, e),
+                _ => unreachable!("an RSA key"),
+            };
+            let keygrip = Keygrip::of(key.mpis())?;
+            let sexp = Sexp::from_bytes(crate::tests::file(
+                &format!("sexp/keys/rsa3072-{}.sexp", keygrip)))?;
+
+            // The modulus' most significant bit is set, hence
+            // libgcrypt prepends a zero byte.
+            let raw_n = sexp.get_mpi(b"n").expect("has n");
+            assert_eq!(raw_n.len(), 385);
+            assert_eq!(raw_n[0], 0);
+            assert_eq!(&raw_n[1..], n.value());
+            assert_eq!(sexp.get_openpgp_mpi(b"n").as_ref(), Some(n));
+
+            assert_eq!(sexp.get_mpi(b"e"), Some(e.value()));
+            assert_eq!(sexp.get_openpgp_mpi(b"e").as_ref(), Some(e));
+
+            // Missing, and not an MPI.
+            assert_eq!(sexp.get_mpi(b"x"), None);
+            assert_eq!(sexp.get_mpi(b"rsa"), None);
+        }
+        assert_eq!(Sexp::String("n".into()).get_mpi(b"n"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn display_hints() -> Result<()> {
+        // The advanced encoding, with a quoted display hint.
+        let mut sexp = Sexp::from_bytes(
+            b"(data [ \"text/plain\" ] #48656c6c6f#)")?;
+        let data = sexp.list().and_then(|l| l[1].string())
+            .expect("a list of strings");
+        assert_eq!(data.display_hint(), Some(&b"text/plain"[..]));
+        assert_eq!(data.to_bytes(), b"Hello");
+
+        // The hint is preserved in the canonical encoding.
+        let mut buf = Vec::new();
+        sexp.serialize(&mut buf)?;
+        assert_eq!(buf, b"(4:data[10:text/plain]5:Hello)");
+        assert_eq!(Sexp::from_bytes(&buf)?, sexp);
+
+        sexp.strip_display_hints();
+        let mut buf = Vec::new();
+        sexp.serialize(&mut buf)?;
+        assert_eq!(buf, b"(4:data5:Hello)");
+
+        let mut s = String_::with_display_hint(&b"Hello"[..], &b"n"[..]);
+        assert_eq!(s.take_display_hint().as_deref(), Some(&b"n"[..]));
+        assert_eq!(s.display_hint(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn canonical_equality() -> Result<()> {
+        use std::collections::HashSet;
+
+        let canonical = Sexp::from_bytes(
+            b"(7:private(3:rsa(1:n3:abc)(1:e[4:text]5:Hello)))")?;
+        // The same structure, written differently, and without the
+        // display hint.
+        let advanced = Sexp::from_bytes(
+            b"(private\n  (rsa (n \"abc\")\n       (e #48656c6c6f#)))")?;
+        assert_eq!(canonical, advanced);
+
+        let mut set = HashSet::new();
+        assert!(set.insert(canonical.clone()));
+        assert!(! set.insert(advanced));
+
+        // Different atoms, or a different tree, are different.
+        for other in [&b"(7:private(3:rsa(1:n3:abd)(1:e5:Hello)))"[..],
+                      b"(7:private(3:rsa(1:n3:abc)1:e5:Hello))",
+                      b"(7:private(3:rsa(1:n3:abc)(1:e5:Hello)()))",
+                      b"(7:private)",
+        ] {
+            let other = Sexp::from_bytes(other)?;
+            assert_ne!(canonical, other);
+            assert!(set.insert(other));
+        }
+
+        // A string is not a list containing it.
+        assert_ne!(Sexp::String("a".into()),
+                   Sexp::List(vec![Sexp::String("a".into())]));
+        Ok(())
+    }
+
     #[test]
     fn sexp_lookup() {
         let s = b"(3:foo(3:bar1:x)(5:xyzzy(3:baz1:y1:z)))";
@@ -840,6 +1179,79 @@ mod tests {
             &sexp);
     }
 
+    #[test]
+    fn sexp_iter() {
+        let s = b"(3:foo(3:bar1:x)(5:xyzzy(3:baz1:y1:z)))";
+        let sexp = Sexp::from_bytes(s).expect("valid sexp");
+
+        let elements = sexp.iter().collect::<Vec<_>>();
+        assert_eq!(elements.len(), 3);
+        assert_eq!(elements[0], &Sexp::String("foo".into()));
+        assert_eq!(elements[1].iter().count(), 2);
+
+        // Descend into the nested lists.
+        let mut strings = Vec::new();
+        for element in &sexp {
+            for child in element {
+                for grandchild in child {
+                    strings.push(grandchild.string().unwrap().to_bytes());
+                }
+                if let Some(s) = child.string() {
+                    strings.push(s.to_bytes());
+                }
+            }
+        }
+        assert_eq!(strings, vec![&b"bar"[..], b"x", b"xyzzy", b"baz", b"y", b"z"]);
+
+        // Strings have no elements.
+        assert_eq!(elements[0].iter().count(), 0);
+        // Neither do empty lists.
+        assert_eq!(Sexp::List(vec![]).iter().count(), 0);
+    }
+
+    #[test]
+    fn redacted() -> Result<()> {
+        let sexp = Sexp::from_bytes(crate::tests::file(
+            "sexp/keys/rsa3072-8BC1C5721611EE8BC42D0F3A53D144B3DFF8C8E3.sexp"))?;
+        let d = sexp.lookup_value(&[&b"private-key"[..], b"rsa", b"d"])?
+            .expect("has d");
+        let d = format!("{:?}", String_::new(d.to_bytes()));
+        assert!(format!("{:?}", sexp).contains(&d));
+
+        for redacted in [format!("{:?}", sexp.redacted()),
+                         format!("{:#?}", sexp.redacted())]
+        {
+            assert!(! redacted.contains(&d));
+            assert!(redacted.contains("[redacted]"));
+            // The structure is preserved.
+            assert!(redacted.contains("b\"private-key\""));
+            assert!(redacted.contains("b\"rsa\""));
+            assert!(redacted.contains("b\"d\""));
+        }
+
+        // Errors don't leak the secrets.
+        let err = Sexp::List(vec![Sexp::List(vec![]), sexp.clone()])
+            .key().unwrap_err();
+        assert!(! err.to_string().contains(&d));
+
+        // Secrets are redacted wherever they are.
+        let sexp = Sexp::from_bytes(b"(4:data(1:d6:secret)(1:n6:public))")?;
+        assert_eq!(format!("{:?}", sexp.redacted()),
+                   "[b\"data\", [b\"d\", [redacted]], [b\"n\", b\"public\"]]");
+        let sexp = Sexp::from_bytes(b"(11:private-key6:secret)")?;
+        assert_eq!(format!("{:?}", sexp.redacted()),
+                   "[b\"private-key\", [redacted]]");
+        // Only names are shown, even in the first position.
+        let sexp = Sexp::from_bytes(b"(1:d(17:secret secret key))")?;
+        assert_eq!(format!("{:?}", sexp.redacted()),
+                   "[b\"d\", [[redacted]]]");
+
+        // Other expressions are shown as is.
+        let sexp = Sexp::from_bytes(b"(10:public-key(3:ecc(1:q2:QQ)))")?;
+        assert_eq!(format!("{:?}", sexp.redacted()), format!("{:?}", sexp));
+        Ok(())
+    }
+
     #[test]
     fn to_secret_key() {
         let compare = |allow_unknown: bool,
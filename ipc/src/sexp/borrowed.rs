@@ -0,0 +1,357 @@
+//! Borrowed *S-Expressions*.
+
+use std::fmt;
+use std::ops::Deref;
+
+use sequoia_openpgp as openpgp;
+use openpgp::Error;
+
+use crate::Result;
+use crate::sexp::{MAX_DEPTH, Sexp, String_};
+
+/// An *S-Expression* borrowing its strings from the input.
+///
+/// This is like [`Sexp`], but the strings are slices of the buffer
+/// that was parsed.  Parsing does not copy the strings, only the
+/// lists are allocated.  This is useful when parsing large
+/// expressions, like keys, of which only a part is needed, and avoids
+/// leaving copies of secret key material around.  Use
+/// [`SexpRef::to_owned_sexp`] to convert it to a [`Sexp`].
+///
+/// Only the canonical encoding is supported.  Use
+/// [`Sexp::from_bytes`] to parse the other encodings.
+///
+/// # Examples
+///
+/// ```
+/// # use sequoia_ipc::sexp::{Sexp, SexpRef};
+/// # fn main() -> sequoia_openpgp::Result<()> {
+/// let data = b"(7:private(1:d3:\x01\x02\x03))";
+/// let sexp = SexpRef::from_bytes(data)?;
+///
+/// let d = sexp.iter().nth(1).and_then(|d| d.iter().nth(1))
+///     .and_then(|d| d.string()).expect("d is a string");
+/// // No copy was made.
+/// assert_eq!(d.as_bytes().as_ptr(), data[16..].as_ptr());
+///
+/// assert_eq!(sexp.to_owned_sexp(), Sexp::from_bytes(data)?);
+/// # Ok(()) }
+/// ```
+#[derive(Clone, PartialEq, Eq)]
+pub enum SexpRef<'a> {
+    /// Just a string.
+    String(StringRef<'a>),
+    /// A list of *S-Expressions*.
+    List(Vec<SexpRef<'a>>),
+}
+
+impl Drop for SexpRef<'_> {
+    fn drop(&mut self) {
+        // Dropping nested lists recursively could exhaust the stack,
+        // hence we take them apart here.
+        if let SexpRef::List(l) = self {
+            let mut pending = std::mem::take(l);
+            while let Some(mut sexp) = pending.pop() {
+                if let SexpRef::List(l) = &mut sexp {
+                    pending.append(l);
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for SexpRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SexpRef::String(ref s) => s.fmt(f),
+            SexpRef::List(ref l) => l.fmt(f),
+        }
+    }
+}
+
+impl<'a> SexpRef<'a> {
+    /// Parses the canonical encoding of an *S-Expression*.
+    ///
+    /// The strings of the returned expression borrow from `data`.
+    pub fn from_bytes<D: AsRef<[u8]> + ?Sized>(data: &'a D)
+                                               -> Result<SexpRef<'a>>
+    {
+        let data = data.as_ref();
+        let mut pos = 0;
+        // The lists that have been opened, but not yet closed.
+        let mut stack: Vec<Vec<SexpRef<'a>>> = Vec::new();
+
+        loop {
+            let c = *data.get(pos)
+                .ok_or_else(|| malformed(pos, "unexpected end of input"))?;
+            let sexp = match c {
+                b'(' => {
+                    if stack.len() >= MAX_DEPTH {
+                        return Err(malformed(
+                            pos, "lists are nested too deeply"));
+                    }
+                    pos += 1;
+                    stack.push(Vec::new());
+                    continue;
+                },
+                b')' => {
+                    let list = stack.pop()
+                        .ok_or_else(|| malformed(pos, "unbalanced ')'"))?;
+                    pos += 1;
+                    SexpRef::List(list)
+                },
+                _ => SexpRef::String(StringRef::parse(data, &mut pos)?),
+            };
+
+            if let Some(list) = stack.last_mut() {
+                list.push(sexp);
+            } else if pos < data.len() {
+                return Err(malformed(pos, "trailing data"));
+            } else {
+                return Ok(sexp);
+            }
+        }
+    }
+
+    /// Casts this to a string.
+    pub fn string(&self) -> Option<&StringRef<'a>> {
+        match self {
+            SexpRef::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Casts this to a list.
+    pub fn list(&self) -> Option<&[SexpRef<'a>]> {
+        match self {
+            SexpRef::List(ref s) => Some(s.as_slice()),
+            _ => None,
+        }
+    }
+
+    /// Returns an iterator over the elements of this list.
+    ///
+    /// See [`Sexp::iter`].
+    pub fn iter(&self) -> std::slice::Iter<'_, SexpRef<'a>> {
+        self.list().unwrap_or(&[]).iter()
+    }
+
+    /// Copies the expression into a [`Sexp`].
+    pub fn to_owned_sexp(&self) -> Sexp {
+        // The lists being copied, and their elements that remain to
+        // be copied.  The outermost list holds just this expression.
+        let mut stack = vec![(Vec::new(), std::slice::from_ref(self).iter())];
+        loop {
+            let (list, rest) = stack.last_mut().expect("not empty");
+            match rest.next() {
+                Some(SexpRef::String(s)) =>
+                    list.push(Sexp::String(s.to_owned_string())),
+                Some(SexpRef::List(l)) =>
+                    stack.push((Vec::with_capacity(l.len()), l.iter())),
+                None => {
+                    let (mut list, _) = stack.pop().expect("not empty");
+                    match stack.last_mut() {
+                        Some((parent, _)) => parent.push(Sexp::List(list)),
+                        None => return list.pop().expect("just this"),
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Iterates over the elements of a list.
+///
+/// See [`SexpRef::iter`].
+impl<'a, 'b> IntoIterator for &'b SexpRef<'a> {
+    type Item = &'b SexpRef<'a>;
+    type IntoIter = std::slice::Iter<'b, SexpRef<'a>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// A string borrowed from the input.
+///
+/// This is the borrowed counterpart of [`String_`].
+#[derive(Clone, PartialEq, Eq)]
+pub struct StringRef<'a>(&'a [u8], Option<&'a [u8]>);
+
+impl fmt::Debug for StringRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Format it like the owned string.
+        self.to_owned_string().fmt(f)
+    }
+}
+
+impl<'a> StringRef<'a> {
+    /// Parses a string starting at `*pos`, and advances `pos` past
+    /// it.
+    fn parse(data: &'a [u8], pos: &mut usize) -> Result<Self> {
+        let hint = if data.get(*pos) == Some(&b'[') {
+            *pos += 1;
+            let hint = parse_verbatim(data, pos)?;
+            if data.get(*pos) != Some(&b']') {
+                return Err(malformed(*pos, "expected ']'"));
+            }
+            *pos += 1;
+            Some(hint)
+        } else {
+            None
+        };
+
+        Ok(StringRef(parse_verbatim(data, pos)?, hint))
+    }
+
+    /// Returns the bytes.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
+
+    /// Gets a reference to this *String*'s display hint, if any.
+    pub fn display_hint(&self) -> Option<&'a [u8]> {
+        self.1
+    }
+
+    /// Copies the string into a [`String_`].
+    pub fn to_owned_string(&self) -> String_ {
+        match self.1 {
+            Some(hint) => String_::with_display_hint(self.0, hint),
+            None => String_::new(self.0),
+        }
+    }
+}
+
+impl Deref for StringRef<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
+/// Parses a verbatim string, i.e. a length followed by a colon and
+/// the data, starting at `*pos`, and advances `pos` past it.
+fn parse_verbatim<'a>(data: &'a [u8], pos: &mut usize) -> Result<&'a [u8]> {
+    let start = *pos;
+    let mut len: usize = 0;
+    while let Some(c) = data.get(*pos).filter(|c| c.is_ascii_digit()) {
+        len = len.checked_mul(10)
+            .and_then(|l| l.checked_add((c - b'0') as usize))
+            .ok_or_else(|| malformed(start, "length overflows"))?;
+        *pos += 1;
+    }
+    if *pos == start {
+        return Err(malformed(*pos, "expected a length"));
+    }
+    if data.get(*pos) != Some(&b':') {
+        return Err(malformed(*pos, "expected ':'"));
+    }
+    *pos += 1;
+
+    let s = data.get(*pos..).and_then(|rest| rest.get(..len))
+        .ok_or_else(|| malformed(start, "string exceeds the input"))?;
+    *pos += len;
+    Ok(s)
+}
+
+/// Returns an error for malformed input at `pos`.
+fn malformed(pos: usize, msg: &str) -> anyhow::Error {
+    Error::InvalidArgument(
+        format!("Malformed S-Expression at offset {}: {}", pos, msg)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    quickcheck::quickcheck! {
+        fn roundtrip(s: Sexp) -> bool {
+            let mut buf = Vec::new();
+            s.serialize(&mut buf).unwrap();
+            SexpRef::from_bytes(&buf).unwrap().to_owned_sexp() == s
+        }
+    }
+
+    #[test]
+    fn basics() -> Result<()> {
+        let data = b"(2:hi[5:fancy]2:ho(1:x))";
+        let sexp = SexpRef::from_bytes(data)?;
+        assert_eq!(sexp.to_owned_sexp(), Sexp::from_bytes(data)?);
+
+        let l = sexp.list().expect("a list");
+        assert_eq!(l.len(), 3);
+        assert_eq!(l[0].string().unwrap().as_bytes(), b"hi");
+        assert_eq!(l[0].string().unwrap().display_hint(), None);
+        assert_eq!(&l[1].string().unwrap()[..], b"ho");
+        assert_eq!(l[1].string().unwrap().display_hint(), Some(&b"fancy"[..]));
+        assert_eq!(l[2].iter().count(), 1);
+        assert_eq!(l[2].iter().next().unwrap().string().unwrap().as_bytes(),
+                   b"x");
+
+        // The strings point into the input.
+        assert_eq!(l[1].string().unwrap().as_bytes().as_ptr(),
+                   data[16..].as_ptr());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid() {
+        for data in [
+            &b""[..],
+            b"(",
+            b")",
+            b"(2:hi",
+            b"2:hi)",
+            b"2:hi2:ho",
+            b"3:hi",
+            b"2hi",
+            b":hi",
+            b"[5:fancy2:hi",
+            b"(2:hi 2:ho)",
+            b"99999999999999999999999:hi",
+            b"{KDI6aGkp}",
+        ] {
+            assert!(SexpRef::from_bytes(data).is_err(),
+                    "{:?}", String::from_utf8_lossy(data));
+        }
+    }
+
+    #[test]
+    fn nesting() -> Result<()> {
+        let nested = |depth: usize| {
+            let mut data = b"(".repeat(depth);
+            data.extend_from_slice(&b")".repeat(depth));
+            data
+        };
+        SexpRef::from_bytes(&nested(MAX_DEPTH))?;
+        assert!(SexpRef::from_bytes(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(SexpRef::from_bytes(&b"(".repeat(1 << 20)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn deeply_nested() {
+        const DEPTH: usize = 1 << 20;
+        let mut sexp_ref = SexpRef::List(Vec::new());
+        for _ in 1..DEPTH {
+            sexp_ref = SexpRef::List(vec![sexp_ref]);
+        }
+
+        // Neither copying it nor dropping it recurses.
+        let mut sexp = sexp_ref.to_owned_sexp();
+        drop(sexp_ref);
+
+        // Sexp's destructor recurses, so we take it apart here.
+        let mut depth = 0;
+        while let Sexp::List(mut l) = sexp {
+            depth += 1;
+            sexp = match l.pop() {
+                Some(sexp) => sexp,
+                None => break,
+            };
+        }
+        assert_eq!(depth, DEPTH);
+    }
+}
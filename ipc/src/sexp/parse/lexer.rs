@@ -25,6 +25,7 @@ pub enum LexicalError {
     LengthOverflow(String),
     TruncatedInput(String),
     UnexpectedCharacter(String),
+    TooDeep(String),
 }
 
 impl fmt::Display for LexicalError {
@@ -280,6 +281,12 @@ pub(crate) struct Lexer<'input> {
     pending: Option<Token<'input>>,
     input: &'input [u8],
     pub state: Rc<RefCell<State>>,
+    // The number of open lists.
+    depth: usize,
+    // Whether we are in a quoted string, and whether the previous
+    // token in it was a backslash.
+    quoted: bool,
+    escaped: bool,
 }
 
 impl<'input> Lexer<'input> {
@@ -289,6 +296,9 @@ impl<'input> Lexer<'input> {
             pending: None,
             input,
             state: Rc::new(RefCell::new(State::new())),
+            depth: 0,
+            quoted: false,
+            escaped: false,
         }
     }
 }
@@ -452,6 +462,30 @@ impl<'input> Iterator for Lexer<'input> {
             Ok(x) => x,
             Err(e) => return Some(Err(e)),
         };
+
+        // Track the nesting depth.  Parentheses in quoted strings
+        // don't count.
+        if self.quoted {
+            if self.escaped {
+                self.escaped = false;
+            } else if token == BACKSLASH {
+                self.escaped = true;
+            } else if token == DQUOTE {
+                self.quoted = false;
+            }
+        } else if token == DQUOTE {
+            self.quoted = true;
+        } else if token == LPAREN {
+            self.depth += 1;
+            if self.depth > crate::sexp::MAX_DEPTH {
+                return Some(Err(LexicalError::TooDeep(
+                    format!("Lists are nested more than {} levels deep",
+                            crate::sexp::MAX_DEPTH))));
+            }
+        } else if token == RPAREN {
+            self.depth = self.depth.saturating_sub(1);
+        }
+
         self.input = &self.input[l..];
 
         let start = self.offset;
@@ -6,7 +6,7 @@
 //! [S-Expressions]: https://people.csail.mit.edu/rivest/Sexp.txt
 
 use std::cmp;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::rc::Rc;
 
 use openpgp::parse::buffered_reader::{self, BufferedReader};
@@ -16,7 +16,7 @@ use openpgp::parse::Cookie;
 
 use openpgp::Error;
 use crate::Result;
-use crate::sexp::Sexp;
+use crate::sexp::{Sexp, SexpRef};
 
 mod lexer;
 use lexer::Lexer;
@@ -77,9 +77,56 @@ impl<'a> Sexp {
     pub fn from_bytes<D: AsRef<[u8]> + ?Sized>(data: &'a D) -> Result<Sexp> {
         Self::from_bytes_private(data.as_ref())
     }
+
+    /// Reads exactly one canonically encoded *S-Expression* from the
+    /// given reader.
+    ///
+    /// Unlike [`Sexp::from_reader`], this consumes only the bytes of
+    /// the expression, leaving `reader` positioned at whatever
+    /// follows.  This is useful for protocols like gpg-agent's, which
+    /// send expressions back-to-back.
+    ///
+    /// Returns `None` if `reader` is at the end of the input.  Only
+    /// the canonical encoding is supported.
+    ///
+    /// Because the length of the expression is not known in advance,
+    /// its structure is read one byte at a time.  When reading from
+    /// sockets, wrap them in a [`std::io::BufReader`], and keep using
+    /// that for the rest of the conversation.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use sequoia_ipc::sexp::Sexp;
+    /// # fn main() -> sequoia_openpgp::Result<()> {
+    /// let mut input = &b"(2:hi)(2:ho)OK"[..];
+    /// assert_eq!(Sexp::read_canonical(&mut input)?,
+    ///            Some(Sexp::from_bytes(b"(2:hi)")?));
+    /// assert_eq!(Sexp::read_canonical(&mut input)?,
+    ///            Some(Sexp::from_bytes(b"(2:ho)")?));
+    /// assert_eq!(input, b"OK");
+    /// # Ok(()) }
+    /// ```
+    pub fn read_canonical<R: Read>(reader: &mut R) -> Result<Option<Sexp>> {
+        Self::read_canonical_private(reader)
+    }
 }
 
 impl Sexp {
+    fn read_canonical_private(reader: &mut dyn Read) -> Result<Option<Sexp>> {
+        let mut buf = Vec::new();
+        let sexp = match read_canonical(reader, &mut buf) {
+            Ok(true) => SexpRef::from_bytes(&buf).map(|s| Some(s.to_owned_sexp())),
+            Ok(false) => Ok(None),
+            Err(err) => Err(err),
+        };
+        // The expression may contain secrets.
+        unsafe {
+            memsec::memzero(buf.as_mut_ptr(), buf.len());
+        }
+        sexp
+    }
+
     fn from_bytes_private(data: &[u8]) -> Result<Sexp> {
         let lexer = Lexer::new(data);
         let state = Rc::clone(&lexer.state);
@@ -120,6 +167,115 @@ impl Sexp {
     }
 }
 
+/// Copies one canonically encoded expression from `reader` to `buf`.
+///
+/// Returns `false` if `reader` is at the end of the input.  This
+/// only checks the framing, the result still needs to be parsed.
+fn read_canonical(reader: &mut dyn Read, buf: &mut Vec<u8>) -> Result<bool> {
+    // The number of lists that have been opened, but not yet closed.
+    let mut depth = 0usize;
+    loop {
+        let c = match next_byte(reader)? {
+            Some(c) => c,
+            None if buf.is_empty() => return Ok(false),
+            None => return Err(malformed("unexpected end of input")),
+        };
+        buf.push(c);
+
+        match c {
+            b'(' => {
+                depth += 1;
+                if depth > crate::sexp::MAX_DEPTH {
+                    return Err(malformed("lists are nested too deeply"));
+                }
+            },
+            b')' => {
+                depth = depth.checked_sub(1)
+                    .ok_or_else(|| malformed("unbalanced ')'"))?;
+            },
+            b'[' => {
+                read_verbatim(reader, buf, None)?;
+                if next_byte(reader)? != Some(b']') {
+                    return Err(malformed("expected ']'"));
+                }
+                buf.push(b']');
+                // The string follows.
+                continue;
+            },
+            b'0'..=b'9' => read_verbatim(reader, buf, Some(c))?,
+            _ => return Err(malformed(
+                &format!("unexpected character {:?}", c as char))),
+        }
+
+        if depth == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Copies a verbatim string, i.e. a length followed by a colon and
+/// the data, from `reader` to `buf`.
+///
+/// If the first digit of the length has already been read, it is
+/// passed in `first`.
+fn read_verbatim(reader: &mut dyn Read, buf: &mut Vec<u8>, first: Option<u8>)
+                 -> Result<()>
+{
+    let mut len: usize = 0;
+    let mut digits = 0;
+    let mut c = first;
+    loop {
+        let d = match c {
+            Some(d) => d,
+            None => {
+                let d = next_byte(reader)?
+                    .ok_or_else(|| malformed("unexpected end of input"))?;
+                buf.push(d);
+                d
+            },
+        };
+        c = None;
+
+        match d {
+            b'0'..=b'9' => {
+                len = len.checked_mul(10)
+                    .and_then(|l| l.checked_add((d - b'0') as usize))
+                    .ok_or_else(|| malformed("length overflows"))?;
+                digits += 1;
+            },
+            b':' if digits > 0 => break,
+            _ => return Err(malformed("expected a length")),
+        }
+    }
+
+    // Don't trust the length when allocating.
+    let start = buf.len();
+    Read::take(&mut *reader, len as u64).read_to_end(buf)?;
+    if buf.len() - start != len {
+        return Err(malformed("string exceeds the input"));
+    }
+    Ok(())
+}
+
+/// Reads a single byte.
+///
+/// Returns `None` at the end of the input.
+fn next_byte(reader: &mut dyn Read) -> Result<Option<u8>> {
+    let mut b = [0; 1];
+    loop {
+        match reader.read(&mut b) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(b[0])),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Returns an error for a malformed expression.
+fn malformed(msg: &str) -> anyhow::Error {
+    Error::InvalidArgument(format!("Malformed S-Expression: {}", msg)).into()
+}
 
 #[cfg(test)]
 mod tests {
@@ -283,6 +439,71 @@ mod tests {
             crate::tests::file("sexp/rsa-signature.sexp")).is_ok());
     }
 
+    #[test]
+    fn read_canonical() -> crate::Result<()> {
+        let data = b"(3:foo)[5:fancy]2:hi(1:a(1:b[1:h]1:c))rest";
+        let mut reader = std::io::Cursor::new(&data[..]);
+
+        assert_eq!(Sexp::read_canonical(&mut reader)?,
+                   Some(Sexp::from_bytes(b"(3:foo)")?));
+        assert_eq!(reader.position(), 7);
+        assert_eq!(Sexp::read_canonical(&mut reader)?,
+                   Some(Sexp::String(String_::with_display_hint(
+                       b"hi".to_vec(), b"fancy".to_vec()))));
+        assert_eq!(reader.position(), 20);
+        assert_eq!(Sexp::read_canonical(&mut reader)?,
+                   Some(Sexp::from_bytes(b"(1:a(1:b[1:h]1:c))")?));
+        assert_eq!(reader.position(), 38);
+        assert_eq!(&data[reader.position() as usize..], b"rest");
+
+        let mut reader = &b""[..];
+        assert_eq!(Sexp::read_canonical(&mut reader)?, None);
+
+        for data in [
+            &b"("[..],
+            b")",
+            b"(3:foo",
+            b"3:fo",
+            b"3foo",
+            b":foo",
+            b"[5:fancy2:hi",
+            b"[5:fancy]",
+            b"(3:foo 3:bar)",
+            b"99999999999999999999999:hi",
+        ] {
+            assert!(Sexp::read_canonical(&mut &data[..]).is_err(),
+                    "{:?}", String::from_utf8_lossy(data));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn nesting() -> crate::Result<()> {
+        use crate::sexp::MAX_DEPTH;
+
+        let nested = |depth: usize| {
+            let mut data = b"(".repeat(depth);
+            data.extend_from_slice(&b")".repeat(depth));
+            data
+        };
+        Sexp::from_bytes(&nested(MAX_DEPTH))?;
+        Sexp::read_canonical(&mut &nested(MAX_DEPTH)[..])?;
+        assert!(Sexp::from_bytes(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(Sexp::read_canonical(&mut &nested(MAX_DEPTH + 1)[..]).is_err());
+
+        // Deeply nested input is rejected before it is parsed.
+        let deep = b"(".repeat(1 << 20);
+        assert!(Sexp::from_bytes(&deep).is_err());
+        assert!(Sexp::read_canonical(&mut &deep[..]).is_err());
+
+        // Parentheses in quoted strings don't count.
+        let mut data = b"(\"".to_vec();
+        data.extend_from_slice(&b"(".repeat(MAX_DEPTH + 1));
+        data.extend_from_slice(b"\\\"\")");
+        Sexp::from_bytes(&data)?;
+        Ok(())
+    }
+
     /// Demonstrates a crash in the lexer.
     #[test]
     fn issue_742() {
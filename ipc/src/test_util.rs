@@ -0,0 +1,151 @@
+//! Helpers for testing external servers.
+//!
+//! Exercising the code path that starts an external server requires
+//! a server executable.  Instead of building a separate binary, a
+//! test can use the test binary itself: [`descriptor`] returns a
+//! descriptor whose executable is the current test binary, and
+//! [`serve_if_requested`] turns the re-executed test binary into the
+//! server.
+//!
+//! This module is only available if the `test-util` feature is
+//! enabled.
+//!
+//! # The re-exec convention
+//!
+//! When the client starts the server, it runs the test binary like
+//! this:
+//!
+//! ```text
+//! <test binary> --exact <test> -- --sequoia-ipc-test-server <server args>
+//! ```
+//!
+//! The test harness hence only runs the given test, which must call
+//! [`serve_if_requested`] first thing.  If the process's command
+//! line contains [`SERVER_ARG`], this parses the [`ServerArgs`]
+//! following it, serves clients, and exits the process.  Otherwise,
+//! it returns immediately, and the test continues as the client.
+//!
+//! On Unix, the server exits once the test binary that started it
+//! exits, so that tests don't leave servers behind.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! #[test]
+//! fn external_server() -> Result<()> {
+//!     test_util::serve_if_requested(factory);
+//!
+//!     let ctx = Context::configure()
+//!         .ephemeral()
+//!         .ipc_policy(IPCPolicy::External)
+//!         .build()?;
+//!     let descriptor = test_util::descriptor(&ctx, "external_server", factory)?;
+//!     let response = descriptor.with_client(|hello: hello::Client| async move {
+//!         // ...
+//!     })?;
+//!     Ok(())
+//! }
+//! ```
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+
+use crate::core::Context;
+use crate::{Descriptor, DescriptorBuilder, HandlerFactory, Result, Server,
+            ServerArgs};
+
+/// The argument that turns the test binary into a server.
+///
+/// The [`ServerArgs`] follow it.
+pub const SERVER_ARG: &str = "--sequoia-ipc-test-server";
+
+/// The name of the additional argument carrying the rendezvous
+/// point.
+const RENDEZVOUS_ARG: &str = "test-rendezvous";
+
+/// Returns a descriptor that starts the current test binary as
+/// server.
+///
+/// `test` is the name of the test calling this function, as the test
+/// harness knows it (e.g. `module::test_name`).  The started test
+/// binary only runs this test, which must call
+/// [`serve_if_requested`] with the same `factory` before doing
+/// anything else.
+///
+/// The rendezvous point is in `ctx`'s home directory, and is named
+/// after the test.  Note that the server checks that `ctx`'s lib
+/// directory exists.
+pub fn descriptor(ctx: &Context, test: &str, factory: HandlerFactory)
+                  -> Result<Descriptor>
+{
+    let executable = std::env::current_exe()
+        .context("Getting the test binary")?;
+    let rendezvous = crate::rendezvous_path(ctx, &test.replace("::", "-"));
+
+    let mut builder = DescriptorBuilder::new(
+        ctx, rendezvous.clone(), executable, factory)
+        .arg(RENDEZVOUS_ARG, &rendezvous)?;
+    builder.0.executable_args = vec![
+        "--exact".into(),
+        test.into(),
+        // The test harness treats the following arguments as
+        // filters, which don't match any test.
+        "--".into(),
+        SERVER_ARG.into(),
+    ];
+    Ok(builder.build())
+}
+
+/// Turns this process into a server, if requested.
+///
+/// If the process's command line contains [`SERVER_ARG`], this
+/// serves clients using `factory`, and exits the process once the
+/// server exits.  Otherwise, this does nothing.
+pub fn serve_if_requested(factory: HandlerFactory) {
+    let mut args = std::env::args_os();
+    if ! args.any(|arg| arg == SERVER_ARG) {
+        return;
+    }
+
+    match serve(args.collect(), factory) {
+        Ok(()) => std::process::exit(0),
+        Err(err) => {
+            tracing::error!("Test server failed: {:?}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Serves clients.
+fn serve(args: Vec<OsString>, factory: HandlerFactory) -> Result<()> {
+    let args = ServerArgs::parse(args)?;
+    let ctx = args.context()?;
+    let rendezvous = args.get(RENDEZVOUS_ARG)
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow::anyhow!("--{} is missing", RENDEZVOUS_ARG))?;
+    let executable = std::env::current_exe()
+        .context("Getting the test binary")?;
+
+    #[cfg(unix)]
+    exit_with_parent();
+
+    // The test harness runs the test on its own thread, which is
+    // not a runtime context.
+    let descriptor = Descriptor::new(&ctx, rendezvous, executable, factory);
+    Server::new(descriptor)?.serve()
+}
+
+/// Exits the process once the parent process exits.
+#[cfg(unix)]
+fn exit_with_parent() {
+    // Safety: getppid is always successful.
+    let parent = unsafe { libc::getppid() };
+    std::thread::spawn(move || loop {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        if unsafe { libc::getppid() } != parent {
+            std::process::exit(0);
+        }
+    });
+}
@@ -0,0 +1,80 @@
+//! Looks up fingerprints in a large keybox using the streaming
+//! parser and using a memory-mapped keybox, and checks that they
+//! agree.
+
+#![cfg(feature = "mmap")]
+
+use sequoia_openpgp as openpgp;
+use openpgp::Fingerprint;
+
+use sequoia_ipc::keybox::{Keybox, KeyboxRecord};
+
+/// The number of OpenPGP records in the keybox.
+const RECORDS: usize = 20_000;
+
+/// Looks up `fp` using the streaming parser.
+fn streaming(kbx: Keybox, fp: &Fingerprint) -> Option<usize> {
+    kbx.filter_map(|record| match record {
+        Ok(KeyboxRecord::OpenPGP(r)) => Some(r),
+        _ => None,
+    })
+        .find(|r| r.keys().unwrap().iter().any(|k| &k.fingerprint() == fp))
+        .map(|r| r.offset())
+}
+
+#[test]
+fn large_keybox() -> openpgp::Result<()> {
+    // A keybox consisting of a header record, and testy's record,
+    // which we repeat.
+    let kbx = include_bytes!("data/keyboxes/keybox.kbx");
+    let header_len = u32::from_be_bytes(kbx[..4].try_into().unwrap()) as usize;
+    let record_len = u32::from_be_bytes(
+        kbx[header_len..header_len + 4].try_into().unwrap()) as usize;
+    let mut data = kbx[..header_len].to_vec();
+    for _ in 0..RECORDS {
+        data.extend_from_slice(&kbx[header_len..header_len + record_len]);
+    }
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("pubring.kbx");
+    std::fs::write(&path, &data)?;
+
+    // Testy is the first record, neal is not in the keybox, so we
+    // have to scan all of the records.
+    let testy: Fingerprint = "3E8877C877274692975189F5D03F6F865226FE8B".parse()?;
+    let neal: Fingerprint = "8F17777118A33DDA9BA48E62AACB3243630052D9".parse()?;
+
+    let mapped = Keybox::open_mmap(&path)?;
+    for fp in [&testy, &neal] {
+        assert_eq!(streaming(Keybox::from_file(&path)?, fp),
+                   mapped.find_by_fingerprint(fp)?.map(|r| r.offset()));
+    }
+    assert_eq!(mapped.find_by_fingerprint(&testy)?.map(|r| r.offset()),
+               Some(header_len));
+    assert!(mapped.find_by_fingerprint(&neal)?.is_none());
+
+    // Every record is located, at the same offset as the streaming
+    // parser locates it.
+    let offsets = mapped.blobs()?
+        .map(|blob| blob.map(|b| b.offset()))
+        .collect::<openpgp::Result<Vec<_>>>()?;
+    assert_eq!(offsets.len(), RECORDS + 1);
+    assert_eq!(offsets.last(),
+               Some(&(header_len + (RECORDS - 1) * record_len)));
+    let streamed = Keybox::from_file(&path)?
+        .map(|record| record.map(|r| r.offset()))
+        .collect::<openpgp::Result<Vec<_>>>()?;
+    assert_eq!(offsets, streamed);
+
+    // Only the matching record is parsed: a corrupted record
+    // following it doesn't affect finding testy, but it is noticed
+    // when looking for neal.
+    data.extend_from_slice(&[0, 0, 0, 0xff, 2]);
+    let corrupted = dir.path().join("corrupted.kbx");
+    std::fs::write(&corrupted, &data)?;
+    let mapped = Keybox::open_mmap(&corrupted)?;
+    assert_eq!(mapped.find_by_fingerprint(&testy)?.map(|r| r.offset()),
+               Some(header_len));
+    assert!(mapped.find_by_fingerprint(&neal).is_err());
+    Ok(())
+}
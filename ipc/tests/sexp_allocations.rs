@@ -0,0 +1,65 @@
+//! Compares the number of allocations when parsing a large
+//! S-Expression into a `Sexp` and into a `SexpRef`.
+//!
+//! This lives in its own test binary, because it installs a counting
+//! global allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use sequoia_ipc::sexp::{Sexp, SexpRef};
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize)
+                      -> *mut u8
+    {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Returns the number of allocations `f` makes.
+fn count<T>(f: impl FnOnce() -> T) -> (usize, T) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let r = f();
+    (ALLOCATIONS.load(Ordering::Relaxed) - before, r)
+}
+
+#[test]
+fn allocations() {
+    // A key-like expression with many large MPIs.
+    const MPIS: usize = 1000;
+    let mut data = b"(11:private-key(3:rsa".to_vec();
+    for i in 0..MPIS {
+        data.extend_from_slice(format!("(1:{}512:", i % 10).as_bytes());
+        data.extend(std::iter::repeat(i as u8).take(512));
+        data.push(b')');
+    }
+    data.extend_from_slice(b"))");
+
+    let (owned, sexp) = count(|| Sexp::from_bytes(&data).unwrap());
+    let (borrowed, sexp_ref) = count(|| SexpRef::from_bytes(&data).unwrap());
+
+    assert_eq!(sexp_ref.to_owned_sexp(), sexp);
+
+    // Every string is copied.
+    assert!(owned >= 2 * MPIS, "{}", owned);
+    // Only the lists are allocated.
+    assert!(borrowed <= MPIS + 64, "{}", borrowed);
+}
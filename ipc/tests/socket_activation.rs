@@ -0,0 +1,162 @@
+//! Starts a server the way systemd's socket activation does.
+//!
+//! The test re-executes the test binary with a listening socket on
+//! file descriptor 3, and `LISTEN_FDS` and `LISTEN_PID` set, which
+//! then serves clients using `Server::from_socket_activation`.
+
+#![cfg(unix)]
+
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use capnp_rpc::rpc_twoparty_capnp::Side;
+use capnp_rpc::{twoparty, RpcSystem};
+
+use sequoia_ipc as ipc;
+use ipc::Result;
+
+#[allow(missing_docs, dead_code, clippy::all)]
+#[path = "../examples/ipc-standalone/hello_protocol_capnp.rs"]
+mod hello_protocol_capnp;
+use hello_protocol_capnp::hello;
+
+/// Set for the re-executed test binary, names the home directory.
+const HOME_VAR: &str = "SEQUOIA_IPC_TEST_ACTIVATED_HOME";
+
+struct Hello {
+    c: hello::Client,
+    ctx: ipc::Context,
+}
+
+impl ipc::Handler for Hello {
+    fn handle(
+        &self,
+        network: twoparty::VatNetwork<tokio_util::compat::Compat<tokio::net::tcp::OwnedReadHalf>>,
+    ) -> Result<RpcSystem<Side>> {
+        Ok(ipc::server_rpc_system(&self.ctx, network, self.c.clone().client))
+    }
+}
+
+/// Tells the client the server's process id.
+struct HelloServer {}
+
+impl hello::Server for HelloServer {
+    fn hello(
+        &mut self,
+        _: hello::HelloParams,
+        mut results: hello::HelloResults,
+    ) -> capnp::capability::Promise<(), capnp::Error> {
+        let response = std::process::id().to_string();
+        results.get().set_response(&response);
+        capnp::capability::Promise::ok(())
+    }
+}
+
+fn factory(descriptor: ipc::Descriptor, _: &tokio::task::LocalSet)
+           -> Result<Box<dyn ipc::Handler>>
+{
+    Ok(Box::new(Hello {
+        c: capnp_rpc::new_client(HelloServer {}),
+        ctx: descriptor.context().clone(),
+    }))
+}
+
+fn descriptor(ctx: &ipc::Context) -> ipc::Descriptor {
+    ipc::Descriptor::new(ctx, ipc::rendezvous_path(ctx, "activated"),
+                         PathBuf::from("/does-not-exist"), factory)
+}
+
+/// Kills the server when dropped.
+struct Child(std::process::Child);
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn socket_activation() -> Result<()> {
+    if let Some(home) = std::env::var_os(HOME_VAR) {
+        let ctx = ipc::Context::configure()
+            .home(home)
+            .build()?;
+        ipc::Server::from_socket_activation(descriptor(&ctx))
+            .and_then(|mut server| server.serve())
+            .expect("activated server failed");
+        std::process::exit(0);
+    }
+
+    let ctx = ipc::Context::configure()
+        .ephemeral()
+        .ipc_policy(ipc::IPCPolicy::Internal)
+        .build()?;
+    let descriptor = descriptor(&ctx);
+
+    // Pass the listener as file descriptor 3.  `LISTEN_PID` must be
+    // the server's process id, which the shell knows, and keeps
+    // across the exec.
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let fd = listener.as_raw_fd();
+    let mut cmd = Command::new("/bin/sh");
+    cmd.arg("-c")
+        .arg("LISTEN_PID=$$ LISTEN_FDS=1 exec \"$0\" \"$@\"")
+        .arg(std::env::current_exe()?)
+        .args(["--exact", "socket_activation"])
+        .env(HOME_VAR, ctx.home())
+        .stdin(Stdio::null());
+    unsafe {
+        cmd.pre_exec(move || {
+            // dup2 clears FD_CLOEXEC, but not if the descriptors
+            // are the same.
+            let r = if fd == 3 {
+                libc::fcntl(fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(fd, 3)
+            };
+            if r == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = Child(cmd.spawn()?);
+    drop(listener);
+
+    // The server publishes the listener at the rendezvous point.
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while std::fs::metadata(descriptor.rendez_vous())
+        .map(|m| m.len() == 0).unwrap_or(true)
+    {
+        if let Some(status) = child.0.try_wait()? {
+            panic!("activated server exited: {}", status);
+        }
+        assert!(Instant::now() < deadline, "server didn't publish itself");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    // Clients authenticate using the cookie it wrote, and talk to
+    // it, not to a server of their own.
+    let pid = descriptor.with_client(|hello: hello::Client| async move {
+        let response = hello.hello_request().send().promise.await?;
+        Ok(response.get()?.get_response()?.to_str()?.parse::<u32>()?)
+    })?;
+    assert_eq!(pid, child.0.id());
+
+    // A client that doesn't know the cookie is turned away.
+    let addr = match descriptor.plan_connect()? {
+        ipc::ConnectPlan::Connect(addr) => addr,
+        plan => panic!("unexpected plan: {}", plan),
+    };
+    let mut s = TcpStream::connect(addr)?;
+    s.write_all(&[0; 64])?;
+    s.set_read_timeout(Some(Duration::from_secs(30)))?;
+    assert!(matches!(s.read(&mut [0; 1]), Ok(0) | Err(_)));
+    Ok(())
+}
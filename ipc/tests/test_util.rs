@@ -0,0 +1,116 @@
+//! Starts a real external server by re-executing the test binary,
+//! see `sequoia_ipc::test_util`.
+
+#![cfg(all(feature = "test-util", not(feature = "no-subprocess")))]
+
+use capnp_rpc::rpc_twoparty_capnp::Side;
+use capnp_rpc::{twoparty, RpcSystem};
+
+use sequoia_ipc as ipc;
+use ipc::{test_util, Result};
+
+#[allow(missing_docs, dead_code, clippy::all)]
+#[path = "../examples/ipc-standalone/hello_protocol_capnp.rs"]
+mod hello_protocol_capnp;
+use hello_protocol_capnp::hello;
+
+struct Hello {
+    c: hello::Client,
+    ctx: ipc::Context,
+}
+
+impl ipc::Handler for Hello {
+    fn handle(
+        &self,
+        network: twoparty::VatNetwork<tokio_util::compat::Compat<tokio::net::tcp::OwnedReadHalf>>,
+    ) -> Result<RpcSystem<Side>> {
+        Ok(ipc::server_rpc_system(&self.ctx, network, self.c.clone().client))
+    }
+}
+
+/// Greets the client, and tells it the server's process id.
+struct HelloServer {}
+
+impl hello::Server for HelloServer {
+    fn hello(
+        &mut self,
+        _: hello::HelloParams,
+        mut results: hello::HelloResults,
+    ) -> capnp::capability::Promise<(), capnp::Error> {
+        let response = std::process::id().to_string();
+        results.get().set_response(&response);
+        capnp::capability::Promise::ok(())
+    }
+}
+
+fn factory(descriptor: ipc::Descriptor, _: &tokio::task::LocalSet)
+           -> Result<Box<dyn ipc::Handler>>
+{
+    Ok(Box::new(Hello {
+        c: capnp_rpc::new_client(HelloServer {}),
+        ctx: descriptor.context().clone(),
+    }))
+}
+
+#[test]
+fn external_server() -> Result<()> {
+    test_util::serve_if_requested(factory);
+
+    let exe = std::env::current_exe()?;
+    let ctx = ipc::Context::configure()
+        .ephemeral()
+        .lib(exe.parent().expect("test binary is in a directory"))
+        .ipc_policy(ipc::IPCPolicy::External)
+        .build()?;
+    let descriptor = test_util::descriptor(&ctx, "external_server", factory)?;
+
+    let ask = || descriptor.with_client(|hello: hello::Client| async move {
+        let response = hello.hello_request().send().promise.await?;
+        Ok(response.get()?.get_response()?.to_str()?.parse::<u32>()?)
+    });
+
+    // The server runs in another process.
+    let pid = ask()?;
+    assert_ne!(pid, std::process::id());
+
+    // The second client connects to the same server.
+    assert_eq!(ask()?, pid);
+    Ok(())
+}
+
+#[test]
+fn concurrent_first_connects() -> Result<()> {
+    test_util::serve_if_requested(factory);
+
+    const CLIENTS: usize = 24;
+
+    let exe = std::env::current_exe()?;
+    let ctx = ipc::Context::configure()
+        .ephemeral()
+        .lib(exe.parent().expect("test binary is in a directory"))
+        .ipc_policy(ipc::IPCPolicy::External)
+        .build()?;
+    let descriptor =
+        test_util::descriptor(&ctx, "concurrent_first_connects", factory)?;
+
+    // All clients race to start the server.
+    let barrier = std::sync::Arc::new(std::sync::Barrier::new(CLIENTS));
+    let pids = (0..CLIENTS).map(|_| {
+        let descriptor = descriptor.clone();
+        let barrier = barrier.clone();
+        std::thread::spawn(move || -> Result<u32> {
+            barrier.wait();
+            descriptor.with_client(|hello: hello::Client| async move {
+                let response = hello.hello_request().send().promise.await?;
+                Ok(response.get()?.get_response()?.to_str()?.parse::<u32>()?)
+            })
+        })
+    }).collect::<Vec<_>>().into_iter()
+        .map(|t| t.join().expect("no panic"))
+        .collect::<Result<Vec<_>>>()?;
+
+    // They converge on exactly one server.
+    assert!(pids.iter().all(|pid| *pid == pids[0]), "{:?}", pids);
+    assert_ne!(pids[0], std::process::id());
+    Ok(())
+}